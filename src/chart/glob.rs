@@ -0,0 +1,140 @@
+// This module matches file names against shell patterns, for both
+// .helmignore rules and templates' .Files.Glob. Names are paths separated by
+// '/':
+//
+//   *      matches any run of characters other than '/'
+//   **     matches any run of characters, '/' included
+//   ?      matches any one character other than '/'
+//   [a-z]  matches a class of characters, negated by a leading '^' or '!'
+//   \*     matches the next character as it is
+
+// Returns whether the whole of name matches pattern. Malformed patterns
+// never match, and can be checked for with is_valid_pattern
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&pattern, &name)
+}
+
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) if rest.first() == Some(&'*') => {
+            (0..=name.len()).any(|i| match_chars(&rest[1..], &name[i..]))
+        },
+        Some(('*', rest)) => {
+            let mut i = 0;
+            loop {
+                if match_chars(rest, &name[i..]) {
+                    return true
+                }
+                if i == name.len() || name[i] == '/' {
+                    return false
+                }
+                i += 1;
+            }
+        },
+        Some(('?', rest)) => match name.split_first() {
+            Some((c, name)) if *c != '/' => match_chars(rest, name),
+            _ => false,
+        },
+        Some(('[', rest)) => match name.split_first() {
+            Some((c, name)) if *c != '/' => match match_class(rest, *c) {
+                Some((true, len)) => match_chars(&rest[len..], name),
+                _ => false,
+            },
+            _ => false,
+        },
+        Some(('\\', rest)) => match (rest.split_first(), name.split_first()) {
+            (Some((e, rest)), Some((c, name))) if e == c => match_chars(rest, name),
+            _ => false,
+        },
+        Some((l, rest)) => match name.split_first() {
+            Some((c, name)) if c == l => match_chars(rest, name),
+            _ => false,
+        },
+    }
+}
+
+// Matches c against the character class that starts just after a '['. Returns
+// whether it matched and how much of the pattern the class took up, or None
+// if the class is malformed
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negate = matches!(pattern.first(), Some('^') | Some('!'));
+    let mut i = if negate { 1 } else { 0 };
+    let mut matched = false;
+    let mut ranges = 0;
+    loop {
+        if pattern.get(i)? == &']' && ranges > 0 {
+            return Some((matched != negate, i + 1))
+        }
+        let (lo, len) = class_char(&pattern[i..])?;
+        i += len;
+        let mut hi = lo;
+        if pattern.get(i) == Some(&'-') {
+            let (h, len) = class_char(&pattern[i + 1..])?;
+            hi = h;
+            i += 1 + len;
+        }
+        if lo <= c && c <= hi {
+            matched = true;
+        }
+        ranges += 1;
+    }
+}
+
+fn class_char(pattern: &[char]) -> Option<(char, usize)> {
+    match pattern {
+        [] | ['-', ..] | [']', ..] | ['\\'] => None,
+        ['\\', c, ..] => Some((*c, 2)),
+        [c, ..] => Some((*c, 1)),
+    }
+}
+
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            '\\' if i + 1 == pattern.len() => { return false },
+            '\\' => i += 2,
+            '[' => match match_class(&pattern[i + 1..], 'a') {
+                Some((_, len)) => i += 1 + len,
+                None => { return false },
+            },
+            _ => i += 1,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let cases = [
+            ("*.yaml", "values.yaml", true),
+            ("*.yaml", "templates/cm.yaml", false),
+            ("templates/*", "templates/cm.yaml", true),
+            ("**.yaml", "templates/sub/cm.yaml", true),
+            ("files/**", "files/a/b.txt", true),
+            ("files/**/b.txt", "files/a/b.txt", true),
+            ("?.txt", "a.txt", true),
+            ("?.txt", "/.txt", false),
+            ("[a-c].txt", "b.txt", true),
+            ("[^a-c].txt", "b.txt", false),
+            ("[!a-c].txt", "d.txt", true),
+            ("\\*.txt", "*.txt", true),
+            ("\\*.txt", "a.txt", false),
+            ("[a-", "a", false),
+        ];
+        for (pattern, name, want) in cases.iter() {
+            assert_eq!(glob_match(pattern, name), *want, "{} against {}", pattern, name);
+        }
+        assert!(is_valid_pattern("[a-c]*.txt"));
+        assert!(!is_valid_pattern("[a-"));
+        assert!(!is_valid_pattern("a\\"));
+    }
+}
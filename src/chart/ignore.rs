@@ -0,0 +1,186 @@
+// This module reads .helmignore files, which list the files in a chart's
+// directory to leave out when it is loaded or packaged. Rules behave the same
+// as Helm's, which are like .gitignore rules without ** support:
+//
+//   # comment
+//   *.bak          matches the file name in any directory
+//   docs/*.md      matches the path from the chart's directory
+//   /secrets.yaml  also matches the path, written to look rooted
+//   tmp/           only matches directories
+//   !keep.bak      negates the rule
+use crate::chart::ChartError;
+use crate::chart::glob::{glob_match, is_valid_pattern};
+
+pub const HELM_IGNORE: &str = ".helmignore";
+
+// Hidden files in templates/ are always ignored, as editors leave swap files
+// there
+const DEFAULT_RULES: &[&str] = &["templates/.?*"];
+
+#[derive(Debug, Default)]
+pub struct Rules {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug)]
+struct Pattern {
+    pattern: String,
+    negate: bool,
+    // The pattern only matches directories
+    must_dir: bool,
+    // The pattern is matched against the whole path rather than the last
+    // part of it
+    match_path: bool,
+}
+
+impl Pattern {
+    fn matches(&self, path: &str) -> bool {
+        let name = if self.match_path {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        glob_match(&self.pattern, name)
+    }
+}
+
+impl Rules {
+    // Parses the contents of a .helmignore file
+    pub fn parse(data: &str) -> Result<Rules, ChartError> {
+        let mut rules = Rules::default();
+        for line in data.lines() {
+            rules.add(line)?;
+        }
+        Ok(rules)
+    }
+
+    pub fn add_defaults(&mut self) {
+        for rule in DEFAULT_RULES.iter() {
+            self.add(rule).expect("default .helmignore rules are valid");
+        }
+    }
+
+    fn add(&mut self, rule: &str) -> Result<(), ChartError> {
+        let rule = rule.trim();
+        if rule.is_empty() || rule.starts_with('#') {
+            return Ok(())
+        }
+        let invalid = |reason: &str| Err(ChartError::InvalidIgnoreRule{rule: rule.to_string(), reason: reason.to_string()});
+        if rule.contains("**") {
+            return invalid("** is not supported")
+        }
+        if !is_valid_pattern(rule) {
+            return invalid("the pattern is malformed")
+        }
+        if rule == "!" {
+            return invalid("there is nothing to negate")
+        }
+        let (negate, pattern) = match rule.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, rule),
+        };
+        let (must_dir, pattern) = match pattern.strip_suffix('/') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        let match_path = pattern.contains('/');
+        self.patterns.push(Pattern {
+            pattern: pattern.trim_start_matches('/').to_string(),
+            negate,
+            must_dir,
+            match_path,
+        });
+        Ok(())
+    }
+
+    // Returns whether the file or directory at `path`, relative to the chart's
+    // directory and separated by '/', should be ignored. Ignoring a directory
+    // ignores everything in it.
+    //
+    // Like Helm, a negated rule ignores every path it does not match, so it is
+    // only useful after rules that ignore the paths it matches
+    pub fn ignore(&self, path: &str, is_dir: bool) -> bool {
+        if path.is_empty() || path == "." || path == "./" {
+            return false
+        }
+        for p in self.patterns.iter() {
+            if p.negate {
+                if (p.must_dir && !is_dir) || !p.matches(path) {
+                    return true
+                }
+                continue
+            }
+            if p.must_dir && !is_dir {
+                continue
+            }
+            if p.matches(path) {
+                return true
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let rules = Rules::parse("# comment\n\n*.bak\ndocs/*.md\n/secrets.yaml\ntmp/\n").unwrap();
+        let cases = &[
+            ("backup.bak", false, true),
+            ("templates/backup.bak", false, true),
+            ("docs/README.md", false, true),
+            ("docs/api/README.md", false, false),
+            ("README.md", false, false),
+            ("secrets.yaml", false, true),
+            ("templates/secrets.yaml", false, false),
+            ("tmp", true, true),
+            ("templates/tmp", true, true),
+            // Directory rules don't match files
+            ("tmp", false, false),
+            ("values.yaml", false, false),
+            ("", true, false),
+            (".", true, false),
+        ];
+        for (path, is_dir, ignored) in cases.iter() {
+            assert_eq!(rules.ignore(path, *is_dir), *ignored, "{} (directory: {})", path, is_dir);
+        }
+    }
+
+    #[test]
+    fn negation() {
+        // Like Helm, a negated rule ignores everything it doesn't match
+        let rules = Rules::parse("!*.yaml").unwrap();
+        assert!(!rules.ignore("values.yaml", false));
+        assert!(rules.ignore("README.md", false));
+
+        let rules = Rules::parse("!templates/").unwrap();
+        assert!(!rules.ignore("templates", true));
+        assert!(rules.ignore("templates", false));
+        assert!(rules.ignore("docs", true));
+
+        // A rule before the negation still ignores what it matches
+        let rules = Rules::parse("*.bak\n!*.yaml").unwrap();
+        assert!(rules.ignore("values.yaml.bak", false));
+        assert!(!rules.ignore("values.yaml", false));
+    }
+
+    #[test]
+    fn defaults() {
+        let mut rules = Rules::default();
+        rules.add_defaults();
+        assert!(rules.ignore("templates/.deployment.yaml.swp", false));
+        assert!(!rules.ignore("templates/deployment.yaml", false));
+        assert!(!rules.ignore(".helmignore", false));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in &["**/*.bak", "docs/**", "!", "[a-"] {
+            let err = Rules::parse(rule).unwrap_err();
+            assert!(matches!(err, ChartError::InvalidIgnoreRule{..}), "{}: {}", rule, err);
+        }
+    }
+}
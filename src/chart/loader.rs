@@ -0,0 +1,493 @@
+// This module loads charts, either from a directory or from a chart archive: a
+// gzipped tarball holding the chart in a single top-level directory. Both are
+// read into a list of files first, which load_files turns into a chart the
+// same way Helm does
+use crate::chart::*;
+use crate::chart::ignore::{Rules, HELM_IGNORE};
+use crate::chart::metadata::{API_VERSION_V1, Dependency, Metadata};
+use flate2::read::GzDecoder;
+use log::warn;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Editors on Windows like to start files with one
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+// Like Helm, archives are only decompressed up to these sizes so that a small
+// archive can't use up all memory. The limit for the whole chart includes the
+// archives of any subcharts in it
+pub const MAX_DECOMPRESSED_CHART_SIZE: u64 = 100 * 1024 * 1024;
+pub const MAX_DECOMPRESSED_FILE_SIZE: u64 = 5 * 1024 * 1024;
+
+// The part of requirements.yaml that is kept
+#[derive(Deserialize, Default)]
+struct Requirements {
+    #[serde(default)]
+    dependencies: Vec<Dependency>,
+}
+
+fn io_error(path: &Path, error: io::Error) -> ChartError {
+    ChartError::Io{path: path.display().to_string(), error}
+}
+
+fn strip_bom(mut data: Vec<u8>) -> Vec<u8> {
+    if data.starts_with(UTF8_BOM) {
+        data.drain(..UTF8_BOM.len());
+    }
+    data
+}
+
+// Parses a YAML file that may be empty, or only hold comments, as charts'
+// values.yaml often does
+pub(crate) fn parse_yaml<T: DeserializeOwned + Default>(data: &[u8]) -> Result<T, ChartError> {
+    let is_empty = String::from_utf8_lossy(data).lines()
+        .map(|l| l.trim())
+        .all(|l| l.is_empty() || l.starts_with('#') || l == "---");
+    if is_empty {
+        return Ok(T::default())
+    }
+    let parsed: Option<T> = serde_yaml::from_slice(data).map_err(ChartError::ParseError)?;
+    Ok(parsed.unwrap_or_default())
+}
+
+// Loads a chart from either a directory or a chart archive
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Chart, ChartError> {
+    let path = path.as_ref();
+    let meta = fs::metadata(path).map_err(|e| io_error(path, e))?;
+    if meta.is_dir() {
+        return load_dir(path)
+    }
+    let data = fs::read(path).map_err(|e| io_error(path, e))?;
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Err(ChartError::InvalidArchive{message: format!("{} is not a gzipped archive", path.display())})
+    }
+    load_archive(data.as_slice())
+}
+
+// Loads a chart from a directory, leaving out the files its .helmignore
+// ignores
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Chart, ChartError> {
+    let dir = dir.as_ref();
+    let ignore_path = dir.join(HELM_IGNORE);
+    let mut rules = match fs::read_to_string(&ignore_path) {
+        Ok(data) => Rules::parse(&data).map_err(|e| e.in_file(HELM_IGNORE))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Rules::default(),
+        Err(e) => { return Err(io_error(&ignore_path, e)) }
+    };
+    rules.add_defaults();
+
+    let mut files: Vec<File> = Vec::new();
+    walk(dir, "", &rules, &mut Vec::new(), &mut files)?;
+    load_files(files)
+}
+
+// Reads every file under dir that isn't ignored, naming each by its path
+// relative to the chart. Entries are read in order of name so that charts
+// always load the same way. `parents` holds the directories being walked, to
+// catch symlinks that would otherwise be followed forever
+fn walk(dir: &Path, prefix: &str, rules: &Rules, parents: &mut Vec<PathBuf>, files: &mut Vec<File>) -> Result<(), ChartError> {
+    let canonical = fs::canonicalize(dir).map_err(|e| io_error(dir, e))?;
+    if parents.contains(&canonical) {
+        return Err(ChartError::SymlinkCycle{path: dir.display().to_string()})
+    }
+    parents.push(canonical);
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<Result<Vec<fs::DirEntry>, io::Error>>())
+        .map_err(|e| io_error(dir, e))?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries.into_iter() {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let path = entry.path();
+        // Symlinks are followed, as they are by Helm
+        let meta = fs::metadata(&path).map_err(|e| io_error(&path, e))?;
+        if meta.is_dir() {
+            if !rules.ignore(&name, true) {
+                walk(&path, &format!("{}/", name), rules, parents, files)?;
+            }
+            continue
+        }
+        if rules.ignore(&name, false) {
+            continue
+        }
+        if !meta.is_file() {
+            return Err(ChartError::IrregularFile{path: path.display().to_string()})
+        }
+        let data = fs::read(&path).map_err(|e| io_error(&path, e))?;
+        files.push(File{name, data: strip_bom(data)});
+    }
+    parents.pop();
+    Ok(())
+}
+
+// Loads a chart from a gzipped tarball, such as one made by `helm package`
+pub fn load_archive<R: Read>(r: R) -> Result<Chart, ChartError> {
+    let mut remaining = MAX_DECOMPRESSED_CHART_SIZE;
+    load_archive_within(r, &mut remaining)
+}
+
+// Loads a chart archive, failing if it decompresses to more than `remaining`
+// bytes, which is then reduced by what it did decompress to
+fn load_archive_within<R: Read>(r: R, remaining: &mut u64) -> Result<Chart, ChartError> {
+    let invalid = |message: String| ChartError::InvalidArchive{message};
+    let mut files: Vec<File> = Vec::new();
+    let mut archive = tar::Archive::new(GzDecoder::new(r));
+    for entry in archive.entries().map_err(|e| invalid(e.to_string()))? {
+        let mut entry = entry.map_err(|e| invalid(e.to_string()))?;
+        // Directories are implied by the files in them, and the pax headers
+        // some tools write aren't files at all
+        if !entry.header().entry_type().is_file() {
+            continue
+        }
+        let raw = String::from_utf8_lossy(&entry.path_bytes()).replace('\\', "/");
+        let name = archive_path(&raw).map_err(invalid)?;
+        // One byte more than the limit is read to tell a file that is too
+        // large apart from one that is exactly at the limit
+        let limit = MAX_DECOMPRESSED_FILE_SIZE.min(*remaining);
+        let mut data = Vec::new();
+        (&mut entry).take(limit + 1).read_to_end(&mut data).map_err(|e| invalid(format!("unable to read {}: {}", raw, e)))?;
+        let size = data.len() as u64;
+        if size > MAX_DECOMPRESSED_FILE_SIZE {
+            return Err(invalid(format!("{} is larger than the limit of {} bytes", raw, MAX_DECOMPRESSED_FILE_SIZE)))
+        }
+        if size > *remaining {
+            return Err(invalid(format!("chart is larger than the limit of {} bytes once decompressed", MAX_DECOMPRESSED_CHART_SIZE)))
+        }
+        *remaining -= size;
+        files.push(File{name, data: strip_bom(data)});
+    }
+    if files.is_empty() {
+        return Err(invalid("no files in chart archive".to_string()))
+    }
+    load_files_within(files, remaining)
+}
+
+// Turns the path of a file in a chart archive into its path in the chart by
+// dropping the top-level directory. Paths that would end up outside the
+// chart are rejected
+fn archive_path(raw: &str) -> Result<String, String> {
+    if raw.starts_with('/') {
+        return Err(format!("{} is an absolute path", raw))
+    }
+    let mut parts = raw.splitn(2, '/');
+    let top = parts.next().unwrap_or("");
+    if top == CHART_FILE {
+        return Err(format!("{} is not in the chart's directory", CHART_FILE))
+    }
+    let name = clean_path(parts.next().unwrap_or(top));
+    if name == "." {
+        return Err(format!("{} is outside the chart's directory", raw))
+    }
+    if name == ".." || name.starts_with("../") {
+        return Err(format!("{} refers to a parent directory", raw))
+    }
+    // Mixing Windows and Unix separators can sneak a drive letter past the
+    // checks above
+    let bytes = name.as_bytes();
+    if bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'/' {
+        return Err(format!("{} is an absolute path", raw))
+    }
+    Ok(name)
+}
+
+// Resolves the "." and ".." parts of a relative path, like Go's path.Clean
+fn clean_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." if !parts.is_empty() && parts[parts.len() - 1] != ".." => { parts.pop(); },
+            p => parts.push(p),
+        }
+    }
+    if parts.is_empty() {
+        return ".".to_string()
+    }
+    parts.join("/")
+}
+
+// Builds a chart out of its files, named by their paths relative to the
+// chart. Charts in charts/, either as directories or archives, are loaded as
+// the chart's dependencies
+pub fn load_files(files: Vec<File>) -> Result<Chart, ChartError> {
+    let mut remaining = MAX_DECOMPRESSED_CHART_SIZE;
+    load_files_within(files, &mut remaining)
+}
+
+// Like load_files, with subchart archives decompressing to no more than
+// `remaining` bytes between them
+fn load_files_within(files: Vec<File>, remaining: &mut u64) -> Result<Chart, ChartError> {
+    // Chart.yaml is read first, as the apiVersion decides how
+    // requirements.yaml is treated
+    let mut metadata: Metadata = match files.iter().find(|f| f.name == CHART_FILE) {
+        Some(f) => parse_yaml(&f.data).map_err(|e| e.in_file(CHART_FILE))?,
+        None => { return Err(ChartError::MissingFile{name: CHART_FILE.to_string()}) }
+    };
+    metadata.set_defaults();
+    let is_v1 = metadata.api_version == API_VERSION_V1;
+
+    let mut chart = Chart{
+        raw: files.iter().filter(|f| f.name == VALUES_FILE).cloned().collect(),
+        ..Default::default()
+    };
+    let mut subcharts: BTreeMap<String, Vec<File>> = BTreeMap::new();
+    for f in files.into_iter() {
+        match f.name.as_str() {
+            CHART_FILE => (),
+            VALUES_FILE => chart.values = parse_yaml(&f.data).map_err(|e| e.in_file(VALUES_FILE))?,
+            SCHEMA_FILE => chart.schema = Some(f.data),
+            LOCK_FILE => chart.lock = Some(parse_lock(&f)?),
+            REQUIREMENTS_FILE => {
+                if !is_v1 {
+                    warn!("dependencies are listed in {} since apiVersion v2, but {} has a {}", CHART_FILE, metadata.name, REQUIREMENTS_FILE);
+                }
+                let requirements: Requirements = parse_yaml(&f.data).map_err(|e| e.in_file(REQUIREMENTS_FILE))?;
+                metadata.dependencies = requirements.dependencies;
+                if is_v1 {
+                    chart.files.push(f);
+                }
+            },
+            REQUIREMENTS_LOCK_FILE => {
+                chart.lock = Some(parse_lock(&f)?);
+                if is_v1 {
+                    chart.files.push(f);
+                }
+            },
+            n if n.starts_with(TEMPLATES_DIR) => chart.templates.push(f),
+            // Provenance files are kept alongside the charts they sign
+            n if n.starts_with(CHARTS_DIR) && !n.ends_with(".prov") => {
+                let name = n[CHARTS_DIR.len()..].to_string();
+                let subchart = name.split('/').next().unwrap_or("").to_string();
+                subcharts.entry(subchart).or_default().push(File{name, data: f.data});
+            },
+            _ => chart.files.push(f),
+        }
+    }
+    metadata.validate().map_err(|e| e.in_file(CHART_FILE))?;
+    chart.metadata = Some(metadata);
+
+    for (name, files) in subcharts.into_iter() {
+        // Lets charts/ hold things like a README without them being taken
+        // for charts
+        if name.starts_with('_') || name.starts_with('.') {
+            continue
+        }
+        let path = format!("{}{}", CHARTS_DIR, name);
+        let dep = if name.ends_with(".tgz") {
+            match files.iter().find(|f| f.name == name) {
+                Some(f) => load_archive_within(f.data.as_slice(), remaining),
+                None => Err(ChartError::InvalidArchive{message: "a directory can't be named like an archive".to_string()}),
+            }
+        } else {
+            let prefix = format!("{}/", name);
+            let files = files.into_iter()
+                .filter(|f| f.name.starts_with(&prefix))
+                .map(|f| File{name: f.name[prefix.len()..].to_string(), data: f.data})
+                .collect();
+            load_files_within(files, remaining)
+        };
+        chart.dependencies.push(dep.map_err(|e| e.in_file(&path))?);
+    }
+    Ok(chart)
+}
+
+fn parse_lock(f: &File) -> Result<Lock, ChartError> {
+    serde_yaml::from_slice(&f.data).map_err(|e| ChartError::ParseError(e).in_file(&f.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (name, data) in files.iter() {
+            let mut header = tar::Header::new_gnu();
+            // Set by hand rather than with set_path, which refuses paths
+            // with .. in them
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    const CHART: &[u8] = b"apiVersion: v2\nname: app\nversion: 1.0.0\n";
+
+    #[test]
+    fn archives_have_size_limits() {
+        let chart = load_archive(archive(&[("app/Chart.yaml", CHART)]).as_slice()).unwrap();
+        assert_eq!(chart.metadata.unwrap().name, "app");
+
+        let big = vec![0u8; MAX_DECOMPRESSED_FILE_SIZE as usize + 1];
+        let err = load_archive(archive(&[("app/Chart.yaml", CHART), ("app/big", &big)]).as_slice()).unwrap_err();
+        assert!(matches!(err, ChartError::InvalidArchive{..}), "{}", err);
+
+        // Files under the limit for a single file that add up to more than
+        // the limit for a chart, partly in a subchart archive
+        let part = vec![0u8; MAX_DECOMPRESSED_FILE_SIZE as usize];
+        let mut sub: Vec<(String, &[u8])> = vec![("sub/Chart.yaml".to_string(), b"apiVersion: v2\nname: sub\nversion: 1.0.0\n")];
+        for i in 0..15 {
+            sub.push((format!("sub/part{}", i), &part));
+        }
+        let sub: Vec<(&str, &[u8])> = sub.iter().map(|(n, d)| (n.as_str(), *d)).collect();
+        let sub = archive(&sub);
+        let mut files: Vec<(String, &[u8])> = vec![("app/Chart.yaml".to_string(), CHART), ("app/charts/sub.tgz".to_string(), &sub)];
+        for i in 0..6 {
+            files.push((format!("app/part{}", i), &part));
+        }
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(n, d)| (n.as_str(), *d)).collect();
+        let err = load_archive(archive(&files).as_slice()).unwrap_err();
+        assert!(err.to_string().contains("once decompressed"), "{}", err);
+    }
+
+    // A new directory holding the given files
+    fn chart_dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pilothouse-loader-{}-{}", name, std::process::id()));
+        for (path, data) in files.iter() {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        dir
+    }
+
+    fn file(name: &str, data: &[u8]) -> File {
+        File{name: name.to_string(), data: data.to_vec()}
+    }
+
+    fn names(files: &[File]) -> Vec<&str> {
+        files.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    fn dependency_names(chart: &Chart) -> Vec<String> {
+        chart.dependencies.iter().map(|d| d.metadata.as_ref().unwrap().name.clone()).collect()
+    }
+
+    const SUBCHART: &[u8] = b"apiVersion: v2\nname: sub\nversion: 1.0.0\n";
+
+    #[test]
+    fn loads_directories() {
+        let packed = archive(&[("packed/Chart.yaml", b"apiVersion: v2\nname: packed\nversion: 1.0.0\n")]);
+        let dir = chart_dir("dir", &[
+            ("Chart.yaml", CHART),
+            (".helmignore", b"*.bak\n"),
+            ("values.yaml", b"replicas: 2\n"),
+            ("README.md", b"# app\n"),
+            ("notes.bak", b""),
+            ("templates/deployment.yaml", b"kind: Deployment\n"),
+            ("templates/.deployment.yaml.swp", b""),
+            ("charts/sub/Chart.yaml", SUBCHART),
+            ("charts/sub/templates/service.yaml", b"kind: Service\n"),
+            ("charts/packed.tgz", &packed),
+        ]);
+        let res = load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let chart = res.unwrap();
+        assert_eq!(chart.metadata.as_ref().unwrap().name, "app");
+        assert_eq!(chart.values["replicas"], 2);
+        assert_eq!(names(&chart.templates), vec!["templates/deployment.yaml"]);
+        assert_eq!(names(&chart.files), vec![".helmignore", "README.md"]);
+        // Subcharts are loaded from directories and archives alike
+        assert_eq!(dependency_names(&chart), vec!["packed", "sub"]);
+        assert_eq!(names(&chart.dependencies[1].templates), vec!["templates/service.yaml"]);
+    }
+
+    #[test]
+    fn loads_archives() {
+        let packed = archive(&[("packed/Chart.yaml", b"apiVersion: v2\nname: packed\nversion: 1.0.0\n")]);
+        let data = archive(&[
+            ("app/Chart.yaml", CHART),
+            ("app/values.yaml", b"\xef\xbb\xbfreplicas: 2\n"),
+            ("app/templates/deployment.yaml", b"kind: Deployment\n"),
+            ("app/charts/sub/Chart.yaml", SUBCHART),
+            ("app/charts/packed.tgz", &packed),
+            ("app/charts/packed.tgz.prov", b"signature"),
+        ]);
+        let chart = load_archive(data.as_slice()).unwrap();
+        assert_eq!(chart.metadata.as_ref().unwrap().name, "app");
+        // The byte order mark is dropped
+        assert_eq!(chart.values["replicas"], 2);
+        assert_eq!(names(&chart.templates), vec!["templates/deployment.yaml"]);
+        assert_eq!(names(&chart.files), vec!["charts/packed.tgz.prov"]);
+        assert_eq!(dependency_names(&chart), vec!["packed", "sub"]);
+    }
+
+    #[test]
+    fn v1_requirements() {
+        let chart = load_files(vec![
+            file(CHART_FILE, b"apiVersion: v1\nname: app\nversion: 1.0.0\n"),
+            file(REQUIREMENTS_FILE, b"dependencies:\n- name: sub\n  version: ^1.0.0\n  repository: https://example.com/charts\n"),
+            file(REQUIREMENTS_LOCK_FILE, b"generated: 2019-01-01T00:00:00Z\ndigest: sha256:0\ndependencies:\n- name: sub\n  version: 1.0.0\n  repository: https://example.com/charts\n"),
+            file("charts/sub/Chart.yaml", SUBCHART),
+        ]).unwrap();
+        let metadata = chart.metadata.as_ref().unwrap();
+        assert_eq!(metadata.dependencies.len(), 1);
+        assert_eq!(metadata.dependencies[0].name, "sub");
+        assert_eq!(metadata.dependencies[0].version, "^1.0.0");
+        assert_eq!(chart.lock.as_ref().unwrap().dependencies[0].version, "1.0.0");
+        // Helm 2 kept these with the chart's other files
+        assert_eq!(names(&chart.files), vec![REQUIREMENTS_FILE, REQUIREMENTS_LOCK_FILE]);
+        assert_eq!(dependency_names(&chart), vec!["sub"]);
+    }
+
+    #[test]
+    fn load_errors() {
+        let err = load_files(vec![file(VALUES_FILE, b"")]).unwrap_err();
+        assert!(matches!(err, ChartError::MissingFile{ref name} if name == CHART_FILE), "{}", err);
+
+        let err = load_files(vec![file(CHART_FILE, b"name: [app\n")]).unwrap_err();
+        assert!(matches!(err, ChartError::InFile{ref path, ref error} if path == CHART_FILE && matches!(**error, ChartError::ParseError(_))), "{}", err);
+
+        let err = load_files(vec![file(CHART_FILE, CHART), file(VALUES_FILE, b"replicas: : 2\n")]).unwrap_err();
+        assert!(matches!(err, ChartError::InFile{ref path, ..} if path == VALUES_FILE), "{}", err);
+
+        // Errors in subcharts name the file within the parent chart
+        let err = load_files(vec![file(CHART_FILE, CHART), file("charts/sub/values.yaml", b"")]).unwrap_err();
+        assert_eq!(err.to_string(), "charts/sub: Chart.yaml is missing");
+
+        let err = load_archive(archive(&[("app/Chart.yaml", CHART), ("app/../../etc/passwd", b"")]).as_slice()).unwrap_err();
+        assert!(matches!(err, ChartError::InvalidArchive{..}), "{}", err);
+    }
+
+    #[test]
+    fn archive_paths() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("app/Chart.yaml", Some("Chart.yaml")),
+            ("app/templates/deployment.yaml", Some("templates/deployment.yaml")),
+            ("app/./templates//deployment.yaml", Some("templates/deployment.yaml")),
+            ("app/templates/../values.yaml", Some("values.yaml")),
+            ("Chart.yaml", None),
+            ("/app/Chart.yaml", None),
+            ("app/..", None),
+            ("app/../Chart.yaml", None),
+            ("app/templates/../../../etc/passwd", None),
+            ("app/.", None),
+            ("app/c:/Windows/system.ini", None),
+        ];
+        for (raw, expected) in cases.iter() {
+            assert_eq!(archive_path(raw).ok().as_deref(), *expected, "{}", raw);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_cycles_are_rejected() {
+        let dir = chart_dir("cycle", &[]);
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::write(dir.join(CHART_FILE), CHART).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("templates/loop")).unwrap();
+        let res = load_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(res, Err(ChartError::SymlinkCycle{..})), "{:?}", res.err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::chart::ChartError;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+// Charts for Helm 2 and later. Their dependencies are listed in a separate
+// requirements.yaml rather than in Chart.yaml
+pub const API_VERSION_V1: &str = "v1";
+// Charts that need Helm 3
+pub const API_VERSION_V2: &str = "v2";
+
+// Metadata is the contents of Chart.yaml. The field names and the fields left
+// out when empty match Helm's, so that the metadata of a release written by
+// Helm comes back out the same
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    // The URL of the project's home page
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub home: String,
+    // URLs of the source code for the project
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    // A SemVer 2 version of the chart
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub version: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintainers: Vec<Maintainer>,
+    // The URL of an icon for the chart
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub icon: String,
+    // Either API_VERSION_V1 or API_VERSION_V2
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub api_version: String,
+    // The path to a value that enables or disables the chart when it is a
+    // dependency of another
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub condition: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub tags: String,
+    // The version of the app the chart installs. It doesn't need to be
+    // SemVer
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub app_version: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deprecated: bool,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+    // A SemVer range of the Kubernetes versions the chart works with
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub kube_version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<Dependency>,
+    // Either "application" (the default when empty) or "library"
+    #[serde(default, rename = "type", skip_serializing_if = "String::is_empty")]
+    pub chart_type: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Maintainer {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub email: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub url: String,
+}
+
+// A chart that this chart depends on, from Chart.yaml (or requirements.yaml
+// for v1 charts)
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Dependency {
+    #[serde(default)]
+    pub name: String,
+    // A SemVer range of versions that can be used
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub version: String,
+    // The URL of the repository the chart comes from
+    #[serde(default)]
+    pub repository: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub condition: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Set when the dependency is resolved, from its condition and tags
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub enabled: bool,
+    // Values to copy from the dependency into the parent. Each is either the
+    // name of an exported value or a map with child and parent keys
+    #[serde(default, rename = "import-values", skip_serializing_if = "Vec::is_empty")]
+    pub import_values: Vec<Value>,
+    // Installs the dependency under this name instead of its own
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub alias: String,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Metadata {
+    // Parses and validates the contents of a Chart.yaml file
+    pub fn from_yaml(data: &str) -> Result<Metadata, ChartError> {
+        let mut metadata: Metadata = serde_yaml::from_str(data).map_err(ChartError::ParseError)?;
+        metadata.set_defaults();
+        metadata.validate()?;
+        Ok(metadata)
+    }
+
+    // Fills in what Helm assumes when it is left out. Charts from before
+    // Helm 3 may not have an apiVersion, and are v1 charts
+    pub fn set_defaults(&mut self) {
+        if self.api_version.is_empty() {
+            self.api_version = API_VERSION_V1.to_string();
+        }
+    }
+
+    // Checks the metadata the same way Helm does before using a chart
+    pub fn validate(&self) -> Result<(), ChartError> {
+        let invalid = |message: String| Err(ChartError::InvalidMetadata{message});
+        match self.api_version.as_str() {
+            "" => { return invalid("apiVersion is required".to_string()) },
+            API_VERSION_V1 | API_VERSION_V2 => (),
+            v => { return invalid(format!("unsupported apiVersion {}", v)) },
+        }
+        if self.name.is_empty() {
+            return invalid("name is required".to_string())
+        }
+        if self.version.is_empty() {
+            return invalid("version is required".to_string())
+        }
+        if !is_semver(&self.version) {
+            return invalid(format!("version {} is not a valid SemVer", self.version))
+        }
+        match self.chart_type.as_str() {
+            "" | "application" | "library" => (),
+            t => { return invalid(format!("chart type {} is not valid", t)) },
+        }
+        // Dependencies are installed under their alias or name, so these
+        // have to be unique
+        let mut names: HashSet<&str> = HashSet::new();
+        for dep in self.dependencies.iter() {
+            if dep.name.is_empty() {
+                return invalid("dependencies must have a name".to_string())
+            }
+            let name = if dep.alias.is_empty() { &dep.name } else { &dep.alias };
+            if !names.insert(name) {
+                return invalid(format!("more than one dependency is named {}", name))
+            }
+        }
+        Ok(())
+    }
+
+    // Library charts only hold templates for other charts to use, and can't
+    // be installed
+    pub fn is_library(&self) -> bool {
+        self.chart_type == "library"
+    }
+}
+
+// Checks for a version such as 1.2.3, 1.2.3-rc.1 or 1.2.3+build. Like Helm,
+// a leading "v" is allowed and the minor and patch numbers may be left out,
+// so 1 and 1.2 are valid too
+fn is_semver(version: &str) -> bool {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let (version, build) = match version.find('+') {
+        Some(i) => (&version[..i], Some(&version[i + 1..])),
+        None => (version, None),
+    };
+    let (core, pre) = match version.find('-') {
+        Some(i) => (&version[..i], Some(&version[i + 1..])),
+        None => (version, None),
+    };
+    let numbers: Vec<&str> = core.split('.').collect();
+    if numbers.len() > 3 || !numbers.iter().all(|n| is_number(n) && n.parse::<u64>().is_ok()) {
+        return false
+    }
+    let is_ident = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    // Numeric pre-release identifiers can't have leading zeros, though the
+    // version numbers themselves can
+    let is_pre_ident = |s: &str| is_ident(s) && !(is_number(s) && s.len() > 1 && s.starts_with('0'));
+    pre.map(|p| p.split('.').all(is_pre_ident)).unwrap_or(true) && build.map(|b| b.split('.').all(is_ident)).unwrap_or(true)
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_checked_like_helm() {
+        for v in ["1", "1.2", "1.2.3", "v1.2.3", "01.2.3", "1.2.3-rc.1", "1.2-beta", "1.2.3+build.5", "1.2.3-0a"].iter() {
+            assert!(is_semver(v), "{} should be valid", v);
+        }
+        for v in ["", "v", "1.2.3.4", "1..3", "a.b.c", "1.2.3-", "1.2.3-01", "1.2.3+", "1.2.3-rc_1", "99999999999999999999"].iter() {
+            assert!(!is_semver(v), "{} should be invalid", v);
+        }
+    }
+
+    #[test]
+    fn missing_api_version_defaults_to_v1() {
+        let metadata = Metadata::from_yaml("name: app\nversion: 1.0\n").unwrap();
+        assert_eq!(metadata.api_version, API_VERSION_V1);
+        assert!(Metadata::from_yaml("apiVersion: v3\nname: app\nversion: 1.0.0\n").is_err());
+    }
+}
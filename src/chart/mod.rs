@@ -0,0 +1,228 @@
+// This module contains the chart a release was installed from. The JSON field
+// names match Helm 3's, where the contents of files are base64 encoded
+pub mod glob;
+pub mod ignore;
+pub mod loader;
+pub mod metadata;
+pub mod package;
+
+use chrono::{DateTime, Utc};
+use metadata::{Dependency, Metadata};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+
+// The files in a chart with a meaning of their own. Everything else is either
+// a template or one of the chart's files
+pub const CHART_FILE: &str = "Chart.yaml";
+pub const VALUES_FILE: &str = "values.yaml";
+pub const SCHEMA_FILE: &str = "values.schema.json";
+pub const LOCK_FILE: &str = "Chart.lock";
+// Where v1 charts list their dependencies, and the lock for them
+pub const REQUIREMENTS_FILE: &str = "requirements.yaml";
+pub const REQUIREMENTS_LOCK_FILE: &str = "requirements.lock";
+pub const TEMPLATES_DIR: &str = "templates/";
+pub const CHARTS_DIR: &str = "charts/";
+pub const CRDS_DIR: &str = "crds/";
+
+#[derive(Debug, Fail)]
+pub enum ChartError {
+    #[fail(display = "invalid YAML: {}", _0)]
+    ParseError(#[fail(cause)] serde_yaml::Error),
+    #[fail(display = "invalid chart metadata: {}", message)]
+    InvalidMetadata {
+        message: String,
+    },
+    #[fail(display = "unable to read {}: {}", path, error)]
+    Io {
+        path: String,
+        #[fail(cause)]
+        error: io::Error,
+    },
+    #[fail(display = "{} is missing", name)]
+    MissingFile {
+        name: String,
+    },
+    #[fail(display = "{} is not a regular file", path)]
+    IrregularFile {
+        path: String,
+    },
+    #[fail(display = "{} links to a directory it is in", path)]
+    SymlinkCycle {
+        path: String,
+    },
+    #[fail(display = "invalid JSON: {}", _0)]
+    InvalidJson(#[fail(cause)] serde_json::Error),
+    #[fail(display = "{} listed in Chart.yaml but missing from charts/", names)]
+    MissingDependencies {
+        names: String,
+    },
+    #[fail(display = "invalid chart archive: {}", message)]
+    InvalidArchive {
+        message: String,
+    },
+    #[fail(display = "invalid .helmignore rule {}: {}", rule, reason)]
+    InvalidIgnoreRule {
+        rule: String,
+        reason: String,
+    },
+    // Wraps an error with the path of the file in the chart it came from
+    #[fail(display = "{}: {}", path, error)]
+    InFile {
+        path: String,
+        #[fail(cause)]
+        error: Box<ChartError>,
+    },
+}
+
+impl ChartError {
+    // Wraps the error with the path it came from. Paths are relative to the
+    // chart, so wrapping an error that already has a path joins the two, as
+    // happens for errors in subcharts
+    pub fn in_file(self, path: &str) -> Self {
+        match self {
+            ChartError::InFile{path: inner, error} => ChartError::InFile{
+                path: format!("{}/{}", path.trim_end_matches('/'), inner),
+                error,
+            },
+            e => ChartError::InFile{
+                path: path.to_string(),
+                error: Box::new(e),
+            },
+        }
+    }
+}
+
+// Lets a boxed error be the cause of an InFile error
+impl failure::Fail for Box<ChartError> {
+    fn cause(&self) -> Option<&dyn failure::Fail> {
+        (**self).cause()
+    }
+
+    fn backtrace(&self) -> Option<&failure::Backtrace> {
+        (**self).backtrace()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Chart {
+    // The contents of Chart.yaml
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+    // The contents of Chart.lock (requirements.lock for v1 charts), if the
+    // chart's dependencies have been resolved
+    #[serde(default)]
+    pub lock: Option<Lock>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub templates: Vec<File>,
+    // The default values from values.yaml
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub values: HashMap<String, Value>,
+    // The JSON schema from values.schema.json, which values are checked
+    // against
+    #[serde(default, with = "base64_option")]
+    pub schema: Option<Vec<u8>>,
+    // Every other file in the chart, such as README.md or files read by
+    // templates
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub files: Vec<File>,
+    // The charts in charts/. Like Helm, these aren't kept with a release, as
+    // they are installed with the chart that depends on them
+    #[serde(skip)]
+    pub dependencies: Vec<Chart>,
+    // The files the chart was loaded from, as they were read. Only
+    // values.yaml is kept, which packaging writes back with its comments
+    // intact as long as the values haven't been changed since
+    #[serde(skip)]
+    pub raw: Vec<File>,
+}
+
+impl Chart {
+    // The name of the chart, or an empty string if it has no metadata
+    pub fn name(&self) -> &str {
+        self.metadata.as_ref().map(|m| m.name.as_str()).unwrap_or("")
+    }
+
+    pub fn version(&self) -> &str {
+        self.metadata.as_ref().map(|m| m.version.as_str()).unwrap_or("")
+    }
+
+    pub fn app_version(&self) -> &str {
+        self.metadata.as_ref().map(|m| m.app_version.as_str()).unwrap_or("")
+    }
+
+    // Returns the custom resource definitions in crds/ of the chart and its
+    // dependencies, which are installed before anything else
+    pub fn crds(&self) -> Vec<&File> {
+        let mut crds: Vec<&File> = self.files.iter().filter(|f| f.name.starts_with(CRDS_DIR)).collect();
+        for dep in self.dependencies.iter() {
+            crds.extend(dep.crds());
+        }
+        crds
+    }
+}
+
+// A file in a chart, named by its path relative to the chart's directory
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct File {
+    pub name: String,
+    #[serde(default, with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Lock {
+    pub generated: DateTime<Utc>,
+    // A hash of the dependencies in Chart.yaml, to tell whether the lock is
+    // out of date
+    pub digest: String,
+    // The exact versions the dependencies resolved to
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub dependencies: Vec<Dependency>,
+}
+
+// Helm writes empty lists and maps as null
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// Go encodes byte slices as base64 strings, or null when they are nil
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => base64::decode(&s).map_err(D::Error::custom),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+mod base64_option {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(data: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match data {
+            Some(d) => serializer.serialize_str(&base64::encode(d)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => base64::decode(&s).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
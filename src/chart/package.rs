@@ -0,0 +1,192 @@
+// This module packages charts into archives named <name>-<version>.tgz, laid
+// out the way `helm package` lays them out so Helm can install them:
+//
+//   <name>/Chart.yaml
+//   <name>/Chart.lock
+//   <name>/values.yaml
+//   <name>/values.schema.json
+//   <name>/templates/...
+//   <name>/<other files>
+//   <name>/charts/<dependency>/...
+use crate::chart::*;
+use crate::chart::loader::parse_yaml;
+use crate::chart::metadata::{API_VERSION_V1, API_VERSION_V2};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::debug;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+pub struct PackageOptions {
+    // Replaces the version in Chart.yaml, and so the archive's name
+    pub version: Option<String>,
+    // Replaces the appVersion in Chart.yaml
+    pub app_version: Option<String>,
+}
+
+// Loads the chart in dir and packages it into out_dir. Dependencies with a
+// file:// repository that aren't in charts/ yet are vendored from their
+// directory, relative to the chart's. Their version ranges aren't checked
+pub fn package_dir<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, out_dir: Q, opts: &PackageOptions) -> Result<PathBuf, ChartError> {
+    let dir = dir.as_ref();
+    let mut chart = loader::load_dir(dir)?;
+    let deps = chart.metadata.as_ref().map(|m| m.dependencies.clone()).unwrap_or_default();
+    for dep in deps.iter() {
+        if chart.dependencies.iter().any(|d| d.name() == dep.name) {
+            continue
+        }
+        let path = match dep.repository.strip_prefix("file://") {
+            Some(p) => dir.join(p),
+            None => continue,
+        };
+        debug!("vendoring {} from {}", dep.name, path.display());
+        let vendored = loader::load(&path).map_err(|e| e.in_file(&format!("{}{}", CHARTS_DIR, dep.name)))?;
+        chart.dependencies.push(vendored);
+    }
+    package(&chart, out_dir, opts)
+}
+
+// Writes the chart to <name>-<version>.tgz in out_dir, creating the directory
+// if it doesn't exist, and returns the path of the archive. Every dependency
+// in Chart.yaml has to be in the chart's dependencies
+pub fn package<P: AsRef<Path>>(chart: &Chart, out_dir: P, opts: &PackageOptions) -> Result<PathBuf, ChartError> {
+    let mut chart = chart.clone();
+    let metadata = chart.metadata.as_mut().ok_or_else(|| ChartError::MissingFile{name: CHART_FILE.to_string()})?;
+    if let Some(ref v) = opts.version {
+        metadata.version = v.clone();
+    }
+    if let Some(ref v) = opts.app_version {
+        metadata.app_version = v.clone();
+    }
+    metadata.validate().map_err(|e| e.in_file(CHART_FILE))?;
+    let vendored = &chart.dependencies;
+    let missing: Vec<&str> = metadata.dependencies.iter()
+        .filter(|dep| !vendored.iter().any(|d| d.name() == dep.name))
+        .map(|dep| dep.name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(ChartError::MissingDependencies{names: missing.join(", ")})
+    }
+
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir).map_err(|e| ChartError::Io{path: out_dir.display().to_string(), error: e})?;
+    let path = out_dir.join(format!("{}-{}.tgz", chart.name(), chart.version()));
+    debug!("packaging {} {} into {}", chart.name(), chart.version(), path.display());
+    let io_error = |e| ChartError::Io{path: path.display().to_string(), error: e};
+    let f = fs::File::create(&path).map_err(io_error)?;
+    // Don't leave a partial archive behind
+    if let Err(e) = write_archive(&chart, f) {
+        let _ = fs::remove_file(&path);
+        return Err(e)
+    }
+    Ok(path)
+}
+
+// Writes the chart to w as a gzipped tarball
+pub fn write_archive<W: Write>(chart: &Chart, w: W) -> Result<(), ChartError> {
+    let mtime = Utc::now().timestamp().max(0) as u64;
+    let mut archive = tar::Builder::new(GzEncoder::new(w, Compression::default()));
+    write_chart(&mut archive, chart, "", mtime)?;
+    archive.into_inner()
+        .and_then(|gz| gz.finish())
+        .map_err(|e| ChartError::InvalidArchive{message: format!("unable to write archive: {}", e)})?;
+    Ok(())
+}
+
+fn write_chart<W: Write>(archive: &mut tar::Builder<W>, chart: &Chart, prefix: &str, mtime: u64) -> Result<(), ChartError> {
+    let metadata = chart.metadata.as_ref().ok_or_else(|| ChartError::MissingFile{name: CHART_FILE.to_string()})?;
+    let base = format!("{}{}/", prefix, metadata.name);
+    let mut append = |name: &str, data: &[u8]| -> Result<(), ChartError> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{}{}", base, name), data)
+            .map_err(|e| ChartError::InvalidArchive{message: format!("unable to write {}{}: {}", base, name, e)})
+    };
+
+    // v1 charts keep their dependencies in requirements.yaml, which is one
+    // of their files
+    let mut chart_file = metadata.clone();
+    if chart_file.api_version == API_VERSION_V1 {
+        chart_file.dependencies.clear();
+    }
+    append(CHART_FILE, &to_yaml(&chart_file)?)?;
+    if let (API_VERSION_V2, Some(lock)) = (metadata.api_version.as_str(), chart.lock.as_ref()) {
+        append(LOCK_FILE, &to_yaml(lock)?)?;
+    }
+    // The values.yaml the chart was loaded from is only written if the values
+    // are still the ones in it, as they may have been changed since
+    let raw_values = chart.raw.iter()
+        .find(|f| f.name == VALUES_FILE)
+        .filter(|f| parse_yaml::<HashMap<String, Value>>(&f.data).map(|v| v == chart.values).unwrap_or(false));
+    match raw_values {
+        Some(f) => append(VALUES_FILE, &f.data)?,
+        None if !chart.values.is_empty() => append(VALUES_FILE, &to_yaml(&chart.values)?)?,
+        None => (),
+    }
+    if let Some(ref schema) = chart.schema {
+        serde_json::from_slice::<serde_json::Value>(schema).map_err(|e| ChartError::InvalidJson(e).in_file(SCHEMA_FILE))?;
+        append(SCHEMA_FILE, schema)?;
+    }
+    for f in chart.templates.iter().chain(chart.files.iter()) {
+        append(&f.name, &f.data)?;
+    }
+    for dep in chart.dependencies.iter() {
+        write_chart(archive, dep, &format!("{}{}", base, CHARTS_DIR), mtime)?;
+    }
+    Ok(())
+}
+
+fn to_yaml<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, ChartError> {
+    serde_yaml::to_vec(value).map_err(ChartError::ParseError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::loader::load_files;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    const VALUES: &str = "# The image to run\nimage: nginx\n";
+
+    fn chart() -> Chart {
+        load_files(vec![
+            File{name: CHART_FILE.to_string(), data: b"apiVersion: v2\nname: app\nversion: 1.0.0\n".to_vec()},
+            File{name: VALUES_FILE.to_string(), data: VALUES.as_bytes().to_vec()},
+            File{name: "templates/cm.yaml".to_string(), data: b"kind: ConfigMap\n".to_vec()},
+        ]).unwrap()
+    }
+
+    fn written_values(chart: &Chart) -> String {
+        let mut data = Vec::new();
+        write_archive(chart, &mut data).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(data.as_slice()));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().to_string_lossy() == "app/values.yaml" {
+                let mut values = String::new();
+                entry.read_to_string(&mut values).unwrap();
+                return values
+            }
+        }
+        panic!("no values.yaml in archive")
+    }
+
+    #[test]
+    fn values_are_written_as_loaded_unless_changed() {
+        let mut chart = chart();
+        assert_eq!(chart.raw.len(), 1);
+        assert_eq!(written_values(&chart), VALUES);
+
+        chart.values.insert("image".to_string(), "httpd".into());
+        let values = written_values(&chart);
+        assert!(!values.contains("# The image to run"), "{}", values);
+        let parsed: HashMap<String, Value> = serde_yaml::from_str(&values).unwrap();
+        assert_eq!(parsed, chart.values);
+    }
+}
@@ -0,0 +1,405 @@
+// This module runs parsed templates, following Go's text/template/exec. The
+// functions that need to run other templates, such as include and tpl, are
+// implemented here; the rest are in funcs
+use crate::engine::funcs;
+use crate::engine::parse::{self, Arg, Branch, Command, List, Node, Pipe};
+use crate::engine::value::*;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::rc::Rc;
+
+// How deeply templates can include each other, which is almost always only
+// reached by a template including itself. Helm allows 1000, but that would
+// overflow the stack of a thread with the default 2MB
+const MAX_DEPTH: usize = 100;
+
+// Functions that are handled here rather than in funcs
+const EXEC_FUNCS: &[&str] = &["and", "or", "include", "tpl", "required", "fail", "lookup"];
+
+pub fn is_func(name: &str) -> bool {
+    EXEC_FUNCS.contains(&name) || funcs::exists(name)
+}
+
+pub type Templates = HashMap<String, Rc<List>>;
+
+#[derive(Debug)]
+pub struct ExecError {
+    pub template: String,
+    pub line: usize,
+    pub message: String,
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+}
+
+pub struct State {
+    templates: Rc<Templates>,
+    // Errors on missing map keys rather than treating them as nil
+    strict: bool,
+    depth: usize,
+    // Where execution is, for errors
+    name: String,
+    line: usize,
+    vars: Vec<(String, Value)>,
+}
+
+impl State {
+    pub fn new(templates: Rc<Templates>, strict: bool) -> Self {
+        State {
+            templates,
+            strict,
+            depth: 0,
+            name: String::new(),
+            line: 0,
+            vars: Vec::new(),
+        }
+    }
+
+    fn error(&self, message: String) -> ExecError {
+        ExecError{template: self.name.clone(), line: self.line, message}
+    }
+
+    // Runs the named template with dot as its data and returns its output
+    pub fn execute(&mut self, name: &str, dot: Value) -> Result<String, ExecError> {
+        let list = match self.templates.get(name) {
+            Some(l) => l.clone(),
+            None => { return Err(self.error(format!("no template {:?} associated with template {:?}", name, self.name))) }
+        };
+        self.run(name, &list, dot)
+    }
+
+    fn run(&mut self, name: &str, list: &[Node], dot: Value) -> Result<String, ExecError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error(format!("rendering template has a nested reference name: {}", name)))
+        }
+        self.depth += 1;
+        let name = mem::replace(&mut self.name, name.to_string());
+        let line = self.line;
+        let vars = mem::replace(&mut self.vars, vec![("$".to_string(), dot.clone())]);
+        let mut out = String::new();
+        let res = self.walk(&dot, list, &mut out);
+        self.vars = vars;
+        self.line = line;
+        self.name = name;
+        self.depth -= 1;
+        res.map(|_| out)
+    }
+
+    fn walk(&mut self, dot: &Value, list: &[Node], out: &mut String) -> Result<Flow, ExecError> {
+        for node in list.iter() {
+            let flow = match node {
+                Node::Text(t) => { out.push_str(t); Flow::Normal },
+                Node::Action(pipe) => {
+                    self.line = pipe.line;
+                    let v = self.eval_pipe(dot, pipe)?;
+                    // Helm renders missing values as nothing, rather than
+                    // Go's "<no value>"
+                    if pipe.decl.is_empty() && !matches!(v, Value::Nil) {
+                        out.push_str(&v.to_string());
+                    }
+                    Flow::Normal
+                },
+                Node::If(b) => self.branch(dot, b, out, false)?,
+                Node::With(b) => self.branch(dot, b, out, true)?,
+                Node::Range(b) => self.range(dot, b, out)?,
+                Node::Template{line, name, pipe} => {
+                    self.line = *line;
+                    let data = match pipe {
+                        Some(p) => self.eval_pipe(dot, p)?,
+                        None => Value::Nil,
+                    };
+                    out.push_str(&self.execute(name, data)?);
+                    Flow::Normal
+                },
+                Node::Break(_) => Flow::Break,
+                Node::Continue(_) => Flow::Continue,
+            };
+            if !matches!(flow, Flow::Normal) {
+                return Ok(flow)
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    // Runs an if, or a with when `with` is set, which also sets dot
+    fn branch(&mut self, dot: &Value, b: &Branch, out: &mut String, with: bool) -> Result<Flow, ExecError> {
+        let mark = self.vars.len();
+        self.line = b.pipe.line;
+        let v = self.eval_pipe(dot, &b.pipe)?;
+        let res = if v.is_true() {
+            let dot = if with { &v } else { dot };
+            self.walk(dot, &b.list, out)
+        } else if let Some(ref else_list) = b.else_list {
+            self.walk(dot, else_list, out)
+        } else {
+            Ok(Flow::Normal)
+        };
+        self.vars.truncate(mark);
+        res
+    }
+
+    fn range(&mut self, dot: &Value, b: &Branch, out: &mut String) -> Result<Flow, ExecError> {
+        self.line = b.pipe.line;
+        let v = self.eval_cmds(dot, &b.pipe)?;
+        // Pairs of index or key, and element
+        let items: Vec<(Value, Value)> = match v {
+            Value::Nil => Vec::new(),
+            Value::List(l) => l.into_iter().enumerate().map(|(i, v)| (Value::Int(i as i64), v)).collect(),
+            Value::Map(m) => m.borrow().iter().map(|(k, v)| (Value::String(k.clone()), v.clone())).collect(),
+            Value::VersionSet(s) => s.iter().enumerate().map(|(i, v)| (Value::Int(i as i64), Value::String(v.clone()))).collect(),
+            Value::Int(n) => (0..n).map(|i| (Value::Int(i), Value::Int(i))).collect(),
+            v => { return Err(self.error(format!("range can't iterate over {}", v))) }
+        };
+        if items.is_empty() {
+            return match b.else_list {
+                Some(ref else_list) => self.walk(dot, else_list, out),
+                None => Ok(Flow::Normal),
+            }
+        }
+        let mark = self.vars.len();
+        for (key, elem) in items.into_iter() {
+            self.vars.truncate(mark);
+            let assigns: Vec<Value> = match b.pipe.decl.len() {
+                1 => vec![elem.clone()],
+                2 => vec![key, elem.clone()],
+                _ => Vec::new(),
+            };
+            for (name, v) in b.pipe.decl.iter().zip(assigns) {
+                self.declare(name, v, b.pipe.is_assign)?;
+            }
+            if let Flow::Break = self.walk(&elem, &b.list, out)? {
+                break
+            }
+        }
+        self.vars.truncate(mark);
+        Ok(Flow::Normal)
+    }
+
+    fn declare(&mut self, name: &str, v: Value, is_assign: bool) -> Result<(), ExecError> {
+        if !is_assign {
+            self.vars.push((name.to_string(), v));
+            return Ok(())
+        }
+        match self.vars.iter_mut().rev().find(|(n, _)| n == name) {
+            Some(var) => { var.1 = v; Ok(()) },
+            None => Err(self.error(format!("undefined variable: {}", name))),
+        }
+    }
+
+    fn var(&self, name: &str) -> Result<Value, ExecError> {
+        match self.vars.iter().rev().find(|(n, _)| n == name) {
+            Some((_, v)) => Ok(v.clone()),
+            None => Err(self.error(format!("undefined variable: {}", name))),
+        }
+    }
+
+    fn eval_pipe(&mut self, dot: &Value, pipe: &Pipe) -> Result<Value, ExecError> {
+        let v = self.eval_cmds(dot, pipe)?;
+        for name in pipe.decl.iter() {
+            self.declare(name, v.clone(), pipe.is_assign)?;
+        }
+        Ok(v)
+    }
+
+    // Runs the commands of a pipeline, passing the value of each to the next
+    // as its last argument
+    fn eval_cmds(&mut self, dot: &Value, pipe: &Pipe) -> Result<Value, ExecError> {
+        let mut value: Option<Value> = None;
+        for cmd in pipe.cmds.iter() {
+            value = Some(self.eval_command(dot, cmd, value)?);
+        }
+        Ok(value.unwrap_or(Value::Nil))
+    }
+
+    fn eval_command(&mut self, dot: &Value, cmd: &Command, last: Option<Value>) -> Result<Value, ExecError> {
+        let args = &cmd.args[1..];
+        match &cmd.args[0] {
+            Arg::Identifier(name) => self.call(dot, name, args, last),
+            Arg::Field(fields) => self.eval_fields(dot, dot.clone(), fields, args, last),
+            Arg::Variable(name, fields) if !fields.is_empty() => {
+                let v = self.var(name)?;
+                self.eval_fields(dot, v, fields, args, last)
+            },
+            Arg::Chain(term, fields) => {
+                let v = self.eval_arg(dot, term)?;
+                self.eval_fields(dot, v, fields, args, last)
+            },
+            arg => {
+                if !args.is_empty() || last.is_some() {
+                    return Err(self.error(format!("can't give argument to non-function {}", describe(arg))))
+                }
+                self.eval_arg(dot, arg)
+            },
+        }
+    }
+
+    fn eval_arg(&mut self, dot: &Value, arg: &Arg) -> Result<Value, ExecError> {
+        Ok(match arg {
+            Arg::Dot => dot.clone(),
+            Arg::Nil => Value::Nil,
+            Arg::Bool(b) => Value::Bool(*b),
+            Arg::Int(i) => Value::Int(*i),
+            Arg::Float(f) => Value::Float(*f),
+            Arg::String(s) => Value::String(s.clone()),
+            Arg::Identifier(name) => self.call(dot, name, &[], None)?,
+            Arg::Field(fields) => self.eval_fields(dot, dot.clone(), fields, &[], None)?,
+            Arg::Variable(name, fields) => {
+                let v = self.var(name)?;
+                self.eval_fields(dot, v, fields, &[], None)?
+            },
+            Arg::Chain(term, fields) => {
+                let v = self.eval_arg(dot, term)?;
+                self.eval_fields(dot, v, fields, &[], None)?
+            },
+            Arg::Pipe(pipe) => self.eval_pipe(dot, pipe)?,
+        })
+    }
+
+    // Looks up a chain of fields, the last of which may be a method that
+    // takes the command's arguments
+    fn eval_fields(&mut self, dot: &Value, receiver: Value, fields: &[String], args: &[Arg], last: Option<Value>) -> Result<Value, ExecError> {
+        let mut v = receiver;
+        for (i, field) in fields.iter().enumerate() {
+            let is_last = i == fields.len() - 1;
+            let mut method_args = Vec::new();
+            if is_last {
+                for a in args.iter() {
+                    method_args.push(self.eval_arg(dot, a)?);
+                }
+                if let Some(ref l) = last {
+                    method_args.push(l.clone());
+                }
+            }
+            let has_args = is_last && (!args.is_empty() || last.is_some());
+            v = self.field(v, field, method_args, has_args)?;
+        }
+        Ok(v)
+    }
+
+    fn field(&self, receiver: Value, name: &str, args: Vec<Value>, has_args: bool) -> Result<Value, ExecError> {
+        let string_arg = |i: usize| -> Result<String, ExecError> {
+            match args.get(i) {
+                Some(Value::String(s)) => Ok(s.clone()),
+                Some(v) => Err(self.error(format!("wrong type for value; expected string; got {}", v.type_name()))),
+                None => Err(self.error(format!("wrong number of args for {}: want 1 got {}", name, args.len()))),
+            }
+        };
+        match receiver {
+            Value::Map(m) => {
+                if has_args {
+                    return Err(self.error(format!("{} is not a method but has arguments", name)))
+                }
+                match m.borrow().get(name) {
+                    Some(v) => Ok(v.clone()),
+                    None if self.strict => Err(self.error(format!("map has no entry for key {:?}", name))),
+                    None => Ok(Value::Nil),
+                }
+            },
+            Value::Files(f) => Ok(match name {
+                "Get" => Value::String(f.get(&string_arg(0)?)),
+                "GetBytes" => Value::String(String::from_utf8_lossy(&f.get_bytes(&string_arg(0)?)).into_owned()),
+                "Glob" => Value::Files(Rc::new(f.glob(&string_arg(0)?))),
+                "Lines" => Value::List(f.lines(&string_arg(0)?)),
+                "AsConfig" => Value::String(funcs::to_yaml(&new_map(f.as_config()))),
+                "AsSecrets" => Value::String(funcs::to_yaml(&new_map(f.as_secrets()))),
+                _ => { return Err(self.error(format!("can't evaluate field {} in type {}", name, Value::Files(f).type_name()))) }
+            }),
+            Value::VersionSet(v) if name == "Has" => Ok(Value::Bool(v.contains(&string_arg(0)?))),
+            Value::Nil => Err(self.error(format!("nil pointer evaluating interface {{}}.{}", name))),
+            v => Err(self.error(format!("can't evaluate field {} in type {}", name, v.type_name()))),
+        }
+    }
+
+    fn call(&mut self, dot: &Value, name: &str, args: &[Arg], last: Option<Value>) -> Result<Value, ExecError> {
+        // and and or stop evaluating their arguments once they know the answer
+        if name == "and" || name == "or" {
+            if args.is_empty() && last.is_none() {
+                return Err(self.error(format!("wrong number of args for {}: want at least 1 got 0", name)))
+            }
+            let mut v = Value::Nil;
+            for a in args.iter() {
+                v = self.eval_arg(dot, a)?;
+                if v.is_true() != (name == "and") {
+                    return Ok(v)
+                }
+            }
+            return Ok(last.unwrap_or(v))
+        }
+        let mut values = Vec::with_capacity(args.len() + 1);
+        for a in args.iter() {
+            values.push(self.eval_arg(dot, a)?);
+        }
+        if let Some(l) = last {
+            values.push(l);
+        }
+        let want = |n: usize| -> Result<(), ExecError> {
+            if values.len() != n {
+                return Err(self.error(format!("wrong number of args for {}: want {} got {}", name, n, values.len())))
+            }
+            Ok(())
+        };
+        match name {
+            "include" => {
+                want(2)?;
+                let template = funcs::to_str(&values[0]);
+                let data = values.pop().unwrap_or(Value::Nil);
+                Ok(Value::String(self.execute(&template, data)?))
+            },
+            "tpl" => {
+                want(2)?;
+                let text = funcs::to_str(&values[0]);
+                let data = values.pop().unwrap_or(Value::Nil);
+                self.tpl(&text, data).map(Value::String)
+            },
+            "required" => {
+                want(2)?;
+                match values[1] {
+                    Value::Nil => Err(self.error(funcs::to_str(&values[0]))),
+                    Value::String(ref s) if s.is_empty() => Err(self.error(funcs::to_str(&values[0]))),
+                    _ => Ok(values.pop().unwrap_or(Value::Nil)),
+                }
+            },
+            "fail" => {
+                want(1)?;
+                Err(self.error(funcs::to_str(&values[0])))
+            },
+            // There's no cluster to look things up in while rendering, so
+            // this behaves like `helm template`
+            "lookup" => Ok(new_map(BTreeMap::new())),
+            _ => funcs::call(name, values).map_err(|e| self.error(format!("error calling {}: {}", name, e))),
+        }
+    }
+
+    // Renders a string as a template. It can use the templates defined so
+    // far, and what it defines is only visible to itself
+    fn tpl(&mut self, text: &str, data: Value) -> Result<String, ExecError> {
+        let (list, defines) = parse::parse(text, &is_func)
+            .map_err(|(line, message)| self.error(format!("cannot parse template {:?}: line {}: {}", text, line, message)))?;
+        let name = self.name.clone();
+        if defines.is_empty() {
+            return self.run(&name, &list, data)
+        }
+        let mut templates: Templates = (*self.templates).clone();
+        templates.extend(defines);
+        let outer = mem::replace(&mut self.templates, Rc::new(templates));
+        let res = self.run(&name, &list, data);
+        self.templates = outer;
+        res
+    }
+}
+
+fn describe(arg: &Arg) -> String {
+    match arg {
+        Arg::Dot => ".".to_string(),
+        Arg::Nil => "nil".to_string(),
+        Arg::Bool(b) => b.to_string(),
+        Arg::Int(i) => i.to_string(),
+        Arg::Float(f) => format_float(*f),
+        Arg::String(s) => format!("{:?}", s),
+        Arg::Variable(v, _) => v.clone(),
+        _ => "pipeline".to_string(),
+    }
+}
@@ -0,0 +1,1344 @@
+// This module holds the functions templates can call: Go's builtins and the
+// Sprig functions Helm adds, minus the ones Helm removes (env and expandenv)
+// and the ones that need a template to run, which are in exec. Arguments are
+// converted as leniently as Sprig's own helpers do
+use crate::engine::value::*;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+const NAMES: &[&str] = &[
+    // Go
+    "not", "len", "index", "slice", "print", "println", "printf", "eq", "ne", "lt", "le", "gt", "ge",
+    "html", "js", "urlquery",
+    // Strings
+    "upper", "lower", "title", "untitle", "trim", "trimAll", "trimall", "trimPrefix", "trimSuffix",
+    "nospace", "repeat", "substr", "trunc", "abbrev", "contains", "hasPrefix", "hasSuffix", "quote",
+    "squote", "cat", "indent", "nindent", "replace", "plural", "snakecase", "camelcase", "kebabcase",
+    "b64enc", "b64dec", "sha256sum", "toString", "toStrings", "join", "split", "splitList", "splitn",
+    "sortAlpha", "regexMatch", "regexFind", "regexFindAll", "regexReplaceAll", "regexReplaceAllLiteral",
+    "regexSplit", "regexQuoteMeta", "randAlphaNum", "randAlpha", "randNumeric", "randAscii", "uuidv4",
+    // Defaults
+    "default", "empty", "coalesce", "all", "any", "ternary",
+    // Encoding
+    "toJson", "toPrettyJson", "toRawJson", "fromJson", "fromJsonArray", "toYaml", "fromYaml", "fromYamlArray",
+    // Types
+    "kindOf", "kindIs", "typeOf", "typeIs", "typeIsLike", "deepEqual",
+    // Numbers
+    "int", "int64", "float64", "atoi", "add", "add1", "sub", "mul", "div", "mod", "max", "min",
+    "addf", "add1f", "subf", "mulf", "divf", "maxf", "minf", "floor", "ceil", "round", "until", "untilStep",
+    // Lists
+    "list", "tuple", "append", "push", "prepend", "first", "last", "rest", "initial", "uniq", "without",
+    "has", "compact", "concat", "reverse",
+    // Dicts
+    "dict", "get", "set", "unset", "hasKey", "keys", "values", "pluck", "pick", "omit", "merge",
+    "mergeOverwrite", "deepCopy", "dig",
+    // Versions
+    "semverCompare", "semver",
+];
+
+pub fn exists(name: &str) -> bool {
+    NAMES.contains(&name)
+}
+
+pub fn call(name: &str, args: Vec<Value>) -> Result<Value, String> {
+    let n = args.len();
+    let want = |min: usize, max: usize| -> Result<(), String> {
+        if n < min || n > max {
+            return Err(match (min, max) {
+                (min, usize::MAX) => format!("wrong number of args: want at least {} got {}", min, n),
+                (min, max) if min == max => format!("wrong number of args: want {} got {}", min, n),
+                (min, max) => format!("wrong number of args: want {} to {} got {}", min, max, n),
+            })
+        }
+        Ok(())
+    };
+    let any = usize::MAX;
+    let s = |i: usize| to_str(&args[i]);
+    let int = |i: usize| to_int(&args[i]);
+    let float = |i: usize| to_float(&args[i]);
+    let string = |s: String| Value::String(s);
+
+    Ok(match name {
+        "not" => { want(1, 1)?; Value::Bool(!args[0].is_true()) },
+        "len" => {
+            want(1, 1)?;
+            Value::Int(match &args[0] {
+                Value::String(s) => s.len(),
+                Value::List(l) => l.len(),
+                Value::Map(m) => m.borrow().len(),
+                Value::Files(f) => f.files.len(),
+                Value::VersionSet(v) => v.len(),
+                Value::Nil => { return Err("len of nil pointer".to_string()) },
+                v => { return Err(format!("len of type {}", v.type_name())) },
+            } as i64)
+        },
+        "index" => { want(1, any)?; index(&args[0], &args[1..])? },
+        "slice" => { want(1, 4)?; slice(&args[0], &args[1..])? },
+        "print" => { want(0, any)?; string(sprint(&args)) },
+        "println" => {
+            let items: Vec<String> = args.iter().map(|v| v.to_string()).collect();
+            string(format!("{}\n", items.join(" ")))
+        },
+        "printf" => { want(1, any)?; string(sprintf(&s(0), &args[1..])) },
+        "eq" => {
+            want(1, any)?;
+            if n == 1 {
+                return Err("missing argument for comparison".to_string())
+            }
+            let mut truth = false;
+            for b in args[1..].iter() {
+                if go_eq(&args[0], b)? {
+                    truth = true;
+                    break
+                }
+            }
+            Value::Bool(truth)
+        },
+        "ne" => { want(2, 2)?; Value::Bool(!go_eq(&args[0], &args[1])?) },
+        "lt" => { want(2, 2)?; Value::Bool(go_cmp(&args[0], &args[1])? == Ordering::Less) },
+        "le" => { want(2, 2)?; Value::Bool(go_cmp(&args[0], &args[1])? != Ordering::Greater) },
+        "gt" => { want(2, 2)?; Value::Bool(go_cmp(&args[0], &args[1])? == Ordering::Greater) },
+        "ge" => { want(2, 2)?; Value::Bool(go_cmp(&args[0], &args[1])? != Ordering::Less) },
+        "html" => string(html_escape(&sprint(&args))),
+        "js" => string(js_escape(&sprint(&args))),
+        "urlquery" => string(query_escape(&sprint(&args))),
+
+        "upper" => { want(1, 1)?; string(s(0).to_uppercase()) },
+        "lower" => { want(1, 1)?; string(s(0).to_lowercase()) },
+        "title" => { want(1, 1)?; string(title(&s(0))) },
+        "untitle" => {
+            want(1, 1)?;
+            let mut prev_space = true;
+            string(s(0).chars().map(|c| {
+                let out = if prev_space { c.to_lowercase().next().unwrap_or(c) } else { c };
+                prev_space = c.is_whitespace();
+                out
+            }).collect())
+        },
+        "trim" => { want(1, 1)?; string(s(0).trim().to_string()) },
+        "trimAll" | "trimall" => {
+            want(2, 2)?;
+            let cutset: Vec<char> = s(0).chars().collect();
+            string(s(1).trim_matches(|c| cutset.contains(&c)).to_string())
+        },
+        "trimPrefix" => { want(2, 2)?; let v = s(1); string(v.strip_prefix(s(0).as_str()).unwrap_or(&v).to_string()) },
+        "trimSuffix" => { want(2, 2)?; let v = s(1); string(v.strip_suffix(s(0).as_str()).unwrap_or(&v).to_string()) },
+        "nospace" => { want(1, 1)?; string(s(0).chars().filter(|c| !c.is_whitespace()).collect()) },
+        "repeat" => { want(2, 2)?; string(s(1).repeat(int(0).max(0) as usize)) },
+        "substr" => {
+            want(3, 3)?;
+            let chars: Vec<char> = s(2).chars().collect();
+            let (start, end) = (int(0), int(1));
+            let len = chars.len() as i64;
+            let (start, end) = if start < 0 {
+                (0, end.clamp(0, len))
+            } else if end < 0 || end > len {
+                (start.min(len), len)
+            } else {
+                (start.min(end), end)
+            };
+            string(chars[start as usize..end as usize].iter().collect())
+        },
+        "trunc" => {
+            want(2, 2)?;
+            let chars: Vec<char> = s(1).chars().collect();
+            let (c, len) = (int(0), chars.len() as i64);
+            string(if c < 0 && len + c > 0 {
+                chars[(len + c) as usize..].iter().collect()
+            } else if c >= 0 && len > c {
+                chars[..c as usize].iter().collect()
+            } else {
+                s(1)
+            })
+        },
+        "abbrev" => {
+            want(2, 2)?;
+            let (width, v) = (int(0), s(1));
+            let chars: Vec<char> = v.chars().collect();
+            string(if width < 4 || chars.len() as i64 <= width {
+                v
+            } else {
+                format!("{}...", chars[..width as usize - 3].iter().collect::<String>())
+            })
+        },
+        "contains" => { want(2, 2)?; Value::Bool(s(1).contains(s(0).as_str())) },
+        "hasPrefix" => { want(2, 2)?; Value::Bool(s(1).starts_with(s(0).as_str())) },
+        "hasSuffix" => { want(2, 2)?; Value::Bool(s(1).ends_with(s(0).as_str())) },
+        "quote" => {
+            let items: Vec<String> = args.iter().filter(|v| !matches!(v, Value::Nil)).map(|v| go_quote(&to_str(v))).collect();
+            string(items.join(" "))
+        },
+        "squote" => {
+            let items: Vec<String> = args.iter().filter(|v| !matches!(v, Value::Nil)).map(|v| format!("'{}'", to_str(v))).collect();
+            string(items.join(" "))
+        },
+        "cat" => {
+            let items: Vec<String> = args.iter().filter(|v| !matches!(v, Value::Nil)).map(to_str).collect();
+            string(items.join(" "))
+        },
+        "indent" => { want(2, 2)?; string(indent(int(0), &s(1))) },
+        "nindent" => { want(2, 2)?; string(format!("\n{}", indent(int(0), &s(1)))) },
+        "replace" => { want(3, 3)?; string(s(2).replace(s(0).as_str(), &s(1))) },
+        "plural" => { want(3, 3)?; string(if int(2) == 1 { s(0) } else { s(1) }) },
+        "snakecase" => { want(1, 1)?; string(join_words(&s(0), '_')) },
+        "kebabcase" => { want(1, 1)?; string(join_words(&s(0), '-')) },
+        "camelcase" => {
+            want(1, 1)?;
+            string(s(0).split('_').map(|w| {
+                let mut c = w.chars();
+                match c.next() {
+                    Some(f) => f.to_uppercase().chain(c).collect::<String>(),
+                    None => String::new(),
+                }
+            }).collect())
+        },
+        "b64enc" => { want(1, 1)?; string(base64::encode(s(0).as_bytes())) },
+        "b64dec" => {
+            want(1, 1)?;
+            // Sprig returns the error as the result rather than failing
+            string(match base64::decode(s(0).as_bytes()) {
+                Ok(d) => String::from_utf8_lossy(&d).into_owned(),
+                Err(e) => e.to_string(),
+            })
+        },
+        "sha256sum" => { want(1, 1)?; string(format!("{:x}", Sha256::digest(s(0).as_bytes()))) },
+        "toString" => {
+            want(1, 1)?;
+            string(match args[0] {
+                Value::Nil => "<nil>".to_string(),
+                ref v => to_str(v),
+            })
+        },
+        "toStrings" => { want(1, 1)?; Value::List(list_arg(&args[0], name)?.iter().map(|v| string(to_str(v))).collect()) },
+        "join" => {
+            want(2, 2)?;
+            let items: Vec<String> = match &args[1] {
+                Value::List(_) | Value::VersionSet(_) => list_arg(&args[1], name)?.iter()
+                    .filter(|v| !matches!(v, Value::Nil))
+                    .map(to_str)
+                    .collect(),
+                v => vec![to_str(v)],
+            };
+            string(items.join(&s(0)))
+        },
+        "split" => {
+            want(2, 2)?;
+            new_map(s(1).split(s(0).as_str()).enumerate().map(|(i, p)| (format!("_{}", i), string(p.to_string()))).collect())
+        },
+        "splitn" => {
+            want(3, 3)?;
+            new_map(s(2).splitn(int(1).max(0) as usize, s(0).as_str()).enumerate()
+                .map(|(i, p)| (format!("_{}", i), string(p.to_string())))
+                .collect())
+        },
+        "splitList" => { want(2, 2)?; Value::List(s(1).split(s(0).as_str()).map(|p| string(p.to_string())).collect()) },
+        "sortAlpha" => {
+            want(1, 1)?;
+            let mut items: Vec<String> = list_arg(&args[0], name)?.iter().map(to_str).collect();
+            items.sort();
+            Value::List(items.into_iter().map(string).collect())
+        },
+        "regexMatch" => { want(2, 2)?; Value::Bool(regex(&s(0))?.is_match(&s(1))) },
+        "regexFind" => { want(2, 2)?; string(regex(&s(0))?.find(&s(1)).map(|m| m.as_str().to_string()).unwrap_or_default()) },
+        "regexFindAll" => {
+            want(3, 3)?;
+            let v = s(1);
+            let limit = if int(2) < 0 { usize::MAX } else { int(2) as usize };
+            Value::List(regex(&s(0))?.find_iter(&v).take(limit).map(|m| string(m.as_str().to_string())).collect())
+        },
+        "regexReplaceAll" => { want(3, 3)?; string(regex(&s(0))?.replace_all(&s(1), s(2).as_str()).into_owned()) },
+        "regexReplaceAllLiteral" => { want(3, 3)?; string(regex(&s(0))?.replace_all(&s(1), regex::NoExpand(&s(2))).into_owned()) },
+        "regexSplit" => {
+            want(3, 3)?;
+            let v = s(1);
+            let re = regex(&s(0))?;
+            let parts: Vec<Value> = if int(2) < 0 {
+                re.split(&v).map(|p| string(p.to_string())).collect()
+            } else {
+                re.splitn(&v, int(2) as usize).map(|p| string(p.to_string())).collect()
+            };
+            Value::List(parts)
+        },
+        "regexQuoteMeta" => { want(1, 1)?; string(regex::escape(&s(0))) },
+        "randAlphaNum" => { want(1, 1)?; string(random_string(int(0), b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789")) },
+        "randAlpha" => { want(1, 1)?; string(random_string(int(0), b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ")) },
+        "randNumeric" => { want(1, 1)?; string(random_string(int(0), b"0123456789")) },
+        "randAscii" => {
+            want(1, 1)?;
+            let printable: Vec<u8> = (b' '..=b'~').collect();
+            string(random_string(int(0), &printable))
+        },
+        "uuidv4" => {
+            want(0, 0)?;
+            let (a, b) = (random_u64(), random_u64());
+            let b = (b & 0x3fff_ffff_ffff_ffff) | 0x8000_0000_0000_0000;
+            let a = (a & 0xffff_ffff_ffff_0fff) | 0x4000;
+            string(format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}", a >> 32, (a >> 16) & 0xffff, a & 0xffff, b >> 48, b & 0xffff_ffff_ffff))
+        },
+
+        "default" => {
+            want(1, any)?;
+            if n < 2 || !args[1].is_true() { args[0].clone() } else { args[1].clone() }
+        },
+        "empty" => { want(1, 1)?; Value::Bool(!args[0].is_true()) },
+        "coalesce" => args.iter().find(|v| v.is_true()).cloned().unwrap_or(Value::Nil),
+        "all" => Value::Bool(args.iter().all(|v| v.is_true())),
+        "any" => Value::Bool(args.iter().any(|v| v.is_true())),
+        "ternary" => { want(3, 3)?; if args[2].is_true() { args[0].clone() } else { args[1].clone() } },
+
+        "toJson" => { want(1, 1)?; string(escape_json_html(&to_json(&args[0], false))) },
+        "toPrettyJson" => { want(1, 1)?; string(escape_json_html(&to_json(&args[0], true))) },
+        "toRawJson" => { want(1, 1)?; string(to_json(&args[0], false)) },
+        "fromJson" => {
+            want(1, 1)?;
+            match serde_json::from_str::<serde_json::Value>(&s(0)) {
+                Ok(j @ serde_json::Value::Object(_)) => Value::from_json(&j),
+                Ok(_) => error_map("json: cannot unmarshal into Go value of type map[string]interface {}"),
+                Err(e) => error_map(&e.to_string()),
+            }
+        },
+        "fromJsonArray" => {
+            want(1, 1)?;
+            match serde_json::from_str::<serde_json::Value>(&s(0)) {
+                Ok(j @ serde_json::Value::Array(_)) => Value::from_json(&j),
+                Ok(_) => Value::List(vec![string("json: cannot unmarshal into Go value of type []interface {}".to_string())]),
+                Err(e) => Value::List(vec![string(e.to_string())]),
+            }
+        },
+        "toYaml" => { want(1, 1)?; string(to_yaml(&args[0])) },
+        "fromYaml" => {
+            want(1, 1)?;
+            match from_yaml(&s(0)) {
+                Ok(Value::Nil) => new_map(BTreeMap::new()),
+                Ok(v @ Value::Map(_)) => v,
+                Ok(_) => error_map("error unmarshaling JSON: cannot unmarshal into Go value of type map[string]interface {}"),
+                Err(e) => error_map(&e),
+            }
+        },
+        "fromYamlArray" => {
+            want(1, 1)?;
+            match from_yaml(&s(0)) {
+                Ok(Value::Nil) => Value::List(Vec::new()),
+                Ok(v @ Value::List(_)) => v,
+                Ok(_) => Value::List(vec![string("error unmarshaling JSON: cannot unmarshal into Go value of type []interface {}".to_string())]),
+                Err(e) => Value::List(vec![string(e)]),
+            }
+        },
+
+        "kindOf" => { want(1, 1)?; string(args[0].kind_name().to_string()) },
+        "kindIs" => { want(2, 2)?; Value::Bool(args[1].kind_name() == s(0)) },
+        "typeOf" => { want(1, 1)?; string(args[0].type_name().to_string()) },
+        "typeIs" => { want(2, 2)?; Value::Bool(args[1].type_name() == s(0)) },
+        "typeIsLike" => {
+            want(2, 2)?;
+            let t = args[1].type_name();
+            Value::Bool(t == s(0) || format!("*{}", t) == s(0))
+        },
+        "deepEqual" => { want(2, 2)?; Value::Bool(deep_equal(&args[0], &args[1])) },
+
+        "int" | "int64" => { want(1, 1)?; Value::Int(int(0)) },
+        "float64" => { want(1, 1)?; Value::Float(float(0)) },
+        "atoi" => { want(1, 1)?; Value::Int(s(0).parse().unwrap_or(0)) },
+        "add" => Value::Int(args.iter().map(to_int).fold(0i64, |a, b| a.wrapping_add(b))),
+        "add1" => { want(1, 1)?; Value::Int(int(0).wrapping_add(1)) },
+        "sub" => { want(2, 2)?; Value::Int(int(0).wrapping_sub(int(1))) },
+        "mul" => { want(1, any)?; Value::Int(args.iter().map(to_int).fold(1i64, |a, b| a.wrapping_mul(b))) },
+        "div" => {
+            want(2, 2)?;
+            if int(1) == 0 {
+                return Err("runtime error: integer divide by zero".to_string())
+            }
+            Value::Int(int(0).wrapping_div(int(1)))
+        },
+        "mod" => {
+            want(2, 2)?;
+            if int(1) == 0 {
+                return Err("runtime error: integer divide by zero".to_string())
+            }
+            Value::Int(int(0).wrapping_rem(int(1)))
+        },
+        "max" => { want(1, any)?; Value::Int(args.iter().map(to_int).max().unwrap_or(0)) },
+        "min" => { want(1, any)?; Value::Int(args.iter().map(to_int).min().unwrap_or(0)) },
+        "addf" => Value::Float(args.iter().map(to_float).sum()),
+        "add1f" => { want(1, 1)?; Value::Float(float(0) + 1.0) },
+        "subf" => { want(1, any)?; Value::Float(args[1..].iter().map(to_float).fold(float(0), |a, b| a - b)) },
+        "mulf" => { want(1, any)?; Value::Float(args.iter().map(to_float).product()) },
+        "divf" => { want(1, any)?; Value::Float(args[1..].iter().map(to_float).fold(float(0), |a, b| a / b)) },
+        "maxf" => { want(1, any)?; Value::Float(args.iter().map(to_float).fold(f64::NEG_INFINITY, f64::max)) },
+        "minf" => { want(1, any)?; Value::Float(args.iter().map(to_float).fold(f64::INFINITY, f64::min)) },
+        "floor" => { want(1, 1)?; Value::Float(float(0).floor()) },
+        "ceil" => { want(1, 1)?; Value::Float(float(0).ceil()) },
+        "round" => {
+            want(2, 3)?;
+            let point = if n == 3 { float(2) } else { 0.5 };
+            let pow = 10f64.powi(int(1) as i32);
+            let digit = pow * float(0);
+            let rounded = if digit - digit.trunc() >= point { digit.trunc() + 1.0 } else { digit.trunc() };
+            Value::Float(rounded / pow)
+        },
+        "until" => {
+            want(1, 1)?;
+            let count = int(0);
+            Value::List(until_step(0, count, if count < 0 { -1 } else { 1 }))
+        },
+        "untilStep" => { want(3, 3)?; Value::List(until_step(int(0), int(1), int(2))) },
+
+        "list" | "tuple" => Value::List(args),
+        "append" | "push" => {
+            want(2, 2)?;
+            let mut l = list_arg(&args[0], name)?;
+            l.push(args[1].clone());
+            Value::List(l)
+        },
+        "prepend" => {
+            want(2, 2)?;
+            let mut l = list_arg(&args[0], name)?;
+            l.insert(0, args[1].clone());
+            Value::List(l)
+        },
+        "first" => { want(1, 1)?; list_arg(&args[0], name)?.first().cloned().unwrap_or(Value::Nil) },
+        "last" => { want(1, 1)?; list_arg(&args[0], name)?.last().cloned().unwrap_or(Value::Nil) },
+        "rest" => {
+            want(1, 1)?;
+            let l = list_arg(&args[0], name)?;
+            if l.is_empty() { Value::Nil } else { Value::List(l[1..].to_vec()) }
+        },
+        "initial" => {
+            want(1, 1)?;
+            let l = list_arg(&args[0], name)?;
+            if l.is_empty() { Value::Nil } else { Value::List(l[..l.len() - 1].to_vec()) }
+        },
+        "uniq" => {
+            want(1, 1)?;
+            let mut out: Vec<Value> = Vec::new();
+            for v in list_arg(&args[0], name)?.into_iter() {
+                if !out.iter().any(|o| deep_equal(o, &v)) {
+                    out.push(v);
+                }
+            }
+            Value::List(out)
+        },
+        "without" => {
+            want(1, any)?;
+            let omit = &args[1..];
+            Value::List(list_arg(&args[0], name)?.into_iter().filter(|v| !omit.iter().any(|o| deep_equal(o, v))).collect())
+        },
+        "has" => {
+            want(2, 2)?;
+            Value::Bool(match args[1] {
+                Value::Nil => false,
+                ref l => list_arg(l, name)?.iter().any(|v| deep_equal(v, &args[0])),
+            })
+        },
+        "compact" => { want(1, 1)?; Value::List(list_arg(&args[0], name)?.into_iter().filter(|v| v.is_true()).collect()) },
+        "concat" => {
+            let mut out = Vec::new();
+            for l in args.iter() {
+                out.extend(list_arg(l, name)?);
+            }
+            Value::List(out)
+        },
+        "reverse" => {
+            want(1, 1)?;
+            let mut l = list_arg(&args[0], name)?;
+            l.reverse();
+            Value::List(l)
+        },
+
+        "dict" => {
+            let mut map = BTreeMap::new();
+            for pair in args.chunks(2) {
+                map.insert(to_str(&pair[0]), pair.get(1).cloned().unwrap_or_else(|| string(String::new())));
+            }
+            new_map(map)
+        },
+        "get" => {
+            want(2, 2)?;
+            let v = map_arg(&args[0], name)?.borrow().get(&s(1)).cloned();
+            v.unwrap_or_else(|| string(String::new()))
+        },
+        "set" => {
+            want(3, 3)?;
+            map_arg(&args[0], name)?.borrow_mut().insert(s(1), args[2].clone());
+            args[0].clone()
+        },
+        "unset" => {
+            want(2, 2)?;
+            map_arg(&args[0], name)?.borrow_mut().remove(&s(1));
+            args[0].clone()
+        },
+        "hasKey" => { want(2, 2)?; Value::Bool(map_arg(&args[0], name)?.borrow().contains_key(&s(1))) },
+        "keys" => {
+            let mut keys = Vec::new();
+            for d in args.iter() {
+                keys.extend(map_arg(d, name)?.borrow().keys().map(|k| string(k.clone())));
+            }
+            Value::List(keys)
+        },
+        "values" => { want(1, 1)?; Value::List(map_arg(&args[0], name)?.borrow().values().cloned().collect()) },
+        "pluck" => {
+            want(1, any)?;
+            let key = s(0);
+            let mut out = Vec::new();
+            for d in args[1..].iter() {
+                if let Some(v) = map_arg(d, name)?.borrow().get(&key) {
+                    out.push(v.clone());
+                }
+            }
+            Value::List(out)
+        },
+        "pick" | "omit" => {
+            want(1, any)?;
+            let keys: Vec<String> = args[1..].iter().map(to_str).collect();
+            let map = map_arg(&args[0], name)?;
+            let picked = map.borrow().iter()
+                .filter(|(k, _)| keys.contains(k) == (name == "pick"))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            new_map(picked)
+        },
+        "merge" | "mergeOverwrite" => {
+            want(1, any)?;
+            let dst = map_arg(&args[0], name)?;
+            for src in args[1..].iter() {
+                let src = map_arg(src, name)?;
+                // Merging a map into itself changes nothing
+                if !std::rc::Rc::ptr_eq(&dst, &src) {
+                    merge(&dst, &src.borrow(), name == "mergeOverwrite");
+                }
+            }
+            args[0].clone()
+        },
+        "deepCopy" => { want(1, 1)?; deep_copy(&args[0]) },
+        "dig" => {
+            want(3, any)?;
+            let mut v = args[n - 1].clone();
+            for key in args[..n - 2].iter() {
+                let next = map_arg(&v, name)?.borrow().get(&to_str(key)).cloned();
+                match next {
+                    Some(next) => v = next,
+                    None => { return Ok(args[n - 2].clone()) }
+                }
+            }
+            v
+        },
+
+        "semverCompare" => {
+            want(2, 2)?;
+            let version = Version::parse(&s(1)).ok_or_else(|| format!("invalid semantic version {:?}", s(1)))?;
+            Value::Bool(constraint_matches(&s(0), &version)?)
+        },
+        "semver" => {
+            want(1, 1)?;
+            let v = Version::parse(&s(0)).ok_or_else(|| format!("invalid semantic version {:?}", s(0)))?;
+            let mut map = BTreeMap::new();
+            map.insert("Major".to_string(), Value::Int(v.major as i64));
+            map.insert("Minor".to_string(), Value::Int(v.minor as i64));
+            map.insert("Patch".to_string(), Value::Int(v.patch as i64));
+            map.insert("Prerelease".to_string(), string(v.pre.join(".")));
+            map.insert("Metadata".to_string(), string(v.build.clone()));
+            map.insert("Original".to_string(), string(s(0)));
+            new_map(map)
+        },
+
+        _ => { return Err(format!("function {:?} not defined", name)) }
+    })
+}
+
+// Converts a value to a string the way Sprig's strval does, except that nil
+// is empty rather than "<nil>"
+pub fn to_str(v: &Value) -> String {
+    match v {
+        Value::Nil => String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+// Converts a value to an integer like Sprig's toInt64, which is zero for
+// anything it can't convert
+pub fn to_int(v: &Value) -> i64 {
+    match v {
+        Value::Int(i) => *i,
+        Value::Float(f) => *f as i64,
+        Value::Bool(b) => *b as i64,
+        Value::String(s) => {
+            let s = s.trim();
+            let (neg, digits) = match s.strip_prefix('-') {
+                Some(d) => (true, d),
+                None => (false, s.trim_start_matches('+')),
+            };
+            let lower = digits.to_ascii_lowercase();
+            let parsed = if let Some(h) = lower.strip_prefix("0x") {
+                i64::from_str_radix(h, 16)
+            } else if let Some(b) = lower.strip_prefix("0b") {
+                i64::from_str_radix(b, 2)
+            } else if lower.len() > 1 && lower.starts_with('0') {
+                i64::from_str_radix(lower.trim_start_matches("0o").trim_start_matches('0'), 8)
+            } else {
+                lower.parse()
+            };
+            parsed.map(|i| if neg { -i } else { i }).unwrap_or(0)
+        },
+        _ => 0,
+    }
+}
+
+pub fn to_float(v: &Value) -> f64 {
+    match v {
+        Value::Int(i) => *i as f64,
+        Value::Float(f) => *f,
+        Value::Bool(b) => *b as i64 as f64,
+        Value::String(s) => s.trim().parse().unwrap_or(0.0),
+        _ => 0.0,
+    }
+}
+
+// Renders a value as YAML without the document marker or trailing newline,
+// as Helm's toYaml does
+pub fn to_yaml(v: &Value) -> String {
+    if let Value::Nil = v {
+        return "null".to_string()
+    }
+    let yaml = serde_yaml::to_string(&v.to_json()).unwrap_or_default();
+    let yaml = yaml.strip_prefix("---\n").unwrap_or(&yaml).trim_end_matches('\n');
+    // serde_yaml writes null as ~, where Go writes null. A string of ~ is
+    // quoted, so a bare one is always a null
+    let null = Regex::new(r"(?m)(^|: |- )~$").expect("invalid null regex");
+    null.replace_all(yaml, "${1}null").into_owned()
+}
+
+fn from_yaml(s: &str) -> Result<Value, String> {
+    if s.lines().all(|l| l.trim().is_empty() || l.trim_start().starts_with('#') || l.trim() == "---") {
+        return Ok(Value::Nil)
+    }
+    serde_yaml::from_str::<serde_json::Value>(s)
+        .map(|j| Value::from_json(&j))
+        .map_err(|e| format!("error converting YAML to JSON: {}", e))
+}
+
+fn to_json(v: &Value, pretty: bool) -> String {
+    let json = v.to_json();
+    let res = if pretty { serde_json::to_string_pretty(&json) } else { serde_json::to_string(&json) };
+    res.unwrap_or_default()
+}
+
+// Go's JSON encoder escapes the characters that are special in HTML. They
+// can only appear inside strings, so replacing them is safe
+fn escape_json_html(json: &str) -> String {
+    json.replace('<', "\\u003c").replace('>', "\\u003e").replace('&', "\\u0026")
+}
+
+// Helm reports a document that fails to parse with fromYaml or fromJson as a
+// map holding the error
+fn error_map(message: &str) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("Error".to_string(), Value::String(message.to_string()));
+    new_map(map)
+}
+
+fn list_arg(v: &Value, name: &str) -> Result<Vec<Value>, String> {
+    match v {
+        Value::List(l) => Ok(l.clone()),
+        Value::VersionSet(s) => Ok(s.iter().map(|v| Value::String(v.clone())).collect()),
+        Value::Nil => Ok(Vec::new()),
+        v => Err(format!("cannot {} on type {}", name, v.type_name())),
+    }
+}
+
+fn map_arg(v: &Value, name: &str) -> Result<Map, String> {
+    match v {
+        Value::Map(m) => Ok(m.clone()),
+        v => Err(format!("cannot {} on type {}", name, v.type_name())),
+    }
+}
+
+// Merges src into dst the way mergo does for Sprig: nested maps are merged,
+// and other values only replace those in dst when overwriting, or when dst
+// doesn't have them
+fn merge(dst: &Map, src: &BTreeMap<String, Value>, overwrite: bool) {
+    for (k, v) in src.iter() {
+        let existing = dst.borrow().get(k).cloned();
+        match (existing, v) {
+            (Some(Value::Map(d)), Value::Map(s)) if !std::rc::Rc::ptr_eq(&d, s) => merge(&d, &s.borrow(), overwrite),
+            (Some(Value::Map(_)), Value::Map(_)) => {},
+            (Some(Value::Nil), _) | (None, _) => { dst.borrow_mut().insert(k.clone(), deep_copy(v)); },
+            (Some(_), v) if overwrite => { dst.borrow_mut().insert(k.clone(), deep_copy(v)); },
+            _ => {},
+        }
+    }
+}
+
+pub fn deep_copy(v: &Value) -> Value {
+    match v {
+        Value::Map(m) => new_map(m.borrow().iter().map(|(k, v)| (k.clone(), deep_copy(v))).collect()),
+        Value::List(l) => Value::List(l.iter().map(deep_copy).collect()),
+        v => v.clone(),
+    }
+}
+
+// Compares values like Go's reflect.DeepEqual, so 1 and 1.0 differ
+fn deep_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Float(a), Value::Float(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::List(a), Value::List(b)) => a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| deep_equal(a, b)),
+        (Value::Map(a), Value::Map(b)) => {
+            let (a, b) = (a.borrow(), b.borrow());
+            a.len() == b.len() && a.iter().all(|(k, v)| b.get(k).map(|o| deep_equal(v, o)).unwrap_or(false))
+        },
+        (Value::VersionSet(a), Value::VersionSet(b)) => a == b,
+        _ => false,
+    }
+}
+
+// eq for one pair of arguments. Like Go, values of different basic kinds
+// can't be compared, except with nil
+fn go_eq(a: &Value, b: &Value) -> Result<bool, String> {
+    match (a, b) {
+        (Value::Nil, Value::Nil) => Ok(true),
+        (Value::Nil, _) | (_, Value::Nil) => Ok(false),
+        (Value::Bool(a), Value::Bool(b)) => Ok(a == b),
+        (Value::Int(a), Value::Int(b)) => Ok(a == b),
+        (Value::Float(a), Value::Float(b)) => Ok(a == b),
+        (Value::String(a), Value::String(b)) => Ok(a == b),
+        (a, b) if a.kind_name() == b.kind_name() => Err(format!("non-comparable type {}", a.type_name())),
+        _ => Err("incompatible types for comparison".to_string()),
+    }
+}
+
+fn go_cmp(a: &Value, b: &Value) -> Result<Ordering, String> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Ok(a.cmp(b)),
+        (Value::Float(a), Value::Float(b)) => Ok(a.partial_cmp(b).unwrap_or(Ordering::Equal)),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        (Value::Int(_), _) | (Value::Float(_), _) | (Value::String(_), _) if a.kind_name() != b.kind_name() && b.kind_name() != "invalid" => {
+            Err("incompatible types for comparison".to_string())
+        },
+        _ => Err("invalid type for comparison".to_string()),
+    }
+}
+
+fn index(item: &Value, keys: &[Value]) -> Result<Value, String> {
+    let mut v = item.clone();
+    for key in keys.iter() {
+        v = match (&v, key) {
+            (Value::Nil, _) => { return Err("index of untyped nil".to_string()) },
+            (Value::List(l), Value::Int(i)) => {
+                if *i < 0 || *i as usize >= l.len() {
+                    return Err(format!("index out of range: {}", i))
+                }
+                l[*i as usize].clone()
+            },
+            (Value::String(s), Value::Int(i)) => {
+                if *i < 0 || *i as usize >= s.len() {
+                    return Err(format!("index out of range: {}", i))
+                }
+                Value::Int(s.as_bytes()[*i as usize] as i64)
+            },
+            (Value::Map(m), Value::String(k)) => m.borrow().get(k).cloned().unwrap_or(Value::Nil),
+            (Value::Map(_), Value::Nil) => Value::Nil,
+            (Value::List(_), k) | (Value::String(_), k) => { return Err(format!("cannot index slice/array with type {}", k.type_name())) },
+            (Value::Map(_), k) => { return Err(format!("value has type {}; should be string", k.type_name())) },
+            (v, _) => { return Err(format!("can't index item of type {}", v.type_name())) },
+        };
+    }
+    Ok(v)
+}
+
+fn slice(item: &Value, indices: &[Value]) -> Result<Value, String> {
+    let len = match item {
+        Value::List(l) => l.len(),
+        Value::String(s) => s.len(),
+        Value::Nil => { return Err("slice of untyped nil".to_string()) },
+        v => { return Err(format!("can't slice item of type {}", v.type_name())) },
+    };
+    if indices.len() == 3 && matches!(item, Value::String(_)) {
+        return Err("cannot 3-index slice a string".to_string())
+    }
+    let mut idx = [0, len, len];
+    for (i, v) in indices.iter().enumerate() {
+        match v {
+            Value::Int(n) if *n >= 0 && (*n as usize) <= len => idx[i] = *n as usize,
+            Value::Int(n) => { return Err(format!("index out of range: {}", n)) },
+            v => { return Err(format!("cannot index slice/array with type {}", v.type_name())) },
+        }
+    }
+    if idx[0] > idx[1] {
+        return Err(format!("invalid slice index: {} > {}", idx[0], idx[1]))
+    }
+    Ok(match item {
+        Value::List(l) => Value::List(l[idx[0]..idx[1]].to_vec()),
+        Value::String(s) => Value::String(String::from_utf8_lossy(&s.as_bytes()[idx[0]..idx[1]]).into_owned()),
+        _ => Value::Nil,
+    })
+}
+
+fn until_step(start: i64, stop: i64, step: i64) -> Vec<Value> {
+    let mut out = Vec::new();
+    if step == 0 || (step > 0 && start >= stop) || (step < 0 && start <= stop) {
+        return out
+    }
+    let mut i = start;
+    while (step > 0 && i < stop) || (step < 0 && i > stop) {
+        out.push(Value::Int(i));
+        // A step past the largest or smallest int ends the list
+        i = match i.checked_add(step) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    out
+}
+
+fn regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+fn indent(n: i64, s: &str) -> String {
+    let pad = " ".repeat(n.max(0) as usize);
+    format!("{}{}", pad, s.replace('\n', &format!("\n{}", pad)))
+}
+
+// Upper cases the first letter of each word, like Go's strings.Title
+fn title(s: &str) -> String {
+    let mut prev = ' ';
+    s.chars().map(|c| {
+        let separator = !(prev.is_alphanumeric() || prev == '_');
+        prev = c;
+        if separator { c.to_uppercase().next().unwrap_or(c) } else { c }
+    }).collect()
+}
+
+// Splits camel case and separated words and joins them in lower case, for
+// snakecase and kebabcase
+fn join_words(s: &str, sep: char) -> String {
+    let mut out = String::new();
+    let chars: Vec<char> = s.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' || *c == '-' || c.is_whitespace() {
+            if !out.is_empty() && !out.ends_with(sep) {
+                out.push(sep);
+            }
+            continue
+        }
+        if c.is_uppercase() && i > 0 && !out.is_empty() && !out.ends_with(sep) {
+            let prev_lower = chars[i - 1].is_lowercase() || chars[i - 1].is_ascii_digit();
+            let next_lower = chars.get(i + 1).map(|n| n.is_lowercase()).unwrap_or(false);
+            if prev_lower || (chars[i - 1].is_uppercase() && next_lower) {
+                out.push(sep);
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+// Like Go's fmt.Sprint, which puts spaces between operands when neither side
+// is a string
+fn sprint(args: &[Value]) -> String {
+    let mut out = String::new();
+    for (i, v) in args.iter().enumerate() {
+        if i > 0 && !matches!(v, Value::String(_)) && !matches!(args[i - 1], Value::String(_)) {
+            out.push(' ');
+        }
+        out.push_str(&v.to_string());
+    }
+    out
+}
+
+// Quotes a string the way Go's %q does
+fn go_quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\x07' => out.push_str("\\a"),
+            '\x08' => out.push_str("\\b"),
+            '\x0c' => out.push_str("\\f"),
+            '\x0b' => out.push_str("\\v"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("&#34;"),
+            '\'' => out.push_str("&#39;"),
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\0' => out.push('\u{fffd}'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn js_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\'' => out.push_str("\\'"),
+            '"' => out.push_str("\\\""),
+            '<' => out.push_str("\\u003C"),
+            '>' => out.push_str("\\u003E"),
+            '&' => out.push_str("\\u0026"),
+            '=' => out.push_str("\\u003D"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn query_escape(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b' ' => out.push('+'),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// Randomness comes from the OS, as it does for Sprig, since charts use these
+// functions to generate passwords
+fn random_bytes(buf: &mut [u8]) {
+    getrandom::getrandom(buf).expect("unable to read random bytes from the OS");
+}
+
+fn random_u64() -> u64 {
+    let mut buf = [0u8; 8];
+    random_bytes(&mut buf);
+    u64::from_le_bytes(buf)
+}
+
+fn random_string(len: i64, alphabet: &[u8]) -> String {
+    let len = len.max(0) as usize;
+    // Bytes past the last whole multiple of the alphabet's length are thrown
+    // away, so that every character is as likely as any other
+    let limit = 256 - 256 % alphabet.len();
+    let mut out = String::with_capacity(len);
+    let mut buf = [0u8; 64];
+    while out.len() < len {
+        random_bytes(&mut buf);
+        for b in buf.iter().map(|b| *b as usize).filter(|b| *b < limit).take(len - out.len()) {
+            out.push(alphabet[b % alphabet.len()] as char);
+        }
+    }
+    out
+}
+
+#[derive(Default)]
+struct Spec {
+    minus: bool,
+    plus: bool,
+    sharp: bool,
+    space: bool,
+    zero: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+// Formats like Go's fmt.Sprintf, including its complaints about missing,
+// extra and mismatched arguments
+pub fn sprintf(format: &str, args: &[Value]) -> String {
+    let chars: Vec<char> = format.chars().collect();
+    let mut out = String::new();
+    let mut next = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if c != '%' {
+            out.push(c);
+            continue
+        }
+        let mut spec = Spec::default();
+        while i < chars.len() {
+            match chars[i] {
+                '-' => spec.minus = true,
+                '+' => spec.plus = true,
+                '#' => spec.sharp = true,
+                ' ' => spec.space = true,
+                '0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        let number = |i: &mut usize, next: &mut usize| -> Option<usize> {
+            if *i < chars.len() && chars[*i] == '*' {
+                *i += 1;
+                *next += 1;
+                return args.get(*next - 1).map(|v| to_int(v).max(0) as usize)
+            }
+            let start = *i;
+            while *i < chars.len() && chars[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            chars[start..*i].iter().collect::<String>().parse().ok()
+        };
+        spec.width = number(&mut i, &mut next);
+        if i < chars.len() && chars[i] == '.' {
+            i += 1;
+            spec.precision = Some(number(&mut i, &mut next).unwrap_or(0));
+        }
+        let verb = match chars.get(i) {
+            Some(v) => *v,
+            None => {
+                out.push_str("%!(NOVERB)");
+                break
+            },
+        };
+        i += 1;
+        if verb == '%' {
+            out.push('%');
+            continue
+        }
+        match args.get(next) {
+            Some(arg) => out.push_str(&format_verb(verb, &spec, arg)),
+            None => out.push_str(&format!("%!{}(MISSING)", verb)),
+        }
+        next += 1;
+    }
+    if next < args.len() {
+        let extra: Vec<String> = args[next..].iter().map(|v| format!("{}={}", v.type_name(), v)).collect();
+        out.push_str(&format!("%!(EXTRA {})", extra.join(", ")));
+    }
+    out
+}
+
+fn format_verb(verb: char, spec: &Spec, arg: &Value) -> String {
+    let bad = || match arg {
+        Value::Nil => format!("%!{}(<nil>)", verb),
+        v => format!("%!{}({}={})", verb, v.type_name(), v),
+    };
+    let mut numeric = false;
+    let body = match (verb, arg) {
+        ('v', Value::Float(f)) | ('g', Value::Float(f)) | ('G', Value::Float(f)) => {
+            numeric = true;
+            let s = match spec.precision {
+                Some(p) => format_g(*f, p.max(1)),
+                None => format_float(*f),
+            };
+            if verb == 'G' { s.to_uppercase() } else { s }
+        },
+        ('v', Value::Int(i)) | ('d', Value::Int(i)) => { numeric = true; i.to_string() },
+        ('v', v) => v.to_string(),
+        ('s', Value::String(s)) => match spec.precision {
+            Some(p) => s.chars().take(p).collect(),
+            None => s.clone(),
+        },
+        ('s', v @ Value::List(_)) | ('s', v @ Value::Map(_)) => v.to_string(),
+        ('q', Value::String(s)) => go_quote(s),
+        ('q', Value::Int(i)) => format!("'{}'", std::char::from_u32(*i as u32).unwrap_or('\u{fffd}')),
+        ('t', Value::Bool(b)) => b.to_string(),
+        ('f', Value::Float(f)) | ('F', Value::Float(f)) => { numeric = true; format!("{:.*}", spec.precision.unwrap_or(6), f) },
+        ('e', Value::Float(f)) | ('E', Value::Float(f)) => {
+            numeric = true;
+            let s = format_e(*f, spec.precision.unwrap_or(6));
+            if verb == 'E' { s.to_uppercase() } else { s }
+        },
+        ('x', Value::Int(i)) => { numeric = true; signed_radix(*i, 16, spec.sharp, "0x") },
+        ('X', Value::Int(i)) => { numeric = true; signed_radix(*i, 16, spec.sharp, "0X").to_uppercase() },
+        ('o', Value::Int(i)) => { numeric = true; signed_radix(*i, 8, spec.sharp, "0") },
+        ('b', Value::Int(i)) => { numeric = true; signed_radix(*i, 2, false, "") },
+        ('x', Value::String(s)) => s.bytes().map(|b| format!("{:02x}", b)).collect(),
+        ('X', Value::String(s)) => s.bytes().map(|b| format!("{:02X}", b)).collect(),
+        ('c', Value::Int(i)) => std::char::from_u32(*i as u32).unwrap_or('\u{fffd}').to_string(),
+        ('U', Value::Int(i)) => format!("U+{:04X}", i),
+        ('T', v) => v.type_name().to_string(),
+        _ => bad(),
+    };
+    let mut body = body;
+    if numeric && !body.starts_with('-') {
+        if spec.plus {
+            body.insert(0, '+');
+        } else if spec.space {
+            body.insert(0, ' ');
+        }
+    }
+    let width = spec.width.unwrap_or(0);
+    let len = body.chars().count();
+    if len >= width {
+        return body
+    }
+    let pad = width - len;
+    if spec.minus {
+        return format!("{}{}", body, " ".repeat(pad))
+    }
+    if spec.zero && numeric {
+        let sign_len = if body.starts_with(['-', '+', ' ']) { 1 } else { 0 };
+        return format!("{}{}{}", &body[..sign_len], "0".repeat(pad), &body[sign_len..])
+    }
+    format!("{}{}", " ".repeat(pad), body)
+}
+
+fn signed_radix(i: i64, radix: u32, sharp: bool, prefix: &str) -> String {
+    let digits = match radix {
+        16 => format!("{:x}", i.unsigned_abs()),
+        8 => format!("{:o}", i.unsigned_abs()),
+        _ => format!("{:b}", i.unsigned_abs()),
+    };
+    let prefix = if sharp { prefix } else { "" };
+    format!("{}{}{}", if i < 0 { "-" } else { "" }, prefix, digits)
+}
+
+// Formats like Go's %e: d.dddddde+XX
+fn format_e(f: f64, precision: usize) -> String {
+    if !f.is_finite() {
+        return format_float(f)
+    }
+    let s = format!("{:.*e}", precision, f);
+    let (mantissa, exp) = s.split_at(s.find('e').unwrap_or(s.len()));
+    let exp: i32 = exp.trim_start_matches('e').parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if exp < 0 { '-' } else { '+' }, exp.abs())
+}
+
+// Formats like Go's %g with a precision: that many significant digits,
+// switching to an exponent when it's below -4 or reaches the precision
+fn format_g(f: f64, precision: usize) -> String {
+    if !f.is_finite() || f == 0.0 {
+        return format_float(f)
+    }
+    let sci = format!("{:.*e}", precision - 1, f.abs());
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap_or(sci.len()));
+    let exp: i32 = exp.trim_start_matches('e').parse().unwrap_or(0);
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let digits = digits.trim_end_matches('0');
+    let digits = if digits.is_empty() { "0" } else { digits };
+    let sign = if f < 0.0 { "-" } else { "" };
+    if exp < -4 || exp >= precision as i32 {
+        return format!("{}{}", sign, format_exponent(digits, exp))
+    }
+    if exp < 0 {
+        return format!("{}0.{}{}", sign, "0".repeat((-exp - 1) as usize), digits)
+    }
+    let exp = exp as usize;
+    if digits.len() <= exp + 1 {
+        return format!("{}{}{}", sign, digits, "0".repeat(exp + 1 - digits.len()))
+    }
+    format!("{}{}.{}", sign, &digits[..exp + 1], &digits[exp + 1..])
+}
+
+// A semantic version, parsed as leniently as Masterminds/semver does: a
+// leading v is allowed and missing minor and patch numbers are zero
+struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+    pre: Vec<String>,
+    build: String,
+}
+
+impl Version {
+    fn parse(s: &str) -> Option<Version> {
+        let s = s.trim();
+        let s = s.strip_prefix('v').unwrap_or(s);
+        let (s, build) = match s.find('+') {
+            Some(i) => (&s[..i], s[i + 1..].to_string()),
+            None => (s, String::new()),
+        };
+        let (core, pre) = match s.find('-') {
+            Some(i) => (&s[..i], s[i + 1..].split('.').map(|p| p.to_string()).collect()),
+            None => (s, Vec::new()),
+        };
+        let parts: Vec<&str> = core.split('.').collect();
+        if parts.is_empty() || parts.len() > 3 {
+            return None
+        }
+        let mut nums = [0u64; 3];
+        for (i, p) in parts.iter().enumerate() {
+            if p.is_empty() || !p.chars().all(|c| c.is_ascii_digit()) {
+                return None
+            }
+            nums[i] = p.parse().ok()?;
+        }
+        Some(Version{major: nums[0], minor: nums[1], patch: nums[2], pre, build})
+    }
+
+    fn compare(&self, other: &Version) -> Ordering {
+        let core = (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch));
+        if core != Ordering::Equal {
+            return core
+        }
+        match (self.pre.is_empty(), other.pre.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Greater,
+            (false, true) => return Ordering::Less,
+            _ => {},
+        }
+        for (a, b) in self.pre.iter().zip(other.pre.iter()) {
+            let ord = match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                _ => a.cmp(b),
+            };
+            if ord != Ordering::Equal {
+                return ord
+            }
+        }
+        self.pre.len().cmp(&other.pre.len())
+    }
+}
+
+// Checks a version against a constraint such as ">=1.19-0" or "~1.2 || ^2",
+// following Masterminds/semver: comma or space separated constraints must all
+// match, and || separates alternatives. A prerelease version only matches
+// constraints that have a prerelease themselves
+fn constraint_matches(constraint: &str, version: &Version) -> Result<bool, String> {
+    for alternative in constraint.split("||") {
+        let mut terms: Vec<String> = Vec::new();
+        let words: Vec<&str> = alternative.split(|c: char| c == ',' || c.is_whitespace()).filter(|w| !w.is_empty()).collect();
+        let mut i = 0;
+        while i < words.len() {
+            // Hyphen ranges, such as 1.2 - 1.4.5
+            if i + 2 < words.len() && words[i + 1] == "-" {
+                terms.push(format!(">={}", words[i]));
+                terms.push(format!("<={}", words[i + 2]));
+                i += 3;
+                continue
+            }
+            // Operators separated from their versions by a space
+            if words[i].chars().all(|c| "<>=!~^".contains(c)) && i + 1 < words.len() {
+                terms.push(format!("{}{}", words[i], words[i + 1]));
+                i += 2;
+                continue
+            }
+            terms.push(words[i].to_string());
+            i += 1;
+        }
+        if terms.is_empty() {
+            return Err(format!("improper constraint: {}", constraint))
+        }
+        let mut all = true;
+        for term in terms.iter() {
+            if !term_matches(term, version).ok_or_else(|| format!("improper constraint: {}", constraint))? {
+                all = false;
+                break
+            }
+        }
+        if all {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+fn term_matches(term: &str, v: &Version) -> Option<bool> {
+    let op_len = term.find(|c: char| !"<>=!~^".contains(c)).unwrap_or(term.len());
+    let (op, rest) = term.split_at(op_len);
+    let rest = rest.strip_prefix('v').unwrap_or(rest);
+    let (rest, pre) = match rest.find('-') {
+        Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+        None => (rest.split('+').next().unwrap_or(rest), None),
+    };
+    // Each part of the version is a number, or None for a wildcard or a
+    // missing part
+    let mut parts: Vec<Option<u64>> = Vec::new();
+    for p in rest.split('.') {
+        match p {
+            "x" | "X" | "*" => parts.push(None),
+            p => parts.push(Some(p.parse().ok()?)),
+        }
+    }
+    if parts.len() > 3 {
+        return None
+    }
+    parts.resize(3, None);
+    // Everything after a wildcard is a wildcard too
+    if let Some(i) = parts.iter().position(|p| p.is_none()) {
+        for p in parts[i..].iter_mut() {
+            *p = None;
+        }
+    }
+    if !v.pre.is_empty() && pre.is_none() {
+        return Some(false)
+    }
+    let c = Version {
+        major: parts[0].unwrap_or(0),
+        minor: parts[1].unwrap_or(0),
+        patch: parts[2].unwrap_or(0),
+        pre: pre.map(|p| p.split('.').map(|s| s.to_string()).collect()).unwrap_or_default(),
+        build: String::new(),
+    };
+    let wild = parts.iter().position(|p| p.is_none()).unwrap_or(3);
+    // The version after the range a wildcard covers, e.g. 1.3.0 for 1.2.x
+    let next = |wild: usize| -> Version {
+        match wild {
+            0 => Version{major: u64::MAX, minor: 0, patch: 0, pre: Vec::new(), build: String::new()},
+            1 => Version{major: c.major + 1, minor: 0, patch: 0, pre: Vec::new(), build: String::new()},
+            _ => Version{major: c.major, minor: c.minor + 1, patch: 0, pre: Vec::new(), build: String::new()},
+        }
+    };
+    let cmp = v.compare(&c);
+    Some(match op {
+        "" | "=" | "==" if wild < 3 => cmp != Ordering::Less && v.compare(&next(wild)) == Ordering::Less,
+        "" | "=" | "==" => cmp == Ordering::Equal,
+        "!=" if wild < 3 => cmp == Ordering::Less || v.compare(&next(wild)) != Ordering::Less,
+        "!=" => cmp != Ordering::Equal,
+        ">" if wild < 3 => v.compare(&next(wild)) != Ordering::Less,
+        ">" => cmp == Ordering::Greater,
+        ">=" | "=>" => cmp != Ordering::Less,
+        "<" => cmp == Ordering::Less,
+        "<=" | "=<" if wild < 3 => v.compare(&next(wild)) == Ordering::Less,
+        "<=" | "=<" => cmp != Ordering::Greater,
+        "~" | "~>" => cmp != Ordering::Less && v.compare(&next(if wild < 2 { wild } else { 2 })) == Ordering::Less,
+        "^" => {
+            let upper = if c.major > 0 || wild <= 1 {
+                next(1)
+            } else if c.minor > 0 || wild == 2 {
+                next(2)
+            } else {
+                Version{major: 0, minor: 0, patch: c.patch + 1, pre: Vec::new(), build: String::new()}
+            };
+            cmp != Ordering::Less && v.compare(&upper) == Ordering::Less
+        },
+        _ => { return None }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_strings_use_the_alphabet() {
+        let s = random_string(200, b"ab");
+        assert_eq!(s.len(), 200);
+        assert!(s.chars().all(|c| c == 'a' || c == 'b'));
+        assert!(s.contains('a') && s.contains('b'));
+        assert_eq!(random_string(-1, b"ab"), "");
+        assert_ne!(random_u64(), random_u64());
+    }
+
+    #[test]
+    fn until_step_stops_on_overflow() {
+        let ints = |v: Vec<Value>| -> Vec<i64> { v.into_iter().map(|v| match v { Value::Int(i) => i, _ => panic!("not an int") }).collect() };
+        assert_eq!(ints(until_step(0, 10, 3)), vec![0, 3, 6, 9]);
+        assert_eq!(ints(until_step(3, 0, -1)), vec![3, 2, 1]);
+        assert!(until_step(0, 10, 0).is_empty());
+        assert_eq!(ints(until_step(i64::MAX - 1, i64::MAX, 5)), vec![i64::MAX - 1]);
+        assert_eq!(ints(until_step(i64::MIN + 1, i64::MIN, -5)), vec![i64::MIN + 1]);
+    }
+}
@@ -0,0 +1,331 @@
+// This module splits a template into text and the tokens of its actions, the
+// way Go's text/template lexer does. Trim markers ("{{- " and " -}}") are
+// applied here by trimming the whitespace of the neighbouring text, and
+// comments are dropped
+const LEFT_DELIM: &str = "{{";
+const RIGHT_DELIM: &str = "}}";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Text(String),
+    LeftDelim,
+    RightDelim,
+    // A function name or keyword
+    Identifier(String),
+    // .Name, which is either a field of dot or, without a space before it,
+    // of the term before it
+    Field(String),
+    Dot,
+    // $name, or $ on its own
+    Variable(String),
+    String(String),
+    Char(char),
+    Number(String),
+    Bool(bool),
+    Nil,
+    Pipe,
+    LeftParen,
+    RightParen,
+    Declare,
+    Assign,
+    Comma,
+}
+
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub token: Token,
+    pub line: usize,
+    // Whether there was whitespace before the token, which decides whether
+    // a field is chained onto the term before it
+    pub space_before: bool,
+}
+
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+    items: Vec<Item>,
+    // Whether the text after the current action should lose its leading
+    // whitespace
+    trim_next: bool,
+}
+
+pub type LexError = (usize, String);
+
+pub fn lex(src: &str) -> Result<Vec<Item>, LexError> {
+    let mut lexer = Lexer {
+        src,
+        pos: 0,
+        line: 1,
+        items: Vec::new(),
+        trim_next: false,
+    };
+    lexer.run()?;
+    Ok(lexer.items)
+}
+
+fn is_space(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r' || c == '\n'
+}
+
+fn is_alphanumeric(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+impl<'a> Lexer<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn advance(&mut self, n: usize) -> &'a str {
+        let s = &self.src[self.pos..self.pos + n];
+        self.line += s.matches('\n').count();
+        self.pos += n;
+        s
+    }
+
+    fn push(&mut self, token: Token, line: usize, space_before: bool) {
+        self.items.push(Item{token, line, space_before});
+    }
+
+    fn run(&mut self) -> Result<(), LexError> {
+        while self.pos < self.src.len() {
+            let line = self.line;
+            let end = self.rest().find(LEFT_DELIM).unwrap_or_else(|| self.rest().len());
+            let mut text = self.advance(end).to_string();
+            if self.trim_next {
+                text = text.trim_start_matches(is_space).to_string();
+                self.trim_next = false;
+            }
+            if self.pos >= self.src.len() {
+                if !text.is_empty() {
+                    self.push(Token::Text(text), line, false);
+                }
+                break
+            }
+            // A left trim marker is a dash followed by whitespace
+            let after = &self.rest()[LEFT_DELIM.len()..];
+            let trim_left = after.starts_with('-') && after[1..].starts_with(is_space);
+            if trim_left {
+                text = text.trim_end_matches(is_space).to_string();
+            }
+            if !text.is_empty() {
+                self.push(Token::Text(text), line, false);
+            }
+            self.advance(LEFT_DELIM.len() + if trim_left { 2 } else { 0 });
+            if self.rest().starts_with("/*") {
+                self.comment()?;
+                continue
+            }
+            self.push(Token::LeftDelim, self.line, false);
+            self.action()?;
+        }
+        Ok(())
+    }
+
+    // Skips a comment, which has to be the whole of its action
+    fn comment(&mut self) -> Result<(), LexError> {
+        let line = self.line;
+        let end = match self.rest().find("*/") {
+            Some(i) => i + 2,
+            None => { return Err((line, "unclosed comment".to_string())) }
+        };
+        self.advance(end);
+        if self.rest().starts_with(RIGHT_DELIM) {
+            self.advance(RIGHT_DELIM.len());
+        } else if self.rest().starts_with(" -}}") || self.rest().starts_with("\t-}}") || self.rest().starts_with("\n-}}") {
+            self.advance(4);
+            self.trim_next = true;
+        } else {
+            return Err((line, "comment ends before closing delimiter".to_string()))
+        }
+        Ok(())
+    }
+
+    // Reads the tokens of an action up to and including its right delimiter
+    fn action(&mut self) -> Result<(), LexError> {
+        let mut paren_depth = 0;
+        loop {
+            let start = self.pos;
+            while self.peek().map(is_space).unwrap_or(false) {
+                self.advance(1);
+            }
+            let space_before = self.pos > start;
+            let line = self.line;
+            let rest = self.rest();
+            if space_before && rest.starts_with("-}}") {
+                self.advance(3);
+                self.trim_next = true;
+                self.push(Token::RightDelim, line, true);
+                return Ok(())
+            }
+            if rest.starts_with(RIGHT_DELIM) {
+                if paren_depth > 0 {
+                    return Err((line, "unclosed left paren".to_string()))
+                }
+                self.advance(RIGHT_DELIM.len());
+                self.push(Token::RightDelim, line, space_before);
+                return Ok(())
+            }
+            let c = match self.peek() {
+                Some(c) => c,
+                None => { return Err((line, "unclosed action".to_string())) }
+            };
+            let token = match c {
+                '|' => { self.advance(1); Token::Pipe },
+                '(' => { self.advance(1); paren_depth += 1; Token::LeftParen },
+                ')' => {
+                    if paren_depth == 0 {
+                        return Err((line, "unexpected right paren".to_string()))
+                    }
+                    paren_depth -= 1;
+                    self.advance(1);
+                    Token::RightParen
+                },
+                ',' => { self.advance(1); Token::Comma },
+                ':' => {
+                    if !rest.starts_with(":=") {
+                        return Err((line, "expected :=".to_string()))
+                    }
+                    self.advance(2);
+                    Token::Declare
+                },
+                '=' => { self.advance(1); Token::Assign },
+                '"' => Token::String(self.quoted_string()?),
+                '`' => {
+                    let end = match rest[1..].find('`') {
+                        Some(i) => i + 2,
+                        None => { return Err((line, "unterminated raw quoted string".to_string())) }
+                    };
+                    let s = self.advance(end);
+                    Token::String(s[1..s.len() - 1].to_string())
+                },
+                '\'' => self.char_constant()?,
+                '$' => {
+                    let len = 1 + rest[1..].find(|c| !is_alphanumeric(c)).unwrap_or(rest.len() - 1);
+                    Token::Variable(self.advance(len).to_string())
+                },
+                '.' if rest[1..].starts_with(|c: char| c.is_ascii_digit()) => Token::Number(self.number()),
+                '.' => {
+                    let len = 1 + rest[1..].find(|c| !is_alphanumeric(c)).unwrap_or(rest.len() - 1);
+                    if len == 1 {
+                        self.advance(1);
+                        Token::Dot
+                    } else {
+                        Token::Field(self.advance(len)[1..].to_string())
+                    }
+                },
+                '+' | '-' | '0'..='9' => {
+                    let n = self.number();
+                    if n == "+" || n == "-" {
+                        return Err((line, format!("bad number syntax: {:?}", n)))
+                    }
+                    Token::Number(n)
+                },
+                c if is_alphanumeric(c) => {
+                    let len = rest.find(|c| !is_alphanumeric(c)).unwrap_or(rest.len());
+                    match self.advance(len) {
+                        "true" => Token::Bool(true),
+                        "false" => Token::Bool(false),
+                        "nil" => Token::Nil,
+                        word => Token::Identifier(word.to_string()),
+                    }
+                },
+                c => { return Err((line, format!("unrecognized character in action: {:?}", c))) }
+            };
+            self.push(token, line, space_before);
+        }
+    }
+
+    // Reads a number, leaving it to the parser to decide what kind it is
+    fn number(&mut self) -> String {
+        let rest = self.rest();
+        let mut len = 0;
+        let mut prev = ' ';
+        for (i, c) in rest.char_indices() {
+            let ok = match c {
+                '+' | '-' => i == 0 || prev == 'e' || prev == 'E' || prev == 'p' || prev == 'P',
+                '.' | '_' => true,
+                c => c.is_ascii_alphanumeric(),
+            };
+            if !ok {
+                break
+            }
+            // e and p after a hex number are digits or the start of an
+            // exponent, which the parser sorts out
+            len = i + c.len_utf8();
+            prev = c;
+        }
+        self.advance(len).to_string()
+    }
+
+    fn quoted_string(&mut self) -> Result<String, LexError> {
+        let line = self.line;
+        let rest = self.rest();
+        let mut out = String::new();
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.advance(i + 1);
+                    return Ok(out)
+                },
+                '\n' => break,
+                '\\' => match chars.next() {
+                    Some((_, e)) => out.push(self.escape(e, &mut chars)?),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        Err((line, "unterminated quoted string".to_string()))
+    }
+
+    fn char_constant(&mut self) -> Result<Token, LexError> {
+        let line = self.line;
+        let rest = self.rest();
+        let mut chars = rest.char_indices().skip(1);
+        let c = match chars.next() {
+            Some((_, '\\')) => match chars.next() {
+                Some((_, e)) => self.escape(e, &mut chars)?,
+                None => { return Err((line, "unterminated character constant".to_string())) }
+            },
+            Some((_, '\'')) | Some((_, '\n')) | None => { return Err((line, "malformed character constant".to_string())) },
+            Some((_, c)) => c,
+        };
+        match chars.next() {
+            Some((i, '\'')) => {
+                self.advance(i + 1);
+                Ok(Token::Char(c))
+            },
+            _ => Err((line, "malformed character constant".to_string())),
+        }
+    }
+
+    fn escape<I: Iterator<Item = (usize, char)>>(&self, e: char, chars: &mut I) -> Result<char, LexError> {
+        let hex = |chars: &mut I, n: usize| -> Result<char, LexError> {
+            let digits: String = chars.take(n).map(|(_, c)| c).collect();
+            u32::from_str_radix(&digits, 16).ok()
+                .and_then(std::char::from_u32)
+                .ok_or_else(|| (self.line, format!("invalid escape \\{}{}", e, digits)))
+        };
+        match e {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'a' => Ok('\x07'),
+            'b' => Ok('\x08'),
+            'f' => Ok('\x0c'),
+            'v' => Ok('\x0b'),
+            '\\' | '"' | '\'' => Ok(e),
+            'x' => hex(chars, 2),
+            'u' => hex(chars, 4),
+            'U' => hex(chars, 8),
+            e => Err((self.line, format!("unknown escape sequence \\{}", e))),
+        }
+    }
+}
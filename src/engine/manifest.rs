@@ -0,0 +1,220 @@
+// This module sorts rendered templates into the manifest of a release and its
+// hooks, the way Helm's releaseutil.SortManifests does
+use crate::engine::EngineError;
+use crate::release::hook::{Hook, HookDeletePolicy, HookEvent};
+use regex::Regex;
+use serde::Deserialize;
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+
+pub const HOOK_ANNOTATION: &str = "helm.sh/hook";
+pub const HOOK_WEIGHT_ANNOTATION: &str = "helm.sh/hook-weight";
+pub const HOOK_DELETE_POLICY_ANNOTATION: &str = "helm.sh/hook-delete-policy";
+
+// The order Helm installs resources in. Kinds not listed here go last, in
+// alphabetical order
+const INSTALL_ORDER: &[&str] = &[
+    "PriorityClass",
+    "Namespace",
+    "NetworkPolicy",
+    "ResourceQuota",
+    "LimitRange",
+    "PodSecurityPolicy",
+    "PodDisruptionBudget",
+    "ServiceAccount",
+    "Secret",
+    "SecretList",
+    "ConfigMap",
+    "StorageClass",
+    "PersistentVolume",
+    "PersistentVolumeClaim",
+    "CustomResourceDefinition",
+    "ClusterRole",
+    "ClusterRoleList",
+    "ClusterRoleBinding",
+    "ClusterRoleBindingList",
+    "Role",
+    "RoleList",
+    "RoleBinding",
+    "RoleBindingList",
+    "Service",
+    "DaemonSet",
+    "Pod",
+    "ReplicationController",
+    "ReplicaSet",
+    "Deployment",
+    "HorizontalPodAutoscaler",
+    "StatefulSet",
+    "Job",
+    "CronJob",
+    "IngressClass",
+    "Ingress",
+    "APIService",
+];
+
+// A single YAML document from a rendered template
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    // The template it was rendered from
+    pub name: String,
+    pub content: String,
+    pub kind: String,
+}
+
+// The parts of a resource needed to sort it
+#[derive(Deserialize, Default)]
+struct Head {
+    #[serde(default)]
+    kind: String,
+    #[serde(default)]
+    metadata: Option<HeadMetadata>,
+}
+
+#[derive(Deserialize, Default)]
+struct HeadMetadata {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    annotations: Option<HashMap<String, String>>,
+}
+
+// Splits rendered templates into resources, separating out the hooks. Both
+// are returned in install order
+pub fn sort_manifests(files: &BTreeMap<String, String>) -> Result<(Vec<Hook>, Vec<Manifest>), EngineError> {
+    let mut hooks: Vec<Hook> = Vec::new();
+    let mut manifests: Vec<Manifest> = Vec::new();
+    for (path, content) in files.iter() {
+        let base = path.rsplit('/').next().unwrap_or(path);
+        if base.starts_with('_') || content.trim().is_empty() {
+            continue
+        }
+        for doc in split_manifests(content).into_iter() {
+            let head = parse_head(&doc).map_err(|e| EngineError::InvalidManifest{
+                template: path.clone(),
+                message: e.to_string(),
+            })?;
+            let metadata = head.metadata.unwrap_or_default();
+            let annotations = metadata.annotations.unwrap_or_default();
+            let events = match annotations.get(HOOK_ANNOTATION) {
+                Some(events) => events,
+                None => {
+                    manifests.push(Manifest{name: path.clone(), content: doc, kind: head.kind});
+                    continue
+                },
+            };
+            let mut hook = Hook {
+                name: metadata.name,
+                kind: head.kind,
+                path: path.clone(),
+                manifest: doc,
+                weight: annotations.get(HOOK_WEIGHT_ANNOTATION).and_then(|w| w.trim().parse().ok()).unwrap_or(0),
+                ..Default::default()
+            };
+            // Like Helm, events it doesn't know are left out, and the hook is
+            // kept for the ones it does
+            for event in split_annotation(events).into_iter() {
+                match parse_event(&event) {
+                    Some(e) => hook.events.push(e),
+                    None => info!("ignoring unknown event {} of hook {} in {}", event, hook.name, path),
+                }
+            }
+            if let Some(policies) = annotations.get(HOOK_DELETE_POLICY_ANNOTATION) {
+                hook.delete_policies = split_annotation(policies).iter().filter_map(|p| parse_delete_policy(p)).collect();
+            }
+            hooks.push(hook);
+        }
+    }
+    hooks.sort_by(|a, b| compare_kinds(&a.kind, &b.kind));
+    manifests.sort_by(|a, b| compare_kinds(&a.kind, &b.kind));
+    Ok((hooks, manifests))
+}
+
+// Splits a file into its YAML documents, trimmed and without empty ones
+fn split_manifests(content: &str) -> Vec<String> {
+    let sep = Regex::new(r"(?:^|\s*\n)---\s*").expect("invalid separator regex");
+    sep.split(content).map(|d| d.trim()).filter(|d| !d.is_empty()).map(|d| d.to_string()).collect()
+}
+
+fn parse_head(doc: &str) -> Result<Head, serde_yaml::Error> {
+    // A document of nothing but comments is an empty resource
+    if doc.lines().all(|l| l.trim().is_empty() || l.trim_start().starts_with('#')) {
+        return Ok(Head::default())
+    }
+    serde_yaml::from_str(doc)
+}
+
+fn split_annotation(value: &str) -> Vec<String> {
+    value.split(',').map(|v| v.trim().to_lowercase()).collect()
+}
+
+// Unlike stored releases, which keep names they don't know, rendered hooks
+// only get the events and delete policies Helm acts on
+fn parse_event(event: &str) -> Option<HookEvent> {
+    match HookEvent::from(event.to_string()) {
+        HookEvent::Other(_) => None,
+        e => Some(e),
+    }
+}
+
+fn parse_delete_policy(policy: &str) -> Option<HookDeletePolicy> {
+    match HookDeletePolicy::from(policy.to_string()) {
+        HookDeletePolicy::Other(_) => None,
+        p => Some(p),
+    }
+}
+
+// Orders kinds for installation. The sorts using this are stable, so
+// resources of the same kind stay in the order they were rendered
+fn compare_kinds(a: &str, b: &str) -> std::cmp::Ordering {
+    let pos = |kind: &str| INSTALL_ORDER.iter().position(|k| *k == kind);
+    match (pos(a), pos(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_and_hooks_are_sorted() {
+        let mut files: BTreeMap<String, String> = BTreeMap::new();
+        files.insert("app/templates/deploy.yaml".to_string(), "kind: Deployment\nmetadata:\n  name: web\n---\nkind: Service\nmetadata:\n  name: web\n".to_string());
+        files.insert("app/templates/ns.yaml".to_string(), "# only a comment\n---\nkind: Namespace\nmetadata:\n  name: ns\n".to_string());
+        files.insert("app/templates/z.yaml".to_string(), "kind: Widget\n---\nkind: Gadget\n".to_string());
+        files.insert("app/templates/_helpers.tpl".to_string(), "kind: Secret\n".to_string());
+        files.insert("app/templates/hooks.yaml".to_string(), "\
+kind: Job
+metadata:
+  name: migrate
+  annotations:
+    helm.sh/hook: pre-install, post-upgrade, post-renderer
+    helm.sh/hook-weight: \"5\"
+    helm.sh/hook-delete-policy: hook-succeeded,keep
+---
+kind: ConfigMap
+metadata:
+  name: config
+  annotations:
+    helm.sh/hook: test-success
+".to_string());
+
+        let (hooks, manifests) = sort_manifests(&files).unwrap();
+        let kinds: Vec<&str> = manifests.iter().map(|m| m.kind.as_str()).collect();
+        // Kinds Helm doesn't know go last, by name
+        assert_eq!(kinds, vec!["Namespace", "Service", "Deployment", "", "Gadget", "Widget"]);
+
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0].name, "config");
+        assert_eq!(hooks[0].events, vec![HookEvent::Test]);
+        assert_eq!(hooks[1].name, "migrate");
+        assert_eq!(hooks[1].path, "app/templates/hooks.yaml");
+        assert_eq!(hooks[1].weight, 5);
+        // Events and policies it doesn't know are left out, not the hook
+        assert_eq!(hooks[1].events, vec![HookEvent::PreInstall, HookEvent::PostUpgrade]);
+        assert_eq!(hooks[1].delete_policies, vec![HookDeletePolicy::HookSucceeded]);
+    }
+}
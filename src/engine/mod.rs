@@ -0,0 +1,532 @@
+// This module renders the templates of a chart, the way Helm's engine does.
+// Templates are Go text/template templates with Helm's and Sprig's functions,
+// rendered against the chart's values merged with the ones given for the
+// release. The rendered templates are then split into the release's manifest
+// and its hooks
+pub mod exec;
+pub mod funcs;
+pub mod lex;
+pub mod manifest;
+pub mod parse;
+pub mod value;
+
+use crate::chart::metadata::{Dependency, Metadata};
+use crate::chart::{Chart, TEMPLATES_DIR};
+use crate::release::hook::Hook;
+use exec::{State, Templates};
+use serde_json::Map;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use value::{new_map, Files, Value};
+
+const NOTES_FILE: &str = "NOTES.txt";
+// The key of the values every chart in a release shares
+const GLOBAL_KEY: &str = "global";
+
+#[derive(Debug, Fail)]
+pub enum EngineError {
+    #[fail(display = "parse error at ({}:{}): {}", template, line, message)]
+    Parse {
+        template: String,
+        line: usize,
+        message: String,
+    },
+    #[fail(display = "execution error at ({}:{}): {}", template, line, message)]
+    Exec {
+        template: String,
+        line: usize,
+        message: String,
+    },
+    #[fail(display = "YAML parse error on {}: {}", template, message)]
+    InvalidManifest {
+        template: String,
+        message: String,
+    },
+    #[fail(display = "invalid chart: {}", message)]
+    InvalidChart {
+        message: String,
+    },
+}
+
+// What templates see of the release as .Release
+#[derive(Default)]
+pub struct ReleaseOptions {
+    pub name: String,
+    pub namespace: String,
+    pub revision: usize,
+    pub is_upgrade: bool,
+}
+
+// What templates see of the cluster as .Capabilities
+pub struct Capabilities {
+    pub kube_version: String,
+    pub api_versions: Vec<String>,
+}
+
+// Without a cluster to ask, templates see the Kubernetes version the crate
+// is built against, like `helm template` does
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            kube_version: "v1.15.0".to_string(),
+            api_versions: [
+                "v1",
+                "admissionregistration.k8s.io/v1beta1",
+                "apiextensions.k8s.io/v1beta1",
+                "apiregistration.k8s.io/v1",
+                "apps/v1",
+                "apps/v1beta1",
+                "apps/v1beta2",
+                "authentication.k8s.io/v1",
+                "authorization.k8s.io/v1",
+                "autoscaling/v1",
+                "autoscaling/v2beta1",
+                "autoscaling/v2beta2",
+                "batch/v1",
+                "batch/v1beta1",
+                "certificates.k8s.io/v1beta1",
+                "coordination.k8s.io/v1",
+                "events.k8s.io/v1beta1",
+                "extensions/v1beta1",
+                "networking.k8s.io/v1",
+                "networking.k8s.io/v1beta1",
+                "node.k8s.io/v1beta1",
+                "policy/v1beta1",
+                "rbac.authorization.k8s.io/v1",
+                "scheduling.k8s.io/v1",
+                "storage.k8s.io/v1",
+            ].iter().map(|v| v.to_string()).collect(),
+        }
+    }
+}
+
+// The result of rendering a chart for a release
+#[derive(Debug, Default)]
+pub struct Rendered {
+    // The chart's resources in install order, as stored in Release.manifest
+    pub manifest: String,
+    pub hooks: Vec<Hook>,
+    // The chart's rendered templates/NOTES.txt
+    pub notes: String,
+}
+
+#[derive(Default)]
+pub struct Engine {
+    // Fails on values that don't exist rather than rendering them as empty
+    pub strict: bool,
+}
+
+impl Engine {
+    // Renders a chart and sorts it into a manifest and hooks
+    pub fn render_release(&self, chart: &Chart, values: &HashMap<String, serde_json::Value>, opts: &ReleaseOptions, caps: &Capabilities) -> Result<Rendered, EngineError> {
+        if chart.metadata.as_ref().map(|m| m.is_library()).unwrap_or(false) {
+            return Err(EngineError::InvalidChart{message: "library charts are not installable".to_string()})
+        }
+        let mut files = self.render(chart, values, opts, caps)?;
+        // Only the chart's own notes are shown, not those of its dependencies
+        let notes_name = format!("{}/{}{}", chart.name(), TEMPLATES_DIR, NOTES_FILE);
+        let notes = files.remove(&notes_name).unwrap_or_default();
+        files.retain(|name, _| !name.ends_with(NOTES_FILE));
+        let (hooks, manifests) = manifest::sort_manifests(&files)?;
+        let mut out = String::new();
+        for m in manifests.iter() {
+            out.push_str(&format!("---\n# Source: {}\n{}\n", m.name, m.content));
+        }
+        Ok(Rendered{manifest: out, hooks, notes})
+    }
+
+    // Renders every template of a chart and its enabled dependencies,
+    // returning the output by template name, such as
+    // mychart/templates/deployment.yaml or mychart/charts/sub/templates/...
+    // Templates whose names start with _ only define templates for others to
+    // use, and aren't rendered
+    pub fn render(&self, chart: &Chart, values: &HashMap<String, serde_json::Value>, opts: &ReleaseOptions, caps: &Capabilities) -> Result<BTreeMap<String, String>, EngineError> {
+        let metadata = match chart.metadata {
+            Some(ref m) => m,
+            None => { return Err(EngineError::InvalidChart{message: "chart has no metadata".to_string()}) }
+        };
+        let user: Map<String, serde_json::Value> = values.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let tags = match user.get("tags") {
+            Some(serde_json::Value::Object(t)) => t.clone(),
+            _ => Map::new(),
+        };
+        let chart = enabled_chart(chart, &user, &tags);
+        let mut values = user;
+        coalesce(&chart, &mut values);
+        let top = Value::from_json(&serde_json::Value::Object(values));
+
+        let release = release_value(opts);
+        let capabilities = capabilities_value(caps);
+        let mut entries: Vec<Entry> = Vec::new();
+        collect_templates(&chart, metadata.name.clone(), top, &release, &capabilities, &mut entries);
+
+        // Templates are parsed deepest first, so that a chart's definitions
+        // replace those of its dependencies
+        entries.sort_by(|a, b| depth(&b.name).cmp(&depth(&a.name)).then_with(|| b.name.cmp(&a.name)));
+        let mut templates: Templates = HashMap::new();
+        for entry in entries.iter() {
+            let (list, defines) = parse::parse(&entry.source, &exec::is_func).map_err(|(line, message)| EngineError::Parse{
+                template: entry.name.clone(),
+                line,
+                message,
+            })?;
+            templates.extend(defines);
+            templates.insert(entry.name.clone(), Rc::new(list));
+        }
+        let templates = Rc::new(templates);
+
+        let mut out = BTreeMap::new();
+        for entry in entries.iter() {
+            let base = entry.name.rsplit('/').next().unwrap_or(&entry.name);
+            if base.starts_with('_') || entry.library {
+                continue
+            }
+            let mut state = State::new(templates.clone(), self.strict);
+            let rendered = state.execute(&entry.name, entry.context.clone()).map_err(|e| EngineError::Exec{
+                template: e.template,
+                line: e.line,
+                message: e.message,
+            })?;
+            out.insert(entry.name.clone(), rendered);
+        }
+        Ok(out)
+    }
+}
+
+// A template and the data it's rendered with
+struct Entry {
+    name: String,
+    source: String,
+    context: Value,
+    // Templates of library charts are only there to be included
+    library: bool,
+}
+
+fn depth(name: &str) -> usize {
+    name.split('/').count()
+}
+
+fn collect_templates(chart: &Chart, path: String, values: Value, release: &Value, capabilities: &Value, out: &mut Vec<Entry>) {
+    let metadata = chart.metadata.clone().unwrap_or_default();
+    let files = Value::Files(Rc::new(Files {
+        files: chart.files.iter().map(|f| (f.name.clone(), f.data.clone())).collect(),
+    }));
+    let base_path = format!("{}/{}", path, TEMPLATES_DIR.trim_end_matches('/'));
+    for t in chart.templates.iter() {
+        let name = format!("{}/{}", path, t.name);
+        let mut template = BTreeMap::new();
+        template.insert("Name".to_string(), Value::String(name.clone()));
+        template.insert("BasePath".to_string(), Value::String(base_path.clone()));
+        let mut context = BTreeMap::new();
+        context.insert("Values".to_string(), values.clone());
+        context.insert("Release".to_string(), release.clone());
+        context.insert("Chart".to_string(), chart_value(&metadata));
+        context.insert("Capabilities".to_string(), capabilities.clone());
+        context.insert("Files".to_string(), files.clone());
+        context.insert("Template".to_string(), new_map(template));
+        out.push(Entry {
+            name,
+            source: String::from_utf8_lossy(&t.data).into_owned(),
+            context: new_map(context),
+            library: metadata.is_library(),
+        });
+    }
+    for dep in chart.dependencies.iter() {
+        // A dependency sees the values under its name, which share their
+        // maps with the parent's
+        let sub = match values {
+            Value::Map(ref m) => match m.borrow().get(dep.name()) {
+                Some(v @ Value::Map(_)) => v.clone(),
+                _ => new_map(BTreeMap::new()),
+            },
+            _ => new_map(BTreeMap::new()),
+        };
+        collect_templates(dep, format!("{}/charts/{}", path, dep.name()), sub, release, capabilities, out);
+    }
+}
+
+// Returns a copy of the chart with the dependencies that its values enable,
+// named by their aliases. Dependencies in charts/ that Chart.yaml doesn't
+// list are always enabled
+fn enabled_chart(chart: &Chart, values: &Map<String, serde_json::Value>, tags: &Map<String, serde_json::Value>) -> Chart {
+    let listed = chart.metadata.as_ref().map(|m| m.dependencies.clone()).unwrap_or_default();
+    let mut deps: Vec<(Option<&Dependency>, Chart)> = Vec::new();
+    for sub in chart.dependencies.iter() {
+        let uses: Vec<&Dependency> = listed.iter().filter(|d| d.name == sub.name()).collect();
+        if uses.is_empty() {
+            deps.push((None, sub.clone()));
+        }
+        // The same chart can be used more than once under different aliases
+        for dep in uses.into_iter() {
+            let mut sub = sub.clone();
+            if !dep.alias.is_empty() {
+                if let Some(ref mut m) = sub.metadata {
+                    m.name = dep.alias.clone();
+                }
+            }
+            deps.push((Some(dep), sub));
+        }
+    }
+
+    // Conditions are checked against the values with every dependency's
+    // defaults, as a dependency can enable itself
+    let mut out = Chart{dependencies: deps.iter().map(|(_, c)| c.clone()).collect(), ..chart.clone()};
+    let mut cvals = values.clone();
+    coalesce(&out, &mut cvals);
+    out.dependencies = deps.into_iter()
+        .filter(|(dep, _)| dep.map(|d| is_enabled(d, &cvals, tags)).unwrap_or(true))
+        .map(|(_, sub)| {
+            let sub_values = match cvals.get(sub.name()) {
+                Some(serde_json::Value::Object(v)) => v.clone(),
+                _ => Map::new(),
+            };
+            enabled_chart(&sub, &sub_values, tags)
+        })
+        .collect();
+    out
+}
+
+// Decides whether a dependency is enabled the way Helm does: a tag set to
+// true enables it, otherwise one set to false disables it. The first of its
+// conditions that is a boolean overrides that
+fn is_enabled(dep: &Dependency, values: &Map<String, serde_json::Value>, tags: &Map<String, serde_json::Value>) -> bool {
+    let tag_values: Vec<Option<bool>> = dep.tags.iter().map(|t| tags.get(t).and_then(|v| v.as_bool())).collect();
+    let mut enabled = tag_values.contains(&Some(true)) || !tag_values.contains(&Some(false));
+    for condition in dep.condition.split(',').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+        let mut v = values.get(condition.split('.').next().unwrap_or(""));
+        for key in condition.split('.').skip(1) {
+            v = v.and_then(|v| v.get(key));
+        }
+        if let Some(b) = v.and_then(|v| v.as_bool()) {
+            enabled = b;
+            break
+        }
+    }
+    enabled
+}
+
+// Merges a chart's default values into dest, which holds the values given
+// for it, then does the same for its dependencies. Given values win, and a
+// given null removes a default
+fn coalesce(chart: &Chart, dest: &mut Map<String, serde_json::Value>) {
+    let defaults: Map<String, serde_json::Value> = chart.values.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    coalesce_tables(dest, &defaults);
+    let globals = match dest.get(GLOBAL_KEY) {
+        Some(serde_json::Value::Object(g)) => g.clone(),
+        _ => Map::new(),
+    };
+    for dep in chart.dependencies.iter() {
+        let entry = dest.entry(dep.name().to_string()).or_insert_with(|| serde_json::Value::Object(Map::new()));
+        if let serde_json::Value::Object(sub) = entry {
+            coalesce_globals(sub, &globals);
+            coalesce(dep, sub);
+        }
+    }
+}
+
+fn coalesce_tables(dest: &mut Map<String, serde_json::Value>, src: &Map<String, serde_json::Value>) {
+    for (key, value) in src.iter() {
+        match dest.get_mut(key) {
+            None => { dest.insert(key.clone(), value.clone()); },
+            Some(serde_json::Value::Null) => { dest.remove(key); },
+            Some(serde_json::Value::Object(d)) => {
+                if let serde_json::Value::Object(s) = value {
+                    coalesce_tables(d, s);
+                }
+            },
+            Some(_) => (),
+        }
+    }
+}
+
+// Copies the parent's globals into a dependency's values. The parent's win
+// where both have a global
+fn coalesce_globals(dest: &mut Map<String, serde_json::Value>, globals: &Map<String, serde_json::Value>) {
+    let mut merged = match dest.get(GLOBAL_KEY) {
+        Some(serde_json::Value::Object(g)) => g.clone(),
+        _ => Map::new(),
+    };
+    for (key, value) in globals.iter() {
+        match (value, merged.get(key)) {
+            (serde_json::Value::Object(v), Some(serde_json::Value::Object(d))) => {
+                let mut v = v.clone();
+                coalesce_tables(&mut v, d);
+                merged.insert(key.clone(), serde_json::Value::Object(v));
+            },
+            (serde_json::Value::Object(_), Some(_)) => (),
+            (_, Some(serde_json::Value::Object(_))) => (),
+            (value, _) => { merged.insert(key.clone(), value.clone()); },
+        }
+    }
+    dest.insert(GLOBAL_KEY.to_string(), serde_json::Value::Object(merged));
+}
+
+fn release_value(opts: &ReleaseOptions) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("Name".to_string(), Value::String(opts.name.clone()));
+    map.insert("Namespace".to_string(), Value::String(opts.namespace.clone()));
+    map.insert("Revision".to_string(), Value::Int(opts.revision as i64));
+    map.insert("IsUpgrade".to_string(), Value::Bool(opts.is_upgrade));
+    map.insert("IsInstall".to_string(), Value::Bool(!opts.is_upgrade));
+    map.insert("Service".to_string(), Value::String("Helm".to_string()));
+    new_map(map)
+}
+
+fn capabilities_value(caps: &Capabilities) -> Value {
+    let version = caps.kube_version.trim_start_matches('v');
+    let mut parts = version.split('.');
+    let mut kube = BTreeMap::new();
+    kube.insert("Version".to_string(), Value::String(caps.kube_version.clone()));
+    kube.insert("GitVersion".to_string(), Value::String(caps.kube_version.clone()));
+    kube.insert("Major".to_string(), Value::String(parts.next().unwrap_or("").to_string()));
+    kube.insert("Minor".to_string(), Value::String(parts.next().unwrap_or("").to_string()));
+    let mut map = BTreeMap::new();
+    map.insert("KubeVersion".to_string(), new_map(kube));
+    map.insert("APIVersions".to_string(), Value::VersionSet(Rc::new(caps.api_versions.clone())));
+    new_map(map)
+}
+
+// The chart's metadata as templates see it in .Chart, with Go's field names
+fn chart_value(m: &Metadata) -> Value {
+    let string = |s: &str| Value::String(s.to_string());
+    let strings = |l: &[String]| Value::List(l.iter().map(|s| string(s)).collect());
+    let mut map = BTreeMap::new();
+    map.insert("Name".to_string(), string(&m.name));
+    map.insert("Home".to_string(), string(&m.home));
+    map.insert("Sources".to_string(), strings(&m.sources));
+    map.insert("Version".to_string(), string(&m.version));
+    map.insert("Description".to_string(), string(&m.description));
+    map.insert("Keywords".to_string(), strings(&m.keywords));
+    map.insert("Maintainers".to_string(), Value::List(m.maintainers.iter().map(|mt| {
+        let mut maintainer = BTreeMap::new();
+        maintainer.insert("Name".to_string(), string(&mt.name));
+        maintainer.insert("Email".to_string(), string(&mt.email));
+        maintainer.insert("URL".to_string(), string(&mt.url));
+        new_map(maintainer)
+    }).collect()));
+    map.insert("Icon".to_string(), string(&m.icon));
+    map.insert("APIVersion".to_string(), string(&m.api_version));
+    map.insert("Condition".to_string(), string(&m.condition));
+    map.insert("Tags".to_string(), string(&m.tags));
+    map.insert("AppVersion".to_string(), string(&m.app_version));
+    map.insert("Deprecated".to_string(), Value::Bool(m.deprecated));
+    map.insert("Annotations".to_string(), new_map(m.annotations.iter().map(|(k, v)| (k.clone(), string(v))).collect()));
+    map.insert("KubeVersion".to_string(), string(&m.kube_version));
+    map.insert("Dependencies".to_string(), Value::List(m.dependencies.iter().map(|d| {
+        let mut dep = BTreeMap::new();
+        dep.insert("Name".to_string(), string(&d.name));
+        dep.insert("Version".to_string(), string(&d.version));
+        dep.insert("Repository".to_string(), string(&d.repository));
+        dep.insert("Condition".to_string(), string(&d.condition));
+        dep.insert("Tags".to_string(), strings(&d.tags));
+        dep.insert("Enabled".to_string(), Value::Bool(d.enabled));
+        dep.insert("ImportValues".to_string(), Value::List(d.import_values.iter().map(Value::from_json).collect()));
+        dep.insert("Alias".to_string(), string(&d.alias));
+        new_map(dep)
+    }).collect()));
+    map.insert("Type".to_string(), string(&m.chart_type));
+    new_map(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::{File, CHART_FILE, VALUES_FILE};
+    use crate::chart::loader::load_files;
+
+    fn file(name: &str, data: &str) -> File {
+        File{name: name.to_string(), data: data.as_bytes().to_vec()}
+    }
+
+    fn chart(values: &str, templates: &[(&str, &str)]) -> Chart {
+        let mut files = vec![
+            file(CHART_FILE, "apiVersion: v2\nname: app\nversion: 1.0.0\n"),
+            file(VALUES_FILE, values),
+        ];
+        for (name, source) in templates.iter() {
+            files.push(file(&format!("{}{}", TEMPLATES_DIR, name), source));
+        }
+        load_files(files).unwrap()
+    }
+
+    fn render(chart: &Chart, values: &str) -> BTreeMap<String, String> {
+        let values: HashMap<String, serde_json::Value> = serde_yaml::from_str(values).unwrap();
+        let opts = ReleaseOptions{name: "rel".to_string(), namespace: "ns".to_string(), revision: 1, ..Default::default()};
+        Engine::default().render(chart, &values, &opts, &Capabilities::default()).unwrap()
+    }
+
+    // Renders a single template against the given values.yaml
+    fn render_one(values: &str, source: &str) -> String {
+        let out = render(&chart(values, &[("t.yaml", source)]), "{}");
+        out["app/templates/t.yaml"].clone()
+    }
+
+    #[test]
+    fn pipelines() {
+        assert_eq!(render_one("name: web\n", "{{ .Values.name | upper | quote }}"), "\"WEB\"");
+        assert_eq!(render_one("{}", "{{ \"a b\" | replace \" \" \"-\" | printf \"%s!\" }}"), "a-b!");
+        assert_eq!(render_one("{}", "{{ .Values.missing | default \"x\" }}"), "x");
+        assert_eq!(render_one("{}", "{{ $v := 3 }}{{ add $v 4 }}"), "7");
+        assert_eq!(render_one("{}", "{{ .Release.Name }}-{{ .Release.Namespace }}-{{ .Chart.Name }}"), "rel-ns-app");
+    }
+
+    #[test]
+    fn define_template_include() {
+        let helpers = "{{- define \"app.name\" -}}{{ .Chart.Name }}-{{ .Values.suffix }}{{- end -}}";
+        let chart = chart("suffix: x\n", &[
+            ("_helpers.tpl", helpers),
+            ("t.yaml", "{{ template \"app.name\" . }}|{{ include \"app.name\" . | upper }}"),
+        ]);
+        let out = render(&chart, "{}");
+        assert_eq!(out["app/templates/t.yaml"], "app-x|APP-X");
+        // Templates starting with _ only define templates
+        assert!(!out.contains_key("app/templates/_helpers.tpl"));
+    }
+
+    #[test]
+    fn range_with_else() {
+        let values = "list: [a, b]\nmap: {y: 2, x: 1}\nempty: []\nobj: {name: n}\n";
+        assert_eq!(render_one(values, "{{ range $i, $v := .Values.list }}{{ $i }}={{ $v }};{{ end }}"), "0=a;1=b;");
+        // Maps are ranged over in order of key
+        assert_eq!(render_one(values, "{{ range $k, $v := .Values.map }}{{ $k }}{{ $v }}{{ end }}"), "x1y2");
+        assert_eq!(render_one(values, "{{ range .Values.empty }}item{{ else }}none{{ end }}"), "none");
+        assert_eq!(render_one(values, "{{ with .Values.obj }}{{ .name }}{{ end }}"), "n");
+        assert_eq!(render_one(values, "{{ with .Values.missing }}yes{{ else }}no{{ end }}"), "no");
+        assert_eq!(render_one(values, "{{ if eq (len .Values.list) 2 }}two{{ else if .Values.obj }}obj{{ end }}"), "two");
+    }
+
+    #[test]
+    fn trimming() {
+        assert_eq!(render_one("{}", "a  \n  {{- \"b\" -}}  \n  c"), "abc");
+        assert_eq!(render_one("{}", "a\n{{ \"b\" -}}\n\nc"), "a\nbc");
+        assert_eq!(render_one("{}", "a\n\n{{- \"b\" }}\nc"), "ab\nc");
+        assert_eq!(render_one("{}", "a {{- /* comment */ -}} b"), "ab");
+    }
+
+    #[test]
+    fn printf() {
+        let values = "m: {a: x, b: 2}\n";
+        let source = r#"{{ printf "%05.2f|%-4d|%x|%q|%v|%s|%t|%e|%g" 3.14159 7 255 "a\"b" .Values.m "s" true 1234.5 0.00001 }}"#;
+        assert_eq!(render_one(values, source), r#"03.14|7   |ff|"a\"b"|map[a:x b:2]|s|true|1.234500e+03|1e-05"#);
+    }
+
+    #[test]
+    fn values_are_coalesced() {
+        let mut chart = chart("replicas: 1\nimage: {repo: nginx, tag: \"1\"}\nsub: {port: 80}\nglobal: {env: prod}\n", &[
+            ("t.yaml", "{{ .Values.replicas }} {{ .Values.image.repo }}:{{ .Values.image.tag }} {{ .Values.sub.name }}"),
+        ]);
+        let sub = load_files(vec![
+            file(CHART_FILE, "apiVersion: v2\nname: sub\nversion: 1.0.0\n"),
+            file(VALUES_FILE, "port: 8080\nname: sub\nglobal: {env: dev, region: eu}\n"),
+            file("templates/t.yaml", "{{ .Values.port }} {{ .Values.name }} {{ .Values.global.env }} {{ .Values.global.region }}"),
+        ]).unwrap();
+        chart.dependencies.push(sub);
+
+        let out = render(&chart, "image: {tag: \"2\"}\nreplicas: null\n");
+        // Given values win over defaults, and a null removes one
+        assert_eq!(out["app/templates/t.yaml"], " nginx:2 sub");
+        // A dependency sees the parent's values for it and the parent's
+        // globals over its own
+        assert_eq!(out["app/charts/sub/templates/t.yaml"], "80 sub prod eu");
+    }
+}
@@ -0,0 +1,454 @@
+// This module parses the tokens of a template into a tree, following Go's
+// text/template/parse. Parsing a template also returns the templates it
+// defines with define and block
+use crate::engine::lex::{self, Item, Token};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub type List = Vec<Node>;
+
+#[derive(Debug)]
+pub enum Node {
+    Text(String),
+    // An action that prints the value of its pipeline, unless the pipeline
+    // declares a variable
+    Action(Pipe),
+    If(Branch),
+    Range(Branch),
+    With(Branch),
+    Template {
+        line: usize,
+        name: String,
+        pipe: Option<Pipe>,
+    },
+    Break(usize),
+    Continue(usize),
+}
+
+#[derive(Debug)]
+pub struct Branch {
+    pub pipe: Pipe,
+    pub list: List,
+    pub else_list: Option<List>,
+}
+
+#[derive(Debug)]
+pub struct Pipe {
+    pub line: usize,
+    // The variables the pipeline declares or assigns
+    pub decl: Vec<String>,
+    pub is_assign: bool,
+    pub cmds: Vec<Command>,
+}
+
+#[derive(Debug)]
+pub struct Command {
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug)]
+pub enum Arg {
+    Dot,
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    // A function
+    Identifier(String),
+    // .A.B, evaluated on dot
+    Field(Vec<String>),
+    // $x.A.B
+    Variable(String, Vec<String>),
+    // (pipeline).A.B
+    Chain(Box<Arg>, Vec<String>),
+    Pipe(Box<Pipe>),
+}
+
+pub type ParseError = (usize, String);
+
+// The templates a template defines, by name
+pub type Defines = HashMap<String, Rc<List>>;
+
+struct Parser<'a> {
+    items: Vec<Item>,
+    pos: usize,
+    // Decides whether an identifier names a function
+    is_func: &'a dyn Fn(&str) -> bool,
+    defines: Defines,
+    // The variables in scope, which have to be declared before they're used
+    vars: Vec<String>,
+    range_depth: usize,
+}
+
+// Parses a template, returning its tree along with the templates it defines
+pub fn parse(src: &str, is_func: &dyn Fn(&str) -> bool) -> Result<(List, Defines), ParseError> {
+    let items = lex::lex(src)?;
+    let mut parser = Parser {
+        items,
+        pos: 0,
+        is_func,
+        defines: HashMap::new(),
+        vars: vec!["$".to_string()],
+        range_depth: 0,
+    };
+    let (list, end) = parser.list(true)?;
+    if let Some((line, keyword)) = end {
+        return Err((line, format!("unexpected {{{{{}}}}}", keyword)))
+    }
+    Ok((list, parser.defines))
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Item> {
+        self.items.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Item, ParseError> {
+        let line = self.items.last().map(|i| i.line).unwrap_or(1);
+        let item = self.items.get(self.pos).cloned().ok_or((line, "unexpected end of template".to_string()))?;
+        self.pos += 1;
+        Ok(item)
+    }
+
+    fn expect(&mut self, token: Token, context: &str) -> Result<Item, ParseError> {
+        let item = self.next()?;
+        if item.token != token {
+            return Err((item.line, format!("unexpected {} in {}", describe(&item.token), context)))
+        }
+        Ok(item)
+    }
+
+    // Parses nodes up to an {{end}} or {{else}}, which is returned along with
+    // its line. {{else}} is returned as "else", followed by what comes after
+    // it in the same action
+    fn list(&mut self, top: bool) -> Result<(List, Option<(usize, String)>), ParseError> {
+        let mut list = Vec::new();
+        while let Some(item) = self.peek().cloned() {
+            self.pos += 1;
+            match item.token {
+                Token::Text(t) => list.push(Node::Text(t)),
+                Token::LeftDelim => {
+                    let keyword = match self.peek() {
+                        Some(Item{token: Token::Identifier(k), ..}) => k.clone(),
+                        _ => String::new(),
+                    };
+                    match keyword.as_str() {
+                        "end" | "else" => {
+                            self.pos += 1;
+                            return Ok((list, Some((item.line, keyword))))
+                        },
+                        "define" if top => { self.pos += 1; self.define(item.line)?; },
+                        "define" => { return Err((item.line, "define is only allowed at the top level".to_string())) },
+                        _ => list.push(self.action(item.line)?),
+                    }
+                },
+                t => { return Err((item.line, format!("unexpected {}", describe(&t)))) }
+            }
+        }
+        Ok((list, None))
+    }
+
+    fn action(&mut self, line: usize) -> Result<Node, ParseError> {
+        let keyword = match self.peek() {
+            Some(Item{token: Token::Identifier(k), ..}) => k.clone(),
+            _ => String::new(),
+        };
+        match keyword.as_str() {
+            "if" => { self.pos += 1; Ok(Node::If(self.branch("if")?)) },
+            "range" => { self.pos += 1; Ok(Node::Range(self.branch("range")?)) },
+            "with" => { self.pos += 1; Ok(Node::With(self.branch("with")?)) },
+            "template" => { self.pos += 1; self.template(line) },
+            "block" => { self.pos += 1; self.block(line) },
+            "break" | "continue" => {
+                self.pos += 1;
+                if self.range_depth == 0 {
+                    return Err((line, format!("{{{{{}}}}} outside {{{{range}}}}", keyword)))
+                }
+                self.expect(Token::RightDelim, &keyword)?;
+                Ok(if keyword == "break" { Node::Break(line) } else { Node::Continue(line) })
+            },
+            _ => {
+                let pipe = self.pipeline("command", Token::RightDelim)?;
+                Ok(Node::Action(pipe))
+            },
+        }
+    }
+
+    // Parses the rest of an if, range or with, up to its {{end}}. An
+    // {{else if ...}} or {{else with ...}} starts a nested branch that
+    // shares the {{end}}
+    fn branch(&mut self, keyword: &str) -> Result<Branch, ParseError> {
+        let scope = self.vars.len();
+        let pipe = self.pipeline(keyword, Token::RightDelim)?;
+        if keyword == "range" {
+            self.range_depth += 1;
+        }
+        let res = self.branch_body(keyword, pipe);
+        if keyword == "range" {
+            self.range_depth -= 1;
+        }
+        self.vars.truncate(scope);
+        res
+    }
+
+    fn branch_body(&mut self, keyword: &str, pipe: Pipe) -> Result<Branch, ParseError> {
+        let (list, end) = self.list(false)?;
+        let else_list = match end {
+            Some((_, ref k)) if k == "end" => { self.expect(Token::RightDelim, "end")?; None },
+            Some((line, ref k)) if k == "else" => {
+                match self.peek().map(|i| i.token.clone()) {
+                    Some(Token::Identifier(ref k)) if (k == "if" || k == "with") && keyword != "range" => {
+                        let k = k.clone();
+                        self.pos += 1;
+                        let nested = self.branch(&k)?;
+                        Some(vec![if k == "if" { Node::If(nested) } else { Node::With(nested) }])
+                    },
+                    _ => {
+                        self.expect(Token::RightDelim, "else")?;
+                        let (else_list, end) = self.list(false)?;
+                        match end {
+                            Some((_, ref k)) if k == "end" => { self.expect(Token::RightDelim, "end")?; },
+                            _ => { return Err((line, format!("expected end; found {}", end.map(|e| e.1).unwrap_or_else(|| "end of template".to_string())))) }
+                        }
+                        Some(else_list)
+                    },
+                }
+            },
+            _ => { return Err((pipe.line, format!("unexpected EOF in {}", keyword))) }
+        };
+        Ok(Branch{pipe, list, else_list})
+    }
+
+    fn template_name(&mut self, context: &str) -> Result<(usize, String), ParseError> {
+        let item = self.next()?;
+        match item.token {
+            Token::String(s) => Ok((item.line, s)),
+            t => Err((item.line, format!("unexpected {} in {} clause", describe(&t), context))),
+        }
+    }
+
+    fn template(&mut self, line: usize) -> Result<Node, ParseError> {
+        let (_, name) = self.template_name("template")?;
+        let pipe = match self.peek() {
+            Some(Item{token: Token::RightDelim, ..}) => { self.pos += 1; None },
+            _ => Some(self.pipeline("template", Token::RightDelim)?),
+        };
+        Ok(Node::Template{line, name, pipe})
+    }
+
+    fn define(&mut self, line: usize) -> Result<(), ParseError> {
+        let (_, name) = self.template_name("define")?;
+        self.expect(Token::RightDelim, "define clause")?;
+        let vars = std::mem::replace(&mut self.vars, vec!["$".to_string()]);
+        let (list, end) = self.list(false)?;
+        self.vars = vars;
+        match end {
+            Some((_, ref k)) if k == "end" => { self.expect(Token::RightDelim, "end")?; },
+            _ => { return Err((line, format!("unexpected EOF in define of {:?}", name))) }
+        }
+        self.defines.insert(name, Rc::new(list));
+        Ok(())
+    }
+
+    // {{block "name" pipeline}}...{{end}} defines a template and runs it in
+    // place
+    fn block(&mut self, line: usize) -> Result<Node, ParseError> {
+        let (_, name) = self.template_name("block")?;
+        let pipe = self.pipeline("block", Token::RightDelim)?;
+        let vars = std::mem::replace(&mut self.vars, vec!["$".to_string()]);
+        let (list, end) = self.list(false)?;
+        self.vars = vars;
+        match end {
+            Some((_, ref k)) if k == "end" => { self.expect(Token::RightDelim, "end")?; },
+            _ => { return Err((line, format!("unexpected EOF in block {:?}", name))) }
+        }
+        self.defines.insert(name.clone(), Rc::new(list));
+        Ok(Node::Template{line, name, pipe: Some(pipe)})
+    }
+
+    // Parses a pipeline up to and including `end`, which is either the right
+    // delimiter or a right paren
+    fn pipeline(&mut self, context: &str, end: Token) -> Result<Pipe, ParseError> {
+        let line = self.peek().map(|i| i.line).unwrap_or(1);
+        let mut decl = Vec::new();
+        let mut is_assign = false;
+        // Look for $x :=, $x = or, in a range, $i, $v :=
+        if let Some(Token::Variable(_)) = self.peek().map(|i| &i.token) {
+            let next = self.items.get(self.pos + 1).map(|i| i.token.clone());
+            match next {
+                Some(Token::Declare) | Some(Token::Assign) => {
+                    if let Token::Variable(v) = self.next()?.token {
+                        decl.push(v);
+                    }
+                    is_assign = self.next()?.token == Token::Assign;
+                },
+                Some(Token::Comma) if context == "range" => {
+                    if let Token::Variable(v) = self.next()?.token {
+                        decl.push(v);
+                    }
+                    self.pos += 1;
+                    match self.next()?.token {
+                        Token::Variable(v) => decl.push(v),
+                        t => { return Err((line, format!("range can only initialize variables, found {}", describe(&t)))) }
+                    }
+                    match self.next()?.token {
+                        Token::Declare => (),
+                        Token::Assign => is_assign = true,
+                        t => { return Err((line, format!("expected := in range, found {}", describe(&t)))) }
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        let mut cmds = Vec::new();
+        loop {
+            let cmd = self.command(&end)?;
+            cmds.push(cmd);
+            let item = self.next()?;
+            if item.token == end {
+                break
+            }
+            if item.token != Token::Pipe {
+                return Err((item.line, format!("unexpected {} in {}", describe(&item.token), context)))
+            }
+        }
+        for (i, cmd) in cmds.iter().enumerate().skip(1) {
+            if let Some(Arg::Bool(_) | Arg::Dot | Arg::Nil | Arg::Int(_) | Arg::Float(_) | Arg::String(_)) = cmd.args.first() {
+                return Err((line, format!("non executable command in pipeline stage {}", i + 1)))
+            }
+        }
+        for v in decl.iter() {
+            if is_assign {
+                if !self.vars.contains(v) {
+                    return Err((line, format!("undefined variable {:?}", v)))
+                }
+            } else {
+                self.vars.push(v.clone());
+            }
+        }
+        Ok(Pipe{line, decl, is_assign, cmds})
+    }
+
+    fn command(&mut self, end: &Token) -> Result<Command, ParseError> {
+        let mut args = Vec::new();
+        loop {
+            match self.peek() {
+                Some(item) if item.token == Token::Pipe || &item.token == end => break,
+                Some(item) if !args.is_empty() && !item.space_before => {
+                    return Err((item.line, format!("missing space before {}", describe(&item.token))))
+                },
+                Some(_) => args.push(self.operand()?),
+                None => break,
+            }
+        }
+        if args.is_empty() {
+            let line = self.peek().map(|i| i.line).unwrap_or(1);
+            return Err((line, "missing value for command".to_string()))
+        }
+        Ok(Command{args})
+    }
+
+    // A term, followed by any fields chained onto it
+    fn operand(&mut self) -> Result<Arg, ParseError> {
+        let term = self.term()?;
+        let mut fields = Vec::new();
+        while let Some(Item{token: Token::Field(f), space_before: false, ..}) = self.peek() {
+            fields.push(f.clone());
+            self.pos += 1;
+        }
+        if fields.is_empty() {
+            return Ok(term)
+        }
+        Ok(match term {
+            Arg::Field(mut f) => { f.extend(fields); Arg::Field(f) },
+            Arg::Variable(v, mut f) => { f.extend(fields); Arg::Variable(v, f) },
+            Arg::Dot => Arg::Field(fields),
+            Arg::Bool(_) | Arg::Nil | Arg::Int(_) | Arg::Float(_) | Arg::String(_) => {
+                let line = self.items[self.pos - 1].line;
+                return Err((line, "unexpected . after term".to_string()))
+            },
+            term => Arg::Chain(Box::new(term), fields),
+        })
+    }
+
+    fn term(&mut self) -> Result<Arg, ParseError> {
+        let item = self.next()?;
+        Ok(match item.token {
+            Token::Identifier(name) => {
+                if !(self.is_func)(&name) {
+                    return Err((item.line, format!("function {:?} not defined", name)))
+                }
+                Arg::Identifier(name)
+            },
+            Token::Dot => Arg::Dot,
+            Token::Nil => Arg::Nil,
+            Token::Bool(b) => Arg::Bool(b),
+            Token::String(s) => Arg::String(s),
+            Token::Char(c) => Arg::Int(c as i64),
+            Token::Number(n) => parse_number(&n).ok_or((item.line, format!("bad number syntax: {:?}", n)))?,
+            Token::Field(f) => Arg::Field(vec![f]),
+            Token::Variable(v) => {
+                if !self.vars.contains(&v) {
+                    return Err((item.line, format!("undefined variable {:?}", v)))
+                }
+                Arg::Variable(v, Vec::new())
+            },
+            Token::LeftParen => Arg::Pipe(Box::new(self.pipeline("parenthesized pipeline", Token::RightParen)?)),
+            t => { return Err((item.line, format!("unexpected {} in operand", describe(&t)))) }
+        })
+    }
+}
+
+// Parses a number the way Go constants are written: an int if it can be one,
+// otherwise a float
+fn parse_number(n: &str) -> Option<Arg> {
+    let clean = n.replace('_', "");
+    let (sign, digits) = match clean.strip_prefix('-') {
+        Some(d) => (-1, d.to_string()),
+        None => (1, clean.trim_start_matches('+').to_string()),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let int = if let Some(h) = lower.strip_prefix("0x") {
+        i64::from_str_radix(h, 16).ok()
+    } else if let Some(o) = lower.strip_prefix("0o") {
+        i64::from_str_radix(o, 8).ok()
+    } else if let Some(b) = lower.strip_prefix("0b") {
+        i64::from_str_radix(b, 2).ok()
+    } else if lower.len() > 1 && lower.starts_with('0') && lower.chars().all(|c| c.is_ascii_digit()) {
+        i64::from_str_radix(&lower[1..], 8).ok()
+    } else {
+        lower.parse::<i64>().ok()
+    };
+    if let Some(i) = int {
+        return Some(Arg::Int(sign * i))
+    }
+    if lower.starts_with("0x") {
+        return None
+    }
+    lower.parse::<f64>().ok().filter(|f| f.is_finite()).map(|f| Arg::Float(sign as f64 * f))
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Text(_) => "text".to_string(),
+        Token::LeftDelim => "\"{{\"".to_string(),
+        Token::RightDelim => "\"}}\"".to_string(),
+        Token::Identifier(i) => format!("<{}>", i),
+        Token::Field(f) => format!("<.{}>", f),
+        Token::Dot => "<.>".to_string(),
+        Token::Variable(v) => format!("<{}>", v),
+        Token::String(s) => format!("{:?}", s),
+        Token::Char(c) => format!("{:?}", c),
+        Token::Number(n) => format!("<{}>", n),
+        Token::Bool(b) => format!("<{}>", b),
+        Token::Nil => "<nil>".to_string(),
+        Token::Pipe => "\"|\"".to_string(),
+        Token::LeftParen => "\"(\"".to_string(),
+        Token::RightParen => "\")\"".to_string(),
+        Token::Declare => "\":=\"".to_string(),
+        Token::Assign => "\"=\"".to_string(),
+        Token::Comma => "\",\"".to_string(),
+    }
+}
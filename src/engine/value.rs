@@ -0,0 +1,240 @@
+// This module holds the values templates are rendered against. They behave
+// like the values Go templates see in Helm: values read from YAML or JSON are
+// maps, lists, strings, booleans and float64s, while numbers written in
+// templates are ints. Maps are shared rather than copied, so that functions
+// like set change them for the rest of the render, as they do in Helm
+use crate::chart::glob::glob_match;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+pub type Map = Rc<RefCell<BTreeMap<String, Value>>>;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(Map),
+    // .Files, whose methods read the files in a chart
+    Files(Rc<Files>),
+    // .Capabilities.APIVersions, which has a Has method
+    VersionSet(Rc<Vec<String>>),
+}
+
+pub fn new_map(map: BTreeMap<String, Value>) -> Value {
+    Value::Map(Rc::new(RefCell::new(map)))
+}
+
+impl Value {
+    // Converts JSON into a value. Like Helm, every number becomes a float
+    pub fn from_json(json: &serde_json::Value) -> Value {
+        match json {
+            serde_json::Value::Null => Value::Nil,
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => Value::Float(n.as_f64().unwrap_or(0.0)),
+            serde_json::Value::String(s) => Value::String(s.clone()),
+            serde_json::Value::Array(a) => Value::List(a.iter().map(Value::from_json).collect()),
+            serde_json::Value::Object(o) => new_map(o.iter().map(|(k, v)| (k.clone(), Value::from_json(v))).collect()),
+        }
+    }
+
+    // Converts the value to JSON. Floats without a fractional part become
+    // integers, as Go writes them without one
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Nil => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            Value::Int(i) => serde_json::Value::from(*i),
+            Value::Float(f) if f.fract() == 0.0 && f.abs() < 1e15 => serde_json::Value::from(*f as i64),
+            Value::Float(f) => serde_json::Number::from_f64(*f).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null),
+            Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::List(l) => serde_json::Value::Array(l.iter().map(|v| v.to_json()).collect()),
+            Value::Map(m) => serde_json::Value::Object(m.borrow().iter().map(|(k, v)| (k.clone(), v.to_json())).collect()),
+            Value::Files(f) => serde_json::Value::Object(f.files.iter()
+                .map(|(k, v)| (k.clone(), serde_json::Value::String(String::from_utf8_lossy(v).into_owned())))
+                .collect()),
+            Value::VersionSet(v) => serde_json::Value::Array(v.iter().map(|s| serde_json::Value::String(s.clone())).collect()),
+        }
+    }
+
+    // Go's notion of truth: the zero value of a type and empty collections
+    // are false
+    pub fn is_true(&self) -> bool {
+        match self {
+            Value::Nil => false,
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::String(s) => !s.is_empty(),
+            Value::List(l) => !l.is_empty(),
+            Value::Map(m) => !m.borrow().is_empty(),
+            Value::Files(f) => !f.files.is_empty(),
+            Value::VersionSet(v) => !v.is_empty(),
+        }
+    }
+
+    // The name of the value's Go type, as typeOf reports it
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "<nil>",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float64",
+            Value::String(_) => "string",
+            Value::List(_) => "[]interface {}",
+            Value::Map(_) => "map[string]interface {}",
+            Value::Files(_) => "engine.files",
+            Value::VersionSet(_) => "chartutil.VersionSet",
+        }
+    }
+
+    // The name of the value's Go kind, as kindOf reports it
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "invalid",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float64",
+            Value::String(_) => "string",
+            Value::List(_) | Value::VersionSet(_) => "slice",
+            Value::Map(_) | Value::Files(_) => "map",
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+// Formats a value the way Go's %v does
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "<nil>"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(v) => write!(f, "{}", format_float(*v)),
+            Value::String(s) => write!(f, "{}", s),
+            Value::List(l) => {
+                let items: Vec<String> = l.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(" "))
+            },
+            Value::Map(m) => {
+                let items: Vec<String> = m.borrow().iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+                write!(f, "map[{}]", items.join(" "))
+            },
+            Value::Files(files) => {
+                let items: Vec<String> = files.files.iter().map(|(k, v)| format!("{}:{:?}", k, v)).collect();
+                write!(f, "map[{}]", items.join(" "))
+            },
+            Value::VersionSet(v) => write!(f, "[{}]", v.join(" ")),
+        }
+    }
+}
+
+// Formats a float like Go's %v: the shortest representation that reads back
+// the same, switching to an exponent for large and small numbers. This is why
+// Helm renders 1000000 from values.yaml as 1e+06
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        return "NaN".to_string()
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    }
+    if f == 0.0 {
+        return if f.is_sign_negative() { "-0".to_string() } else { "0".to_string() }
+    }
+    // Rust gives the shortest digits too, as d.ddde<exp>
+    let sci = format!("{:e}", f.abs());
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap_or(sci.len()));
+    let exp: i32 = exp.trim_start_matches('e').parse().unwrap_or(0);
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let sign = if f < 0.0 { "-" } else { "" };
+    if !(-4..6).contains(&exp) {
+        return format!("{}{}", sign, format_exponent(&digits, exp))
+    }
+    if exp < 0 {
+        return format!("{}0.{}{}", sign, "0".repeat((-exp - 1) as usize), digits)
+    }
+    let exp = exp as usize;
+    if digits.len() <= exp + 1 {
+        return format!("{}{}{}", sign, digits, "0".repeat(exp + 1 - digits.len()))
+    }
+    format!("{}{}.{}", sign, &digits[..exp + 1], &digits[exp + 1..])
+}
+
+// Formats digits as d.ddde+XX, with at least two digits of exponent
+pub fn format_exponent(digits: &str, exp: i32) -> String {
+    let mantissa = if digits.len() > 1 {
+        format!("{}.{}", &digits[..1], &digits[1..])
+    } else {
+        digits.to_string()
+    };
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exp.abs())
+}
+
+// The files in a chart other than its templates and values, which templates
+// read through .Files
+#[derive(Debug, Default)]
+pub struct Files {
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Files {
+    pub fn get(&self, name: &str) -> String {
+        self.files.get(name).map(|d| String::from_utf8_lossy(d).into_owned()).unwrap_or_default()
+    }
+
+    pub fn get_bytes(&self, name: &str) -> Vec<u8> {
+        self.files.get(name).cloned().unwrap_or_default()
+    }
+
+    // Returns the files whose names match a glob, where ** matches across
+    // directories
+    pub fn glob(&self, pattern: &str) -> Files {
+        Files {
+            files: self.files.iter()
+                .filter(|(name, _)| glob_match(pattern, name))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }
+    }
+
+    pub fn lines(&self, name: &str) -> Vec<Value> {
+        let data = self.get(name);
+        if data.is_empty() {
+            return Vec::new()
+        }
+        data.strip_suffix('\n').unwrap_or(&data).split('\n').map(|l| Value::String(l.to_string())).collect()
+    }
+
+    // The files as the data of a ConfigMap, keyed by their base names
+    pub fn as_config(&self) -> BTreeMap<String, Value> {
+        self.files.iter()
+            .map(|(k, v)| (base_name(k), Value::String(String::from_utf8_lossy(v).into_owned())))
+            .collect()
+    }
+
+    // The files as the data of a Secret, base64 encoded and keyed by their
+    // base names
+    pub fn as_secrets(&self) -> BTreeMap<String, Value> {
+        self.files.iter()
+            .map(|(k, v)| (base_name(k), Value::String(base64::encode(v))))
+            .collect()
+    }
+}
+
+fn base_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_string()
+}
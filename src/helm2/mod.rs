@@ -0,0 +1,477 @@
+// This module imports the release history Helm 2 kept in Tiller's storage.
+// Tiller stored each revision in a ConfigMap in its own namespace, holding
+// the gzipped protobuf encoding of a hapi.release.Release
+mod proto;
+
+use crate::chart::{Chart, File};
+use crate::chart::metadata::{Maintainer, Metadata};
+use crate::release::{Release, Info, Status};
+use crate::release::hook::{Hook, HookDeletePolicy, HookEvent};
+use crate::storage::{Storage, ReleaseLock, Operation, DEFAULT_LOCK_TTL};
+use crate::storage::lock::new_holder;
+use crate::storage::validate::validate_release_name;
+use crate::storage::driver::{Driver, DriverError, DecodeFailure, ReleaseList};
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use kube::api::{Api, v1ConfigMap, ListParams};
+use kube::client::APIClient;
+use log::{debug, info};
+use proto::Reader;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Read;
+
+// The namespace Tiller was installed into unless told otherwise
+pub const TILLER_NAMESPACE: &str = "kube-system";
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+
+// Tiller reads releases out of the ConfigMaps written by Tiller
+pub struct Tiller {
+    client: Api<v1ConfigMap>,
+}
+
+impl Tiller {
+    pub fn new(client: APIClient, namespace: String) -> Self {
+        Tiller {
+            client: Api::v1ConfigMap(client).within(&namespace)
+        }
+    }
+
+    // Returns every revision of the named releases, or of all releases if no
+    // names are given. Revisions that can't be decoded are reported as
+    // failures rather than failing the whole list. Names are checked before
+    // going into the label selector, so one holding a ',' or ')' can't change
+    // what is selected
+    pub fn releases(&self, names: &[String]) -> Result<ReleaseList, DriverError> {
+        for name in names.iter() {
+            validate_release_name(name)?;
+        }
+        let mut selector = "OWNER=TILLER".to_string();
+        if !names.is_empty() {
+            selector.push_str(&format!(",NAME in ({})", names.join(",")));
+        }
+        let mut lp = ListParams::default();
+        lp.label_selector = Some(selector);
+        let res = self.client.list(&lp)?;
+        let mut release_list = ReleaseList::default();
+        for cm in res.items.into_iter() {
+            let decoded = match cm.data.get("release") {
+                Some(d) => decode_tiller_release(d),
+                None => Err(DriverError::InvalidData{message: "no 'release' key found".to_string()}),
+            };
+            match decoded {
+                Ok(r) => release_list.releases.push(r),
+                Err(e) => release_list.failures.push(DecodeFailure{name: cm.metadata.name, cause: e}),
+            }
+        }
+        Ok(release_list)
+    }
+}
+
+// Decodes the base64 encoded payload of a Tiller ConfigMap. Older versions of
+// Tiller did not compress releases, so the payload is only gunzipped if it
+// looks like gzip data
+pub fn decode_tiller_release(data: &str) -> Result<Release, DriverError> {
+    let raw = base64::decode(data)?;
+    if !raw.starts_with(&GZIP_MAGIC) {
+        return convert_release(&raw)
+    }
+    let mut buf = Vec::new();
+    GzDecoder::new(raw.as_slice()).read_to_end(&mut buf).map_err(DriverError::Gzip)?;
+    convert_release(&buf)
+}
+
+// hapi.release.Release
+fn convert_release(buf: &[u8]) -> Result<Release, DriverError> {
+    let mut rel = Release::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => rel.name = value.as_string()?,
+            2 => rel.info = convert_info(value.as_bytes()?)?,
+            3 => rel.chart = Some(convert_chart(value.as_bytes()?)?),
+            4 => rel.config = convert_config(value.as_bytes()?)?,
+            5 => rel.manifest = value.as_string()?,
+            6 => rel.hooks.push(convert_hook(value.as_bytes()?)?),
+            7 => rel.version = value.as_u64()? as usize,
+            8 => rel.namespace = value.as_string()?,
+            _ => (),
+        }
+    }
+    if rel.name.is_empty() {
+        return Err(DriverError::InvalidData{message: "release has no name".to_string()})
+    }
+    Ok(rel)
+}
+
+// hapi.release.Info
+fn convert_info(buf: &[u8]) -> Result<Info, DriverError> {
+    let mut info = Info::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => convert_status(value.as_bytes()?, &mut info)?,
+            2 => info.first_deployed = convert_timestamp(value.as_bytes()?)?,
+            3 => info.last_deployed = convert_timestamp(value.as_bytes()?)?,
+            4 => info.deleted = convert_timestamp(value.as_bytes()?)?,
+            5 => info.description = value.as_string()?,
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
+// hapi.release.Status, which also holds the notes that Helm 3 keeps in Info
+fn convert_status(buf: &[u8], info: &mut Info) -> Result<(), DriverError> {
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => info.status = convert_status_code(value.as_u64()?),
+            4 => info.notes = value.as_string()?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn convert_status_code(code: u64) -> Status {
+    match code {
+        1 => Status::Deployed,
+        2 => Status::Uninstalled,
+        3 => Status::Superseded,
+        4 => Status::Failed,
+        5 => Status::Uninstalling,
+        6 => Status::PendingInstall,
+        7 => Status::PendingUpgrade,
+        8 => Status::PendingRollback,
+        _ => Status::Unknown,
+    }
+}
+
+// google.protobuf.Timestamp. An empty timestamp means the time was never set
+fn convert_timestamp(buf: &[u8]) -> Result<Option<DateTime<Utc>>, DriverError> {
+    let mut seconds: i64 = 0;
+    let mut nanos: u32 = 0;
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => seconds = value.as_u64()? as i64,
+            2 => nanos = value.as_u64()? as u32,
+            _ => (),
+        }
+    }
+    if seconds == 0 && nanos == 0 {
+        return Ok(None)
+    }
+    match Utc.timestamp_opt(seconds, nanos).single() {
+        Some(t) => Ok(Some(t)),
+        None => Err(DriverError::InvalidData{message: format!("invalid timestamp {}s {}ns", seconds, nanos)}),
+    }
+}
+
+// hapi.release.Hook
+fn convert_hook(buf: &[u8]) -> Result<Hook, DriverError> {
+    let mut hook = Hook::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => hook.name = value.as_string()?,
+            2 => hook.kind = value.as_string()?,
+            3 => hook.path = value.as_string()?,
+            4 => hook.manifest = value.as_string()?,
+            5 => hook.events.extend(value.as_packed_u64s()?.into_iter().filter_map(convert_hook_event)),
+            // Tiller only kept when a hook was last started, not how it went
+            6 => hook.last_run.started_at = convert_timestamp(value.as_bytes()?)?,
+            // A negative int32 is sign extended to 64 bits, so truncating it
+            // gives back the original value
+            7 => hook.weight = value.as_u64()? as i32,
+            8 => hook.delete_policies.extend(value.as_packed_u64s()?.into_iter().filter_map(convert_hook_delete_policy)),
+            _ => (),
+        }
+    }
+    Ok(hook)
+}
+
+// Helm 3 dropped the test-failure and crd-install events, so hooks for them
+// lose those events
+fn convert_hook_event(code: u64) -> Option<HookEvent> {
+    match code {
+        1 => Some(HookEvent::PreInstall),
+        2 => Some(HookEvent::PostInstall),
+        3 => Some(HookEvent::PreDelete),
+        4 => Some(HookEvent::PostDelete),
+        5 => Some(HookEvent::PreUpgrade),
+        6 => Some(HookEvent::PostUpgrade),
+        7 => Some(HookEvent::PreRollback),
+        8 => Some(HookEvent::PostRollback),
+        9 => Some(HookEvent::Test),
+        _ => None,
+    }
+}
+
+fn convert_hook_delete_policy(code: u64) -> Option<HookDeletePolicy> {
+    match code {
+        0 => Some(HookDeletePolicy::HookSucceeded),
+        1 => Some(HookDeletePolicy::HookFailed),
+        2 => Some(HookDeletePolicy::BeforeHookCreation),
+        _ => None,
+    }
+}
+
+// hapi.chart.Chart. Like Helm 3, the chart's dependencies (3) aren't kept
+// with the release
+fn convert_chart(buf: &[u8]) -> Result<Chart, DriverError> {
+    let mut chart = Chart::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => chart.metadata = Some(convert_metadata(value.as_bytes()?)?),
+            2 => chart.templates.push(convert_template(value.as_bytes()?)?),
+            4 => chart.values = convert_config(value.as_bytes()?)?,
+            5 => chart.files.push(convert_file(value.as_bytes()?)?),
+            _ => (),
+        }
+    }
+    Ok(chart)
+}
+
+// hapi.chart.Metadata. Tiller's engine and tillerVersion have no equivalent
+// in Helm 3
+fn convert_metadata(buf: &[u8]) -> Result<Metadata, DriverError> {
+    let mut metadata = Metadata::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => metadata.name = value.as_string()?,
+            2 => metadata.home = value.as_string()?,
+            3 => metadata.sources.push(value.as_string()?),
+            4 => metadata.version = value.as_string()?,
+            5 => metadata.description = value.as_string()?,
+            6 => metadata.keywords.push(value.as_string()?),
+            7 => metadata.maintainers.push(convert_maintainer(value.as_bytes()?)?),
+            9 => metadata.icon = value.as_string()?,
+            10 => metadata.api_version = value.as_string()?,
+            11 => metadata.condition = value.as_string()?,
+            12 => metadata.tags = value.as_string()?,
+            13 => metadata.app_version = value.as_string()?,
+            14 => metadata.deprecated = value.as_u64()? != 0,
+            16 => {
+                let (k, v) = convert_map_entry(value.as_bytes()?)?;
+                metadata.annotations.insert(k, v);
+            },
+            17 => metadata.kube_version = value.as_string()?,
+            _ => (),
+        }
+    }
+    // Every Helm 2 chart is a v1 chart, whether or not it said so
+    metadata.set_defaults();
+    Ok(metadata)
+}
+
+// hapi.chart.Maintainer
+fn convert_maintainer(buf: &[u8]) -> Result<Maintainer, DriverError> {
+    let mut maintainer = Maintainer::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => maintainer.name = value.as_string()?,
+            2 => maintainer.email = value.as_string()?,
+            3 => maintainer.url = value.as_string()?,
+            _ => (),
+        }
+    }
+    Ok(maintainer)
+}
+
+// An entry of a map<string, string>, which protobuf encodes as a message
+// with the key and value as fields 1 and 2
+fn convert_map_entry(buf: &[u8]) -> Result<(String, String), DriverError> {
+    let (mut k, mut v) = (String::new(), String::new());
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => k = value.as_string()?,
+            2 => v = value.as_string()?,
+            _ => (),
+        }
+    }
+    Ok((k, v))
+}
+
+// hapi.chart.Template
+fn convert_template(buf: &[u8]) -> Result<File, DriverError> {
+    let mut file = File::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => file.name = value.as_string()?,
+            2 => file.data = value.as_bytes()?.to_vec(),
+            _ => (),
+        }
+    }
+    Ok(file)
+}
+
+// Tiller kept the chart's other files as google.protobuf.Any, with the file
+// name as the type URL
+fn convert_file(buf: &[u8]) -> Result<File, DriverError> {
+    let mut file = File::default();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        match number {
+            1 => file.name = value.as_string()?,
+            2 => file.data = value.as_bytes()?.to_vec(),
+            _ => (),
+        }
+    }
+    Ok(file)
+}
+
+// hapi.chart.Config. Tiller kept both the user supplied values and the
+// chart's default values as raw YAML
+fn convert_config(buf: &[u8]) -> Result<HashMap<String, Value>, DriverError> {
+    let mut raw = String::new();
+    for field in Reader::new(buf) {
+        let (number, value) = field?;
+        if number == 1 {
+            raw = value.as_string()?;
+        }
+    }
+    // The YAML parser fails on documents with nothing but comments, which is
+    // how many values.yaml files start out
+    if raw.lines().all(|l| l.trim().is_empty() || l.trim_start().starts_with('#')) {
+        return Ok(HashMap::new())
+    }
+    let values: Option<HashMap<String, Value>> = serde_yaml::from_str(&raw)
+        .map_err(|e| DriverError::InvalidData{message: format!("unable to parse release values: {}", e)})?;
+    Ok(values.unwrap_or_default())
+}
+
+#[derive(Default)]
+pub struct ImportOptions {
+    // Reports what would be imported without writing anything
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    // The revisions that were (or, on a dry run, would be) imported
+    pub imported: Vec<String>,
+    // The revisions that already exist in the destination, and so were left
+    // alone. This makes it safe to rerun an import
+    pub skipped: Vec<String>,
+}
+
+// Writes the given Helm 2 releases into `to`. The storage should be for the
+// namespace the releases were deployed to and have no history limit, as the
+// oldest revisions are written first
+pub fn import<D: Driver>(releases: &[Release], to: &Storage<D>, opts: &ImportOptions) -> Result<ImportReport, DriverError> {
+    let mut sorted: Vec<&Release> = releases.iter().collect();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+    let mut report = ImportReport::default();
+    let holder = new_holder();
+    // As releases are sorted by name, each one is locked just once
+    let mut lock: Option<ReleaseLock<'_>> = None;
+    for rel in sorted.into_iter() {
+        let name = format!("{}.v{}", rel.name, rel.version);
+        let exists = if opts.dry_run {
+            match to.get(&rel.name, &rel.version) {
+                Ok(_) => true,
+                Err(ref e) if e.is_not_found() => false,
+                Err(e) => { return Err(e) }
+            }
+        } else {
+            debug!("importing {}", name);
+            if lock.as_ref().map(|l| !l.is_for(&rel.name)).unwrap_or(true) {
+                lock = Some(to.lock(&rel.name, Operation::Import, &holder, DEFAULT_LOCK_TTL)?);
+            }
+            match to.create(rel.clone(), lock.as_ref().expect("release is locked")) {
+                Ok(_) => false,
+                Err(ref e) if e.is_already_exists() => true,
+                Err(e) => { return Err(e) }
+            }
+        };
+        if exists {
+            report.skipped.push(name);
+        } else {
+            report.imported.push(name);
+        }
+    }
+    info!("imported {} Helm 2 releases, skipped {}", report.imported.len(), report.skipped.len());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::metadata::API_VERSION_V1;
+    use crate::kube::fake::FakeServer;
+
+    fn varint(mut n: u64, out: &mut Vec<u8>) {
+        while n >= 0x80 {
+            out.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    fn bytes(number: u64, data: &[u8], out: &mut Vec<u8>) {
+        varint(number << 3 | 2, out);
+        varint(data.len() as u64, out);
+        out.extend_from_slice(data);
+    }
+
+    fn message(fields: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (number, data) in fields.iter() {
+            bytes(*number, data, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn converts_chart() {
+        let maintainer = message(&[(1, b"jane"), (2, b"jane@example.com")]);
+        let annotation = message(&[(1, b"category"), (2, b"database")]);
+        let metadata = message(&[(1, b"mysql"), (4, b"1.6.2"), (7, &maintainer), (13, b"5.7.28"), (16, &annotation)]);
+        let template = message(&[(1, b"templates/svc.yaml"), (2, b"kind: Service")]);
+        let values = message(&[(1, b"# defaults\nport: 3306\n")]);
+        let file = message(&[(1, b"README.md"), (2, b"# MySQL")]);
+        let chart = message(&[(1, &metadata), (2, &template), (4, &values), (5, &file)]);
+        let rel = convert_release(&message(&[(1, b"db"), (3, &chart)])).unwrap();
+
+        let chart = rel.chart.unwrap();
+        let metadata = chart.metadata.as_ref().unwrap();
+        assert_eq!(chart.name(), "mysql");
+        assert_eq!(chart.version(), "1.6.2");
+        assert_eq!(chart.app_version(), "5.7.28");
+        assert_eq!(metadata.api_version, API_VERSION_V1);
+        assert_eq!(metadata.maintainers[0].email, "jane@example.com");
+        assert_eq!(metadata.annotations["category"], "database");
+        assert_eq!(chart.templates, vec![File{name: "templates/svc.yaml".to_string(), data: b"kind: Service".to_vec()}]);
+        assert_eq!(chart.values["port"], 3306);
+        assert_eq!(chart.files[0].name, "README.md");
+    }
+
+    #[test]
+    fn converts_chart_with_comment_only_values() {
+        let values = message(&[(1, b"# nothing to see here\n")]);
+        let chart = message(&[(4, &values)]);
+        let rel = convert_release(&message(&[(1, b"db"), (3, &chart)])).unwrap();
+        assert!(rel.chart.unwrap().values.is_empty());
+    }
+
+    #[test]
+    fn rejects_names_that_would_change_the_selector() {
+        let server = FakeServer::start();
+        let tiller = Tiller::new(server.client(), "kube-system".to_string());
+        for name in ["app),OWNER in (x", "app,other", "App", ""].iter() {
+            match tiller.releases(&["db".to_string(), name.to_string()]) {
+                Err(DriverError::InvalidReleaseName{..}) => (),
+                r => panic!("expected {:?} to be rejected, got {:?}", name, r),
+            }
+        }
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+// A minimal decoder for the protobuf wire format. It only understands as much
+// as is needed to read the messages Tiller stored, so unknown fields are
+// skipped rather than rejected
+use crate::storage::driver::DriverError;
+
+pub enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+fn decode_error(message: String) -> DriverError {
+    DriverError::InvalidData{message}
+}
+
+impl<'a> Field<'a> {
+    pub fn as_u64(&self) -> Result<u64, DriverError> {
+        match self {
+            Field::Varint(v) | Field::Fixed64(v) => Ok(*v),
+            Field::Fixed32(v) => Ok(*v as u64),
+            Field::Bytes(_) => Err(decode_error("expected a number, found a length delimited field".to_string())),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8], DriverError> {
+        match self {
+            Field::Bytes(b) => Ok(b),
+            _ => Err(decode_error("expected a length delimited field, found a number".to_string())),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String, DriverError> {
+        String::from_utf8(self.as_bytes()?.to_vec())
+            .map_err(|e| decode_error(format!("string field is not valid UTF-8: {}", e)))
+    }
+
+    // Repeated numbers are packed into one length delimited field by proto3,
+    // but older encoders write a field per number, so both are accepted
+    pub fn as_packed_u64s(&self) -> Result<Vec<u64>, DriverError> {
+        let packed = match self {
+            Field::Bytes(b) => b,
+            _ => { return Ok(vec![self.as_u64()?]) }
+        };
+        let mut reader = Reader::new(packed);
+        let mut values = Vec::new();
+        while reader.pos < reader.buf.len() {
+            values.push(reader.read_varint()?);
+        }
+        Ok(values)
+    }
+}
+
+// Reader walks over the fields of a single message, yielding each field
+// number along with its value
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader {
+            buf,
+            pos: 0,
+        }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, DriverError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = match self.buf.get(self.pos) {
+                Some(b) => *b,
+                None => { return Err(decode_error("message ends in the middle of a varint".to_string())) }
+            };
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value)
+            }
+        }
+        Err(decode_error("varint is longer than 10 bytes".to_string()))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DriverError> {
+        if self.buf.len() - self.pos < len {
+            return Err(decode_error(format!("field of {} bytes runs past the end of the message", len)))
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_field(&mut self) -> Result<(u64, Field<'a>), DriverError> {
+        let tag = self.read_varint()?;
+        let number = tag >> 3;
+        let field = match tag & 0x7 {
+            0 => Field::Varint(self.read_varint()?),
+            1 => {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(self.read_bytes(8)?);
+                Field::Fixed64(u64::from_le_bytes(b))
+            },
+            2 => {
+                let len = self.read_varint()? as usize;
+                Field::Bytes(self.read_bytes(len)?)
+            },
+            5 => {
+                let mut b = [0u8; 4];
+                b.copy_from_slice(self.read_bytes(4)?);
+                Field::Fixed32(u32::from_le_bytes(b))
+            },
+            t => { return Err(decode_error(format!("unsupported wire type {} for field {}", t, number))) }
+        };
+        Ok((number, field))
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<(u64, Field<'a>), DriverError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None
+        }
+        let res = self.read_field();
+        if res.is_err() {
+            // Stop at the first error as the position is no longer reliable
+            self.pos = self.buf.len();
+        }
+        Some(res)
+    }
+}
@@ -0,0 +1,256 @@
+// This module is a fake Kubernetes API server for tests. It keeps objects in
+// memory and understands just enough of the API for the drivers and lockers:
+// getting, listing by label, creating, replacing, merge patching and deleting
+// namespaced objects, with resource versions checked the way the real server
+// checks them. Requests can also be made to lose their response after they
+// are applied, as happens when a connection drops
+use external_kube::client::APIClient;
+use external_kube::config::Configuration;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+// The last part of the path of every collection the server knows about
+const COLLECTIONS: &[&str] = &["secrets", "configmaps", "leases"];
+
+#[derive(Default)]
+struct State {
+    // Objects by their path, such as /api/v1/namespaces/default/secrets/name
+    objects: BTreeMap<String, Value>,
+    last_version: u64,
+    // How many of the next requests with a method lose their response
+    lose: HashMap<String, usize>,
+    // Every request received, as "METHOD path"
+    requests: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct FakeServer {
+    state: Arc<Mutex<State>>,
+    base: String,
+}
+
+impl FakeServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("unable to listen for fake API server");
+        let server = FakeServer {
+            state: Arc::new(Mutex::new(State::default())),
+            base: format!("http://{}", listener.local_addr().expect("no address to listen on")),
+        };
+        let state = server.state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve(&state, stream);
+            }
+        });
+        server
+    }
+
+    pub fn client(&self) -> APIClient {
+        APIClient::new(Configuration::new(self.base.clone(), reqwest::Client::new()))
+    }
+
+    // Makes the next `count` requests with `method` lose their response after
+    // they have been applied
+    pub fn lose_responses(&self, method: &str, count: usize) {
+        self.state.lock().unwrap().lose.insert(method.to_string(), count);
+    }
+
+    pub fn object(&self, path: &str) -> Option<Value> {
+        self.state.lock().unwrap().objects.get(path).cloned()
+    }
+
+    pub fn remove_object(&self, path: &str) {
+        self.state.lock().unwrap().objects.remove(path);
+    }
+
+    // The names of the objects in a collection, such as
+    // /api/v1/namespaces/default/secrets
+    pub fn names(&self, collection: &str) -> Vec<String> {
+        let prefix = format!("{}/", collection);
+        self.state.lock().unwrap().objects.keys()
+            .filter_map(|p| p.strip_prefix(&prefix).map(|n| n.to_string()))
+            .collect()
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+fn serve(state: &Mutex<State>, stream: TcpStream) {
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => { return }
+    });
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return
+    }
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 {
+        return
+    }
+    let (method, target) = (parts[0].to_string(), parts[1].to_string());
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).is_err() {
+            return
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0u8; length];
+    if reader.read_exact(&mut body).is_err() {
+        return
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p.to_string(), q.to_string()),
+        None => (target.clone(), String::new()),
+    };
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!("{} {}", method, path));
+    let (code, response) = handle(&mut state, &method, &path, &query, &body);
+    let lose = state.lose.get(&method).cloned().unwrap_or(0);
+    if lose > 0 {
+        state.lose.insert(method, lose - 1);
+        // Dropping the connection without a response
+        return
+    }
+    drop(state);
+    let data = response.to_string();
+    let mut stream = stream;
+    let _ = write!(stream, "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", code, data.len(), data);
+}
+
+fn status(code: u16, reason: &str, message: &str) -> (u16, Value) {
+    (code, json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": code,
+    }))
+}
+
+fn handle(state: &mut State, method: &str, path: &str, query: &str, body: &[u8]) -> (u16, Value) {
+    let is_collection = COLLECTIONS.iter().any(|c| path.ends_with(&format!("/{}", c)));
+    let body: Value = if body.is_empty() { Value::Null } else {
+        match serde_json::from_slice(body) {
+            Ok(v) => v,
+            Err(e) => { return status(400, "BadRequest", &e.to_string()) }
+        }
+    };
+    match (method, is_collection) {
+        ("GET", true) => {
+            let selector = query.split('&')
+                .filter_map(|p| p.split_once('='))
+                .find(|(k, _)| *k == "labelSelector")
+                .map(|(_, v)| percent_decode(v))
+                .unwrap_or_default();
+            let prefix = format!("{}/", path);
+            let items: Vec<Value> = state.objects.iter()
+                .filter(|(p, o)| p.starts_with(&prefix) && matches_selector(o, &selector))
+                .map(|(_, o)| o.clone())
+                .collect();
+            (200, json!({"metadata": {"resourceVersion": state.last_version.to_string()}, "items": items}))
+        },
+        ("POST", true) => {
+            let name = body["metadata"]["name"].as_str().unwrap_or("").to_string();
+            let path = format!("{}/{}", path, name);
+            if state.objects.contains_key(&path) {
+                return status(409, "AlreadyExists", &format!("{} already exists", name))
+            }
+            store(state, path, body)
+        },
+        (_, true) => status(405, "MethodNotAllowed", method),
+        ("GET", false) => match state.objects.get(path) {
+            Some(o) => (200, o.clone()),
+            None => status(404, "NotFound", path),
+        },
+        ("DELETE", false) => match state.objects.remove(path) {
+            Some(o) => (200, o),
+            None => status(404, "NotFound", path),
+        },
+        ("PUT", false) | ("PATCH", false) => {
+            let current = match state.objects.get(path) {
+                Some(o) => o.clone(),
+                None => { return status(404, "NotFound", path) }
+            };
+            if let Some(v) = body["metadata"]["resourceVersion"].as_str() {
+                if Some(v) != current["metadata"]["resourceVersion"].as_str() {
+                    return status(409, "Conflict", "the object has been modified")
+                }
+            }
+            let object = if method == "PUT" { body } else { merge_patch(current, &body) };
+            store(state, path.to_string(), object)
+        },
+        _ => status(405, "MethodNotAllowed", method),
+    }
+}
+
+fn store(state: &mut State, path: String, mut object: Value) -> (u16, Value) {
+    state.last_version += 1;
+    object["metadata"]["resourceVersion"] = state.last_version.to_string().into();
+    state.objects.insert(path, object.clone());
+    (200, object)
+}
+
+// Applies a JSON merge patch, where null removes a key
+fn merge_patch(target: Value, patch: &Value) -> Value {
+    let patch = match patch {
+        Value::Object(p) => p,
+        p => { return p.clone() }
+    };
+    let mut target = match target {
+        Value::Object(t) => t,
+        _ => Map::new(),
+    };
+    for (k, v) in patch.iter() {
+        if v.is_null() {
+            target.remove(k);
+            continue
+        }
+        let current = target.remove(k).unwrap_or(Value::Null);
+        target.insert(k.clone(), merge_patch(current, v));
+    }
+    Value::Object(target)
+}
+
+// Only equality selectors such as owner=helm,name=app are supported
+fn matches_selector(object: &Value, selector: &str) -> bool {
+    selector.split(',').filter(|s| !s.is_empty()).all(|s| match s.split_once('=') {
+        Some((k, v)) => object["metadata"]["labels"][k.trim()].as_str() == Some(v.trim()),
+        None => false,
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                out.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+                i += 3;
+            },
+            b'+' => { out.push(b' '); i += 1 },
+            b => { out.push(b); i += 1 },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
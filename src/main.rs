@@ -1,6 +1,9 @@
+mod chart;
 mod storage;
 mod release;
 mod kube;
+mod helm2;
+mod engine;
 
 extern crate chrono;
 extern crate env_logger;
@@ -14,21 +17,63 @@ extern crate log;
 #[macro_use]
 extern crate serde_json;
 extern crate flate2;
+extern crate tar;
 extern crate reqwest;
+extern crate hyper;
+extern crate rusqlite;
+extern crate aes_gcm;
+extern crate tokio;
+extern crate futures;
+extern crate regex;
+extern crate sha2;
+extern crate getrandom;
 
+use storage::driver::factory;
 use storage::driver::secrets::Secrets;
 use storage::driver::configmaps::ConfigMaps;
+use storage::migrate::{migrate, MigrateOptions};
+use helm2::{Tiller, ImportOptions, ImportReport, import};
 use release::Release;
 use external_kube::config;
 use external_kube::client::APIClient;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use serde_json::Value;
 use log::{info, debug, error};
-use storage::{Storage, MaxHistory};
+use storage::{Storage, MaxHistory, Operation, DEFAULT_LOCK_TTL};
+use storage::lock::new_holder;
 use crate::kube::client::Client;
+use failure::Error;
+
+const MIGRATE_USAGE: &str = "usage: pilothouse migrate <from> <to> [--namespace <namespace>] [--delete-source]
+
+Copies the history of every release from one storage driver to another.
+Drivers are one of 'secret', 'configmap', 'memory', 'sql' (using the database
+in $HELM_DRIVER_SQL_CONNECTION_STRING) or 'sql:<path to sqlite database>'";
+
+const IMPORT_HELM2_USAGE: &str = "usage: pilothouse import-helm2 [release...] [--tiller-namespace <namespace>] [--driver <driver>] [--dry-run]
+
+Imports the history of Helm 2 releases from Tiller's ConfigMaps, or of every
+release if none are named. Releases are written to the namespace they were
+deployed to using the given driver, one of 'secret', 'configmap', 'memory',
+'sql' or 'sql:<path to sqlite database>'. The driver defaults to $HELM_DRIVER,
+or 'secret' if that is not set";
 
 fn main() {
     env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    let res = match args.get(1).map(|a| a.as_str()) {
+        Some("migrate") => Some(migrate_command(&args[2..])),
+        Some("import-helm2") => Some(import_helm2_command(&args[2..])),
+        _ => None,
+    };
+    if let Some(res) = res {
+        if let Err(e) = res {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return
+    }
+
     let config = config::load_kube_config().expect("failed to load kubeconfig");
     let client = APIClient::new(config);
 
@@ -45,8 +90,12 @@ fn main() {
         ..Default::default()
     };
 
+    // Writes need the release lock
+    let holder = new_holder();
+    let lock = store.lock(&name, Operation::Install, &holder, DEFAULT_LOCK_TTL).unwrap();
+
     // Create secret example
-    store.create(rel).unwrap();
+    store.create(rel, &lock).unwrap();
 
     let mut updated_rel = store.get(&name, &1).unwrap();
     println!("{:?}", updated_rel);
@@ -54,7 +103,7 @@ fn main() {
     // Update secret example
     updated_rel.manifest = "kind: Blah\napiVersion:bar".into();
 
-    store.update(updated_rel).unwrap();
+    store.update(updated_rel, &lock).unwrap();
 
     let mut updated_rel = store.get(&name, &1).unwrap();
     println!("{:?}", updated_rel);
@@ -63,7 +112,7 @@ fn main() {
     updated_rel.version = 2;
     updated_rel.manifest = "kind: Last\napiVersion:bar".into();
 
-    store.create(updated_rel).unwrap();
+    store.create(updated_rel, &lock).unwrap();
 
     let updated_rel = store.get(&name, &1).unwrap();
     println!("{:?}", updated_rel);
@@ -89,8 +138,10 @@ fn main() {
         ..Default::default()
     };
 
+    let lock = store.lock(&name, Operation::Install, &holder, DEFAULT_LOCK_TTL).unwrap();
+
     // Create ConfigMap example
-    store.create(rel).unwrap();
+    store.create(rel, &lock).unwrap();
 
     let mut updated_rel = store.get(&name, &1).unwrap();
     println!("{:?}", updated_rel);
@@ -98,7 +149,7 @@ fn main() {
     // Update configmap example
     updated_rel.manifest = "kind: Blah\napiVersion:bar".into();
 
-    store.update(updated_rel).unwrap();
+    store.update(updated_rel, &lock).unwrap();
 
     let mut updated_rel = store.get(&name, &1).unwrap();
     println!("{:?}", updated_rel);
@@ -107,7 +158,7 @@ fn main() {
     updated_rel.version = 2;
     updated_rel.manifest = "kind: Last\napiVersion:bar".into();
 
-    store.create(updated_rel).unwrap();
+    store.create(updated_rel, &lock).unwrap();
 
     let updated_rel = store.get(&name, &2).unwrap();
     println!("{:?}", updated_rel);
@@ -119,3 +170,91 @@ fn main() {
     let rel = store.delete(&name, &1).unwrap();
     println!("{:?}", rel);
 }
+
+fn migrate_command(args: &[String]) -> Result<(), Error> {
+    let mut drivers: Vec<&str> = Vec::new();
+    let mut namespace = "default".to_string();
+    let mut opts = MigrateOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--namespace" | "-n" => {
+                namespace = iter.next().ok_or_else(|| format_err!("--namespace requires a value\n\n{}", MIGRATE_USAGE))?.clone();
+            },
+            "--delete-source" => opts.delete_source = true,
+            _ => drivers.push(arg),
+        }
+    }
+    if drivers.len() != 2 {
+        bail!("{}", MIGRATE_USAGE)
+    }
+    migrate_from(drivers[0], drivers[1], namespace, &opts)
+}
+
+fn migrate_from(from: &str, to: &str, namespace: String, opts: &MigrateOptions) -> Result<(), Error> {
+    let from = Storage::new(factory::from_name(from, namespace.clone())?, MaxHistory::NoLimit);
+    let to = Storage::new(factory::from_name(to, namespace)?, MaxHistory::NoLimit);
+    let report = migrate(&from, &to, opts)?;
+    for key in report.migrated.iter() {
+        println!("migrated {}", key);
+    }
+    for key in report.skipped.iter() {
+        println!("skipped {} (already migrated)", key);
+    }
+    for f in report.failures.iter() {
+        println!("unable to migrate {}: {}", f.name, f.cause);
+    }
+    Ok(())
+}
+
+fn import_helm2_command(args: &[String]) -> Result<(), Error> {
+    let mut names: Vec<String> = Vec::new();
+    let mut tiller_namespace = helm2::TILLER_NAMESPACE.to_string();
+    let mut driver = std::env::var(factory::HELM_DRIVER).unwrap_or_default();
+    let mut opts = ImportOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--tiller-namespace" => {
+                tiller_namespace = iter.next().ok_or_else(|| format_err!("--tiller-namespace requires a value\n\n{}", IMPORT_HELM2_USAGE))?.clone();
+            },
+            "--driver" => {
+                driver = iter.next().ok_or_else(|| format_err!("--driver requires a value\n\n{}", IMPORT_HELM2_USAGE))?.clone();
+            },
+            "--dry-run" => opts.dry_run = true,
+            a if a.starts_with('-') => bail!("unknown flag {}\n\n{}", a, IMPORT_HELM2_USAGE),
+            _ => names.push(arg.clone()),
+        }
+    }
+    // Fail on a bad driver before anything is read from Tiller
+    factory::validate_name(&driver)?;
+
+    let client = APIClient::new(config::load_kube_config()?);
+    let list = Tiller::new(client, tiller_namespace).releases(&names)?;
+    for f in list.failures.iter() {
+        println!("unable to decode {}: {}", f.name, f.cause);
+    }
+    // Helm 3 keeps releases in the namespace they were deployed to rather
+    // than in one central place
+    let mut by_namespace: BTreeMap<String, Vec<Release>> = BTreeMap::new();
+    for rel in list.releases.into_iter() {
+        let namespace = if rel.namespace.is_empty() { "default".to_string() } else { rel.namespace.clone() };
+        by_namespace.entry(namespace).or_default().push(rel);
+    }
+    for (namespace, releases) in by_namespace.into_iter() {
+        let store = Storage::new(factory::from_name(&driver, namespace)?, MaxHistory::NoLimit);
+        let report = import(&releases, &store, &opts)?;
+        print_import_report(&report, &opts);
+    }
+    Ok(())
+}
+
+fn print_import_report(report: &ImportReport, opts: &ImportOptions) {
+    let verb = if opts.dry_run { "would import" } else { "imported" };
+    for name in report.imported.iter() {
+        println!("{} {}", verb, name);
+    }
+    for name in report.skipped.iter() {
+        println!("skipped {} (already exists)", name);
+    }
+}
@@ -0,0 +1,209 @@
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+
+// A hook is a resource from a chart's templates that is run at a point in a
+// release's lifecycle rather than installed with the rest of the release.
+// Unlike the rest of a release, Helm serializes hooks with snake_case names
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Hook {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    // The kind of the hook's resource, such as Job or Pod
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub kind: String,
+    // The template the hook was rendered from, such as mychart/templates/job.yaml
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub manifest: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<HookEvent>,
+    pub last_run: HookExecution,
+    // Hooks for the same event are run in order of weight, then name
+    #[serde(skip_serializing_if = "is_zero")]
+    pub weight: i32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub delete_policies: Vec<HookDeletePolicy>,
+}
+
+fn is_zero(n: &i32) -> bool {
+    *n == 0
+}
+
+impl Hook {
+    pub fn runs_on(&self, event: &HookEvent) -> bool {
+        self.events.contains(event)
+    }
+}
+
+// The last time a hook was run
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct HookExecution {
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub phase: HookPhase,
+}
+
+// Hooks are stored by their names. Names this doesn't know, such as events
+// added by later versions of Helm, are kept as they are so that a release
+// can still be read and written back unchanged
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
+pub enum HookEvent {
+    PreInstall,
+    PostInstall,
+    PreDelete,
+    PostDelete,
+    PreUpgrade,
+    PostUpgrade,
+    PreRollback,
+    PostRollback,
+    Test,
+    Other(String),
+}
+
+impl From<String> for HookEvent {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "pre-install" => HookEvent::PreInstall,
+            "post-install" => HookEvent::PostInstall,
+            "pre-delete" => HookEvent::PreDelete,
+            "post-delete" => HookEvent::PostDelete,
+            "pre-upgrade" => HookEvent::PreUpgrade,
+            "post-upgrade" => HookEvent::PostUpgrade,
+            "pre-rollback" => HookEvent::PreRollback,
+            "post-rollback" => HookEvent::PostRollback,
+            // Helm 2 called this test-success, which Helm 3 still accepts
+            "test" | "test-success" => HookEvent::Test,
+            _ => HookEvent::Other(s),
+        }
+    }
+}
+
+impl From<HookEvent> for String {
+    fn from(e: HookEvent) -> Self {
+        e.to_string()
+    }
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HookEvent::PreInstall => write!(f, "pre-install"),
+            HookEvent::PostInstall => write!(f, "post-install"),
+            HookEvent::PreDelete => write!(f, "pre-delete"),
+            HookEvent::PostDelete => write!(f, "post-delete"),
+            HookEvent::PreUpgrade => write!(f, "pre-upgrade"),
+            HookEvent::PostUpgrade => write!(f, "post-upgrade"),
+            HookEvent::PreRollback => write!(f, "pre-rollback"),
+            HookEvent::PostRollback => write!(f, "post-rollback"),
+            HookEvent::Test => write!(f, "test"),
+            HookEvent::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// When the resources a hook created are deleted
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
+pub enum HookDeletePolicy {
+    HookSucceeded,
+    HookFailed,
+    // Deletes the resources left by the last run before running the hook again
+    BeforeHookCreation,
+    Other(String),
+}
+
+impl From<String> for HookDeletePolicy {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "hook-succeeded" => HookDeletePolicy::HookSucceeded,
+            "hook-failed" => HookDeletePolicy::HookFailed,
+            "before-hook-creation" => HookDeletePolicy::BeforeHookCreation,
+            _ => HookDeletePolicy::Other(s),
+        }
+    }
+}
+
+impl From<HookDeletePolicy> for String {
+    fn from(p: HookDeletePolicy) -> Self {
+        p.to_string()
+    }
+}
+
+impl std::fmt::Display for HookDeletePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HookDeletePolicy::HookSucceeded => write!(f, "hook-succeeded"),
+            HookDeletePolicy::HookFailed => write!(f, "hook-failed"),
+            HookDeletePolicy::BeforeHookCreation => write!(f, "before-hook-creation"),
+            HookDeletePolicy::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(from = "String", into = "String")]
+pub enum HookPhase {
+    #[default]
+    Unknown,
+    Running,
+    Succeeded,
+    Failed,
+    Other(String),
+}
+
+impl From<String> for HookPhase {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            // Helm leaves the phase empty for hooks that have never been run
+            "" | "Unknown" => HookPhase::Unknown,
+            "Running" => HookPhase::Running,
+            "Succeeded" => HookPhase::Succeeded,
+            "Failed" => HookPhase::Failed,
+            _ => HookPhase::Other(s),
+        }
+    }
+}
+
+impl From<HookPhase> for String {
+    fn from(p: HookPhase) -> Self {
+        p.to_string()
+    }
+}
+
+impl std::fmt::Display for HookPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HookPhase::Unknown => write!(f, "Unknown"),
+            HookPhase::Running => write!(f, "Running"),
+            HookPhase::Succeeded => write!(f, "Succeeded"),
+            HookPhase::Failed => write!(f, "Failed"),
+            HookPhase::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_names_round_trip() {
+        let data = r#"{"events":["pre-install","test-success","post-renderer"],"last_run":{"phase":"Skipped"},"delete_policies":["hook-failed","keep-forever"]}"#;
+        let hook: Hook = serde_json::from_str(data).unwrap();
+        assert_eq!(hook.events, vec![HookEvent::PreInstall, HookEvent::Test, HookEvent::Other("post-renderer".to_string())]);
+        assert_eq!(hook.last_run.phase, HookPhase::Other("Skipped".to_string()));
+        assert_eq!(hook.delete_policies, vec![HookDeletePolicy::HookFailed, HookDeletePolicy::Other("keep-forever".to_string())]);
+
+        let written = serde_json::to_value(&hook).unwrap();
+        assert_eq!(written["events"], json!(["pre-install", "test", "post-renderer"]));
+        assert_eq!(written["last_run"]["phase"], "Skipped");
+        assert_eq!(written["delete_policies"], json!(["hook-failed", "keep-forever"]));
+
+        let never_run: HookExecution = serde_json::from_str(r#"{"phase":""}"#).unwrap();
+        assert_eq!(never_run.phase, HookPhase::Unknown);
+    }
+}
@@ -1,5 +1,8 @@
+pub mod hook;
 pub mod sort;
 
+use crate::chart::Chart;
+use hook::{Hook, HookEvent};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 use std::collections::HashMap;
@@ -10,13 +13,32 @@ use serde_json::Value;
 #[serde(default)]
 pub struct Release {
     pub name: String,
-    // TODO: add chart
     pub info: Info,
+    // The chart that was installed. Helm 2 releases imported from Tiller and
+    // releases written before charts were kept have none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chart: Option<Chart>,
     pub config: HashMap<String, Value>,
     pub manifest: String,
-    // TODO: hooks
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
     pub version: usize,
     pub namespace: String,
+    // The version of the storage object this release was read from, if any.
+    // It is not part of the stored release and is only used by drivers to
+    // reject updates made against stale data
+    #[serde(skip)]
+    pub resource_version: Option<String>,
+}
+
+impl Release {
+    // Returns the hooks to run for an event in the order Helm runs them: by
+    // weight, then by name
+    pub fn hooks_for(&self, event: &HookEvent) -> Vec<&Hook> {
+        let mut hooks: Vec<&Hook> = self.hooks.iter().filter(|h| h.runs_on(event)).collect();
+        hooks.sort_by(|a, b| a.weight.cmp(&b.weight).then_with(|| a.name.cmp(&b.name)));
+        hooks
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -50,6 +72,13 @@ pub enum Status {
     PendingRollback,
 }
 
+impl Status {
+    // Returns true if the status is one an operation sets while it is running
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Status::PendingInstall | Status::PendingUpgrade | Status::PendingRollback)
+    }
+}
+
 impl Default for Status {
     fn default() -> Self {
         Status::Unknown
@@ -0,0 +1,258 @@
+// This module exports the history of a release to a tar.gz archive and
+// imports it again, into any driver or namespace. Archives hold a manifest
+// listing every revision along with the labels of the object it was stored
+// in, followed by each revision as JSON:
+//
+//   <name>/manifest.json
+//   <name>/v1.json
+//   <name>/v2.json
+//   ...
+use crate::chart::loader::{MAX_DECOMPRESSED_CHART_SIZE, MAX_DECOMPRESSED_FILE_SIZE};
+use crate::release::Release;
+use crate::release::sort::*;
+use crate::storage::{Storage, Operation, DEFAULT_LOCK_TTL};
+use crate::storage::lock::new_holder;
+use crate::storage::driver::{Driver, DriverError};
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::debug;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+// Bumped whenever the layout of an archive changes in a way older versions
+// can't read
+const ARCHIVE_VERSION: &str = "v1";
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    api_version: String,
+    name: String,
+    namespace: String,
+    exported_at: DateTime<Utc>,
+    revisions: Vec<Revision>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Revision {
+    version: usize,
+    // The labels of the storage object, including createdAt and modifiedAt.
+    // Only the timestamps are restored on import, as drivers set the rest
+    // from the release
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct ArchiveImportOptions {
+    // Moves the imported releases to this namespace. It should be the
+    // namespace of the driver they are imported into
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveImportReport {
+    pub name: String,
+    // Revisions that were written to storage
+    pub imported: Vec<usize>,
+    // Revisions that were already in storage, and were left alone
+    pub skipped: Vec<usize>,
+}
+
+fn revision_file(version: usize) -> String {
+    format!("v{}.json", version)
+}
+
+fn append_file<W: Write>(archive: &mut tar::Builder<W>, path: &str, data: &[u8], mtime: u64) -> Result<(), DriverError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data).map_err(DriverError::ArchiveError)
+}
+
+impl<T: Driver> Storage<T> {
+    // Writes every revision of a release to `w` as a tar.gz archive, returning
+    // how many revisions were written
+    pub fn export_release<W: Write>(&self, release_name: &str, w: W) -> Result<usize, DriverError> {
+        debug!("exporting history of {}", release_name);
+        let mut history = self.history(release_name)?;
+        if history.is_empty() {
+            return Err(DriverError::ReleaseNotExist)
+        }
+        history.sort_unstable_by(revision);
+        let mut revisions: Vec<Revision> = Vec::with_capacity(history.len());
+        for rel in history.iter() {
+            let key = self.make_key(&rel.name, &rel.version);
+            let labels = self.driver.labels(&key).map_err(|e| e.context(&key, &self.driver.name()))?;
+            revisions.push(Revision{version: rel.version, labels});
+        }
+        let manifest = Manifest {
+            api_version: ARCHIVE_VERSION.to_string(),
+            name: release_name.to_string(),
+            namespace: history[0].namespace.clone(),
+            exported_at: Utc::now(),
+            revisions,
+        };
+
+        let mtime = manifest.exported_at.timestamp().max(0) as u64;
+        let mut archive = tar::Builder::new(GzEncoder::new(w, Compression::default()));
+        let data = serde_json::to_vec_pretty(&manifest).map_err(DriverError::Encode)?;
+        append_file(&mut archive, &format!("{}/{}", release_name, MANIFEST_FILE), &data, mtime)?;
+        for rel in history.iter() {
+            let data = serde_json::to_vec_pretty(rel).map_err(DriverError::Encode)?;
+            append_file(&mut archive, &format!("{}/{}", release_name, revision_file(rel.version)), &data, mtime)?;
+        }
+        archive.into_inner()
+            .and_then(|gz| gz.finish())
+            .map_err(DriverError::ArchiveError)?;
+        Ok(history.len())
+    }
+
+    // Reads an archive made by export_release and writes every revision in it
+    // to storage, oldest first, keeping when each was created and modified.
+    // Revisions that already exist are skipped, so an interrupted import can
+    // be run again
+    pub fn import_release<R: Read>(&self, r: R, opts: &ArchiveImportOptions) -> Result<ArchiveImportReport, DriverError> {
+        let invalid = |message: String| DriverError::InvalidData{message: format!("invalid release archive: {}", message)};
+        // Archives are decompressed within the same limits as charts
+        let mut remaining = MAX_DECOMPRESSED_CHART_SIZE;
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut archive = tar::Archive::new(GzDecoder::new(r));
+        for entry in archive.entries().map_err(DriverError::ArchiveError)? {
+            let mut entry = entry.map_err(DriverError::ArchiveError)?;
+            if !entry.header().entry_type().is_file() {
+                continue
+            }
+            let path = entry.path().map_err(DriverError::ArchiveError)?.to_string_lossy().into_owned();
+            let limit = MAX_DECOMPRESSED_FILE_SIZE.min(remaining);
+            let mut data = Vec::new();
+            (&mut entry).take(limit + 1).read_to_end(&mut data).map_err(DriverError::ArchiveError)?;
+            let size = data.len() as u64;
+            if size > MAX_DECOMPRESSED_FILE_SIZE {
+                return Err(invalid(format!("{} is larger than the limit of {} bytes", path, MAX_DECOMPRESSED_FILE_SIZE)))
+            }
+            if size > remaining {
+                return Err(invalid(format!("archive is larger than the limit of {} bytes once decompressed", MAX_DECOMPRESSED_CHART_SIZE)))
+            }
+            remaining -= size;
+            files.insert(path, data);
+        }
+        let (dir, data) = match files.iter().find(|(path, _)| path.ends_with(&format!("/{}", MANIFEST_FILE))) {
+            Some((path, data)) => (path[..path.len() - MANIFEST_FILE.len()].to_string(), data),
+            None => { return Err(invalid(format!("no {} found", MANIFEST_FILE))) }
+        };
+        let manifest: Manifest = serde_json::from_slice(data).map_err(DriverError::Decode)?;
+        if manifest.api_version != ARCHIVE_VERSION {
+            return Err(invalid(format!("unsupported version {}", manifest.api_version)))
+        }
+
+        // Check every revision before writing any of them, so that a bad
+        // archive doesn't leave behind part of a history
+        let mut releases: Vec<(Release, &HashMap<String, String>)> = Vec::with_capacity(manifest.revisions.len());
+        for r in manifest.revisions.iter() {
+            let path = format!("{}{}", dir, revision_file(r.version));
+            let data = match files.get(&path) {
+                Some(d) => d,
+                None => { return Err(invalid(format!("revision {} is missing", r.version))) }
+            };
+            let mut rel: Release = serde_json::from_slice(data).map_err(DriverError::Decode)?;
+            if rel.name != manifest.name || rel.version != r.version {
+                return Err(invalid(format!("{} holds {} v{}", path, rel.name, rel.version)))
+            }
+            if let Some(ref ns) = opts.namespace {
+                rel.namespace = ns.clone();
+            }
+            releases.push((rel, &r.labels));
+        }
+        releases.sort_unstable_by(|a, b| revision(&a.0, &b.0));
+
+        let mut report = ArchiveImportReport {
+            name: manifest.name.clone(),
+            ..Default::default()
+        };
+        let lock = self.lock(&manifest.name, Operation::Import, &new_holder(), DEFAULT_LOCK_TTL)?;
+        for (rel, labels) in releases.into_iter() {
+            let version = rel.version;
+            let key = self.make_key(&rel.name, &version);
+            debug!("importing {} v{}", rel.name, version);
+            match self.create(rel, &lock) {
+                Ok(_) => {
+                    self.driver.restore_timestamps(&key, labels).map_err(|e| e.context(&key, &self.driver.name()))?;
+                    report.imported.push(version)
+                },
+                Err(ref e) if e.is_already_exists() => report.skipped.push(version),
+                Err(e) => { return Err(e) }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{make_key, MaxHistory};
+    use crate::storage::driver::memory::Memory;
+
+    #[test]
+    fn import_restores_timestamps() {
+        let from = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        for version in 1..=2 {
+            from.create_locked(Release {
+                name: "app".to_string(),
+                namespace: "default".to_string(),
+                version,
+                ..Default::default()
+            }).unwrap();
+        }
+        let key = make_key("app", &1);
+        let mut stamps: HashMap<String, String> = HashMap::new();
+        stamps.insert("createdAt".to_string(), "1000".to_string());
+        stamps.insert("modifiedAt".to_string(), "2000".to_string());
+        from.driver.restore_timestamps(&key, &stamps).unwrap();
+
+        let mut archive: Vec<u8> = Vec::new();
+        assert_eq!(from.export_release("app", &mut archive).unwrap(), 2);
+        let to = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        let report = to.import_release(&archive[..], &ArchiveImportOptions::default()).unwrap();
+        assert_eq!(report.imported, vec![1, 2]);
+
+        let labels = to.driver.labels(&key).unwrap();
+        assert_eq!(labels.get("createdAt").map(|s| s.as_str()), Some("1000"));
+        assert_eq!(labels.get("modifiedAt").map(|s| s.as_str()), Some("2000"));
+        assert_eq!(to.driver.labels(&make_key("app", &2)).unwrap().get("createdAt"), from.driver.labels(&make_key("app", &2)).unwrap().get("createdAt"));
+    }
+
+    fn archive(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, data) in files.iter() {
+            append_file(&mut builder, path, data, 0).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn import_limits_decompressed_size() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        let import = |files: &[(String, Vec<u8>)]| storage.import_release(&archive(files)[..], &ArchiveImportOptions::default());
+
+        let big = vec![b' '; MAX_DECOMPRESSED_FILE_SIZE as usize + 1];
+        let err = import(&[("app/v1.json".to_string(), big)]).err().unwrap();
+        assert!(err.to_string().contains("app/v1.json is larger than the limit"), "{}", err);
+
+        // Files under the limit for a single file that add up to more than
+        // the limit for an archive
+        let part = vec![b' '; MAX_DECOMPRESSED_FILE_SIZE as usize];
+        let count = (MAX_DECOMPRESSED_CHART_SIZE / MAX_DECOMPRESSED_FILE_SIZE) as usize + 1;
+        let files: Vec<(String, Vec<u8>)> = (1..=count).map(|v| (format!("app/v{}.json", v), part.clone())).collect();
+        let err = import(&files).err().unwrap();
+        assert!(err.to_string().contains("archive is larger than the limit"), "{}", err);
+    }
+}
@@ -0,0 +1,200 @@
+// This module contains AsyncStorage, the async counterpart to Storage for use
+// from within a tokio runtime. It runs each Storage call on tokio's blocking
+// thread pool, so releases are validated, locked and pruned exactly as they
+// are by Storage
+use crate::release::Release;
+use crate::storage::{MaxHistory, Operation, Storage};
+use crate::storage::driver::{Driver, DriverError, ReleaseList};
+use crate::storage::driver::asynchronous::Blocking;
+use log::error;
+use std::time::Duration;
+
+pub struct AsyncStorage<T> {
+    storage: Blocking<Storage<T>>,
+}
+
+impl<T: Driver + Send + Sync + 'static> AsyncStorage<T> {
+    pub fn new(d: T, max: MaxHistory) -> Self {
+        AsyncStorage::from_storage(Storage::new(d, max))
+    }
+
+    // Wraps a Storage that has already been set up, such as with a locker
+    // or retention policy of its own
+    pub fn from_storage(storage: Storage<T>) -> Self {
+        AsyncStorage {
+            storage: Blocking::new(storage),
+        }
+    }
+
+    // The wrapped storage, for calls that are fine to block on
+    pub fn blocking(&self) -> &Storage<T> {
+        self.storage.get_ref()
+    }
+
+    pub async fn prune(&self, release_name: &str) -> Result<Vec<usize>, DriverError> {
+        let release_name = release_name.to_string();
+        self.storage.run(move |s| s.prune(&release_name)).await
+    }
+
+    // See Storage::lock. The returned lock is not tied to a borrow of the
+    // storage, so it can be held across awaits
+    pub async fn lock(&self, release_name: &str, op: Operation, holder: &str, ttl: Duration) -> Result<AsyncReleaseLock<T>, DriverError> {
+        let (release_name, holder) = (release_name.to_string(), holder.to_string());
+        let (key, holder) = self.storage.run(move |s| Ok(s.lock(&release_name, op, &holder, ttl)?.detach())).await?;
+        Ok(AsyncReleaseLock {
+            storage: self.storage.clone(),
+            key,
+            holder,
+            released: false,
+        })
+    }
+
+    pub async fn break_lock(&self, release_name: &str) -> Result<(), DriverError> {
+        let release_name = release_name.to_string();
+        self.storage.run(move |s| s.break_lock(&release_name)).await
+    }
+
+    pub async fn get(&self, release_name: &str, version: &usize) -> Result<Release, DriverError> {
+        let (release_name, version) = (release_name.to_string(), *version);
+        self.storage.run(move |s| s.get(&release_name, &version)).await
+    }
+
+    pub async fn create(&self, rel: Release, lock: &AsyncReleaseLock<T>) -> Result<(), DriverError> {
+        let (key, holder) = (lock.key.clone(), lock.holder.clone());
+        self.storage.run(move |s| s.create(rel, &s.held_lock(key, holder))).await
+    }
+
+    pub async fn update(&self, rel: Release, lock: &AsyncReleaseLock<T>) -> Result<(), DriverError> {
+        let (key, holder) = (lock.key.clone(), lock.holder.clone());
+        self.storage.run(move |s| s.update(rel, &s.held_lock(key, holder))).await
+    }
+
+    // See Storage::compare_and_swap
+    pub async fn compare_and_swap(&self, current: &Release, rel: Release, lock: &AsyncReleaseLock<T>) -> Result<(), DriverError> {
+        let current = current.clone();
+        let (key, holder) = (lock.key.clone(), lock.holder.clone());
+        self.storage.run(move |s| s.compare_and_swap(&current, rel, &s.held_lock(key, holder))).await
+    }
+
+    pub async fn delete(&self, release_name: &str, version: &usize) -> Result<Release, DriverError> {
+        let (release_name, version) = (release_name.to_string(), *version);
+        self.storage.run(move |s| s.delete(&release_name, &version)).await
+    }
+
+    pub async fn list_all(&self) -> Result<Vec<Release>, DriverError> {
+        self.storage.run(|s| s.list_all()).await
+    }
+
+    pub async fn list_all_with_report(&self) -> Result<ReleaseList, DriverError> {
+        self.storage.run(|s| s.list_all_with_report()).await
+    }
+
+    pub async fn list_uninstalled(&self) -> Result<Vec<Release>, DriverError> {
+        self.storage.run(|s| s.list_uninstalled()).await
+    }
+
+    pub async fn list_deployed(&self) -> Result<Vec<Release>, DriverError> {
+        self.storage.run(|s| s.list_deployed()).await
+    }
+
+    pub async fn history(&self, release_name: &str) -> Result<Vec<Release>, DriverError> {
+        let release_name = release_name.to_string();
+        self.storage.run(move |s| s.history(&release_name)).await
+    }
+
+    pub async fn get_all_deployed(&self, release_name: &str) -> Result<Vec<Release>, DriverError> {
+        let release_name = release_name.to_string();
+        self.storage.run(move |s| s.get_all_deployed(&release_name)).await
+    }
+
+    // returns the last release with a deployed state
+    pub async fn last_deployed(&self, release_name: &str) -> Result<Release, DriverError> {
+        let release_name = release_name.to_string();
+        self.storage.run(move |s| s.last_deployed(&release_name)).await
+    }
+
+    // Returns the last release, regardless of status
+    pub async fn last(&self, release_name: &str) -> Result<Release, DriverError> {
+        let release_name = release_name.to_string();
+        self.storage.run(move |s| s.last(&release_name)).await
+    }
+}
+
+// AsyncReleaseLock is a release lock held through AsyncStorage. Like
+// ReleaseLock it is released when dropped, but can be released explicitly
+// with `unlock` to see any error from doing so
+pub struct AsyncReleaseLock<T: Driver + Send + Sync + 'static> {
+    storage: Blocking<Storage<T>>,
+    key: String,
+    holder: String,
+    released: bool,
+}
+
+impl<T: Driver + Send + Sync + 'static> AsyncReleaseLock<T> {
+    pub async fn unlock(mut self) -> Result<(), DriverError> {
+        self.released = true;
+        let (key, holder) = (self.key.clone(), self.holder.clone());
+        self.storage.run(move |s| s.held_lock(key, holder).unlock()).await
+    }
+}
+
+impl<T: Driver + Send + Sync + 'static> Drop for AsyncReleaseLock<T> {
+    fn drop(&mut self) {
+        if self.released {
+            return
+        }
+        let (key, holder) = (std::mem::take(&mut self.key), std::mem::take(&mut self.holder));
+        let release = move |s: &Storage<T>| {
+            s.held_lock(key.clone(), holder).unlock().unwrap_or_else(|e| {
+                error!("unable to release lock {}: {}", key, e);
+            });
+            Ok(())
+        };
+        // Outside of a runtime there is no blocking pool to hand this to
+        match tokio::runtime::Handle::try_current() {
+            Ok(_) => drop(self.storage.run(release)),
+            Err(_) => { let _ = release(self.storage.get_ref()); },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::{Info, Status};
+    use crate::storage::driver::memory::Memory;
+
+    fn release(name: &str, version: usize) -> Release {
+        Release {
+            name: name.to_string(),
+            version,
+            info: Info {
+                status: Status::Deployed,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn create_get_history() {
+        let storage = AsyncStorage::new(Memory::new(), MaxHistory::Limit(2));
+        let lock = storage.lock("app", Operation::Install, "test", Duration::from_secs(60)).await.unwrap();
+        for version in 1..=4 {
+            storage.create(release("app", version), &lock).await.unwrap();
+        }
+        assert_eq!(storage.get("app", &4).await.unwrap().version, 4);
+        // Older revisions are pruned as they are by Storage
+        let history = storage.history("app").await.unwrap();
+        assert!(history.iter().all(|r| r.version > 1));
+        assert_eq!(storage.last("app").await.unwrap().version, 4);
+
+        // The lock is for app alone, and is gone once released
+        assert!(matches!(storage.create(release("web", 1), &lock).await, Err(DriverError::LockNotHeld{..})));
+        let stale = (lock.key.clone(), lock.holder.clone());
+        lock.unlock().await.unwrap();
+        assert!(storage.blocking().create(release("app", 5), &storage.blocking().held_lock(stale.0, stale.1)).is_err());
+        let other = storage.lock("app", Operation::Upgrade, "other", Duration::from_secs(60)).await.unwrap();
+        storage.create(release("app", 5), &other).await.unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use crate::release::Release;
+use crate::storage::driver::*;
+use crate::storage::driver::memory::Memory;
+use futures::future::{ready, Future};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::vec::Vec;
+
+// AsyncDriver is the async counterpart to Driver, for use from within a
+// tokio runtime
+pub trait AsyncDriver: Send + Sync {
+    fn name(&self) -> String;
+    fn create(&self, key: &str, rel: Release) -> impl Future<Output = Result<(), DriverError>> + Send;
+    fn update(&self, key: &str, rel: Release) -> impl Future<Output = Result<(), DriverError>> + Send;
+    fn delete(&self, key: &str) -> impl Future<Output = Result<Release, DriverError>> + Send;
+    fn get(&self, key: &str) -> impl Future<Output = Result<Release, DriverError>> + Send;
+    fn list<F>(&self, filter: F) -> impl Future<Output = Result<Vec<Release>, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static,
+    {
+        let list = self.list_with_report(filter);
+        async move {
+            Ok(list.await?.into_releases())
+        }
+    }
+    fn list_with_report<F>(&self, filter: F) -> impl Future<Output = Result<ReleaseList, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static;
+    fn list_page<F>(&self, params: &PageParams, filter: F) -> impl Future<Output = Result<ReleasePage, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static;
+    fn query(&self, labels: HashMap<String, String>) -> impl Future<Output = Result<Vec<Release>, DriverError>> + Send;
+}
+
+// Blocking adapts a blocking Driver to AsyncDriver by running each call on
+// tokio's blocking thread pool. This is how the Secrets and ConfigMaps drivers
+// are used asynchronously, as the kube client they are built on only has a
+// blocking API. AsyncStorage wraps a Storage with it the same way
+pub struct Blocking<D> {
+    driver: Arc<D>,
+}
+
+impl<D> Clone for Blocking<D> {
+    fn clone(&self) -> Self {
+        Blocking {
+            driver: self.driver.clone(),
+        }
+    }
+}
+
+impl<D: Send + Sync + 'static> Blocking<D> {
+    pub fn new(driver: D) -> Self {
+        Blocking {
+            driver: Arc::new(driver),
+        }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.driver
+    }
+
+    pub(crate) fn run<R, F>(&self, f: F) -> impl Future<Output = Result<R, DriverError>> + Send
+    where
+        R: Send + 'static,
+        F: FnOnce(&D) -> Result<R, DriverError> + Send + 'static,
+    {
+        let driver = self.driver.clone();
+        let task = tokio::task::spawn_blocking(move || f(&driver));
+        async move {
+            match task.await {
+                Ok(res) => res,
+                // Carry on panicking in the caller, as the blocking call would
+                // have done
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(_) => Err(DriverError::Cancelled),
+            }
+        }
+    }
+}
+
+impl<D: Driver + Send + Sync + 'static> AsyncDriver for Blocking<D> {
+    fn name(&self) -> String {self.driver.name()}
+    fn create(&self, key: &str, rel: Release) -> impl Future<Output = Result<(), DriverError>> + Send {
+        let key = key.to_string();
+        self.run(move |d| d.create(&key, rel))
+    }
+    fn update(&self, key: &str, rel: Release) -> impl Future<Output = Result<(), DriverError>> + Send {
+        let key = key.to_string();
+        self.run(move |d| d.update(&key, rel))
+    }
+    fn delete(&self, key: &str) -> impl Future<Output = Result<Release, DriverError>> + Send {
+        let key = key.to_string();
+        self.run(move |d| d.delete(&key))
+    }
+    fn get(&self, key: &str) -> impl Future<Output = Result<Release, DriverError>> + Send {
+        let key = key.to_string();
+        self.run(move |d| d.get(&key))
+    }
+    fn list_with_report<F>(&self, filter: F) -> impl Future<Output = Result<ReleaseList, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static,
+    {
+        self.run(move |d| d.list_with_report(&filter))
+    }
+    fn list_page<F>(&self, params: &PageParams, filter: F) -> impl Future<Output = Result<ReleasePage, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static,
+    {
+        let params = params.clone();
+        self.run(move |d| d.list_page(&params, &filter))
+    }
+    fn query(&self, labels: HashMap<String, String>) -> impl Future<Output = Result<Vec<Release>, DriverError>> + Send {
+        self.run(move |d| d.query(labels))
+    }
+}
+
+// The memory driver never blocks for long, so it is called directly rather
+// than going through the blocking thread pool
+impl AsyncDriver for Memory {
+    fn name(&self) -> String {Driver::name(self)}
+    fn create(&self, key: &str, rel: Release) -> impl Future<Output = Result<(), DriverError>> + Send {
+        ready(Driver::create(self, &key.to_string(), rel))
+    }
+    fn update(&self, key: &str, rel: Release) -> impl Future<Output = Result<(), DriverError>> + Send {
+        ready(Driver::update(self, &key.to_string(), rel))
+    }
+    fn delete(&self, key: &str) -> impl Future<Output = Result<Release, DriverError>> + Send {
+        ready(Driver::delete(self, &key.to_string()))
+    }
+    fn get(&self, key: &str) -> impl Future<Output = Result<Release, DriverError>> + Send {
+        ready(Driver::get(self, &key.to_string()))
+    }
+    fn list_with_report<F>(&self, filter: F) -> impl Future<Output = Result<ReleaseList, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static,
+    {
+        ready(Driver::list_with_report(self, &filter))
+    }
+    fn list_page<F>(&self, params: &PageParams, filter: F) -> impl Future<Output = Result<ReleasePage, DriverError>> + Send
+    where
+        F: Fn(&Release) -> bool + Send + 'static,
+    {
+        ready(Driver::list_page(self, params, &filter))
+    }
+    fn query(&self, labels: HashMap<String, String>) -> impl Future<Output = Result<Vec<Release>, DriverError>> + Send {
+        ready(Driver::query(self, labels))
+    }
+}
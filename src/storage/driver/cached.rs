@@ -0,0 +1,272 @@
+use crate::release::Release;
+use crate::storage::driver::*;
+use crate::storage::lock::Locker;
+use crate::storage::{make_key, HELM_STORAGE_TYPE};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::vec::Vec;
+
+// Cached wraps another driver, keeping every release it has read so that
+// later reads of the same release (or the same query) don't have to fetch and
+// decode it again. It is meant to be short lived, such as for the length of a
+// single upgrade, as changes made by anyone else are only noticed through
+// watch events (see the Watch impl) or when a newer copy of a release is read
+pub struct Cached<D> {
+    driver: D,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // Releases by the key they are stored under
+    releases: HashMap<String, Release>,
+    // The results of each query, by the query labels
+    queries: HashMap<String, CachedQuery>,
+}
+
+struct CachedQuery {
+    // The release name the query was limited to, if any
+    name: Option<String>,
+    // The keys of the releases it returned
+    keys: Vec<String>,
+}
+
+impl CacheState {
+    // Stores a release that has just been read. If a different version of it
+    // was cached, any query that returned it may be stale too
+    fn insert(&mut self, rel: &Release) {
+        let key = make_key(&rel.name, &rel.version);
+        let stale = match self.releases.get(&key) {
+            Some(cached) => cached.resource_version != rel.resource_version,
+            None => false,
+        };
+        if stale {
+            self.invalidate_queries(&rel.name);
+        }
+        self.releases.insert(key, rel.clone());
+    }
+
+    fn invalidate(&mut self, key: &str, release_name: &str) {
+        self.releases.remove(key);
+        self.invalidate_queries(release_name);
+    }
+
+    // Drops every query that could return a release with this name
+    fn invalidate_queries(&mut self, release_name: &str) {
+        self.queries.retain(|_, q| match q.name {
+            Some(ref n) => n != release_name,
+            None => false,
+        });
+    }
+}
+
+// Label keys and values can't contain '=' or ',' (see validate), so these can
+// be joined without escaping
+fn query_key(labels: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    pairs.sort();
+    pairs.join(",")
+}
+
+// Takes the release name from a key made by make_key, for writes that are
+// only given a key
+fn release_name(key: &str) -> &str {
+    let name = match key.rfind(".v") {
+        Some(i) => &key[..i],
+        None => key,
+    };
+    // The name itself may contain dots, so only the fixed prefix is removed
+    name.strip_prefix(HELM_STORAGE_TYPE).and_then(|n| n.strip_prefix('.')).unwrap_or(name)
+}
+
+impl<D: Driver> Cached<D> {
+    pub fn new(driver: D) -> Self {
+        Cached {
+            driver,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    // Forgets everything that has been cached
+    pub fn clear(&self) {
+        let mut state = self.state.lock().expect("cache lock poisoned");
+        *state = CacheState::default();
+    }
+
+    // Forgets the release that changed in the given event. This is done for
+    // every event seen through the Watch impl, but can also be used to feed
+    // in events from a watch made elsewhere
+    pub fn invalidate(&self, event: &ReleaseEvent) {
+        let rel = match event {
+            ReleaseEvent::Added(r) | ReleaseEvent::Modified(r) | ReleaseEvent::Deleted(r) => r,
+        };
+        let mut state = self.state.lock().expect("cache lock poisoned");
+        state.invalidate(&make_key(&rel.name, &rel.version), &rel.name);
+    }
+
+    fn invalidate_key(&self, key: &str) {
+        let mut state = self.state.lock().expect("cache lock poisoned");
+        state.invalidate(key, release_name(key));
+    }
+
+    fn remember(&self, releases: &[Release]) {
+        let mut state = self.state.lock().expect("cache lock poisoned");
+        for rel in releases.iter() {
+            state.insert(rel);
+        }
+    }
+}
+
+impl<D: Driver> Driver for Cached<D> {
+    fn name(&self) -> String {self.driver.name()}
+    fn location(&self) -> String {self.driver.location()}
+    // Writes always go to the driver. The cached copy is dropped whether or
+    // not the write worked, as a failed write (such as a conflict) is a sign
+    // that it is out of date
+    fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        self.invalidate_key(key);
+        self.driver.create(key, rel)
+    }
+    fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        let res = self.driver.update(key, rel);
+        self.invalidate_key(key);
+        res
+    }
+    fn delete(&self, key: &String) -> Result<Release, DriverError> {
+        let res = self.driver.delete(key);
+        self.invalidate_key(key);
+        res
+    }
+    fn get(&self, key: &String) -> Result<Release, DriverError> {
+        if let Some(rel) = self.state.lock().expect("cache lock poisoned").releases.get(key) {
+            return Ok(rel.clone())
+        }
+        let rel = self.driver.get(key)?;
+        self.remember(std::slice::from_ref(&rel));
+        Ok(rel)
+    }
+    // Listing can't be answered from the cache, as there is no way to know
+    // whether it holds every release, but what is read is still remembered
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
+        let list = self.driver.list_with_report(filter)?;
+        self.remember(&list.releases);
+        Ok(list)
+    }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        let page = self.driver.list_page(params, filter)?;
+        self.remember(&page.list.releases);
+        Ok(page)
+    }
+    fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
+        let qkey = query_key(&labels);
+        {
+            let state = self.state.lock().expect("cache lock poisoned");
+            if let Some(q) = state.queries.get(&qkey) {
+                // The releases themselves may have been dropped since, in
+                // which case the query has to be made again
+                let cached: Option<Vec<Release>> = q.keys.iter().map(|k| state.releases.get(k).cloned()).collect();
+                if let Some(releases) = cached {
+                    return Ok(releases)
+                }
+            }
+        }
+        let name = labels.get("name").cloned();
+        let releases = self.driver.query(labels)?;
+        let mut state = self.state.lock().expect("cache lock poisoned");
+        for rel in releases.iter() {
+            state.insert(rel);
+        }
+        let keys = releases.iter().map(|r| make_key(&r.name, &r.version)).collect();
+        state.queries.insert(qkey, CachedQuery{name, keys});
+        Ok(releases)
+    }
+    // Labels are small and not decoded, so they aren't worth caching
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        self.driver.labels(key)
+    }
+    // The resource version changes along with the timestamps
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        let res = self.driver.restore_timestamps(key, labels);
+        self.invalidate_key(key);
+        res
+    }
+    fn locker(&self) -> Box<dyn Locker> {self.driver.locker()}
+}
+
+// Watching through the cache keeps it up to date with changes made by others
+impl<D: Driver + Watch> Watch for Cached<D> {
+    fn current_version(&self) -> Result<String, DriverError> {
+        self.driver.current_version()
+    }
+    fn watch_events(&self, resource_version: &str, timeout: Duration) -> Result<(Vec<ReleaseEvent>, String), DriverError> {
+        let res = self.driver.watch_events(resource_version, timeout);
+        match res {
+            Ok((ref events, _)) => {
+                for e in events.iter() {
+                    self.invalidate(e);
+                }
+            },
+            // Changes may have been missed, so nothing cached can be trusted
+            Err(DriverError::OutOfSync) => self.clear(),
+            Err(_) => (),
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::{Info, Status};
+    use crate::storage::driver::memory::Memory;
+
+    fn release(name: &str, version: usize, status: Status) -> Release {
+        Release {
+            name: name.to_string(),
+            version,
+            info: Info {
+                status,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn history(cached: &Cached<Memory>, name: &str) -> Vec<(usize, Status)> {
+        let mut labels = HashMap::new();
+        labels.insert("name".to_string(), name.to_string());
+        labels.insert("owner".to_string(), "helm".to_string());
+        let mut releases: Vec<(usize, Status)> = cached.query(labels).unwrap().into_iter().map(|r| (r.version, r.info.status)).collect();
+        releases.sort_by_key(|(v, _)| *v);
+        releases
+    }
+
+    #[test]
+    fn writes_invalidate_queries() {
+        let cached = Cached::new(Memory::new());
+        cached.create(&make_key("app", &1), release("app", 1, Status::Deployed)).unwrap();
+        cached.create(&make_key("web", &1), release("web", 1, Status::Deployed)).unwrap();
+        assert_eq!(history(&cached, "app"), vec![(1, Status::Deployed)]);
+        assert_eq!(history(&cached, "web"), vec![(1, Status::Deployed)]);
+
+        // Writes made underneath the cache aren't seen, as the query results
+        // are cached
+        cached.driver.create(&make_key("web", &2), release("web", 2, Status::Deployed)).unwrap();
+        assert_eq!(history(&cached, "web"), vec![(1, Status::Deployed)]);
+
+        cached.create(&make_key("app", &2), release("app", 2, Status::PendingUpgrade)).unwrap();
+        assert_eq!(history(&cached, "app"), vec![(1, Status::Deployed), (2, Status::PendingUpgrade)]);
+
+        cached.update(&make_key("app", &2), release("app", 2, Status::Deployed)).unwrap();
+        assert_eq!(history(&cached, "app"), vec![(1, Status::Deployed), (2, Status::Deployed)]);
+        assert_eq!(cached.get(&make_key("app", &2)).unwrap().info.status, Status::Deployed);
+
+        cached.delete(&make_key("app", &1)).unwrap();
+        assert_eq!(history(&cached, "app"), vec![(2, Status::Deployed)]);
+        assert!(cached.get(&make_key("app", &1)).unwrap_err().is_not_found());
+
+        // Only queries for the release that was written are dropped
+        assert_eq!(history(&cached, "web"), vec![(1, Status::Deployed)]);
+    }
+}
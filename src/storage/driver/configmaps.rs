@@ -1,111 +1,280 @@
 use crate::release::Release;
 use crate::storage::driver::*;
-use std::collections::{HashMap, BTreeMap};
+use crate::storage::driver::retry::RetryPolicy;
+use crate::storage::lock::{Locker, LeaseLocker, MemoryLocker};
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::time::Duration;
 use std::vec::Vec;
-use kube::api::{Api, v1ConfigMap, ListParams, PostParams, PatchParams, DeleteParams};
+use kube::api::{Api, RawApi, v1ConfigMap, ListParams, WatchEvent, PostParams, PatchParams, DeleteParams};
 use kube::client::APIClient;
 
 pub struct ConfigMaps {
     client: Api<v1ConfigMap>,
+    // Paged lists are not supported by Api, so they are made with the raw
+    // request builder and client instead
+    raw: RawApi,
+    kube_client: APIClient,
+    // None when listing across all namespaces
+    namespace: Option<String>,
+    // Limits listing across all namespaces to the namespaces matching this
+    // label selector
+    namespace_selector: Option<String>,
+    // Applied to every request made to the API server
+    retry: RetryPolicy,
 }
 
 impl ConfigMaps {
     pub fn new(client: APIClient, namespace: String) -> Self {
         ConfigMaps {
-            client: Api::v1ConfigMap(client).within(&namespace)
+            client: Api::v1ConfigMap(client.clone()).within(&namespace),
+            raw: RawApi::v1ConfigMap().within(&namespace),
+            kube_client: client,
+            namespace: Some(namespace),
+            namespace_selector: None,
+            retry: RetryPolicy::default(),
         }
     }
+
+    // Creates a driver that lists releases across every namespace, or only
+    // those matching `namespace_selector` if one is given. Releases can only
+    // be listed, queried and watched, as every other operation needs to know
+    // which namespace the release is in
+    pub fn all_namespaces(client: APIClient, namespace_selector: Option<String>) -> Self {
+        ConfigMaps {
+            client: Api::v1ConfigMap(client.clone()),
+            raw: RawApi::v1ConfigMap(),
+            kube_client: client,
+            namespace: None,
+            namespace_selector,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    // Replaces the default policy for retrying requests that failed with a
+    // transient error
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
 }
 
 impl ConfigMaps {
-    fn get_cm_list<F>(&self, label_selector: Option<String>, filter: F) -> Result<Vec<Release>, DriverError> 
+    fn get_cm_list<F>(&self, label_selector: Option<String>, filter: F) -> Result<ReleaseList, DriverError>
     where
         F: Fn(&Release) -> bool,
     {
         let mut lp = ListParams::default();
         lp.label_selector = label_selector;
-        let mut res = self.client.list(&lp)?;
-        let mut release_list: Vec<Release> = Vec::with_capacity(res.items.len());
-        while let Some(cm) = res.items.pop() {
-            // TODO: Change decoding errors to just log to match Helm behavior
-            let rel = decode_release(ConfigMaps::get_raw_data(cm.data)?)?;
-            if filter(&rel) {
-                release_list.push(rel);
+        let namespaces = self.selected_namespaces()?;
+        let res = self.retry.run(|| self.client.list(&lp))?;
+        Ok(self.decode_configmaps(res.items, &namespaces, filter))
+    }
+
+    fn decode_configmaps<F>(&self, mut items: Vec<v1ConfigMap>, namespaces: &Option<HashSet<String>>, filter: F) -> ReleaseList
+    where
+        F: Fn(&Release) -> bool,
+    {
+        let mut release_list = ReleaseList::default();
+        release_list.releases.reserve(items.len());
+        while let Some(cm) = items.pop() {
+            if !in_namespaces(namespaces, &cm.metadata.namespace) {
+                continue
             }
-        }
-        Ok(release_list)
-    }
-
-    fn get_raw_data(data: BTreeMap<String, String>) -> Result<Vec<u8>, DriverError> {
-        let raw = match data.get("release") {
-            Some(b) => b,
-            None => { return Err(DriverError::InvalidData{message: "no 'release' key found".to_string()}) }
-        };
-
-        Ok(base64::decode(raw)?)
-    }
-
-    fn generate_cm_data(key: &String, rel: Release, addl_labels: HashMap<String, String>) -> Result<Vec<u8>, DriverError> {
-        let rel_data = encode_release(&rel)?;
-        let mut data = json!({
-            "apiVersion": "v1",
-            "kind": "ConfigMap",
-            "metadata": {
-                "name": key,
-                "labels": {
-                    "name": rel.name,
-                    "owner": "helm",
-                    "status": rel.info.status,
-                    "version": rel.version.to_string()
+            let name = cm.metadata.name.clone();
+            let rel = match self.decode_object(cm) {
+                Ok(r) => r,
+                Err(e) => {
+                    release_list.failures.push(DecodeFailure{name, cause: e});
+                    continue
                 }
-            },
-            "data": {
-                "release": rel_data
+            };
+            if filter(&rel) {
+                release_list.releases.push(rel);
             }
-        });
-        for (k, v) in addl_labels.iter() {
-            data["metadata"]["labels"][k] = v.clone().into()
         }
-        let bytes = serde_json::to_vec(&data)?;
-        Ok(bytes)
+        release_list
+    }
+
+    fn decode_object(&self, cm: v1ConfigMap) -> Result<Release, DriverError> {
+        let client = self.client_for(cm.metadata.namespace.as_deref());
+        let mut rel = decode_release(read_release_data(&client, &self.retry, &cm.metadata.name, &stored_object(&cm)?)?)?;
+        rel.resource_version = cm.metadata.resourceVersion;
+        // When listing across namespaces, this is the only way to know
+        // where a release lives
+        if let Some(ns) = cm.metadata.namespace {
+            rel.namespace = ns;
+        }
+        Ok(rel)
+    }
+
+    // Returns a client for the namespace an object was found in, which is
+    // only needed when listing across all namespaces
+    fn client_for(&self, namespace: Option<&str>) -> Api<v1ConfigMap> {
+        match (&self.namespace, namespace) {
+            (None, Some(ns)) => Api::v1ConfigMap(self.kube_client.clone()).within(ns),
+            _ => self.client.clone(),
+        }
+    }
+
+    fn require_namespace(&self) -> Result<(), DriverError> {
+        match self.namespace {
+            Some(_) => Ok(()),
+            None => Err(DriverError::NamespaceRequired),
+        }
+    }
+
+    fn selected_namespaces(&self) -> Result<Option<HashSet<String>>, DriverError> {
+        match self.namespace_selector {
+            Some(ref selector) => Ok(Some(self.retry.run(|| list_namespaces(&self.kube_client, selector))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Converts the data of a config map. The release is stored base64 encoded,
+// while the other values are plain strings
+fn stored_object(cm: &v1ConfigMap) -> Result<StoredObject, DriverError> {
+    let mut data: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for (k, v) in cm.data.iter() {
+        let value = if k == "release" { base64::decode(v)? } else { v.clone().into_bytes() };
+        data.insert(k.clone(), value);
+    }
+    Ok(StoredObject {
+        data,
+        labels: cm.metadata.labels.clone(),
+    })
+}
+
+impl ReleaseObjects for Api<v1ConfigMap> {
+    fn kind(&self) -> &'static str {"ConfigMap"}
+    fn encode_value(&self, name: &str, value: &[u8]) -> serde_json::Value {
+        if name == "release" {
+            return base64::encode(value).into()
+        }
+        String::from_utf8_lossy(value).into()
+    }
+    fn get_object(&self, name: &str) -> Result<StoredObject, DriverError> {
+        stored_object(&self.get(name)?)
+    }
+    fn create_object(&self, body: &[u8]) -> Result<(), DriverError> {
+        self.create(&PostParams::default(), body.to_vec())?;
+        Ok(())
+    }
+    fn patch_object(&self, name: &str, body: &[u8]) -> Result<(), DriverError> {
+        self.patch(name, &PatchParams::default(), body.to_vec())?;
+        Ok(())
+    }
+    fn delete_object(&self, name: &str) -> Result<(), DriverError> {
+        self.delete(name, &DeleteParams::default())?;
+        Ok(())
     }
 }
 
 impl Driver for ConfigMaps {
     fn name(&self) -> String {String::from("configmaps")}
+    fn location(&self) -> String {format!("configmaps/{}", self.namespace.as_deref().unwrap_or("*"))}
     fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {
-        let mut labels: HashMap<String, String> = HashMap::new();
-        labels.insert("createdAt".to_string(), chrono::Utc::now().timestamp().to_string());
-        let data = ConfigMaps::generate_cm_data(&key, rel, labels)?;
-        self.client.create(&PostParams::default(), data)?;
-        Ok(())
+        self.require_namespace()?;
+        create_release_objects(&self.client, &self.retry, key, rel)
     }
     fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {
-        let mut labels: HashMap<String, String> = HashMap::new();
-        labels.insert("modifiedAt".to_string(), chrono::Utc::now().timestamp().to_string());
-        let data = ConfigMaps::generate_cm_data(&key, rel, labels)?;
-        self.client.patch(&key, &PatchParams::default(), data)?;
-        Ok(())
+        self.require_namespace()?;
+        update_release_objects(&self.client, &self.retry, key, rel)
     }
     fn delete(&self, key: &String) -> Result<Release, DriverError> {
-        let rel = self.get(key)?;
-        self.client.delete(key, &DeleteParams::default())?;
-        Ok(rel)
+        self.require_namespace()?;
+        delete_release_objects(&self.client, &self.retry, key)
     }
     fn get(&self, key: &String) -> Result<Release, DriverError> {
-        let cm = self.client.get(key)?;
-        let rel = decode_release(ConfigMaps::get_raw_data(cm.data)?)?;
-        Ok(rel)
+        self.require_namespace()?;
+        self.decode_object(self.retry.run(|| self.client.get(key))?)
     }
-    fn list<F>(&self, filter: F) -> Result<Vec<Release>, DriverError> 
-    where
-        F: Fn(&Release) -> bool,
-    {
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
         return self.get_cm_list(Some("owner=helm".to_string()), filter);
     }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        let namespaces = self.selected_namespaces()?;
+        let list = self.retry.run(|| list_kube_page(&self.kube_client, &self.raw, "owner=helm".to_string(), params))?;
+        Ok(ReleasePage{
+            list: self.decode_configmaps(list.items, &namespaces, filter),
+            continue_token: list.metadata.continue_token,
+        })
+    }
     fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
-        // TODO: Actually check the label value is valid (hence why this is not just in the actual ListParams)
-        let selector: String = labels.iter().map(|pair| format!("{}={}", pair.0, pair.1)).collect::<Vec<String>>().join(",");
-        return self.get_cm_list(Some(selector), |_| true);
+        let selector = label_selector(&labels)?;
+        return Ok(self.get_cm_list(Some(selector), |_| true)?.into_releases());
+    }
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        self.require_namespace()?;
+        let cm = self.retry.run(|| self.client.get(key))?;
+        Ok(cm.metadata.labels.into_iter().collect())
+    }
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        self.require_namespace()?;
+        let timestamps = timestamp_labels(labels);
+        if timestamps.is_empty() {
+            return Ok(())
+        }
+        let mut data = json!({"metadata": {"labels": {}}});
+        for (label, ts) in timestamps {
+            data["metadata"]["labels"][label] = ts.to_string().into();
+        }
+        let body = serde_json::to_vec(&data).map_err(DriverError::Encode)?;
+        self.retry.run(|| self.client.patch(key, &PatchParams::default(), body.clone()))?;
+        Ok(())
+    }
+    // As with Secrets, releases in a namespace are locked with Leases
+    fn locker(&self) -> Box<dyn Locker> {
+        match self.namespace {
+            Some(ref ns) => Box::new(LeaseLocker::new(self.kube_client.clone(), ns.clone())),
+            None => Box::new(MemoryLocker::new()),
+        }
+    }
+}
+
+impl Watch for ConfigMaps {
+    fn current_version(&self) -> Result<String, DriverError> {
+        self.retry.run(|| current_kube_version(&self.kube_client, &self.raw, "owner=helm".to_string()))
+    }
+    fn watch_events(&self, resource_version: &str, timeout: Duration) -> Result<(Vec<ReleaseEvent>, String), DriverError> {
+        let mut lp = ListParams::default();
+        lp.label_selector = Some("owner=helm".to_string());
+        lp.timeout = Some(timeout.as_secs() as u32);
+        let namespaces = self.selected_namespaces()?;
+        // Not retried here, as ReleaseWatcher already reconnects after a
+        // failed watch
+        let events: Vec<WatchEvent<v1ConfigMap>> = self.client.watch(&lp, resource_version)?;
+        convert_watch_events(events, resource_version, |cm| {
+            if !in_namespaces(&namespaces, &cm.metadata.namespace) {
+                return Ok(None)
+            }
+            self.decode_object(cm).map(Some)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kube::fake::FakeServer;
+    use crate::storage::driver::tests::large_release;
+    use crate::storage::make_key;
+
+    const CONFIGMAPS: &str = "/api/v1/namespaces/default/configmaps";
+
+    #[test]
+    fn chunked_release() {
+        let server = FakeServer::start();
+        let configmaps = ConfigMaps::new(server.client(), "default".to_string()).with_retry(RetryPolicy::never());
+        let key = make_key("app", &1);
+        let rel = large_release("app", 1);
+        configmaps.create(&key, rel.clone()).unwrap();
+        assert_eq!(server.names(CONFIGMAPS).len(), 3);
+        // Only the release is base64 encoded
+        let head = server.object(&format!("{}/{}", CONFIGMAPS, key)).unwrap();
+        assert_eq!(head["data"]["chunks"], "3");
+        assert_eq!(configmaps.get(&key).unwrap().manifest, rel.manifest);
+        assert_eq!(configmaps.delete(&key).unwrap().manifest, rel.manifest);
+        assert!(server.names(CONFIGMAPS).is_empty());
     }
 }
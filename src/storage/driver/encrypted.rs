@@ -0,0 +1,93 @@
+use crate::release::Release;
+use crate::storage::driver::*;
+use crate::storage::lock::Locker;
+use crate::storage::encryption::{KeyProvider, seal, open};
+use std::collections::HashMap;
+use std::vec::Vec;
+
+// Encrypted wraps another driver, encrypting the config values of every
+// release before it is written and decrypting them again when read
+pub struct Encrypted<D, K> {
+    driver: D,
+    provider: K,
+}
+
+impl<D: Driver, K: KeyProvider> Encrypted<D, K> {
+    pub fn new(driver: D, provider: K) -> Self {
+        Encrypted {
+            driver,
+            provider,
+        }
+    }
+}
+
+impl<D: Driver, K: KeyProvider> Encrypted<D, K> {
+    fn open_list<F>(&self, list: ReleaseList, filter: F) -> ReleaseList
+    where
+        F: Fn(&Release) -> bool,
+    {
+        let mut release_list = ReleaseList{
+            releases: Vec::with_capacity(list.releases.len()),
+            failures: list.failures,
+        };
+        for rel in list.releases.into_iter() {
+            let name = format!("{}.v{}", rel.name, rel.version);
+            match open(&self.provider, rel) {
+                Ok(r) => {
+                    if filter(&r) {
+                        release_list.releases.push(r);
+                    }
+                },
+                Err(e) => release_list.failures.push(DecodeFailure{name, cause: e}),
+            }
+        }
+        release_list
+    }
+}
+
+impl<D: Driver, K: KeyProvider> Driver for Encrypted<D, K> {
+    fn name(&self) -> String {self.driver.name()}
+    fn location(&self) -> String {self.driver.location()}
+    fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        self.driver.create(key, seal(&self.provider, rel)?)
+    }
+    fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        self.driver.update(key, seal(&self.provider, rel)?)
+    }
+    fn delete(&self, key: &String) -> Result<Release, DriverError> {
+        open(&self.provider, self.driver.delete(key)?)
+    }
+    fn get(&self, key: &String) -> Result<Release, DriverError> {
+        open(&self.provider, self.driver.get(key)?)
+    }
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
+        // The filter has to see the decrypted release, so it can only be
+        // applied after opening
+        Ok(self.open_list(self.driver.list_with_report(&|_| true)?, filter))
+    }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        let page = self.driver.list_page(params, &|_| true)?;
+        Ok(ReleasePage{
+            list: self.open_list(page.list, filter),
+            continue_token: page.continue_token,
+        })
+    }
+    fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
+        let mut release_list = ReleaseList::default();
+        for rel in self.driver.query(labels)?.into_iter() {
+            let name = format!("{}.v{}", rel.name, rel.version);
+            match open(&self.provider, rel) {
+                Ok(r) => release_list.releases.push(r),
+                Err(e) => release_list.failures.push(DecodeFailure{name, cause: e}),
+            }
+        }
+        Ok(release_list.into_releases())
+    }
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        self.driver.labels(key)
+    }
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        self.driver.restore_timestamps(key, labels)
+    }
+    fn locker(&self) -> Box<dyn Locker> {self.driver.locker()}
+}
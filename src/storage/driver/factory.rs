@@ -0,0 +1,84 @@
+// This module picks a storage driver by name at runtime, the same way Helm
+// does with the HELM_DRIVER environment variable
+use crate::storage::driver::*;
+use crate::storage::driver::configmaps::ConfigMaps;
+use crate::storage::driver::memory::Memory;
+use crate::storage::driver::secrets::Secrets;
+use crate::storage::driver::sql::Sql;
+use kube::config;
+use std::env;
+
+// The environment variable holding the name of the driver to use
+pub const HELM_DRIVER: &str = "HELM_DRIVER";
+// The environment variable holding the database to use with the sql driver
+pub const HELM_DRIVER_SQL_CONNECTION_STRING: &str = "HELM_DRIVER_SQL_CONNECTION_STRING";
+
+// The names Helm accepts for drivers, along with their plurals. The sql
+// driver can also be given the path of its database, as in "sql:<path>"
+pub const DRIVER_NAMES: &[&str] = &["secret", "secrets", "configmap", "configmaps", "memory", "sql"];
+
+// Creates the driver with the given name for releases in `namespace`. The
+// names are those in DRIVER_NAMES, and an empty name is the secret driver.
+// The sql driver opens the SQLite database named by
+// HELM_DRIVER_SQL_CONNECTION_STRING, or the path given after the name
+pub fn from_name(name: &str, namespace: String) -> Result<Box<dyn Driver + Send + Sync>, DriverError> {
+    if let Some(path) = name.strip_prefix("sql:") {
+        return Ok(Box::new(Sql::open(path, namespace)?))
+    }
+    return match name {
+        "" | "secret" | "secrets" => Ok(Box::new(Secrets::new(kube_client()?, namespace))),
+        "configmap" | "configmaps" => Ok(Box::new(ConfigMaps::new(kube_client()?, namespace))),
+        "memory" => Ok(Box::new(Memory::new())),
+        "sql" => Ok(Box::new(Sql::open(&sql_connection_string(name)?, namespace)?)),
+        _ => Err(DriverError::UnknownDriver{name: name.to_string()}),
+    }
+}
+
+// Checks that from_name would accept the name, without creating a driver and
+// so without connecting to anything
+pub fn validate_name(name: &str) -> Result<(), DriverError> {
+    if name.is_empty() || name.starts_with("sql:") {
+        return Ok(())
+    }
+    if !DRIVER_NAMES.contains(&name) {
+        return Err(DriverError::UnknownDriver{name: name.to_string()})
+    }
+    if name == "sql" {
+        sql_connection_string(name)?;
+    }
+    Ok(())
+}
+
+fn sql_connection_string(name: &str) -> Result<String, DriverError> {
+    env::var(HELM_DRIVER_SQL_CONNECTION_STRING).map_err(|_| DriverError::DriverConfig{
+        name: name.to_string(),
+        message: format!("{} must be set", HELM_DRIVER_SQL_CONNECTION_STRING),
+    })
+}
+
+// Creates the driver named by HELM_DRIVER, or the secrets driver if it is not
+// set
+pub fn from_env(namespace: String) -> Result<Box<dyn Driver + Send + Sync>, DriverError> {
+    let name = env::var(HELM_DRIVER).unwrap_or_default();
+    from_name(&name, namespace)
+}
+
+fn kube_client() -> Result<APIClient, DriverError> {
+    Ok(APIClient::new(config::load_kube_config()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_names() {
+        for name in ["", "secret", "configmaps", "memory", "sql:/tmp/helm.db"].iter() {
+            assert!(validate_name(name).is_ok(), "{} should be valid", name);
+        }
+        match validate_name("etcd") {
+            Err(DriverError::UnknownDriver{name}) => assert_eq!(name, "etcd"),
+            other => panic!("expected UnknownDriver, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::release::Release;
+use crate::storage::driver::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+// How many events are kept for watchers. Watching from a version older than
+// the oldest kept event fails, like an expired version does in Kubernetes
+const MAX_EVENTS: usize = 1000;
+
+// A stored release along with the labels it would have been given by one of
+// the Kubernetes backed drivers
+struct Record {
+    labels: HashMap<String, String>,
+    release: Release,
+    resource_version: u64,
+}
+
+// Memory is a driver that keeps all releases in an in-process map. It is
+// mostly useful for testing and dry runs, as nothing is persisted
+pub struct Memory {
+    cache: RwLock<HashMap<String, Record>>,
+    // Like the Kubernetes API server, every write is given a new version
+    // from a single counter so that stale updates can be detected
+    last_version: AtomicU64,
+    // Recent changes along with the version they were made at, for watchers
+    events: Mutex<EventLog>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct EventLog {
+    events: VecDeque<(u64, ReleaseEvent)>,
+    // The version of the newest event that has been dropped from the log
+    dropped: u64,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            cache: RwLock::new(HashMap::new()),
+            last_version: AtomicU64::new(0),
+            events: Mutex::new(EventLog::default()),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Memory {
+    fn generate_labels(rel: &Release, addl_labels: HashMap<String, String>) -> HashMap<String, String> {
+        let mut labels = addl_labels;
+        labels.insert("name".to_string(), rel.name.clone());
+        labels.insert("owner".to_string(), "helm".to_string());
+        labels.insert("status".to_string(), rel.info.status.to_string());
+        labels.insert("version".to_string(), rel.version.to_string());
+        labels
+    }
+
+    fn next_version(&self) -> u64 {
+        self.last_version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn versioned_release(record: &Record) -> Release {
+        let mut rel = record.release.clone();
+        rel.resource_version = Some(record.resource_version.to_string());
+        rel
+    }
+
+    // This must be called while holding the cache lock so that events are
+    // logged in version order
+    fn record_event(&self, version: u64, event: ReleaseEvent) {
+        let mut log = self.events.lock().expect("memory driver event lock poisoned");
+        log.events.push_back((version, event));
+        while log.events.len() > MAX_EVENTS {
+            if let Some((v, _)) = log.events.pop_front() {
+                log.dropped = v;
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl Driver for Memory {
+    fn name(&self) -> String {String::from("memory")}
+    // Every instance has releases of its own
+    fn location(&self) -> String {format!("memory:{:p}", self)}
+    fn create(&self, key: &String, mut rel: Release) -> Result<(), DriverError> {
+        let mut cache = self.cache.write().expect("memory driver lock poisoned");
+        if cache.contains_key(key) {
+            return Err(DriverError::ReleaseAlreadyExists)
+        }
+        let mut labels: HashMap<String, String> = HashMap::new();
+        labels.insert("createdAt".to_string(), chrono::Utc::now().timestamp().to_string());
+        rel.resource_version = None;
+        let record = Record{
+            labels: Memory::generate_labels(&rel, labels),
+            release: rel,
+            resource_version: self.next_version(),
+        };
+        self.record_event(record.resource_version, ReleaseEvent::Added(Memory::versioned_release(&record)));
+        cache.insert(key.clone(), record);
+        Ok(())
+    }
+    fn update(&self, key: &String, mut rel: Release) -> Result<(), DriverError> {
+        let mut cache = self.cache.write().expect("memory driver lock poisoned");
+        let record = match cache.get_mut(key) {
+            Some(r) => r,
+            None => { return Err(DriverError::ReleaseNotExist) }
+        };
+        if let Some(v) = rel.resource_version.take() {
+            if v != record.resource_version.to_string() {
+                return Err(DriverError::OutOfSync)
+            }
+        }
+        // Like a patch against the Kubernetes API, any labels that aren't
+        // regenerated (such as createdAt) are preserved
+        let mut labels: HashMap<String, String> = record.labels.clone();
+        labels.insert("modifiedAt".to_string(), chrono::Utc::now().timestamp().to_string());
+        record.labels = Memory::generate_labels(&rel, labels);
+        record.release = rel;
+        record.resource_version = self.next_version();
+        self.record_event(record.resource_version, ReleaseEvent::Modified(Memory::versioned_release(record)));
+        Ok(())
+    }
+    fn delete(&self, key: &String) -> Result<Release, DriverError> {
+        let mut cache = self.cache.write().expect("memory driver lock poisoned");
+        let mut record = match cache.remove(key) {
+            Some(r) => r,
+            None => { return Err(DriverError::ReleaseNotExist) }
+        };
+        record.resource_version = self.next_version();
+        self.record_event(record.resource_version, ReleaseEvent::Deleted(Memory::versioned_release(&record)));
+        Ok(record.release)
+    }
+    fn get(&self, key: &String) -> Result<Release, DriverError> {
+        let cache = self.cache.read().expect("memory driver lock poisoned");
+        match cache.get(key) {
+            Some(r) => Ok(Memory::versioned_release(r)),
+            None => Err(DriverError::ReleaseNotExist)
+        }
+    }
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
+        // Releases are never encoded in memory, so there is nothing that can
+        // fail to decode
+        let cache = self.cache.read().expect("memory driver lock poisoned");
+        let releases: Vec<Release> = cache.values()
+            .filter(|r| r.labels.get("owner").map(|o| o == "helm").unwrap_or(false))
+            .filter(|r| filter(&r.release))
+            .map(Memory::versioned_release)
+            .collect();
+        Ok(ReleaseList{releases, failures: Vec::new()})
+    }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        // Pages are taken in key order, and the continue token is the last
+        // key of the page so the next page can start after it
+        let cache = self.cache.read().expect("memory driver lock poisoned");
+        let mut keys: Vec<&String> = cache.iter()
+            .filter(|(_, r)| r.labels.get("owner").map(|o| o == "helm").unwrap_or(false))
+            .map(|(k, _)| k)
+            .filter(|k| params.continue_token.as_ref().map(|t| k.as_str() > t.as_str()).unwrap_or(true))
+            .collect();
+        keys.sort();
+        // A limit of 0 means no limit, as it does for Kubernetes
+        let limit = match params.limit {
+            Some(n) if n > 0 => n,
+            _ => keys.len(),
+        };
+        let continue_token = if keys.len() > limit { Some(keys[limit - 1].clone()) } else { None };
+        let releases: Vec<Release> = keys.into_iter()
+            .take(limit)
+            .map(|k| &cache[k])
+            .filter(|r| filter(&r.release))
+            .map(Memory::versioned_release)
+            .collect();
+        Ok(ReleasePage{
+            list: ReleaseList{releases, failures: Vec::new()},
+            continue_token,
+        })
+    }
+    fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
+        let cache = self.cache.read().expect("memory driver lock poisoned");
+        let release_list: Vec<Release> = cache.values()
+            .filter(|r| labels.iter().all(|(k, v)| r.labels.get(k) == Some(v)))
+            .map(Memory::versioned_release)
+            .collect();
+        Ok(release_list)
+    }
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        let cache = self.cache.read().expect("memory driver lock poisoned");
+        match cache.get(key) {
+            Some(r) => Ok(r.labels.clone()),
+            None => Err(DriverError::ReleaseNotExist)
+        }
+    }
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        let mut cache = self.cache.write().expect("memory driver lock poisoned");
+        let record = match cache.get_mut(key) {
+            Some(r) => r,
+            None => { return Err(DriverError::ReleaseNotExist) }
+        };
+        for (label, ts) in timestamp_labels(labels) {
+            record.labels.insert(label.to_string(), ts.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Watch for Memory {
+    fn current_version(&self) -> Result<String, DriverError> {
+        Ok(self.last_version.load(Ordering::SeqCst).to_string())
+    }
+    fn watch_events(&self, resource_version: &str, timeout: Duration) -> Result<(Vec<ReleaseEvent>, String), DriverError> {
+        let from: u64 = resource_version.parse()
+            .map_err(|_| DriverError::InvalidQuery{message: format!("invalid resource version {}", resource_version)})?;
+        let deadline = Instant::now() + timeout;
+        let mut log = self.events.lock().expect("memory driver event lock poisoned");
+        loop {
+            if from < log.dropped {
+                return Err(DriverError::OutOfSync)
+            }
+            let events: Vec<(u64, ReleaseEvent)> = log.events.iter().filter(|(v, _)| *v > from).cloned().collect();
+            if let Some((last, _)) = events.last() {
+                let version = last.to_string();
+                return Ok((events.into_iter().map(|(_, e)| e).collect(), version))
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok((Vec::new(), resource_version.to_string()))
+            }
+            log = self.changed.wait_timeout(log, deadline - now).expect("memory driver event lock poisoned").0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::driver::tests::check_paging;
+    use crate::storage::make_key;
+
+    #[test]
+    fn list_page() {
+        let memory = Memory::new();
+        for version in 1..=5 {
+            memory.create(&make_key("app", &version), Release{
+                name: "app".to_string(),
+                version,
+                ..Default::default()
+            }).unwrap();
+        }
+        check_paging(&memory);
+    }
+}
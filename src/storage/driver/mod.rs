@@ -1,30 +1,55 @@
+pub mod asynchronous;
 pub mod secrets;
 pub mod configmaps;
+pub mod encrypted;
+pub mod memory;
+pub mod sql;
+pub mod factory;
+pub mod cached;
+pub mod retry;
 
-use crate::release::Release;
-use std::collections::HashMap;
+use crate::release::{Release, Status};
+use crate::storage::lock::{Locker, MemoryLocker};
+use crate::storage::validate::validate_label;
+use crate::storage::driver::retry::RetryPolicy;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::vec::Vec;
-use std::error::Error;
 use flate2::write::{GzEncoder, GzDecoder};
 use flate2::Compression;
+use log::warn;
+use kube::api::{RawApi, ListParams, KubeObject, WatchEvent};
+use kube::client::APIClient;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use std::io::prelude::*;
+use std::time::Duration;
 
-// TODO: Expand out to a full enum of all driver errors
 #[derive(Debug, Fail)]
 pub enum DriverError {
     #[fail(display = "unable to perform kubernetes operation")]
     KubeError(#[fail(cause)] kube::Error),
-    #[fail(display = "unable to decode release: {}", message)]
-    DecodeError {
+    #[fail(display = "unable to perform sql operation")]
+    SqlError(#[fail(cause)] rusqlite::Error),
+    #[fail(display = "unable to encode release: {}", _0)]
+    Encode(#[fail(cause)] serde_json::Error),
+    #[fail(display = "unable to decode release: {}", _0)]
+    Decode(#[fail(cause)] serde_json::Error),
+    #[fail(display = "unable to compress or decompress release: {}", _0)]
+    Gzip(#[fail(cause)] std::io::Error),
+    #[fail(display = "release data is not valid base64: {}", _0)]
+    Base64(#[fail(cause)] base64::DecodeError),
+    #[fail(display = "unable to read or write release archive: {}", _0)]
+    ArchiveError(#[fail(cause)] std::io::Error),
+    #[fail(display = "unable to decode release data due to invalid or missing data: {}", message)]
+    InvalidData {
         message: String,
     },
-    // TODO: Figure out how to distinguish between encode and decode error
-    #[fail(display = "unable to encode release: {}", message)]
-    EncodeError {
+    #[fail(display = "unable to encrypt or decrypt release: {}", message)]
+    EncryptionError {
         message: String,
     },
-    #[fail(display = "unable to decode release data due to invalid or missing data: {}", message)]
-    InvalidData {
+    #[fail(display = "invalid release query: {}", message)]
+    InvalidQuery {
         message: String,
     },
     #[fail(display = "release was not found")]
@@ -34,7 +59,108 @@ pub enum DriverError {
     #[fail(display = "storage object has malformed data")]
     MalformedData,
     #[fail(display = "client is out of sync with server and/or has stale data")]
-    OutOfSync
+    OutOfSync,
+    #[fail(display = "release was not read from storage and has no resource version")]
+    MissingResourceVersion,
+    #[fail(display = "invalid release name {}: {}", name, reason)]
+    InvalidReleaseName {
+        name: String,
+        reason: String,
+    },
+    #[fail(display = "invalid label {}: {}", label, reason)]
+    InvalidLabel {
+        label: String,
+        reason: String,
+    },
+    #[fail(display = "driver operation was cancelled")]
+    Cancelled,
+    #[fail(display = "operation requires a driver scoped to a single namespace")]
+    NamespaceRequired,
+    #[fail(display = "release is locked by {}", holder)]
+    ReleaseLocked {
+        holder: String,
+    },
+    #[fail(display = "lock {} is not held, or has expired", key)]
+    LockNotHeld {
+        key: String,
+    },
+    #[fail(display = "another operation is in progress for this release (status {})", status)]
+    OperationInProgress {
+        status: Status,
+    },
+    #[fail(display = "unknown storage driver {}", name)]
+    UnknownDriver {
+        name: String,
+    },
+    #[fail(display = "storage driver {} is missing configuration: {}", name, message)]
+    DriverConfig {
+        name: String,
+        message: String,
+    },
+    #[fail(display = "source and destination are the same storage ({})", location)]
+    SameStorage {
+        location: String,
+    },
+    // Wraps an error with the storage object and driver it came from. Storage
+    // adds this to every error for a single release, so use the is_* methods
+    // or root rather than matching on the error directly
+    #[fail(display = "{} driver: {}: {}", driver, key, error)]
+    Context {
+        key: String,
+        driver: String,
+        #[fail(cause)]
+        error: Box<DriverError>,
+    }
+}
+
+impl DriverError {
+    // Wraps the error with the key and driver it came from. An error that
+    // already has context keeps it, as that is the closest to the source
+    pub fn context(self, key: &str, driver: &str) -> Self {
+        if let DriverError::Context{..} = self {
+            return self
+        }
+        DriverError::Context{
+            key: key.to_string(),
+            driver: driver.to_string(),
+            error: Box::new(self),
+        }
+    }
+
+    // Returns the error without any context added to it
+    pub fn root(&self) -> &DriverError {
+        match self {
+            DriverError::Context{error, ..} => error.root(),
+            e => e,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.root(), DriverError::ReleaseNotExist)
+    }
+
+    pub fn is_already_exists(&self) -> bool {
+        matches!(self.root(), DriverError::ReleaseAlreadyExists)
+    }
+
+    // Conflicts mean that someone else changed the release first or is in
+    // the middle of changing it. Reading the release again and retrying may
+    // work, unlike for other errors. A release that already exists is not a
+    // conflict, as creating it again would fail the same way every time
+    pub fn is_conflict(&self) -> bool {
+        matches!(self.root(), DriverError::OutOfSync | DriverError::ReleaseLocked{..} | DriverError::OperationInProgress{..})
+    }
+}
+
+// Lets a boxed error be the cause of a Context error
+impl failure::Fail for Box<DriverError> {
+    fn cause(&self) -> Option<&dyn failure::Fail> {
+        (**self).cause()
+    }
+
+    fn backtrace(&self) -> Option<&failure::Backtrace> {
+        (**self).backtrace()
+    }
 }
 
 impl From<kube::Error> for DriverError {
@@ -44,64 +170,781 @@ impl From<kube::Error> for DriverError {
             None => { return DriverError::KubeError(error) }
         };
         // Comes from list of reasons exposed by k8s. See: https://godoc.org/k8s.io/apimachinery/pkg/apis/meta/v1#StatusReason
-        return match apierr.reason.as_str() {
-            "AlreadyExists" => DriverError::ReleaseAlreadyExists,
-            "NotFound" => DriverError::ReleaseNotExist,
-            "Invalid" => DriverError::MalformedData,
-            "Conflict" | "Gone" => DriverError::OutOfSync,
+        // An expired resource version is reported as "Expired" by watches and
+        // "Gone" by lists, both with a 410 code
+        return match (apierr.reason.as_str(), apierr.code) {
+            ("AlreadyExists", _) => DriverError::ReleaseAlreadyExists,
+            ("NotFound", _) => DriverError::ReleaseNotExist,
+            ("Invalid", _) => DriverError::MalformedData,
+            ("Conflict", _) | ("Gone", _) | ("Expired", _) | (_, 410) => DriverError::OutOfSync,
             _ => DriverError::KubeError(error)
         };
     }
 }
 
+impl From<rusqlite::Error> for DriverError {
+    fn from(error: rusqlite::Error) -> Self {
+        DriverError::SqlError(error)
+    }
+}
+
+// serde_json and io errors are not converted automatically, as the same error
+// type comes from both encoding and decoding. Pick the variant with map_err
 impl From<base64::DecodeError> for DriverError {
     fn from(error: base64::DecodeError) -> Self {
-        DriverError::DecodeError{
-            message: error.description().to_string()
-        }
+        DriverError::Base64(error)
     }
 }
 
-impl From<serde_json::Error> for DriverError {
-    fn from(error: serde_json::Error) -> Self {
-        DriverError::DecodeError{
-            message: error.description().to_string()
-        }
-    }
+// A storage object that could not be decoded into a release
+#[derive(Debug)]
+pub struct DecodeFailure {
+    pub name: String,
+    pub cause: DriverError,
 }
 
-impl From<std::io::Error> for DriverError {
-    fn from(error: std::io::Error) -> Self {
-        DriverError::DecodeError{
-            message: error.description().to_string()
+// The result of listing releases. A storage object that can't be decoded
+// doesn't fail the whole list, and is reported in `failures` instead
+#[derive(Debug, Default)]
+pub struct ReleaseList {
+    pub releases: Vec<Release>,
+    pub failures: Vec<DecodeFailure>,
+}
+
+impl ReleaseList {
+    // Returns the releases, logging any failures to match Helm's behavior of
+    // skipping objects it can't decode
+    pub fn into_releases(self) -> Vec<Release> {
+        for f in self.failures.iter() {
+            warn!("skipping storage object {} that could not be decoded: {}", f.name, f.cause);
         }
+        self.releases
     }
 }
 
+// Asks a driver for one page of releases. Without a limit, every release is
+// returned in a single page
+#[derive(Clone, Debug, Default)]
+pub struct PageParams {
+    pub limit: Option<usize>,
+    // The token returned with the previous page. None starts from the
+    // beginning
+    pub continue_token: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReleasePage {
+    pub list: ReleaseList,
+    // Set when there are more releases to fetch, and should be passed back
+    // in the PageParams for the next page
+    pub continue_token: Option<String>,
+}
+
 pub trait Driver {
     fn name(&self) -> String;
+    // Identifies where the driver keeps its releases, such as the kind of
+    // object and namespace. Two drivers with the same location read and write
+    // the same releases
+    fn location(&self) -> String;
     fn create(&self, key: &String, rel: Release) -> Result<(), DriverError>;
     fn update(&self, key: &String, rel: Release) -> Result<(), DriverError>;
     fn delete(&self, key: &String) -> Result<Release, DriverError>;
     fn get(&self, key: &String) -> Result<Release, DriverError>;
-    fn list<F>(&self, filter: F) -> Result<Vec<Release>, DriverError>
-    where
-        F: Fn(&Release) -> bool;
+    fn list(&self, filter: &dyn Fn(&Release) -> bool) -> Result<Vec<Release>, DriverError> {
+        Ok(self.list_with_report(filter)?.into_releases())
+    }
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError>;
+    // Lists a single page of releases. The filter is applied after a page is
+    // fetched, so a page may hold fewer releases than the limit (or none)
+    // even when there are more to come
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError>;
     fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError>;
+    // Returns the labels of the object a release is stored in. Besides the
+    // ones used for queries, these include when it was created (createdAt)
+    // and last modified (modifiedAt), as seconds since the epoch
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError>;
+    // Sets the createdAt and modifiedAt of a stored release to the ones in
+    // `labels`, as returned by labels. Used when importing a release so that
+    // it keeps when it was first stored. Other labels are left alone
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError>;
+    // Returns the locker Storage uses for release locks by default. Drivers
+    // that keep releases somewhere other processes can reach should return
+    // one that those processes see as well
+    fn locker(&self) -> Box<dyn Locker> {
+        Box::new(MemoryLocker::new())
+    }
+}
+
+// The labels a driver sets to record when a release was stored
+pub const TIMESTAMP_LABELS: [&str; 2] = ["createdAt", "modifiedAt"];
+
+// Picks the timestamp labels out of `labels`, skipping any that aren't a
+// number of seconds
+pub fn timestamp_labels(labels: &HashMap<String, String>) -> Vec<(&'static str, i64)> {
+    TIMESTAMP_LABELS.iter()
+        .filter_map(|l| labels.get(*l).and_then(|v| v.parse().ok()).map(|v| (*l, v)))
+        .collect()
+}
+
+// Lets a driver chosen at runtime (see the factory module) be used anywhere a
+// Driver is expected, such as in Storage
+impl<D: Driver + ?Sized> Driver for Box<D> {
+    fn name(&self) -> String {(**self).name()}
+    fn location(&self) -> String {(**self).location()}
+    fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        (**self).create(key, rel)
+    }
+    fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        (**self).update(key, rel)
+    }
+    fn delete(&self, key: &String) -> Result<Release, DriverError> {
+        (**self).delete(key)
+    }
+    fn get(&self, key: &String) -> Result<Release, DriverError> {
+        (**self).get(key)
+    }
+    fn list(&self, filter: &dyn Fn(&Release) -> bool) -> Result<Vec<Release>, DriverError> {
+        (**self).list(filter)
+    }
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
+        (**self).list_with_report(filter)
+    }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        (**self).list_page(params, filter)
+    }
+    fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
+        (**self).query(labels)
+    }
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        (**self).labels(key)
+    }
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        (**self).restore_timestamps(key, labels)
+    }
+    fn locker(&self) -> Box<dyn Locker> {(**self).locker()}
+}
+
+// A change to a release seen while watching storage
+#[derive(Clone, Debug)]
+pub enum ReleaseEvent {
+    Added(Release),
+    Modified(Release),
+    Deleted(Release),
+}
+
+// Watch is implemented by drivers that can report changes to releases as
+// they happen. Changes are ordered by resource version, which can be used to
+// resume watching where a previous watch left off
+pub trait Watch {
+    // Returns the resource version to start watching from to only see
+    // changes made from now on
+    fn current_version(&self) -> Result<String, DriverError>;
+    // Waits up to `timeout` for changes made after `resource_version`,
+    // returning them along with the version to pass to the next call. No
+    // changes means the wait timed out. If the version is too old to resume
+    // from, DriverError::OutOfSync is returned
+    fn watch_events(&self, resource_version: &str, timeout: Duration) -> Result<(Vec<ReleaseEvent>, String), DriverError>;
+}
+
+// The ListMeta in kube does not deserialize the continue token, so paged
+// lists are read into this instead
+#[derive(Deserialize)]
+struct PagedList<K> {
+    #[serde(default)]
+    metadata: PagedListMeta,
+    #[serde(default = "Vec::new")]
+    items: Vec<K>,
+}
+
+#[derive(Deserialize, Default)]
+struct PagedListMeta {
+    #[serde(rename = "continue")]
+    continue_token: Option<String>,
+    #[serde(rename = "resourceVersion")]
+    resource_version: Option<String>,
+}
+
+fn query_escape(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// Lists one page of Kubernetes objects. The kube list request has no way to
+// set a limit or continue token, so they are added to its URL here. An empty
+// continue token in the response means this was the last page
+fn list_kube_page<K: DeserializeOwned>(client: &APIClient, api: &RawApi, label_selector: String, params: &PageParams) -> Result<PagedList<K>, DriverError> {
+    let mut lp = ListParams::default();
+    lp.label_selector = Some(label_selector);
+    let mut req = api.list(&lp)?;
+    let mut uri = req.uri().to_string();
+    if let Some(limit) = params.limit {
+        uri.push_str(&format!("&limit={}", limit));
+    }
+    if let Some(ref token) = params.continue_token {
+        uri.push_str(&format!("&continue={}", query_escape(token)));
+    }
+    *req.uri_mut() = uri.parse().map_err(|e| DriverError::InvalidQuery{message: format!("unable to build list request: {}", e)})?;
+    let mut list: PagedList<K> = client.request(req)?;
+    list.metadata.continue_token = list.metadata.continue_token.filter(|t| !t.is_empty());
+    Ok(list)
+}
+
+// Returns the resource version of a Kubernetes list, which is where a watch
+// needs to start to only see changes made from now on
+fn current_kube_version(client: &APIClient, api: &RawApi, label_selector: String) -> Result<String, DriverError> {
+    let params = PageParams{
+        limit: Some(1),
+        continue_token: None,
+    };
+    let list: PagedList<serde_json::Value> = list_kube_page(client, api, label_selector, &params)?;
+    match list.metadata.resource_version {
+        Some(v) => Ok(v),
+        None => Err(DriverError::InvalidData{message: "list has no resource version".to_string()}),
+    }
+}
+
+// Builds an equality based label selector, checking every label first so
+// that nothing can be smuggled into the selector
+fn label_selector(labels: &HashMap<String, String>) -> Result<String, DriverError> {
+    let mut selector: Vec<String> = Vec::with_capacity(labels.len());
+    for (k, v) in labels.iter() {
+        validate_label(k, v)?;
+        selector.push(format!("{}={}", k, v));
+    }
+    Ok(selector.join(","))
+}
+
+// Returns the names of the namespaces matching a label selector
+fn list_namespaces(client: &APIClient, label_selector: &str) -> Result<HashSet<String>, DriverError> {
+    let list: PagedList<serde_json::Value> = list_kube_page(client, &RawApi::v1Namespace(), label_selector.to_string(), &PageParams::default())?;
+    Ok(list.items.iter().filter_map(|ns| ns["metadata"]["name"].as_str().map(String::from)).collect())
+}
+
+// Returns true if an object is in one of the given namespaces, or if there
+// is no limit on namespaces
+fn in_namespaces(namespaces: &Option<HashSet<String>>, namespace: &Option<String>) -> bool {
+    match (namespaces, namespace) {
+        (None, _) => true,
+        (Some(set), Some(ns)) => set.contains(ns),
+        (Some(_), None) => false,
+    }
+}
+
+// Turns the events from a Kubernetes watch into release events, using
+// `decode` to get the release out of each object. Objects that can't be
+// decoded are logged and skipped, as they are when listing, and objects that
+// decode to None are skipped silently
+fn convert_watch_events<K, D>(events: Vec<WatchEvent<K>>, resource_version: &str, decode: D) -> Result<(Vec<ReleaseEvent>, String), DriverError>
+where
+    K: Clone + KubeObject,
+    D: Fn(K) -> Result<Option<Release>, DriverError>,
+{
+    let mut version = resource_version.to_string();
+    let mut release_events = Vec::with_capacity(events.len());
+    for event in events.into_iter() {
+        let (obj, to_event): (K, fn(Release) -> ReleaseEvent) = match event {
+            WatchEvent::Added(o) => (o, ReleaseEvent::Added),
+            WatchEvent::Modified(o) => (o, ReleaseEvent::Modified),
+            WatchEvent::Deleted(o) => (o, ReleaseEvent::Deleted),
+            WatchEvent::Error(e) => { return Err(DriverError::from(kube::Error::from(kube::ErrorKind::Api(e)))) }
+        };
+        let name = obj.meta().name.clone();
+        if let Some(ref v) = obj.meta().resourceVersion {
+            version = v.clone();
+        }
+        match decode(obj) {
+            Ok(Some(rel)) => release_events.push(to_event(rel)),
+            Ok(None) => (),
+            Err(e) => warn!("skipping event for storage object {} that could not be decoded: {}", name, e),
+        }
+    }
+    Ok((release_events, version))
 }
 
-fn decode_release(raw: Vec<u8>) -> Result<Release, DriverError> {
+pub fn decode_release(raw: Vec<u8>) -> Result<Release, DriverError> {
     let mut decoder = GzDecoder::new(Vec::new());
-    decoder.write_all(&raw)?;
-    let buffer = decoder.finish()?;
-    let rel: Release = serde_json::from_slice(&buffer)?;
+    decoder.write_all(&raw).map_err(DriverError::Gzip)?;
+    let buffer = decoder.finish().map_err(DriverError::Gzip)?;
+    let rel: Release = serde_json::from_slice(&buffer).map_err(DriverError::Decode)?;
     Ok(rel)
 }
 
-fn encode_release(rel: &Release) -> Result<String, DriverError> {
-    let enc = serde_json::to_vec(rel)?;
+fn compress_release(rel: &Release) -> Result<Vec<u8>, DriverError> {
+    let enc = serde_json::to_vec(rel).map_err(DriverError::Encode)?;
     let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(&enc)?;
-    let buffer = encoder.finish()?;
-    Ok(base64::encode(&buffer))
+    encoder.write_all(&enc).map_err(DriverError::Gzip)?;
+    let buffer = encoder.finish().map_err(DriverError::Gzip)?;
+    Ok(buffer)
+}
+
+pub fn encode_release(rel: &Release) -> Result<String, DriverError> {
+    Ok(base64::encode(&compress_release(rel)?))
+}
+
+// Kubernetes objects can be at most 1MiB, so compressed releases larger than
+// this are split over several objects. ConfigMaps hold the data base64
+// encoded, so this leaves room for that as well as the object metadata
+const MAX_CHUNK_SIZE: usize = 512 * 1024;
+
+// A compressed release split into the parts stored in each object. The
+// first part is stored in the object named with the release key, which also
+// records how many parts there are, a checksum of the whole payload and the
+// generation of the other parts. Those are stored in objects named by
+// `chunk_key`
+struct Chunks {
+    parts: Vec<Vec<u8>>,
+    checksum: String,
+    generation: String,
+}
+
+impl Chunks {
+    fn is_chunked(&self) -> bool {
+        self.parts.len() > 1
+    }
+}
+
+// Every write of a release gets a new generation, so an update writes its
+// chunks without touching the ones the stored release still refers to.
+// Releases chunked before generations were added have none
+fn chunk_key(key: &str, generation: &str, index: usize) -> String {
+    if generation.is_empty() {
+        return format!("{}.chunk{}", key, index)
+    }
+    format!("{}.{}.chunk{}", key, generation, index)
+}
+
+fn new_generation() -> String {
+    let mut buf = [0u8; 6];
+    getrandom::getrandom(&mut buf).expect("unable to read random bytes from the OS");
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn checksum(data: &[u8]) -> String {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    format!("{:08x}", crc.sum())
+}
+
+fn split_release(rel: &Release) -> Result<Chunks, DriverError> {
+    let data = compress_release(rel)?;
+    let sum = checksum(&data);
+    let mut parts: Vec<Vec<u8>> = data.chunks(MAX_CHUNK_SIZE).map(|c| c.to_vec()).collect();
+    if parts.is_empty() {
+        parts.push(Vec::new());
+    }
+    Ok(Chunks {
+        parts,
+        checksum: sum,
+        generation: new_generation(),
+    })
+}
+
+fn parse_chunk_count(raw: &str) -> Result<usize, DriverError> {
+    match raw.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(DriverError::InvalidData{message: format!("invalid chunk count '{}'", raw)}),
+    }
+}
+
+// Reassembles a release payload from its parts, making sure nothing was lost
+// or changed along the way
+fn join_chunks(parts: Vec<Vec<u8>>, expected: &str) -> Result<Vec<u8>, DriverError> {
+    let data = parts.concat();
+    let actual = checksum(&data);
+    if actual != expected {
+        return Err(DriverError::InvalidData{message: format!("release chunks have checksum {} but expected {}", actual, expected)})
+    }
+    Ok(data)
+}
+
+// The data of a Secret or ConfigMap holding a release or a chunk of one, with
+// every value as the bytes it holds. The release payload is the compressed
+// release for both kinds, as ConfigMaps have it decoded from base64
+struct StoredObject {
+    data: BTreeMap<String, Vec<u8>>,
+    labels: BTreeMap<String, String>,
+}
+
+impl StoredObject {
+    fn value(&self, name: &str) -> Result<&[u8], DriverError> {
+        match self.data.get(name) {
+            Some(v) => Ok(v),
+            None => Err(DriverError::InvalidData{message: format!("no '{}' key found", name)})
+        }
+    }
+
+    fn chunk_count(&self) -> Result<usize, DriverError> {
+        match self.data.get("chunks") {
+            Some(v) => parse_chunk_count(&String::from_utf8_lossy(v)),
+            None => Ok(1)
+        }
+    }
+
+    fn generation(&self) -> String {
+        match self.data.get("generation") {
+            Some(v) => String::from_utf8_lossy(v).into_owned(),
+            None => String::new(),
+        }
+    }
+
+    // Returns true if this is the object written for the first of `chunks`
+    fn holds(&self, chunks: &Chunks) -> bool {
+        if self.data.get("release") != Some(&chunks.parts[0]) {
+            return false
+        }
+        if !chunks.is_chunked() {
+            return !self.data.contains_key("chunks")
+        }
+        self.chunk_count().ok() == Some(chunks.parts.len())
+            && self.generation() == chunks.generation
+            && self.data.get("checksum").map(|v| v.as_slice()) == Some(chunks.checksum.as_bytes())
+    }
+
+    // What can be told about a release from the labels of its object alone
+    fn labeled_release(&self) -> Release {
+        Release {
+            name: self.labels.get("name").cloned().unwrap_or_default(),
+            version: self.labels.get("version").and_then(|v| v.parse().ok()).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+// The requests on Secrets or ConfigMaps that storing releases in them needs,
+// so that both drivers can share the code for splitting releases into chunks
+trait ReleaseObjects {
+    // The kind of object, such as Secret
+    fn kind(&self) -> &'static str;
+    // The type set on objects holding a release, for kinds that have one
+    fn object_type(&self) -> Option<&'static str> {
+        None
+    }
+    // Encodes a value for the data of an object, stored under `name`
+    fn encode_value(&self, name: &str, value: &[u8]) -> serde_json::Value;
+    fn get_object(&self, name: &str) -> Result<StoredObject, DriverError>;
+    fn create_object(&self, body: &[u8]) -> Result<(), DriverError>;
+    fn patch_object(&self, name: &str, body: &[u8]) -> Result<(), DriverError>;
+    fn delete_object(&self, name: &str) -> Result<(), DriverError>;
+}
+
+fn generate_object_data<O: ReleaseObjects>(objects: &O, key: &str, rel: &Release, chunks: &Chunks, addl_labels: HashMap<String, String>) -> serde_json::Value {
+    let mut data = json!({
+        "apiVersion": "v1",
+        "kind": objects.kind(),
+        "metadata": {
+            "name": key,
+            "labels": {
+                "name": rel.name,
+                "owner": "helm",
+                "status": rel.info.status.to_string(),
+                "version": rel.version.to_string()
+            }
+        },
+        "data": {
+            "release": objects.encode_value("release", &chunks.parts[0])
+        }
+    });
+    if let Some(t) = objects.object_type() {
+        data["type"] = t.into();
+    }
+    if chunks.is_chunked() {
+        data["data"]["chunks"] = objects.encode_value("chunks", chunks.parts.len().to_string().as_bytes());
+        data["data"]["checksum"] = objects.encode_value("checksum", chunks.checksum.as_bytes());
+        data["data"]["generation"] = objects.encode_value("generation", chunks.generation.as_bytes());
+    }
+    for (k, v) in addl_labels.iter() {
+        data["metadata"]["labels"][k] = v.clone().into()
+    }
+    // Sending the resource version makes the API server reject the write
+    // with a conflict if the object has changed since it was read
+    if let Some(v) = rel.resource_version.as_ref() {
+        data["metadata"]["resourceVersion"] = v.clone().into();
+    }
+    data
+}
+
+// Chunk objects are deliberately not labeled with owner=helm so they never
+// show up when listing or querying releases
+fn generate_chunk_data<O: ReleaseObjects>(objects: &O, name: &str, rel: &Release, part: &[u8]) -> Result<Vec<u8>, DriverError> {
+    let data = json!({
+        "apiVersion": "v1",
+        "kind": objects.kind(),
+        "metadata": {
+            "name": name,
+            "labels": {
+                "name": rel.name,
+                "owner": "helm-chunk",
+                "version": rel.version.to_string()
+            }
+        },
+        "data": {
+            "release": objects.encode_value("release", part)
+        }
+    });
+    let bytes = serde_json::to_vec(&data).map_err(DriverError::Encode)?;
+    Ok(bytes)
+}
+
+// Returns the compressed release stored in `head`, the object named `key`,
+// fetching and reassembling any other chunks it was split into
+fn read_release_data<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str, head: &StoredObject) -> Result<Vec<u8>, DriverError> {
+    let raw = head.value("release")?.to_vec();
+    let count = head.chunk_count()?;
+    if count == 1 {
+        return Ok(raw)
+    }
+    let sum = String::from_utf8_lossy(head.value("checksum")?).into_owned();
+    let generation = head.generation();
+    let mut parts: Vec<Vec<u8>> = Vec::with_capacity(count);
+    parts.push(raw);
+    for i in 1..count {
+        let chunk = match retry.run(|| objects.get_object(&chunk_key(key, &generation, i))) {
+            Ok(c) => c,
+            Err(e) => match e {
+                DriverError::ReleaseNotExist => {
+                    return Err(DriverError::InvalidData{message: format!("chunk {} of {} is missing", i, key)})
+                },
+                e => { return Err(e) }
+            }
+        };
+        parts.push(chunk.value("release")?.to_vec());
+    }
+    join_chunks(parts, &sum)
+}
+
+// Writes all chunks after the first. If that fails, the chunks that were
+// written are removed again
+fn write_chunks<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str, rel: &Release, chunks: &Chunks) -> Result<(), DriverError> {
+    for (i, part) in chunks.parts.iter().enumerate().skip(1) {
+        if let Err(e) = write_chunk(objects, retry, key, rel, chunks, i, part) {
+            delete_chunks(objects, retry, key, &chunks.generation, 1, i).ok();
+            return Err(e)
+        }
+    }
+    Ok(())
+}
+
+fn write_chunk<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str, rel: &Release, chunks: &Chunks, i: usize, part: &[u8]) -> Result<(), DriverError> {
+    let name = chunk_key(key, &chunks.generation, i);
+    let data = generate_chunk_data(objects, &name, rel, part)?;
+    match retry.run(|| objects.create_object(&data)) {
+        Ok(_) => Ok(()),
+        Err(e) => match e {
+            DriverError::ReleaseAlreadyExists => retry.run(|| objects.patch_object(&name, &data)),
+            e => Err(e)
+        }
+    }
+}
+
+fn delete_chunks<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str, generation: &str, from: usize, to: usize) -> Result<(), DriverError> {
+    for i in from.max(1)..to {
+        match retry.run_delete(|| objects.delete_object(&chunk_key(key, generation, i))) {
+            Ok(_) => (),
+            Err(e) => match e {
+                DriverError::ReleaseNotExist => (),
+                e => { return Err(e) }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn create_release_objects<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str, mut rel: Release) -> Result<(), DriverError> {
+    // New objects can't have a resource version, so drop any that was
+    // carried over from a release that was read earlier
+    rel.resource_version = None;
+    let mut labels: HashMap<String, String> = HashMap::new();
+    labels.insert("createdAt".to_string(), chrono::Utc::now().timestamp().to_string());
+    let chunks = split_release(&rel)?;
+    let data = generate_object_data(objects, key, &rel, &chunks, labels);
+    let body = serde_json::to_vec(&data).map_err(DriverError::Encode)?;
+    retry.run_create(|| objects.create_object(&body), || Ok(retry.run(|| objects.get_object(key))?.holds(&chunks)))?;
+    if let Err(e) = write_chunks(objects, retry, key, &rel, &chunks) {
+        // Don't leave behind a release that can't be read
+        retry.run(|| objects.delete_object(key)).ok();
+        return Err(e)
+    }
+    Ok(())
+}
+
+fn update_release_objects<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str, rel: Release) -> Result<(), DriverError> {
+    let old = retry.run(|| objects.get_object(key))?;
+    let old_count = old.chunk_count()?;
+    let old_generation = old.generation();
+    let mut labels: HashMap<String, String> = HashMap::new();
+    labels.insert("modifiedAt".to_string(), chrono::Utc::now().timestamp().to_string());
+    let chunks = split_release(&rel)?;
+    let mut data = generate_object_data(objects, key, &rel, &chunks, labels);
+    if !chunks.is_chunked() {
+        // Nulls remove the keys of a previously chunked release
+        data["data"]["chunks"] = serde_json::Value::Null;
+        data["data"]["checksum"] = serde_json::Value::Null;
+        data["data"]["generation"] = serde_json::Value::Null;
+    }
+    let body = serde_json::to_vec(&data).map_err(DriverError::Encode)?;
+    // The new chunks are written before the release points at them, and the
+    // old ones are only removed after it no longer does, so the stored
+    // release can be read at every step
+    write_chunks(objects, retry, key, &rel, &chunks)?;
+    if let Err(e) = retry.run_update(|| objects.patch_object(key, &body), || Ok(retry.run(|| objects.get_object(key))?.holds(&chunks))) {
+        // The patch may still have gone through, so the new chunks are only
+        // removed if the release is known not to point at them
+        match retry.run(|| objects.get_object(key)) {
+            Ok(ref current) if current.generation() != chunks.generation => {
+                delete_chunks(objects, retry, key, &chunks.generation, 1, chunks.parts.len()).ok();
+            },
+            _ => warn!("leaving the new chunks of {} in place after a failed update", key),
+        }
+        return Err(e)
+    }
+    // Failing here only leaves unused chunks behind
+    if let Err(e) = delete_chunks(objects, retry, key, &old_generation, 1, old_count) {
+        warn!("unable to delete old chunks of {}: {}", key, e);
+    }
+    Ok(())
+}
+
+// Deletes a release and its chunks, going by the chunk count and generation
+// of the object named `key` rather than decoding the release first, so that
+// a release with missing or corrupt chunks can still be cleaned up. The
+// release is decoded only to be returned. If that fails, what its labels
+// tell is returned instead
+fn delete_release_objects<O: ReleaseObjects>(objects: &O, retry: &RetryPolicy, key: &str) -> Result<Release, DriverError> {
+    let head = retry.run(|| objects.get_object(key))?;
+    let count = head.chunk_count().unwrap_or_else(|e| {
+        warn!("deleting only the first chunk of {}: {}", key, e);
+        1
+    });
+    let generation = head.generation();
+    let rel = match read_release_data(objects, retry, key, &head).and_then(decode_release) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("deleting release {} that could not be decoded: {}", key, e);
+            head.labeled_release()
+        }
+    };
+    retry.run_delete(|| objects.delete_object(key))?;
+    delete_chunks(objects, retry, key, &generation, 1, count)?;
+    Ok(rel)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A release that compresses to more than two chunks
+    pub fn large_release(name: &str, version: usize) -> Release {
+        let mut noise = vec![0u8; MAX_CHUNK_SIZE * 2];
+        getrandom::getrandom(&mut noise).unwrap();
+        Release {
+            name: name.to_string(),
+            version,
+            namespace: "default".to_string(),
+            manifest: base64::encode(&noise),
+            ..Default::default()
+        }
+    }
+
+    // The error the API server gives when watching from a version that has
+    // expired
+    pub fn expired_error() -> kube::Error {
+        kube::Error::from(kube::ErrorKind::Api(kube::ApiError{
+            status: "Failure".to_string(),
+            message: "too old resource version: 1 (2)".to_string(),
+            reason: "Expired".to_string(),
+            code: 410,
+        }))
+    }
+
+    fn page_versions(page: &ReleasePage) -> Vec<usize> {
+        page.list.releases.iter().map(|r| r.version).collect()
+    }
+
+    // Checks the paging of a driver holding nothing but versions 1 to 5 of
+    // app, which sort by key in version order
+    pub fn check_paging<D: Driver>(driver: &D) {
+        let all = |_: &Release| true;
+        let page = |limit: Option<usize>, token: Option<String>, filter: &dyn Fn(&Release) -> bool| {
+            driver.list_page(&PageParams{limit, continue_token: token}, filter).unwrap()
+        };
+
+        // Pages end at the limit, and the last one has no continue token
+        let first = page(Some(2), None, &all);
+        assert_eq!(page_versions(&first), vec![1, 2]);
+        let second = page(Some(2), first.continue_token.clone(), &all);
+        assert_eq!(page_versions(&second), vec![3, 4]);
+        let last = page(Some(2), second.continue_token.clone(), &all);
+        assert_eq!(page_versions(&last), vec![5]);
+        assert_eq!(last.continue_token, None);
+
+        // Filling the last page exactly doesn't leave an empty one after it
+        let exact = page(Some(5), None, &all);
+        assert_eq!(page_versions(&exact), vec![1, 2, 3, 4, 5]);
+        assert_eq!(exact.continue_token, None);
+
+        // No limit, or a limit of 0, is everything at once
+        assert_eq!(page_versions(&page(None, None, &all)).len(), 5);
+        assert_eq!(page_versions(&page(Some(0), None, &all)).len(), 5);
+
+        // A page can be emptied by the filter and still have more after it
+        let filtered = page(Some(2), None, &|r: &Release| r.version > 2);
+        assert!(filtered.list.releases.is_empty());
+        assert_eq!(page_versions(&page(Some(2), filtered.continue_token, &all)), vec![3, 4]);
+
+        // Continuing from the end is an empty last page
+        let end = page(Some(2), Some(crate::storage::make_key("app", &5)), &all);
+        assert!(end.list.releases.is_empty());
+        assert_eq!(end.continue_token, None);
+    }
+
+    #[test]
+    fn split_and_join() {
+        let rel = large_release("app", 1);
+        let chunks = split_release(&rel).unwrap();
+        assert_eq!(chunks.parts.len(), 3);
+        assert!(chunks.parts.iter().all(|p| p.len() <= MAX_CHUNK_SIZE));
+        let data = join_chunks(chunks.parts.clone(), &chunks.checksum).unwrap();
+        assert_eq!(decode_release(data).unwrap().manifest, rel.manifest);
+
+        // Every write gets a generation of its own
+        assert_ne!(split_release(&rel).unwrap().generation, chunks.generation);
+
+        let small = split_release(&Release::default()).unwrap();
+        assert!(!small.is_chunked());
+    }
+
+    #[test]
+    fn join_checks_checksum() {
+        let chunks = split_release(&large_release("app", 1)).unwrap();
+        let mut parts = chunks.parts.clone();
+        parts.swap(1, 2);
+        assert!(matches!(join_chunks(parts, &chunks.checksum), Err(DriverError::InvalidData{..})));
+        let mut parts = chunks.parts.clone();
+        parts.pop();
+        assert!(matches!(join_chunks(parts, &chunks.checksum), Err(DriverError::InvalidData{..})));
+    }
+
+    #[test]
+    fn expired_versions_are_out_of_sync() {
+        assert!(matches!(DriverError::from(expired_error()), DriverError::OutOfSync));
+        let error = match expired_error().api_error() {
+            Some(e) => e.clone(),
+            None => panic!("not an API error"),
+        };
+        let events: Vec<WatchEvent<kube::api::v1Secret>> = vec![WatchEvent::Error(error)];
+        assert!(matches!(convert_watch_events(events, "1", |_| Ok(None)), Err(DriverError::OutOfSync)));
+    }
+
+    #[test]
+    fn conflicts() {
+        assert!(DriverError::OutOfSync.is_conflict());
+        assert!(DriverError::ReleaseLocked{holder: "other".to_string()}.is_conflict());
+        assert!(DriverError::OutOfSync.context("key", "memory").is_conflict());
+        // Retrying a duplicate create can never work
+        assert!(!DriverError::ReleaseAlreadyExists.is_conflict());
+        assert!(!DriverError::ReleaseAlreadyExists.context("key", "memory").is_conflict());
+        assert!(!DriverError::ReleaseNotExist.is_conflict());
+    }
 }
@@ -0,0 +1,260 @@
+// This module retries Kubernetes requests that failed for reasons that are
+// likely to go away on their own, such as throttling or a restarting API
+// server
+use crate::storage::driver::DriverError;
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::error::Error as StdError;
+use std::io;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // How many times a request is retried before its error is returned. 0
+    // turns off retrying
+    pub max_retries: u32,
+    // The wait before the first retry. It doubles with every retry after that
+    pub initial_backoff: Duration,
+    // The longest wait between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // A policy that never retries
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // Calls `f` until it succeeds, fails with an error that isn't transient,
+    // or runs out of retries. Creates should go through run_create instead
+    pub fn run<T, E, F>(&self, mut f: F) -> Result<T, DriverError>
+    where
+        E: Into<DriverError>,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut retries = 0;
+        loop {
+            let err = match f() {
+                Ok(v) => { return Ok(v) }
+                Err(e) => e.into(),
+            };
+            if retries >= self.max_retries || !is_transient(&err) {
+                return Err(err)
+            }
+            let backoff = self.backoff(retries);
+            retries += 1;
+            warn!("kubernetes request failed, retrying in {:?} ({} of {}): {}", backoff, retries, self.max_retries, err);
+            std::thread::sleep(backoff);
+        }
+    }
+
+    // Like run, but for creates, which aren't idempotent. A create whose
+    // response was lost may have gone through, in which case the retry fails
+    // with ReleaseAlreadyExists. When that happens after a retry, `created`
+    // is asked whether the existing object is the one that was sent
+    pub fn run_create<T, E, F, C>(&self, f: F, created: C) -> Result<(), DriverError>
+    where
+        E: Into<DriverError>,
+        F: FnMut() -> Result<T, E>,
+        C: FnOnce() -> Result<bool, DriverError>,
+    {
+        self.run_applied(f, |e| matches!(e, DriverError::ReleaseAlreadyExists), created)
+    }
+
+    // Like run_create, but for updates sent with a resource version. A retry
+    // of one that went through fails with OutOfSync, as the first attempt
+    // changed the version
+    pub fn run_update<T, E, F, C>(&self, f: F, updated: C) -> Result<(), DriverError>
+    where
+        E: Into<DriverError>,
+        F: FnMut() -> Result<T, E>,
+        C: FnOnce() -> Result<bool, DriverError>,
+    {
+        self.run_applied(f, |e| matches!(e, DriverError::OutOfSync), updated)
+    }
+
+    // Like run, but a retry failing with ReleaseNotExist means an earlier
+    // attempt deleted the object
+    pub fn run_delete<T, E, F>(&self, f: F) -> Result<(), DriverError>
+    where
+        E: Into<DriverError>,
+        F: FnMut() -> Result<T, E>,
+    {
+        self.run_applied(f, |e| matches!(e, DriverError::ReleaseNotExist), || Ok(true))
+    }
+
+    // Runs a write that fails with an error matching `conflict` when retried
+    // after it went through. Only then is `applied` asked whether it did
+    fn run_applied<T, E, F, M, C>(&self, mut f: F, conflict: M, applied: C) -> Result<(), DriverError>
+    where
+        E: Into<DriverError>,
+        F: FnMut() -> Result<T, E>,
+        M: Fn(&DriverError) -> bool,
+        C: FnOnce() -> Result<bool, DriverError>,
+    {
+        let mut attempts = 0;
+        let res = self.run(|| {
+            attempts += 1;
+            f()
+        });
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if attempts > 1 && conflict(&e) && applied()? {
+                    return Ok(())
+                }
+                Err(e)
+            },
+        }
+    }
+
+    // Exponential backoff with jitter, so that clients throttled at the same
+    // time don't all retry at the same time too. The wait is somewhere
+    // between half and all of the exponential backoff
+    fn backoff(&self, retries: u32) -> Duration {
+        let max = self.initial_backoff.checked_mul(1 << retries.min(16)).unwrap_or(self.max_backoff).min(self.max_backoff);
+        let half = max / 2;
+        let jitter = random() % (half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+}
+
+// A random number without pulling in a dependency for it. RandomState is
+// seeded randomly every time one is created
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Returns true for errors worth retrying: throttling, server side timeouts,
+// internal errors and dropped connections
+pub fn is_transient(err: &DriverError) -> bool {
+    let err = match err {
+        DriverError::KubeError(e) => e,
+        _ => { return false }
+    };
+    if let Some(apierr) = err.api_error() {
+        return match apierr.reason.as_str() {
+            "TooManyRequests" | "ServerTimeout" | "Timeout" | "InternalError" => true,
+            // Not every response has a reason, in which case the status code
+            // is all there is to go on
+            _ => matches!(apierr.code, 429 | 500 | 503 | 504),
+        }
+    }
+    // Otherwise the request may never have got a response. Look through the
+    // errors that caused it for a connection that was dropped
+    let cause = match failure::Fail::cause(err).and_then(|c| c.downcast_ref::<reqwest::Error>()) {
+        Some(c) => c,
+        None => { return false }
+    };
+    if cause.is_timeout() {
+        return true
+    }
+    // reqwest errors don't implement source, so the chain has to be followed
+    // by hand
+    let inner = match cause.get_ref() {
+        Some(e) => e,
+        None => { return false }
+    };
+    if let Some(e) = inner.downcast_ref::<io::Error>() {
+        return is_dropped_connection(e)
+    }
+    if let Some(e) = inner.downcast_ref::<hyper::Error>() {
+        // An idle connection closed by the server shows up as an incomplete
+        // message when it is next used
+        if e.is_incomplete_message() {
+            return true
+        }
+        return match e.source().and_then(|c| c.downcast_ref::<io::Error>()) {
+            Some(ioerr) => is_dropped_connection(ioerr),
+            None => false,
+        }
+    }
+    false
+}
+
+fn is_dropped_connection(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::{ApiError, ErrorKind};
+
+    fn api_error(reason: &str, code: u16) -> kube::Error {
+        kube::Error::from(ErrorKind::Api(ApiError {
+            status: "Failure".to_string(),
+            message: String::new(),
+            reason: reason.to_string(),
+            code,
+        }))
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        }
+    }
+
+    // The first attempt times out after the object was made, so the retry
+    // finds it already there
+    fn lost_response(calls: &mut u32) -> Result<(), kube::Error> {
+        *calls += 1;
+        if *calls == 1 {
+            return Err(api_error("ServerTimeout", 504))
+        }
+        Err(api_error("AlreadyExists", 409))
+    }
+
+    #[test]
+    fn run_create_accepts_its_own_object_after_a_retry() {
+        let mut calls = 0;
+        let res = policy().run_create(|| lost_response(&mut calls), || Ok(true));
+        assert!(res.is_ok());
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let res = policy().run_create(|| lost_response(&mut calls), || Ok(false));
+        assert!(matches!(res, Err(DriverError::ReleaseAlreadyExists)));
+    }
+
+    #[test]
+    fn run_create_reports_existing_objects_without_a_retry() {
+        let res = policy().run_create(|| -> Result<(), kube::Error> { Err(api_error("AlreadyExists", 409)) }, || {
+            panic!("the object can't be ours if the create was only sent once")
+        });
+        assert!(matches!(res, Err(DriverError::ReleaseAlreadyExists)));
+    }
+
+    #[test]
+    fn run_delete_accepts_not_found_after_a_retry() {
+        let mut calls = 0;
+        let res = policy().run_delete(|| -> Result<(), kube::Error> {
+            calls += 1;
+            if calls == 1 {
+                return Err(api_error("ServerTimeout", 504))
+            }
+            Err(api_error("NotFound", 404))
+        });
+        assert!(res.is_ok());
+
+        let res = policy().run_delete(|| -> Result<(), kube::Error> { Err(api_error("NotFound", 404)) });
+        assert!(matches!(res, Err(DriverError::ReleaseNotExist)));
+    }
+}
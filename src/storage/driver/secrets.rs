@@ -1,121 +1,345 @@
 use crate::release::Release;
 use crate::storage::driver::*;
-use std::collections::{HashMap, BTreeMap};
+use crate::storage::driver::retry::RetryPolicy;
+use crate::storage::lock::{Locker, LeaseLocker, MemoryLocker};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 use std::vec::Vec;
-use kube::api::{Api, v1Secret, ListParams, PostParams, PatchParams, DeleteParams};
+use kube::api::{Api, RawApi, v1Secret, ListParams, WatchEvent, PostParams, PatchParams, DeleteParams};
 use kube::client::APIClient;
-use k8s_openapi::ByteString;
 
 pub struct Secrets {
     client: Api<v1Secret>,
+    // Paged lists are not supported by Api, so they are made with the raw
+    // request builder and client instead
+    raw: RawApi,
+    kube_client: APIClient,
+    // None when listing across all namespaces
+    namespace: Option<String>,
+    // Limits listing across all namespaces to the namespaces matching this
+    // label selector
+    namespace_selector: Option<String>,
+    // Applied to every request made to the API server
+    retry: RetryPolicy,
 }
 
 impl Secrets {
     pub fn new(client: APIClient, namespace: String) -> Self {
         Secrets {
-            client: Api::v1Secret(client).within(&namespace)
+            client: Api::v1Secret(client.clone()).within(&namespace),
+            raw: RawApi::v1Secret().within(&namespace),
+            kube_client: client,
+            namespace: Some(namespace),
+            namespace_selector: None,
+            retry: RetryPolicy::default(),
         }
     }
+
+    // Creates a driver that lists releases across every namespace, or only
+    // those matching `namespace_selector` if one is given. Releases can only
+    // be listed, queried and watched, as every other operation needs to know
+    // which namespace the release is in
+    pub fn all_namespaces(client: APIClient, namespace_selector: Option<String>) -> Self {
+        Secrets {
+            client: Api::v1Secret(client.clone()),
+            raw: RawApi::v1Secret(),
+            kube_client: client,
+            namespace: None,
+            namespace_selector,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    // Replaces the default policy for retrying requests that failed with a
+    // transient error
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
 }
 
 impl Secrets {
-    fn get_secret_list<F>(&self, label_selector: Option<String>, filter: F) -> Result<Vec<Release>, DriverError> 
+    fn get_secret_list<F>(&self, label_selector: Option<String>, filter: F) -> Result<ReleaseList, DriverError>
     where
         F: Fn(&Release) -> bool,
     {
         let mut lp = ListParams::default();
         lp.label_selector = label_selector;
-        let mut res = self.client.list(&lp)?;
-        let mut release_list: Vec<Release> = Vec::with_capacity(res.items.len());
-        while let Some(sec) = res.items.pop() {
-            // TODO: Change decoding errors to just log to match Helm behavior
-            let rel = decode_release(Secrets::get_raw_data(sec.data)?)?;
-            if filter(&rel) {
-                release_list.push(rel);
+        let namespaces = self.selected_namespaces()?;
+        let res = self.retry.run(|| self.client.list(&lp))?;
+        Ok(self.decode_secrets(res.items, &namespaces, filter))
+    }
+
+    fn decode_secrets<F>(&self, mut items: Vec<v1Secret>, namespaces: &Option<HashSet<String>>, filter: F) -> ReleaseList
+    where
+        F: Fn(&Release) -> bool,
+    {
+        let mut release_list = ReleaseList::default();
+        release_list.releases.reserve(items.len());
+        while let Some(sec) = items.pop() {
+            if !in_namespaces(namespaces, &sec.metadata.namespace) {
+                continue
             }
-        }
-        Ok(release_list)
-    }
-    
-    fn get_raw_data(data: BTreeMap<String, ByteString>) -> Result<Vec<u8>, DriverError> {
-        let raw = match data.get("release") {
-            Some(b) => b,
-            None => { return Err(DriverError::InvalidData{message: "no 'release' key found".to_string()}) }
-        };
-        Ok(raw.0.clone())
-    }
-
-    fn generate_secret_data(key: &String, rel: Release, addl_labels: HashMap<String, String>) -> Result<Vec<u8>, DriverError> {
-        let rel_data = encode_release(&rel)?;
-        let mut data = json!({
-            "apiVersion": "v1",
-            "kind": "Secret",
-            "metadata": {
-                "name": key,
-                "labels": {
-                    "name": rel.name,
-                    "owner": "helm",
-                    "status": rel.info.status,
-                    "version": rel.version.to_string()
+            let name = sec.metadata.name.clone();
+            let rel = match self.decode_object(sec) {
+                Ok(r) => r,
+                Err(e) => {
+                    release_list.failures.push(DecodeFailure{name, cause: e});
+                    continue
                 }
-            },
-            "type": "helm.sh/release.v1",
-            "data": {
-                "release": rel_data
+            };
+            if filter(&rel) {
+                release_list.releases.push(rel);
             }
-        });
-        for (k, v) in addl_labels.iter() {
-            data["metadata"]["labels"][k] = v.clone().into()
         }
-        let bytes = serde_json::to_vec(&data)?;
-        Ok(bytes)
+        release_list
+    }
+
+    fn decode_object(&self, sec: v1Secret) -> Result<Release, DriverError> {
+        let client = self.client_for(sec.metadata.namespace.as_deref());
+        let mut rel = decode_release(read_release_data(&client, &self.retry, &sec.metadata.name, &stored_object(&sec))?)?;
+        rel.resource_version = sec.metadata.resourceVersion;
+        // When listing across namespaces, this is the only way to know
+        // where a release lives
+        if let Some(ns) = sec.metadata.namespace {
+            rel.namespace = ns;
+        }
+        Ok(rel)
+    }
+
+    // Returns a client for the namespace an object was found in, which is
+    // only needed when listing across all namespaces
+    fn client_for(&self, namespace: Option<&str>) -> Api<v1Secret> {
+        match (&self.namespace, namespace) {
+            (None, Some(ns)) => Api::v1Secret(self.kube_client.clone()).within(ns),
+            _ => self.client.clone(),
+        }
+    }
+
+    fn require_namespace(&self) -> Result<(), DriverError> {
+        match self.namespace {
+            Some(_) => Ok(()),
+            None => Err(DriverError::NamespaceRequired),
+        }
+    }
+
+    fn selected_namespaces(&self) -> Result<Option<HashSet<String>>, DriverError> {
+        match self.namespace_selector {
+            Some(ref selector) => Ok(Some(self.retry.run(|| list_namespaces(&self.kube_client, selector))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Converts the data of a secret, which the API server has already decoded
+// from base64
+fn stored_object(sec: &v1Secret) -> StoredObject {
+    StoredObject {
+        data: sec.data.iter().map(|(k, v)| (k.clone(), v.0.clone())).collect(),
+        labels: sec.metadata.labels.clone(),
+    }
+}
+
+impl ReleaseObjects for Api<v1Secret> {
+    fn kind(&self) -> &'static str {"Secret"}
+    fn object_type(&self) -> Option<&'static str> {
+        Some("helm.sh/release.v1")
+    }
+    fn encode_value(&self, _name: &str, value: &[u8]) -> serde_json::Value {
+        base64::encode(value).into()
+    }
+    fn get_object(&self, name: &str) -> Result<StoredObject, DriverError> {
+        Ok(stored_object(&self.get(name)?))
+    }
+    fn create_object(&self, body: &[u8]) -> Result<(), DriverError> {
+        self.create(&PostParams::default(), body.to_vec())?;
+        Ok(())
+    }
+    fn patch_object(&self, name: &str, body: &[u8]) -> Result<(), DriverError> {
+        self.patch(name, &PatchParams::default(), body.to_vec())?;
+        Ok(())
+    }
+    fn delete_object(&self, name: &str) -> Result<(), DriverError> {
+        self.delete(name, &DeleteParams::default())?;
+        Ok(())
     }
 }
 
 impl Driver for Secrets {
     fn name(&self) -> String {String::from("secrets")}
+    fn location(&self) -> String {format!("secrets/{}", self.namespace.as_deref().unwrap_or("*"))}
     fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {
-        let mut labels: HashMap<String, String> = HashMap::new();
-        labels.insert("createdAt".to_string(), chrono::Utc::now().timestamp().to_string());
-        let data = Secrets::generate_secret_data(&key, rel, labels)?;
-        self.client.create(&PostParams::default(), data)?;
-        Ok(())
+        self.require_namespace()?;
+        create_release_objects(&self.client, &self.retry, key, rel)
     }
     fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {
-        let mut labels: HashMap<String, String> = HashMap::new();
-        labels.insert("modifiedAt".to_string(), chrono::Utc::now().timestamp().to_string());
-        let data = Secrets::generate_secret_data(&key, rel, labels)?;
-        self.client.patch(&key, &PatchParams::default(), data)?;
-        Ok(())
+        self.require_namespace()?;
+        update_release_objects(&self.client, &self.retry, key, rel)
     }
     fn delete(&self, key: &String) -> Result<Release, DriverError> {
-        let rel = self.get(key)?;
-        self.client.delete(key, &DeleteParams::default())?;
-
-        // For some reason, this block of code doesn't return the secret, just
-        // the status
-
-        // let sec = match thing.left() {
-        //     Some(s) => s,
-        //     None => { return Err(DriverError::ReleaseNotExist) }
-        // };
-        // let rel = decode_release(sec.data)?;
-        Ok(rel)
+        self.require_namespace()?;
+        delete_release_objects(&self.client, &self.retry, key)
     }
     fn get(&self, key: &String) -> Result<Release, DriverError> {
-        let sec = self.client.get(key)?;
-        let rel = decode_release(Secrets::get_raw_data(sec.data)?)?;
-        Ok(rel)
+        self.require_namespace()?;
+        self.decode_object(self.retry.run(|| self.client.get(key))?)
     }
-    fn list<F>(&self, filter: F) -> Result<Vec<Release>, DriverError> 
-    where
-        F: Fn(&Release) -> bool,
-    {
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
         return self.get_secret_list(Some("owner=helm".to_string()), filter);
     }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        let namespaces = self.selected_namespaces()?;
+        let list = self.retry.run(|| list_kube_page(&self.kube_client, &self.raw, "owner=helm".to_string(), params))?;
+        Ok(ReleasePage{
+            list: self.decode_secrets(list.items, &namespaces, filter),
+            continue_token: list.metadata.continue_token,
+        })
+    }
     fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
-        // TODO: Actually check the label value is valid (hence why this is not just in the actual ListParams)
-        let selector: String = labels.iter().map(|pair| format!("{}={}", pair.0, pair.1)).collect::<Vec<String>>().join(",");
-        return self.get_secret_list(Some(selector), |_| true);
+        let selector = label_selector(&labels)?;
+        return Ok(self.get_secret_list(Some(selector), |_| true)?.into_releases());
+    }
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        self.require_namespace()?;
+        let sec = self.retry.run(|| self.client.get(key))?;
+        Ok(sec.metadata.labels.into_iter().collect())
+    }
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        self.require_namespace()?;
+        let timestamps = timestamp_labels(labels);
+        if timestamps.is_empty() {
+            return Ok(())
+        }
+        let mut data = json!({"metadata": {"labels": {}}});
+        for (label, ts) in timestamps {
+            data["metadata"]["labels"][label] = ts.to_string().into();
+        }
+        let body = serde_json::to_vec(&data).map_err(DriverError::Encode)?;
+        self.retry.run(|| self.client.patch(key, &PatchParams::default(), body.clone()))?;
+        Ok(())
+    }
+    // Drivers for a single namespace lock releases with Leases in it, so that
+    // every client of the namespace sees the same locks
+    fn locker(&self) -> Box<dyn Locker> {
+        match self.namespace {
+            Some(ref ns) => Box::new(LeaseLocker::new(self.kube_client.clone(), ns.clone())),
+            None => Box::new(MemoryLocker::new()),
+        }
+    }
+}
+
+impl Watch for Secrets {
+    fn current_version(&self) -> Result<String, DriverError> {
+        self.retry.run(|| current_kube_version(&self.kube_client, &self.raw, "owner=helm".to_string()))
+    }
+    fn watch_events(&self, resource_version: &str, timeout: Duration) -> Result<(Vec<ReleaseEvent>, String), DriverError> {
+        let mut lp = ListParams::default();
+        lp.label_selector = Some("owner=helm".to_string());
+        lp.timeout = Some(timeout.as_secs() as u32);
+        let namespaces = self.selected_namespaces()?;
+        // Not retried here, as ReleaseWatcher already reconnects after a
+        // failed watch
+        let events: Vec<WatchEvent<v1Secret>> = self.client.watch(&lp, resource_version)?;
+        convert_watch_events(events, resource_version, |sec| {
+            if !in_namespaces(&namespaces, &sec.metadata.namespace) {
+                return Ok(None)
+            }
+            self.decode_object(sec).map(Some)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kube::fake::FakeServer;
+    use crate::storage::driver::tests::large_release;
+    use crate::storage::make_key;
+
+    const SECRETS: &str = "/api/v1/namespaces/default/secrets";
+
+    fn driver(server: &FakeServer) -> Secrets {
+        Secrets::new(server.client(), "default".to_string()).with_retry(RetryPolicy::never())
+    }
+
+    fn chunks(server: &FakeServer, key: &str) -> Vec<String> {
+        let prefix = format!("{}.", key);
+        server.names(SECRETS).into_iter().filter(|n| n.starts_with(&prefix)).collect()
+    }
+
+    #[test]
+    fn chunked_release() {
+        let server = FakeServer::start();
+        let secrets = driver(&server);
+        let key = make_key("app", &1);
+        let rel = large_release("app", 1);
+        secrets.create(&key, rel.clone()).unwrap();
+        let first = chunks(&server, &key);
+        assert_eq!(first.len(), 2);
+        assert_eq!(secrets.get(&key).unwrap().manifest, rel.manifest);
+        // Chunks don't show up as releases of their own
+        assert_eq!(secrets.list(&|_| true).unwrap().len(), 1);
+
+        // An update writes a new generation and removes the old one
+        let mut updated = large_release("app", 1);
+        updated.resource_version = secrets.get(&key).unwrap().resource_version;
+        secrets.update(&key, updated.clone()).unwrap();
+        let second = chunks(&server, &key);
+        assert_eq!(second.len(), 2);
+        assert!(second.iter().all(|c| !first.contains(c)));
+        assert_eq!(secrets.get(&key).unwrap().manifest, updated.manifest);
+
+        // Going back to a single object removes every chunk
+        let mut small = secrets.get(&key).unwrap();
+        small.manifest = String::new();
+        secrets.update(&key, small).unwrap();
+        assert!(chunks(&server, &key).is_empty());
+        assert_eq!(secrets.get(&key).unwrap().manifest, "");
+    }
+
+    #[test]
+    fn delete_with_missing_chunk() {
+        let server = FakeServer::start();
+        let secrets = driver(&server);
+        let key = make_key("app", &1);
+        secrets.create(&key, large_release("app", 1)).unwrap();
+        let chunks_before = chunks(&server, &key);
+        server.remove_object(&format!("{}/{}", SECRETS, chunks_before[0]));
+        assert!(matches!(secrets.get(&key), Err(DriverError::InvalidData{..})));
+
+        // The release can't be decoded, but is deleted along with the chunk
+        // that is left, and what its labels tell is returned
+        let rel = secrets.delete(&key).unwrap();
+        assert_eq!((rel.name.as_str(), rel.version), ("app", 1));
+        assert!(server.names(SECRETS).is_empty());
+    }
+
+    #[test]
+    fn lost_responses_are_retried() {
+        let server = FakeServer::start();
+        let secrets = Secrets::new(server.client(), "default".to_string()).with_retry(RetryPolicy{
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        });
+        let key = make_key("app", &1);
+        secrets.create(&key, large_release("app", 1)).unwrap();
+        let first = chunks(&server, &key);
+
+        // The retried patch conflicts with the one that went through, which
+        // is then found to have written this update
+        let mut updated = large_release("app", 1);
+        updated.resource_version = secrets.get(&key).unwrap().resource_version;
+        server.lose_responses("PATCH", 1);
+        secrets.update(&key, updated.clone()).unwrap();
+        assert_eq!(secrets.get(&key).unwrap().manifest, updated.manifest);
+        let second = chunks(&server, &key);
+        assert_eq!(second.len(), 2);
+        assert!(second.iter().all(|c| !first.contains(c)));
+
+        // A retried delete finds the release already gone
+        server.lose_responses("DELETE", 1);
+        assert_eq!(secrets.delete(&key).unwrap().manifest, updated.manifest);
+        assert!(server.names(SECRETS).is_empty());
     }
 }
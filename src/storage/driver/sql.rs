@@ -0,0 +1,373 @@
+use crate::release::Release;
+use crate::storage::driver::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::vec::Vec;
+use rusqlite::{Connection, OptionalExtension, params};
+use rusqlite::types::ToSql;
+
+// These mirror the table and column names used by Helm's SQL driver so that
+// both can share the same database
+const RELEASE_TABLE: &str = "releases_v1";
+const RELEASE_TYPE: &str = "helm.sh/release.v1";
+
+// The label keys that can be used in a query, mapped to the columns holding
+// their values
+const QUERYABLE_COLUMNS: &[&str] = &["name", "owner", "status", "version"];
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS releases_v1 (
+    key VARCHAR(90),
+    type VARCHAR(64) NOT NULL,
+    body TEXT NOT NULL,
+    name VARCHAR(64) NOT NULL,
+    namespace VARCHAR(64) NOT NULL,
+    version INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    createdAt INTEGER NOT NULL,
+    modifiedAt INTEGER NOT NULL DEFAULT 0,
+    resourceVersion INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY(key, namespace)
+);
+CREATE INDEX IF NOT EXISTS releases_v1_name_idx ON releases_v1 (name);
+CREATE INDEX IF NOT EXISTS releases_v1_version_idx ON releases_v1 (version);
+CREATE INDEX IF NOT EXISTS releases_v1_status_idx ON releases_v1 (status);
+CREATE INDEX IF NOT EXISTS releases_v1_owner_idx ON releases_v1 (owner);
+";
+
+// Helm's schema has no column to version rows with, so this one is added to
+// it, including to tables Helm created. Helm leaves it alone, which is why
+// the resource version of a release also includes its modifiedAt
+const RESOURCE_VERSION_COLUMN: &str = "resourceVersion";
+
+// Sql is a driver that stores releases in a relational database table using
+// the same schema as Helm's SQL driver. Releases are scoped to a namespace
+// through the namespace column
+pub struct Sql {
+    conn: Mutex<Connection>,
+    namespace: String,
+}
+
+impl Sql {
+    // Creates a driver from an existing connection, creating the releases
+    // table if it does not exist yet
+    pub fn new(conn: Connection, namespace: String) -> Result<Self, DriverError> {
+        conn.execute_batch(SCHEMA)?;
+        add_resource_version_column(&conn)?;
+        Ok(Sql {
+            conn: Mutex::new(conn),
+            namespace,
+        })
+    }
+
+    // Opens (or creates) the SQLite database at the given path
+    pub fn open(path: &str, namespace: String) -> Result<Self, DriverError> {
+        Sql::new(Connection::open(path)?, namespace)
+    }
+}
+
+fn add_resource_version_column(conn: &Connection) -> Result<(), DriverError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", RELEASE_TABLE))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?.collect::<Result<Vec<String>, _>>()?;
+    if !columns.iter().any(|c| c == RESOURCE_VERSION_COLUMN) {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} INTEGER NOT NULL DEFAULT 0", RELEASE_TABLE, RESOURCE_VERSION_COLUMN))?;
+    }
+    Ok(())
+}
+
+// A row's resource version is its resourceVersion and modifiedAt, so that an
+// update made by Helm changes it too
+fn resource_version(version: i64, modified_at: i64) -> String {
+    format!("{}-{}", version, modified_at)
+}
+
+fn parse_resource_version(v: &str) -> Option<(i64, i64)> {
+    let (version, modified_at) = v.split_once('-')?;
+    Some((version.parse().ok()?, modified_at.parse().ok()?))
+}
+
+// Decodes a row's body, giving the release the row's resource version
+fn decode_row(body: &str, version: i64, modified_at: i64) -> Result<Release, DriverError> {
+    let mut rel = decode_release(base64::decode(body)?)?;
+    rel.resource_version = Some(resource_version(version, modified_at));
+    Ok(rel)
+}
+
+impl Sql {
+    fn get_sql_list<F>(&self, filters: Vec<(String, String)>, filter: F) -> Result<ReleaseList, DriverError>
+    where
+        F: Fn(&Release) -> bool,
+    {
+        Ok(self.get_sql_page(filters, &PageParams::default(), filter)?.list)
+    }
+
+    // Pages are taken in key order, and the continue token is the last key
+    // of the page so the next page can start after it
+    fn get_sql_page<F>(&self, filters: Vec<(String, String)>, params: &PageParams, filter: F) -> Result<ReleasePage, DriverError>
+    where
+        F: Fn(&Release) -> bool,
+    {
+        let mut query = format!("SELECT key, body, resourceVersion, modifiedAt FROM {} WHERE namespace = ?", RELEASE_TABLE);
+        let mut args: Vec<&dyn ToSql> = vec![&self.namespace];
+        for (column, value) in filters.iter() {
+            query.push_str(&format!(" AND {} = ?", column));
+            args.push(value);
+        }
+        if let Some(ref token) = params.continue_token {
+            query.push_str(" AND key > ?");
+            args.push(token);
+        }
+        // One more row than the limit is fetched to tell if there is another
+        // page. A limit of 0 means no limit, as it does for Kubernetes
+        let limit = params.limit.filter(|n| *n > 0).map(|n| n as i64 + 1);
+        if let Some(ref n) = limit {
+            query.push_str(" ORDER BY key LIMIT ?");
+            args.push(n);
+        }
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        let mut stmt = conn.prepare(&query)?;
+        let rows = stmt.query_map(args.as_slice(), |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?, row.get::<_, i64>(3)?)))?;
+        let mut rows: Vec<(String, String, i64, i64)> = rows.collect::<Result<_, _>>()?;
+        let mut page = ReleasePage::default();
+        if let Some(n) = limit {
+            if rows.len() as i64 == n {
+                rows.pop();
+                page.continue_token = rows.last().map(|(key, ..)| key.clone());
+            }
+        }
+        for (key, body, version, modified_at) in rows.into_iter() {
+            let rel = match decode_row(&body, version, modified_at) {
+                Ok(r) => r,
+                Err(e) => {
+                    page.list.failures.push(DecodeFailure{name: key, cause: e});
+                    continue
+                }
+            };
+            if filter(&rel) {
+                page.list.releases.push(rel);
+            }
+        }
+        Ok(page)
+    }
+}
+
+impl Driver for Sql {
+    fn name(&self) -> String {String::from("sql")}
+    fn location(&self) -> String {
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        // In-memory databases have no path, and each connection has its own
+        match conn.path() {
+            Some(path) if !path.is_empty() => format!("sql:{}/{}", path, self.namespace),
+            _ => format!("sql:{:p}/{}", self, self.namespace),
+        }
+    }
+    fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        let body = encode_release(&rel)?;
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        let exists: Option<String> = conn.query_row(
+            &format!("SELECT key FROM {} WHERE key = ? AND namespace = ?", RELEASE_TABLE),
+            params![key, self.namespace],
+            |row| row.get(0),
+        ).optional()?;
+        if exists.is_some() {
+            return Err(DriverError::ReleaseAlreadyExists)
+        }
+        conn.execute(
+            &format!("INSERT INTO {} (key, type, body, name, namespace, version, status, owner, createdAt) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)", RELEASE_TABLE),
+            params![
+                key,
+                RELEASE_TYPE,
+                body,
+                rel.name,
+                self.namespace,
+                rel.version as i64,
+                rel.info.status.to_string(),
+                "helm",
+                chrono::Utc::now().timestamp(),
+            ],
+        )?;
+        Ok(())
+    }
+    fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {
+        // A release read from storage is only written if the row hasn't
+        // changed since, otherwise the update is last write wins
+        let expected = match rel.resource_version {
+            Some(ref v) => Some(parse_resource_version(v).ok_or(DriverError::OutOfSync)?),
+            None => None,
+        };
+        let body = encode_release(&rel)?;
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        let mut query = format!("UPDATE {} SET body = ?, name = ?, version = ?, status = ?, owner = ?, modifiedAt = ?, resourceVersion = resourceVersion + 1 WHERE key = ? AND namespace = ?", RELEASE_TABLE);
+        let name = rel.name.clone();
+        let version = rel.version as i64;
+        let status = rel.info.status.to_string();
+        let now = chrono::Utc::now().timestamp();
+        let mut args: Vec<&dyn ToSql> = vec![&body, &name, &version, &status, &"helm", &now, key, &self.namespace];
+        if let Some((ref resource_version, ref modified_at)) = expected {
+            query.push_str(" AND resourceVersion = ? AND modifiedAt = ?");
+            args.push(resource_version);
+            args.push(modified_at);
+        }
+        let updated = conn.execute(&query, args.as_slice())?;
+        if updated > 0 {
+            return Ok(())
+        }
+        let exists: Option<String> = conn.query_row(
+            &format!("SELECT key FROM {} WHERE key = ? AND namespace = ?", RELEASE_TABLE),
+            params![key, self.namespace],
+            |row| row.get(0),
+        ).optional()?;
+        match exists {
+            Some(_) => Err(DriverError::OutOfSync),
+            None => Err(DriverError::ReleaseNotExist),
+        }
+    }
+    fn delete(&self, key: &String) -> Result<Release, DriverError> {
+        let rel = self.get(key)?;
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        conn.execute(
+            &format!("DELETE FROM {} WHERE key = ? AND namespace = ?", RELEASE_TABLE),
+            params![key, self.namespace],
+        )?;
+        Ok(rel)
+    }
+    fn get(&self, key: &String) -> Result<Release, DriverError> {
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        let row: Option<(String, i64, i64)> = conn.query_row(
+            &format!("SELECT body, resourceVersion, modifiedAt FROM {} WHERE key = ? AND namespace = ?", RELEASE_TABLE),
+            params![key, self.namespace],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional()?;
+        let (body, version, modified_at) = match row {
+            Some(r) => r,
+            None => { return Err(DriverError::ReleaseNotExist) }
+        };
+        decode_row(&body, version, modified_at)
+    }
+    fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
+        return self.get_sql_list(vec![("owner".to_string(), "helm".to_string())], filter);
+    }
+    fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+        return self.get_sql_page(vec![("owner".to_string(), "helm".to_string())], params, filter);
+    }
+    fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {
+        // Only labels that have a matching column can be queried on. As the
+        // column names come from a fixed list, they are safe to put directly
+        // in the statement while the values are bound as parameters
+        let mut filters: Vec<(String, String)> = Vec::with_capacity(labels.len());
+        for (k, v) in labels.into_iter() {
+            if !QUERYABLE_COLUMNS.contains(&k.as_str()) {
+                return Err(DriverError::InvalidQuery{message: format!("unknown label {}", k)})
+            }
+            filters.push((k, v));
+        }
+        return Ok(self.get_sql_list(filters, |_| true)?.into_releases());
+    }
+    fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        let row: Option<(String, String, String, i64, i64, i64)> = conn.query_row(
+            &format!("SELECT name, owner, status, version, createdAt, modifiedAt FROM {} WHERE key = ? AND namespace = ?", RELEASE_TABLE),
+            params![key, self.namespace],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)),
+        ).optional()?;
+        let (name, owner, status, version, created_at, modified_at) = match row {
+            Some(r) => r,
+            None => { return Err(DriverError::ReleaseNotExist) }
+        };
+        let mut labels: HashMap<String, String> = HashMap::new();
+        labels.insert("name".to_string(), name);
+        labels.insert("owner".to_string(), owner);
+        labels.insert("status".to_string(), status);
+        labels.insert("version".to_string(), version.to_string());
+        labels.insert("createdAt".to_string(), created_at.to_string());
+        // A release that was never updated has no modifiedAt label in the
+        // Kubernetes drivers, which the column default of 0 stands for here
+        if modified_at != 0 {
+            labels.insert("modifiedAt".to_string(), modified_at.to_string());
+        }
+        Ok(labels)
+    }
+    // The timestamp labels are columns of the same name
+    fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+        let timestamps = timestamp_labels(labels);
+        if timestamps.is_empty() {
+            return Ok(())
+        }
+        let sets: Vec<String> = timestamps.iter().map(|(label, _)| format!("{} = ?", label)).collect();
+        let mut args: Vec<&dyn ToSql> = timestamps.iter().map(|(_, ts)| ts as &dyn ToSql).collect();
+        args.push(key);
+        args.push(&self.namespace);
+        let conn = self.conn.lock().expect("sql driver lock poisoned");
+        let updated = conn.execute(
+            &format!("UPDATE {} SET {} WHERE key = ? AND namespace = ?", RELEASE_TABLE, sets.join(", ")),
+            &args[..],
+        )?;
+        if updated == 0 {
+            return Err(DriverError::ReleaseNotExist)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::driver::tests::check_paging;
+    use crate::storage::make_key;
+
+    fn release(name: &str, version: usize) -> Release {
+        Release {
+            name: name.to_string(),
+            version,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn update_rejects_stale_release() {
+        let sql = Sql::new(Connection::open_in_memory().unwrap(), "default".to_string()).unwrap();
+        let key = "sh.helm.release.v1.app.v1".to_string();
+        sql.create(&key, release("app", 1)).unwrap();
+        let first = sql.get(&key).unwrap();
+        let second = sql.get(&key).unwrap();
+        assert!(first.resource_version.is_some());
+        sql.update(&key, first).unwrap();
+        match sql.update(&key, second) {
+            Err(DriverError::OutOfSync) => (),
+            other => panic!("expected OutOfSync, got {:?}", other),
+        }
+        // Without a resource version the update always goes through
+        sql.update(&key, release("app", 1)).unwrap();
+        assert_ne!(sql.get(&key).unwrap().resource_version, None);
+    }
+
+    #[test]
+    fn update_missing_release() {
+        let sql = Sql::new(Connection::open_in_memory().unwrap(), "default".to_string()).unwrap();
+        match sql.update(&"missing".to_string(), release("app", 1)) {
+            Err(DriverError::ReleaseNotExist) => (),
+            other => panic!("expected ReleaseNotExist, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn adds_resource_version_to_helm_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&SCHEMA.replace("    resourceVersion INTEGER NOT NULL DEFAULT 0,\n", "")).unwrap();
+        let sql = Sql::new(conn, "default".to_string()).unwrap();
+        let key = "sh.helm.release.v1.app.v1".to_string();
+        sql.create(&key, release("app", 1)).unwrap();
+        let rel = sql.get(&key).unwrap();
+        sql.update(&key, rel).unwrap();
+    }
+
+    #[test]
+    fn list_page() {
+        let sql = Sql::new(Connection::open_in_memory().unwrap(), "default".to_string()).unwrap();
+        for v in 1..=5 {
+            sql.create(&make_key("app", &v), release("app", v)).unwrap();
+        }
+        check_paging(&sql);
+    }
+}
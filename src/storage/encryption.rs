@@ -0,0 +1,226 @@
+// This module contains the envelope encryption used to protect release config
+// values at rest. Each release gets a freshly generated data key that
+// encrypts its values, and the data key is in turn encrypted ("wrapped") by a
+// key provider. Only the wrapped data key is stored, so the master key never
+// has to leave the provider
+use crate::release::Release;
+use crate::storage::driver::DriverError;
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+// The config key an encrypted release stores its envelope under
+pub const ENVELOPE_KEY: &str = "$encrypted";
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+pub trait KeyProvider {
+    // Identifies the master key. It is stored with every wrapped key so that
+    // a provider can tell whether it is able to unwrap it
+    fn key_id(&self) -> String;
+    fn wrap_key(&self, key: &[u8]) -> Result<Vec<u8>, DriverError>;
+    fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, DriverError>;
+}
+
+fn encryption_error(message: String) -> DriverError {
+    DriverError::EncryptionError{message}
+}
+
+fn check_key_size(key: &[u8]) -> Result<(), DriverError> {
+    if key.len() != KEY_SIZE {
+        return Err(encryption_error(format!("key must be {} bytes, got {}", KEY_SIZE, key.len())))
+    }
+    Ok(())
+}
+
+// Encrypts with AES-256-GCM, returning the random nonce followed by the
+// ciphertext
+fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, DriverError> {
+    check_key_size(key)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut out = nonce.to_vec();
+    let ciphertext = cipher.encrypt(&nonce, Payload{msg: plaintext, aad})
+        .map_err(|_| encryption_error("unable to encrypt data".to_string()))?;
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+fn decrypt(key: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, DriverError> {
+    if data.len() < NONCE_SIZE {
+        return Err(encryption_error("encrypted data is too short".to_string()))
+    }
+    check_key_size(key)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    cipher.decrypt(Nonce::from_slice(nonce), Payload{msg: ciphertext, aad})
+        .map_err(|_| encryption_error("unable to decrypt data, it may have been tampered with or encrypted with another key".to_string()))
+}
+
+// LocalKey wraps data keys with a 256 bit master key held in memory
+pub struct LocalKey {
+    id: String,
+    key: Vec<u8>,
+}
+
+impl LocalKey {
+    pub fn new(id: String, key: &[u8]) -> Result<Self, DriverError> {
+        check_key_size(key)?;
+        Ok(LocalKey {
+            id,
+            key: key.to_vec(),
+        })
+    }
+
+    // Reads the key from a file holding either the raw key or its base64
+    // encoding
+    pub fn from_file(path: &Path) -> Result<Self, DriverError> {
+        let raw = std::fs::read(path)
+            .map_err(|e| encryption_error(format!("unable to read key file {}: {}", path.display(), e)))?;
+        let key = if raw.len() == KEY_SIZE {
+            raw
+        } else {
+            base64::decode(String::from_utf8_lossy(&raw).trim())?
+        };
+        LocalKey::new(LocalKey::fingerprint(&key), &key)
+    }
+
+    // Reads the base64 encoded key from an environment variable
+    pub fn from_env(var: &str) -> Result<Self, DriverError> {
+        let raw = std::env::var(var)
+            .map_err(|e| encryption_error(format!("unable to read key from ${}: {}", var, e)))?;
+        let key = base64::decode(raw.trim())?;
+        LocalKey::new(LocalKey::fingerprint(&key), &key)
+    }
+
+    // Identifies a key by the start of its SHA-256 hash, so the same key
+    // keeps its id wherever it is read from
+    pub fn fingerprint(key: &[u8]) -> String {
+        let hash = Sha256::digest(key);
+        let hex: String = hash.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+        format!("sha256:{}", hex)
+    }
+}
+
+impl KeyProvider for LocalKey {
+    fn key_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn wrap_key(&self, key: &[u8]) -> Result<Vec<u8>, DriverError> {
+        encrypt(&self.key, key, self.id.as_bytes())
+    }
+
+    fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, DriverError> {
+        if key_id != self.id {
+            return Err(encryption_error(format!("data key was wrapped with key {}, not {}", key_id, self.id)))
+        }
+        decrypt(&self.key, wrapped, key_id.as_bytes())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    key_id: String,
+    wrapped_key: String,
+    ciphertext: String,
+}
+
+// The ciphertext is bound to the release it belongs to, so the encrypted
+// values can't be copied over to another release or revision
+fn associated_data(rel: &Release) -> Vec<u8> {
+    format!("{}.v{}", rel.name, rel.version).into_bytes()
+}
+
+// Replaces the config of a release with an envelope holding the encrypted
+// values
+pub fn seal(provider: &dyn KeyProvider, mut rel: Release) -> Result<Release, DriverError> {
+    let data_key = Aes256Gcm::generate_key(&mut OsRng);
+    let plaintext = serde_json::to_vec(&rel.config).map_err(DriverError::Encode)?;
+    let envelope = Envelope {
+        key_id: provider.key_id(),
+        wrapped_key: base64::encode(&provider.wrap_key(&data_key)?),
+        ciphertext: base64::encode(&encrypt(&data_key, &plaintext, &associated_data(&rel))?),
+    };
+    let mut config: HashMap<String, Value> = HashMap::new();
+    config.insert(ENVELOPE_KEY.to_string(), serde_json::to_value(envelope).map_err(DriverError::Encode)?);
+    rel.config = config;
+    Ok(rel)
+}
+
+// Restores the config values of a sealed release. Releases that were never
+// sealed are returned as they are
+pub fn open(provider: &dyn KeyProvider, mut rel: Release) -> Result<Release, DriverError> {
+    if rel.config.len() != 1 {
+        return Ok(rel)
+    }
+    let envelope: Envelope = match rel.config.get(ENVELOPE_KEY) {
+        Some(v) => serde_json::from_value(v.clone()).map_err(DriverError::Decode)?,
+        None => { return Ok(rel) }
+    };
+    let data_key = provider.unwrap_key(&envelope.key_id, &base64::decode(&envelope.wrapped_key)?)?;
+    let plaintext = decrypt(&data_key, &base64::decode(&envelope.ciphertext)?, &associated_data(&rel))?;
+    rel.config = serde_json::from_slice(&plaintext).map_err(DriverError::Decode)?;
+    Ok(rel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release() -> Release {
+        let mut config: HashMap<String, Value> = HashMap::new();
+        config.insert("password".to_string(), Value::String("hunter2".to_string()));
+        Release {
+            name: "app".to_string(),
+            version: 1,
+            config,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn key_id_does_not_depend_on_source() {
+        let key = [7u8; KEY_SIZE];
+        let path = std::env::temp_dir().join(format!("pilothouse-key-{}", std::process::id()));
+        std::fs::write(&path, base64::encode(&key)).unwrap();
+        let from_file = LocalKey::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let var = format!("PILOTHOUSE_TEST_KEY_{}", std::process::id());
+        std::env::set_var(&var, base64::encode(&key));
+        let from_env = LocalKey::from_env(&var).unwrap();
+        std::env::remove_var(&var);
+        assert_eq!(from_file.key_id(), from_env.key_id());
+
+        let sealed = seal(&from_file, release()).unwrap();
+        let opened = open(&from_env, sealed).unwrap();
+        assert_eq!(opened.config, release().config);
+    }
+
+    #[test]
+    fn rejects_releases_sealed_with_another_key() {
+        let key = LocalKey::new(LocalKey::fingerprint(&[7u8; KEY_SIZE]), &[7u8; KEY_SIZE]).unwrap();
+        let sealed = seal(&key, release()).unwrap();
+        let other = LocalKey::new(LocalKey::fingerprint(&[8u8; KEY_SIZE]), &[8u8; KEY_SIZE]).unwrap();
+        assert!(matches!(open(&other, sealed), Err(DriverError::EncryptionError{..})));
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_size() {
+        assert!(LocalKey::new("short".to_string(), &[1u8; 16]).is_err());
+        match encrypt(&[1u8; 16], b"data", b"") {
+            Err(DriverError::EncryptionError{..}) => (),
+            other => panic!("expected EncryptionError, got {:?}", other),
+        }
+        match decrypt(&[1u8; 16], &[0u8; 32], b"") {
+            Err(DriverError::EncryptionError{..}) => (),
+            other => panic!("expected EncryptionError, got {:?}", other),
+        }
+    }
+}
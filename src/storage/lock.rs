@@ -0,0 +1,329 @@
+// This module contains the locks used to keep multiple clients from operating
+// on the same release at the same time
+use crate::storage::driver::DriverError;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::LeaseSpec;
+use kube::api::{Api, Object, Void, PostParams, DeleteParams};
+use kube::client::APIClient;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub trait Locker: Send + Sync {
+    // Takes the lock with the given key for `holder`. If the lock is already
+    // held by someone else and has not expired, DriverError::ReleaseLocked is
+    // returned. Taking a lock that is already held by `holder` renews it
+    fn acquire(&self, key: &str, holder: &str, ttl: Duration) -> Result<(), DriverError>;
+    // Gives up the lock if it is held by `holder`
+    fn release(&self, key: &str, holder: &str) -> Result<(), DriverError>;
+    // Returns DriverError::LockNotHeld unless `holder` holds the lock and it
+    // has not expired
+    fn check(&self, key: &str, holder: &str) -> Result<(), DriverError>;
+    // Removes the lock no matter who holds it. This is meant for recovering
+    // from clients that died without releasing their lock
+    fn force_break(&self, key: &str) -> Result<(), DriverError>;
+}
+
+// Returns a new holder name for a lock, unique to the call, for when the
+// caller has no name of its own for who is taking the lock
+pub fn new_holder() -> String {
+    let mut buf = [0u8; 6];
+    getrandom::getrandom(&mut buf).expect("unable to read random bytes from the OS");
+    let id: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("pilothouse-{}-{}", std::process::id(), id)
+}
+
+fn is_expired(renewed: DateTime<Utc>, ttl: Duration) -> bool {
+    // A renew time in the future (from clock skew) can't have expired yet
+    match (Utc::now() - renewed).to_std() {
+        Ok(elapsed) => elapsed >= ttl,
+        Err(_) => false,
+    }
+}
+
+struct HeldLock {
+    holder: String,
+    renewed: DateTime<Utc>,
+    ttl: Duration,
+}
+
+// MemoryLocker keeps locks in process. It only protects against concurrent
+// operations from within the same program
+pub struct MemoryLocker {
+    locks: Mutex<HashMap<String, HeldLock>>,
+}
+
+impl MemoryLocker {
+    pub fn new() -> Self {
+        MemoryLocker {
+            locks: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+impl Default for MemoryLocker {
+    fn default() -> Self {
+        MemoryLocker::new()
+    }
+}
+
+impl Locker for MemoryLocker {
+    fn acquire(&self, key: &str, holder: &str, ttl: Duration) -> Result<(), DriverError> {
+        let mut locks = self.locks.lock().expect("lock table poisoned");
+        if let Some(current) = locks.get(key) {
+            if current.holder != holder && !is_expired(current.renewed, current.ttl) {
+                return Err(DriverError::ReleaseLocked{holder: current.holder.clone()})
+            }
+        }
+        locks.insert(key.to_string(), HeldLock{
+            holder: holder.to_string(),
+            renewed: Utc::now(),
+            ttl,
+        });
+        Ok(())
+    }
+
+    fn release(&self, key: &str, holder: &str) -> Result<(), DriverError> {
+        let mut locks = self.locks.lock().expect("lock table poisoned");
+        if locks.get(key).map(|l| l.holder == holder).unwrap_or(false) {
+            locks.remove(key);
+        }
+        Ok(())
+    }
+
+    fn check(&self, key: &str, holder: &str) -> Result<(), DriverError> {
+        let locks = self.locks.lock().expect("lock table poisoned");
+        match locks.get(key) {
+            Some(l) if l.holder == holder && !is_expired(l.renewed, l.ttl) => Ok(()),
+            _ => Err(DriverError::LockNotHeld{key: key.to_string()}),
+        }
+    }
+
+    fn force_break(&self, key: &str) -> Result<(), DriverError> {
+        self.locks.lock().expect("lock table poisoned").remove(key);
+        Ok(())
+    }
+}
+
+type Lease = Object<LeaseSpec, Void>;
+
+// LeaseLocker uses Kubernetes Leases in the release namespace as locks, so
+// that every client talking to the cluster sees the same locks
+pub struct LeaseLocker {
+    client: Api<Lease>,
+}
+
+impl LeaseLocker {
+    pub fn new(client: APIClient, namespace: String) -> Self {
+        LeaseLocker {
+            client: Api::customResource(client, "leases")
+                .group("coordination.k8s.io")
+                .version("v1")
+                .within(&namespace)
+        }
+    }
+}
+
+impl LeaseLocker {
+    fn generate_lease_data(key: &str, holder: &str, ttl: Duration, transitions: i32, resource_version: Option<String>) -> Result<Vec<u8>, DriverError> {
+        let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string();
+        let mut data = json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {
+                "name": key,
+                "labels": {
+                    "owner": "helm"
+                }
+            },
+            "spec": {
+                "holderIdentity": holder,
+                "leaseDurationSeconds": ttl.as_secs(),
+                "leaseTransitions": transitions,
+                "acquireTime": now,
+                "renewTime": now
+            }
+        });
+        if let Some(v) = resource_version {
+            data["metadata"]["resourceVersion"] = v.into();
+        }
+        let bytes = serde_json::to_vec(&data).map_err(DriverError::Encode)?;
+        Ok(bytes)
+    }
+
+    fn is_expired(spec: &LeaseSpec) -> bool {
+        let renewed = match spec.renew_time.as_ref().or(spec.acquire_time.as_ref()) {
+            Some(t) => t.0,
+            None => { return true }
+        };
+        is_expired(renewed, Duration::from_secs(spec.lease_duration_seconds.unwrap_or(0).max(0) as u64))
+    }
+}
+
+impl Locker for LeaseLocker {
+    fn acquire(&self, key: &str, holder: &str, ttl: Duration) -> Result<(), DriverError> {
+        let data = LeaseLocker::generate_lease_data(key, holder, ttl, 0, None)?;
+        let err = match self.client.create(&PostParams::default(), data) {
+            Ok(_) => { return Ok(()) },
+            Err(e) => DriverError::from(e),
+        };
+        match err {
+            DriverError::ReleaseAlreadyExists => (),
+            _ => { return Err(err) }
+        }
+
+        let lease = self.client.get(key)?;
+        // A released lease is left behind with no holder
+        let current = lease.spec.holder_identity.clone().unwrap_or_default();
+        if current != holder && !current.is_empty() && !LeaseLocker::is_expired(&lease.spec) {
+            return Err(DriverError::ReleaseLocked{holder: current})
+        }
+        debug!("taking over lease {} from {}", key, current);
+        let mut transitions = lease.spec.lease_transitions.unwrap_or(0);
+        if current != holder && !current.is_empty() {
+            transitions += 1;
+        }
+        // Replacing with the resource version we just read means only one of
+        // several clients racing to take over an expired lease will succeed
+        let data = LeaseLocker::generate_lease_data(key, holder, ttl, transitions, lease.metadata.resourceVersion)?;
+        match self.client.replace(key, &PostParams::default(), data) {
+            Ok(_) => Ok(()),
+            Err(e) => match DriverError::from(e) {
+                DriverError::OutOfSync => Err(DriverError::ReleaseLocked{holder: current}),
+                e => Err(e),
+            }
+        }
+    }
+
+    fn release(&self, key: &str, holder: &str) -> Result<(), DriverError> {
+        let lease = match self.client.get(key) {
+            Ok(l) => l,
+            Err(e) => match DriverError::from(e) {
+                DriverError::ReleaseNotExist => { return Ok(()) },
+                e => { return Err(e) }
+            }
+        };
+        if lease.spec.holder_identity.as_deref() != Some(holder) {
+            warn!("not releasing lease {} as it is no longer held by {}", key, holder);
+            return Ok(())
+        }
+        // Deletes can't be made conditional on the resource version, so the
+        // lease is released by clearing its holder instead. If someone took
+        // it over after it was read, the replace fails and their hold is left
+        // alone
+        let transitions = lease.spec.lease_transitions.unwrap_or(0);
+        let data = LeaseLocker::generate_lease_data(key, "", Duration::from_secs(0), transitions, lease.metadata.resourceVersion)?;
+        match self.client.replace(key, &PostParams::default(), data) {
+            Ok(_) => Ok(()),
+            Err(e) => match DriverError::from(e) {
+                DriverError::OutOfSync | DriverError::ReleaseNotExist => {
+                    warn!("not releasing lease {} as it changed while being released", key);
+                    Ok(())
+                },
+                e => Err(e),
+            }
+        }
+    }
+
+    fn check(&self, key: &str, holder: &str) -> Result<(), DriverError> {
+        let lease = match self.client.get(key) {
+            Ok(l) => l,
+            Err(e) => match DriverError::from(e) {
+                DriverError::ReleaseNotExist => { return Err(DriverError::LockNotHeld{key: key.to_string()}) },
+                e => { return Err(e) }
+            }
+        };
+        if lease.spec.holder_identity.as_deref() != Some(holder) || LeaseLocker::is_expired(&lease.spec) {
+            return Err(DriverError::LockNotHeld{key: key.to_string()})
+        }
+        Ok(())
+    }
+
+    fn force_break(&self, key: &str) -> Result<(), DriverError> {
+        match self.client.delete(key, &DeleteParams::default()) {
+            Ok(_) => Ok(()),
+            Err(e) => match DriverError::from(e) {
+                DriverError::ReleaseNotExist => Ok(()),
+                e => Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kube::fake::FakeServer;
+
+    const TTL: Duration = Duration::from_secs(60);
+    const EXPIRED: Duration = Duration::from_secs(0);
+
+    // Runs the same checks against any locker
+    fn check_locker(locker: &dyn Locker) {
+        // Acquire, and renew by the same holder
+        locker.acquire("app", "a", TTL).unwrap();
+        locker.acquire("app", "a", TTL).unwrap();
+        locker.check("app", "a").unwrap();
+        match locker.acquire("app", "b", TTL) {
+            Err(DriverError::ReleaseLocked{holder}) => assert_eq!(holder, "a"),
+            r => panic!("expected the lock to be held by a, got {:?}", r),
+        }
+        assert!(matches!(locker.check("app", "b"), Err(DriverError::LockNotHeld{..})));
+
+        // Release by someone else leaves the lock alone, release by the
+        // holder frees it for anyone
+        locker.release("app", "b").unwrap();
+        locker.check("app", "a").unwrap();
+        locker.release("app", "a").unwrap();
+        assert!(matches!(locker.check("app", "a"), Err(DriverError::LockNotHeld{..})));
+        locker.acquire("app", "b", TTL).unwrap();
+        locker.release("app", "b").unwrap();
+
+        // An expired lock is no longer held, and can be taken over
+        locker.acquire("other", "a", EXPIRED).unwrap();
+        assert!(matches!(locker.check("other", "a"), Err(DriverError::LockNotHeld{..})));
+        locker.acquire("other", "b", TTL).unwrap();
+        locker.check("other", "b").unwrap();
+        // The old holder releasing after the takeover doesn't free it
+        locker.release("other", "a").unwrap();
+        locker.check("other", "b").unwrap();
+
+        locker.force_break("other").unwrap();
+        locker.acquire("other", "a", TTL).unwrap();
+    }
+
+    #[test]
+    fn memory_locker() {
+        check_locker(&MemoryLocker::new());
+    }
+
+    #[test]
+    fn lease_locker() {
+        let server = FakeServer::start();
+        check_locker(&LeaseLocker::new(server.client(), "default".to_string()));
+    }
+
+    #[test]
+    fn lease_release_keeps_lease() {
+        let server = FakeServer::start();
+        let locker = LeaseLocker::new(server.client(), "default".to_string());
+        let path = "/apis/coordination.k8s.io/v1/namespaces/default/leases/app";
+        let spec = |field: &str| server.object(path).unwrap()["spec"][field].clone();
+        locker.acquire("app", "a", TTL).unwrap();
+        locker.release("app", "a").unwrap();
+        // Releasing is a replace conditional on the version that was read,
+        // never an unconditional delete that could remove someone else's
+        // hold taken in between
+        assert!(server.requests().iter().all(|r| !r.starts_with("DELETE")));
+        assert_eq!(spec("holderIdentity"), "");
+
+        // A released lease can be taken without counting as a transition,
+        // while a takeover of an expired one does count
+        locker.acquire("app", "b", EXPIRED).unwrap();
+        assert_eq!(spec("leaseTransitions"), 0);
+        locker.acquire("app", "c", TTL).unwrap();
+        assert_eq!(spec("leaseTransitions"), 1);
+        assert_eq!(spec("holderIdentity"), "c");
+    }
+}
@@ -0,0 +1,153 @@
+// This module copies release history from one storage driver to another
+use crate::release::Release;
+use crate::storage::Storage;
+use crate::storage::driver::{Driver, DriverError, DecodeFailure, encode_release, decode_release};
+use log::{debug, info};
+use serde_json::Value;
+
+#[derive(Default)]
+pub struct MigrateOptions {
+    // Deletes every migrated revision from the source once all of them have
+    // been copied and verified
+    pub delete_source: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    // Keys of the revisions that were copied
+    pub migrated: Vec<String>,
+    // Keys of the revisions that already existed, unchanged, in the
+    // destination. This makes it safe to rerun an interrupted migration
+    pub skipped: Vec<String>,
+    // Objects in the source that could not be decoded, and so were not copied
+    pub failures: Vec<DecodeFailure>,
+}
+
+fn to_value(rel: &Release) -> Result<Value, DriverError> {
+    serde_json::to_value(rel).map_err(DriverError::Encode)
+}
+
+// Makes sure the release survives being encoded and decoded again, so nothing
+// is lost by storing it in another driver
+fn verify_roundtrip(rel: &Release) -> Result<(), DriverError> {
+    let decoded = decode_release(base64::decode(&encode_release(rel)?)?)?;
+    if to_value(&decoded)? != to_value(rel)? {
+        return Err(DriverError::InvalidData{message: format!("release {} v{} changes when encoded", rel.name, rel.version)})
+    }
+    Ok(())
+}
+
+// Copies every revision of every release in `from` into `to`. Each revision is
+// read back from the destination and compared to the source before the
+// source is (optionally) deleted. Nothing is deleted if any revision fails to
+// migrate, and only revisions that were verified are ever deleted
+pub fn migrate<A: Driver, B: Driver>(from: &Storage<A>, to: &Storage<B>, opts: &MigrateOptions) -> Result<MigrationReport, DriverError> {
+    info!("migrating releases from {} to {} storage", from.driver.name(), to.driver.name());
+    // Every revision would already exist in the destination, and deleting the
+    // source would then delete the whole history
+    let location = from.driver.location();
+    if location == to.driver.location() {
+        return Err(DriverError::SameStorage{location})
+    }
+    let list = from.driver.list_with_report(&|_| true)?;
+    let mut report = MigrationReport{
+        failures: list.failures,
+        ..Default::default()
+    };
+    let mut releases = list.releases;
+    releases.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+    // The keys of the revisions found in the destination exactly as they are
+    // in the source
+    let mut verified: Vec<String> = Vec::with_capacity(releases.len());
+
+    for rel in releases.iter() {
+        let key = from.make_key(&rel.name, &rel.version);
+        debug!("migrating {}", key);
+        verify_roundtrip(rel)?;
+        let expected = to_value(rel)?;
+        let existed = match to.driver.create(&key, rel.clone()) {
+            Ok(_) => false,
+            Err(ref e) if e.is_already_exists() => true,
+            Err(e) => { return Err(e) }
+        };
+        if to_value(&to.driver.get(&key)?)? != expected {
+            // If the release was already there, it is a different release
+            // that we shouldn't touch
+            if existed {
+                return Err(DriverError::ReleaseAlreadyExists)
+            }
+            return Err(DriverError::InvalidData{message: format!("release {} does not match the source after migrating", key)})
+        }
+        verified.push(key.clone());
+        if existed {
+            report.skipped.push(key);
+        } else {
+            report.migrated.push(key);
+        }
+    }
+
+    if opts.delete_source {
+        for key in verified.iter() {
+            from.driver.delete(key)?;
+        }
+    }
+    info!("migrated {} releases, skipped {}, unable to decode {}", report.migrated.len(), report.skipped.len(), report.failures.len());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::{Info, Status};
+    use crate::storage::{make_key, MaxHistory};
+    use crate::storage::driver::memory::Memory;
+
+    fn release(name: &str, version: usize) -> Release {
+        Release {
+            name: name.to_string(),
+            version,
+            info: Info {
+                status: Status::Deployed,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn migrates_and_deletes_source() {
+        let from = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        let to = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        from.create_locked(release("app", 1)).unwrap();
+        from.create_locked(release("app", 2)).unwrap();
+        to.create_locked(release("app", 1)).unwrap();
+        let report = migrate(&from, &to, &MigrateOptions{delete_source: true}).unwrap();
+        assert_eq!(report.migrated, vec![make_key("app", &2)]);
+        assert_eq!(report.skipped, vec![make_key("app", &1)]);
+        assert!(from.history("app").unwrap().is_empty());
+        assert_eq!(to.history("app").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_same_storage() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        storage.create_locked(release("app", 1)).unwrap();
+        match migrate(&storage, &storage, &MigrateOptions{delete_source: true}) {
+            Err(DriverError::SameStorage{..}) => (),
+            other => panic!("expected SameStorage, got {:?}", other),
+        }
+        assert_eq!(storage.history("app").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn keeps_source_when_destination_differs() {
+        let from = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        let to = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        from.create_locked(release("app", 1)).unwrap();
+        let mut other = release("app", 1);
+        other.manifest = "changed".to_string();
+        to.create_locked(other).unwrap();
+        assert!(migrate(&from, &to, &MigrateOptions{delete_source: true}).is_err());
+        assert_eq!(from.history("app").unwrap().len(), 1);
+    }
+}
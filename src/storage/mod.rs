@@ -1,80 +1,245 @@
+pub mod archive;
+pub mod asynchronous;
 pub mod driver;
+pub mod encryption;
+pub mod lock;
+pub mod migrate;
+pub mod retention;
+pub mod validate;
 
 use crate::release::{Release, Status};
 use crate::release::sort::*;
-use driver::{Driver, DriverError};
-use log::{info, debug, error};
-use std::collections::HashMap;
+use driver::{Driver, DriverError, ReleaseList, PageParams, ReleasePage, ReleaseEvent, Watch};
+use lock::Locker;
+use retention::RetentionPolicy;
+use validate::validate_release_name;
+use log::{info, debug, error, warn};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 
 pub const HELM_STORAGE_TYPE: &str = "sh.helm.release.v1";
 
+// How long a release lock is held before another client may take it over
+pub const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(300);
+
+// How long a single watch request waits for changes. This has to stay under
+// the 30 second timeout of the underlying HTTP client
+const WATCH_TIMEOUT: Duration = Duration::from_secs(20);
+
+// The longest a watcher waits before reconnecting after an error
+const MAX_WATCH_BACKOFF: Duration = Duration::from_secs(30);
+
+// How many times in a row a watcher tries to reconnect before returning the
+// error
+const MAX_WATCH_RETRIES: u32 = 5;
+
+pub fn make_key(release_name: &str, version: &usize) -> String {
+    return format!("{}.{}.v{}", HELM_STORAGE_TYPE, release_name, version);
+}
+
+pub fn make_lock_key(release_name: &str) -> String {
+    return format!("{}.{}.lock", HELM_STORAGE_TYPE, release_name);
+}
+
 pub enum MaxHistory {
     NoLimit,
     Limit(usize)
 }
 
+// The operations that modify a release and therefore need to hold its lock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Install,
+    Upgrade,
+    Rollback,
+    // Writing revisions copied from elsewhere, such as from an archive or
+    // Tiller. These may well end in a pending revision, so like rollbacks
+    // they are not refused for one
+    Import,
+}
+
 pub struct Storage<T> {
     pub driver: T,
-    max_history: MaxHistory
+    // No policy means the history is never pruned
+    retention: Option<Box<dyn RetentionPolicy>>,
+    locker: Box<dyn Locker>,
 }
 
 impl<T: Driver> Storage<T> {
+    // Release locks are taken with the driver's locker (see Driver::locker),
+    // which for the Kubernetes drivers is shared with every other client of
+    // the namespace. Use `with_locker` to pick another
     pub fn new(d: T, max: MaxHistory) -> Self {
+        let retention: Option<Box<dyn RetentionPolicy>> = match max {
+            MaxHistory::NoLimit => None,
+            MaxHistory::Limit(_) => Some(Box::new(max)),
+        };
+        let locker = d.locker();
         Storage {
             driver: d,
-            max_history: max
+            retention,
+            locker,
         }
     }
 
+    pub fn with_locker<L: Locker + 'static>(mut self, locker: L) -> Self {
+        self.locker = Box::new(locker);
+        self
+    }
+
+    // Replaces the MaxHistory given to `new` with another retention policy
+    pub fn with_retention<P: RetentionPolicy + 'static>(mut self, policy: P) -> Self {
+        self.retention = Some(Box::new(policy));
+        self
+    }
+
     fn make_key(&self, release_name: &str, version: &usize) -> String {
-        return format!("{}.{}.v{}", HELM_STORAGE_TYPE, release_name, version);
+        return make_key(release_name, version);
+    }
+
+    fn make_lock_key(&self, release_name: &str) -> String {
+        return make_lock_key(release_name);
     }
 
     fn remove_least_recent(&self, release_name: &str) -> Result<(), DriverError> {
-        let max = match self.max_history {
-            MaxHistory::NoLimit => { return Ok(()) },
-            MaxHistory::Limit(n) => n
+        self.prune(release_name)?;
+        Ok(())
+    }
+
+    // Deletes every revision of a release that the retention policy does not
+    // keep, returning the deleted revision numbers
+    pub fn prune(&self, release_name: &str) -> Result<Vec<usize>, DriverError> {
+        let policy = match self.retention {
+            Some(ref p) => p,
+            None => { return Ok(Vec::new()) }
         };
         let mut rels = self.history(release_name)?;
-        if rels.len() <= max {
-            return Ok(())
-        }
-        let overage = rels.len() - max;
         rels.sort_unstable_by(revision);
+        let keep = policy.keep(&rels);
+        let mut deleted = Vec::new();
         // Delete as many as possible. In the case of API throughput
         // limitations, multiple invocations of this function will eventually
         // delete them all, so only log an error
-        for r in rels.iter().take(overage) {
-            self.driver.delete(&self.make_key(release_name, &r.version)).unwrap_or_else(|e| {
-                error!("unable to delete old release {}: {}", release_name, e);
-                Release::default()    
-            });
+        for r in rels.iter().filter(|r| !keep.contains(&r.version)) {
+            match self.driver.delete(&self.make_key(release_name, &r.version)) {
+                Ok(_) => deleted.push(r.version),
+                Err(e) => error!("unable to delete old release {}: {}", release_name, e),
+            }
         }
 
-        Ok(())
+        Ok(deleted)
+    }
+
+    // Takes the lock for a release on behalf of `holder` before performing
+    // the given operation. The lock is held until the returned guard is
+    // dropped or `ttl` passes, after which another holder may take it over.
+    // Installs and upgrades are also refused while the latest revision is in
+    // a pending state, as that means another operation is (or was) running.
+    // Rollbacks are allowed so that a release stuck pending can be recovered
+    pub fn lock(&self, release_name: &str, op: Operation, holder: &str, ttl: Duration) -> Result<ReleaseLock<'_>, DriverError> {
+        debug!("locking release {} for {:?} by {}", release_name, op, holder);
+        let key = self.make_lock_key(release_name);
+        self.locker.acquire(&key, holder, ttl)?;
+        let guard = ReleaseLock {
+            locker: self.locker.as_ref(),
+            key,
+            holder: holder.to_string(),
+            released: false,
+        };
+        if op == Operation::Rollback || op == Operation::Import {
+            return Ok(guard)
+        }
+        match self.last(release_name) {
+            Ok(rel) => {
+                if rel.info.status.is_pending() {
+                    return Err(DriverError::OperationInProgress{status: rel.info.status})
+                }
+            },
+            Err(ref e) if e.is_not_found() => (),
+            Err(e) => { return Err(e) }
+        }
+        Ok(guard)
+    }
+
+    // Rebuilds the guard for a lock taken earlier and detached with
+    // ReleaseLock::detach. Dropping it leaves the lock held
+    pub(crate) fn held_lock(&self, key: String, holder: String) -> ReleaseLock<'_> {
+        ReleaseLock {
+            locker: self.locker.as_ref(),
+            key,
+            holder,
+            released: true,
+        }
+    }
+
+    // Removes the lock on a release regardless of who holds it. Only use this
+    // when the holder is known to be gone
+    pub fn break_lock(&self, release_name: &str) -> Result<(), DriverError> {
+        info!("forcibly breaking lock on release {}", release_name);
+        self.locker.force_break(&self.make_lock_key(release_name))
+    }
+
+    // Writes need the lock for the release, taken from this storage with
+    // `lock`, and fail with DriverError::LockNotHeld if it was for another
+    // release or has since expired
+    fn check_lock(&self, release_name: &str, lock: &ReleaseLock<'_>) -> Result<(), DriverError> {
+        if !lock.is_for(release_name) {
+            return Err(DriverError::LockNotHeld{key: self.make_lock_key(release_name)})
+        }
+        self.locker.check(&lock.key, &lock.holder)
+    }
+
+    // Errors from the driver for a single release are wrapped with the key
+    // and driver name, see DriverError::context
+    fn with_context<R>(&self, key: &str, res: Result<R, DriverError>) -> Result<R, DriverError> {
+        res.map_err(|e| e.context(key, &self.driver.name()))
     }
 
-    // TODO: These methods probably shouldn't return a driver error. Take care
-    // of this when refactoring the error code
     pub fn get(&self, release_name: &str, version: &usize) -> Result<Release, DriverError> {
         debug!("getting release {}", release_name);
-        return self.driver.get(&self.make_key(release_name, version))
+        let key = self.make_key(release_name, version);
+        return self.with_context(&key, self.driver.get(&key))
     }
 
-    pub fn create(&self, rel: Release) -> Result<(), DriverError> {
+    pub fn create(&self, rel: Release, lock: &ReleaseLock<'_>) -> Result<(), DriverError> {
         debug!("creating release {}", rel.name);
+        validate_release_name(&rel.name)?;
+        self.check_lock(&rel.name, lock)?;
         self.remove_least_recent(&rel.name)?;
-        return self.driver.create(&self.make_key(&rel.name, &rel.version), rel)
+        let key = self.make_key(&rel.name, &rel.version);
+        return self.with_context(&key, self.driver.create(&key, rel))
     }
 
-    pub fn update(&self, rel: Release) -> Result<(), DriverError> {
+    // If the release was read from storage, it carries the version of the
+    // object it was read from and drivers that support it will refuse to
+    // overwrite a newer version with DriverError::OutOfSync
+    pub fn update(&self, rel: Release, lock: &ReleaseLock<'_>) -> Result<(), DriverError> {
         debug!("updating release {}", rel.name);
-        return self.driver.update(&self.make_key(&rel.name, &rel.version), rel)
+        validate_release_name(&rel.name)?;
+        self.check_lock(&rel.name, lock)?;
+        let key = self.make_key(&rel.name, &rel.version);
+        return self.with_context(&key, self.driver.update(&key, rel))
+    }
+
+    // Writes `rel` only if the stored release has not changed since `current`
+    // was read. When another client got there first, DriverError::OutOfSync
+    // is returned and the caller should get the release again and retry
+    pub fn compare_and_swap(&self, current: &Release, mut rel: Release, lock: &ReleaseLock<'_>) -> Result<(), DriverError> {
+        debug!("updating release {} at version {:?}", rel.name, current.resource_version);
+        validate_release_name(&rel.name)?;
+        self.check_lock(&rel.name, lock)?;
+        rel.resource_version = match current.resource_version {
+            Some(ref v) => Some(v.clone()),
+            None => { return Err(DriverError::MissingResourceVersion) }
+        };
+        let key = self.make_key(&rel.name, &rel.version);
+        return self.with_context(&key, self.driver.update(&key, rel))
     }
 
     pub fn delete(&self, release_name: &str, version: &usize) -> Result<Release, DriverError> {
         debug!("deleting release {}", release_name);
-        return self.driver.delete(&self.make_key(release_name, version))
+        let key = self.make_key(release_name, version);
+        return self.with_context(&key, self.driver.delete(&key))
     }
 
     // These are helpful shorthand methods for the most common listing
@@ -82,17 +247,45 @@ impl<T: Driver> Storage<T> {
     // method
     pub fn list_all(&self) -> Result<Vec<Release>, DriverError> {
         debug!("listing all releases in {} storage", self.driver.name());
-        return self.driver.list(|_| return true)
+        return self.driver.list(&|_| return true)
+    }
+
+    // Like list_all, but also reports the storage objects that could not be
+    // decoded rather than only logging them
+    pub fn list_all_with_report(&self) -> Result<ReleaseList, DriverError> {
+        debug!("listing all releases in {} storage", self.driver.name());
+        return self.driver.list_with_report(&|_| return true)
+    }
+
+    // Lists a single page of releases. Pass the continue token of the
+    // returned page back in `params` to get the next one
+    pub fn list_page(&self, params: &PageParams) -> Result<ReleasePage, DriverError> {
+        debug!("listing page of releases in {} storage", self.driver.name());
+        return self.driver.list_page(params, &|_| return true)
+    }
+
+    // Iterates over every release, fetching and decoding `page_size` storage
+    // objects at a time so that only one page is held in memory
+    pub fn iter_all(&self, page_size: usize) -> ReleaseIter<'_, T> {
+        ReleaseIter {
+            storage: self,
+            params: PageParams{
+                limit: Some(page_size),
+                continue_token: None,
+            },
+            page: Vec::new().into_iter(),
+            done: false,
+        }
     }
 
     pub fn list_uninstalled(&self) -> Result<Vec<Release>, DriverError> {
         debug!("listing all uninstalled releases in {} storage", self.driver.name());
-        return self.driver.list(|rel| return rel.info.status == Status::Uninstalled)
+        return self.driver.list(&|rel| return rel.info.status == Status::Uninstalled)
     }
 
     pub fn list_deployed(&self) -> Result<Vec<Release>, DriverError> {
         debug!("listing all deployed releases in {} storage", self.driver.name());
-        return self.driver.list(|rel| return rel.info.status == Status::Deployed)
+        return self.driver.list(&|rel| return rel.info.status == Status::Deployed)
     }
 
     pub fn history(&self, release_name: &str) -> Result<Vec<Release>, DriverError> {
@@ -137,3 +330,398 @@ impl<T: Driver> Storage<T> {
         Ok(all.pop().unwrap())
     }
 }
+
+impl<T: Driver + Watch> Storage<T> {
+    // Watches for changes to releases made from now on
+    pub fn watch(&self) -> Result<ReleaseWatcher<'_, T>, DriverError> {
+        let version = self.driver.current_version()?;
+        Ok(self.watch_from(version))
+    }
+
+    // Watches for changes made after the given resource version, such as one
+    // saved from ReleaseWatcher::resource_version by an earlier watch
+    pub fn watch_from(&self, resource_version: String) -> ReleaseWatcher<'_, T> {
+        debug!("watching releases in {} storage from version {}", self.driver.name(), resource_version);
+        ReleaseWatcher {
+            storage: self,
+            resource_version,
+            pending: VecDeque::new(),
+            failures: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl<T: Driver> Storage<T> {
+    // Creates a release under a lock of its own, for tests that aren't about
+    // locking
+    pub fn create_locked(&self, rel: Release) -> Result<(), DriverError> {
+        let lock = self.lock(&rel.name, Operation::Import, "test", DEFAULT_LOCK_TTL)?;
+        self.create(rel, &lock)
+    }
+}
+
+// Yields changes to releases as they happen, blocking until there is one.
+// Connection errors are retried with a backoff, reconnecting from the last
+// seen version so nothing is missed. If the version has expired, the watcher
+// restarts from the current version and returns DriverError::OutOfSync so the
+// caller knows to list releases again to catch up on what it missed. The
+// watcher never ends, and can keep being used after returning an error
+pub struct ReleaseWatcher<'a, T> {
+    storage: &'a Storage<T>,
+    resource_version: String,
+    pending: VecDeque<ReleaseEvent>,
+    failures: u32,
+}
+
+impl<'a, T: Driver + Watch> ReleaseWatcher<'a, T> {
+    // The version of the last change returned, which can be used to resume
+    // watching later with Storage::watch_from
+    pub fn resource_version(&self) -> &str {
+        &self.resource_version
+    }
+
+    fn backoff(&self) -> Duration {
+        let backoff = Duration::from_secs(1 << self.failures.min(5));
+        backoff.min(MAX_WATCH_BACKOFF)
+    }
+}
+
+impl<'a, T: Driver + Watch> Iterator for ReleaseWatcher<'a, T> {
+    type Item = Result<ReleaseEvent, DriverError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event))
+            }
+            let err = match self.storage.driver.watch_events(&self.resource_version, WATCH_TIMEOUT) {
+                Ok((events, version)) => {
+                    self.failures = 0;
+                    self.resource_version = version;
+                    self.pending.extend(events);
+                    continue
+                },
+                Err(e) => e,
+            };
+            match err {
+                DriverError::OutOfSync => {
+                    warn!("resource version {} has expired, restarting watch", self.resource_version);
+                    match self.storage.driver.current_version() {
+                        Ok(v) => self.resource_version = v,
+                        Err(e) => { return Some(Err(e)) }
+                    }
+                    return Some(Err(DriverError::OutOfSync))
+                },
+                DriverError::KubeError(_) if self.failures < MAX_WATCH_RETRIES => {
+                    self.failures += 1;
+                    let backoff = self.backoff();
+                    warn!("watch failed, reconnecting in {:?}: {}", backoff, err);
+                    std::thread::sleep(backoff);
+                },
+                e => {
+                    self.failures = 0;
+                    return Some(Err(e))
+                },
+            }
+        }
+    }
+}
+
+// Yields releases a page at a time. Storage objects that can't be decoded are
+// logged and skipped, as they are by list_all. Iteration stops after the
+// first error
+pub struct ReleaseIter<'a, T> {
+    storage: &'a Storage<T>,
+    params: PageParams,
+    page: std::vec::IntoIter<Release>,
+    done: bool,
+}
+
+impl<'a, T: Driver> Iterator for ReleaseIter<'a, T> {
+    type Item = Result<Release, DriverError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Pages can come back empty after filtering, so keep fetching until
+        // there is a release or nothing left
+        loop {
+            if let Some(rel) = self.page.next() {
+                return Some(Ok(rel))
+            }
+            if self.done {
+                return None
+            }
+            let page = match self.storage.list_page(&self.params) {
+                Ok(p) => p,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e))
+                }
+            };
+            self.done = page.continue_token.is_none();
+            self.params.continue_token = page.continue_token;
+            self.page = page.list.into_releases().into_iter();
+        }
+    }
+}
+
+// ReleaseLock is a held release lock. It is released when dropped, but can be
+// released explicitly with `unlock` to see any error from doing so
+pub struct ReleaseLock<'a> {
+    locker: &'a dyn Locker,
+    key: String,
+    holder: String,
+    released: bool,
+}
+
+impl<'a> ReleaseLock<'a> {
+    // Returns whether this is the lock for the named release
+    pub fn is_for(&self, release_name: &str) -> bool {
+        self.key == make_lock_key(release_name)
+    }
+
+    // Returns the key and holder of the lock without releasing it, so that
+    // it can be held without borrowing the storage (see AsyncReleaseLock)
+    pub(crate) fn detach(mut self) -> (String, String) {
+        self.released = true;
+        (std::mem::take(&mut self.key), std::mem::take(&mut self.holder))
+    }
+
+    pub fn unlock(mut self) -> Result<(), DriverError> {
+        self.released = true;
+        self.locker.release(&self.key, &self.holder)
+    }
+}
+
+impl<'a> Drop for ReleaseLock<'a> {
+    fn drop(&mut self) {
+        if self.released {
+            return
+        }
+        self.locker.release(&self.key, &self.holder).unwrap_or_else(|e| {
+            error!("unable to release lock {}: {}", self.key, e);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::Info;
+    use crate::storage::driver::memory::Memory;
+    use crate::storage::driver::tests::expired_error;
+
+    fn release(name: &str, version: usize, status: Status) -> Release {
+        Release {
+            name: name.to_string(),
+            version,
+            info: Info {
+                status,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn versions(storage: &Storage<Memory>, name: &str) -> Vec<usize> {
+        let mut versions: Vec<usize> = storage.history(name).unwrap().iter().map(|r| r.version).collect();
+        versions.sort_unstable();
+        versions
+    }
+
+    #[test]
+    fn create_removes_least_recent() {
+        let storage = Storage::new(Memory::new(), MaxHistory::Limit(2));
+        for v in 1..=5 {
+            storage.create_locked(release("app", v, Status::Superseded)).unwrap();
+        }
+        // The history is pruned to the limit before each new revision is
+        // added
+        assert_eq!(versions(&storage, "app"), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn create_only_prunes_its_release() {
+        let storage = Storage::new(Memory::new(), MaxHistory::Limit(1));
+        for v in 1..=3 {
+            storage.create_locked(release("app", v, Status::Superseded)).unwrap();
+        }
+        storage.create_locked(release("other", 1, Status::Deployed)).unwrap();
+        storage.create_locked(release("other", 2, Status::Deployed)).unwrap();
+        assert_eq!(versions(&storage, "app"), vec![2, 3]);
+        assert_eq!(versions(&storage, "other"), vec![1, 2]);
+    }
+
+    #[test]
+    fn create_without_limit_keeps_everything() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        for v in 1..=5 {
+            storage.create_locked(release("app", v, Status::Superseded)).unwrap();
+        }
+        assert_eq!(versions(&storage, "app"), vec![1, 2, 3, 4, 5]);
+        assert!(storage.prune("app").unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_returns_deleted_revisions() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        for v in 1..=4 {
+            storage.create_locked(release("app", v, Status::Superseded)).unwrap();
+        }
+        let storage = storage.with_retention(MaxHistory::Limit(1));
+        let mut deleted = storage.prune("app").unwrap();
+        deleted.sort_unstable();
+        assert_eq!(deleted, vec![1, 2, 3]);
+        assert_eq!(versions(&storage, "app"), vec![4]);
+    }
+
+    #[test]
+    fn last_deployed_returns_latest_deployed() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        storage.create_locked(release("app", 1, Status::Superseded)).unwrap();
+        storage.create_locked(release("app", 2, Status::Deployed)).unwrap();
+        storage.create_locked(release("app", 3, Status::Failed)).unwrap();
+        storage.create_locked(release("other", 4, Status::Deployed)).unwrap();
+        assert_eq!(storage.last_deployed("app").unwrap().version, 2);
+        assert_eq!(storage.last("app").unwrap().version, 3);
+    }
+
+    #[test]
+    fn last_deployed_ignores_revision_order_in_storage() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        // Revisions sort by number, not by when they were written
+        storage.create_locked(release("app", 10, Status::Deployed)).unwrap();
+        storage.create_locked(release("app", 9, Status::Deployed)).unwrap();
+        assert_eq!(storage.last_deployed("app").unwrap().version, 10);
+    }
+
+    #[test]
+    fn last_deployed_without_deployed_revision() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        assert!(storage.last_deployed("app").unwrap_err().is_not_found());
+        storage.create_locked(release("app", 1, Status::Failed)).unwrap();
+        assert!(storage.last_deployed("app").unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn writes_need_the_release_lock() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        let not_held = |r: Result<(), DriverError>| matches!(r, Err(DriverError::LockNotHeld{..}));
+        let lock = storage.lock("app", Operation::Install, "a", DEFAULT_LOCK_TTL).unwrap();
+        storage.create(release("app", 1, Status::Deployed), &lock).unwrap();
+        storage.update(release("app", 1, Status::Superseded), &lock).unwrap();
+
+        // A lock for another release
+        let other = storage.lock("other", Operation::Install, "a", DEFAULT_LOCK_TTL).unwrap();
+        assert!(not_held(storage.create(release("app", 2, Status::Deployed), &other)));
+
+        // A lock taken from another storage's locker
+        let elsewhere = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        let foreign = elsewhere.lock("app", Operation::Install, "a", DEFAULT_LOCK_TTL).unwrap();
+        drop(lock);
+        assert!(not_held(storage.create(release("app", 2, Status::Deployed), &foreign)));
+
+        // A lock that expired and was taken over by someone else
+        let expired = storage.lock("app", Operation::Install, "a", Duration::from_secs(0)).unwrap();
+        assert!(not_held(storage.create(release("app", 2, Status::Deployed), &expired)));
+        let current = storage.lock("app", Operation::Install, "b", DEFAULT_LOCK_TTL).unwrap();
+        let rel = storage.get("app", &1).unwrap();
+        assert!(not_held(storage.compare_and_swap(&rel, release("app", 1, Status::Failed), &expired)));
+        storage.compare_and_swap(&rel, release("app", 1, Status::Failed), &current).unwrap();
+    }
+
+    // Memory, with list_page failing once `pages` pages have been listed and
+    // watching from the `expired` version failing as it does in Kubernetes
+    struct Faulty {
+        memory: Memory,
+        pages: usize,
+        listed: std::cell::Cell<usize>,
+        expired: String,
+    }
+
+    impl Faulty {
+        fn new(pages: usize, expired: &str) -> Self {
+            Faulty{memory: Memory::new(), pages, listed: std::cell::Cell::new(0), expired: expired.to_string()}
+        }
+    }
+
+    impl Driver for Faulty {
+        fn name(&self) -> String {self.memory.name()}
+        fn location(&self) -> String {self.memory.location()}
+        fn create(&self, key: &String, rel: Release) -> Result<(), DriverError> {self.memory.create(key, rel)}
+        fn update(&self, key: &String, rel: Release) -> Result<(), DriverError> {self.memory.update(key, rel)}
+        fn delete(&self, key: &String) -> Result<Release, DriverError> {self.memory.delete(key)}
+        fn get(&self, key: &String) -> Result<Release, DriverError> {self.memory.get(key)}
+        fn list_with_report(&self, filter: &dyn Fn(&Release) -> bool) -> Result<ReleaseList, DriverError> {
+            self.memory.list_with_report(filter)
+        }
+        fn list_page(&self, params: &PageParams, filter: &dyn Fn(&Release) -> bool) -> Result<ReleasePage, DriverError> {
+            if self.listed.get() == self.pages {
+                return Err(DriverError::Cancelled)
+            }
+            self.listed.set(self.listed.get() + 1);
+            self.memory.list_page(params, filter)
+        }
+        fn query(&self, labels: HashMap<String, String>) -> Result<Vec<Release>, DriverError> {self.memory.query(labels)}
+        fn labels(&self, key: &String) -> Result<HashMap<String, String>, DriverError> {self.memory.labels(key)}
+        fn restore_timestamps(&self, key: &String, labels: &HashMap<String, String>) -> Result<(), DriverError> {
+            self.memory.restore_timestamps(key, labels)
+        }
+    }
+
+    impl Watch for Faulty {
+        fn current_version(&self) -> Result<String, DriverError> {self.memory.current_version()}
+        fn watch_events(&self, resource_version: &str, timeout: Duration) -> Result<(Vec<ReleaseEvent>, String), DriverError> {
+            if resource_version == self.expired {
+                return Err(DriverError::from(expired_error()))
+            }
+            self.memory.watch_events(resource_version, timeout)
+        }
+    }
+
+    fn failing_pages(pages: usize) -> Storage<Faulty> {
+        let storage = Storage::new(Faulty::new(pages, ""), MaxHistory::NoLimit);
+        for v in 1..=5 {
+            storage.create_locked(release("app", v, Status::Superseded)).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn iter_all_fetches_every_page() {
+        let storage = failing_pages(usize::MAX);
+        let versions: Vec<usize> = storage.iter_all(2).map(|r| r.unwrap().version).collect();
+        assert_eq!(versions, vec![1, 2, 3, 4, 5]);
+        assert_eq!(storage.driver.listed.get(), 3);
+    }
+
+    #[test]
+    fn iter_all_stops_after_error() {
+        let storage = failing_pages(1);
+        let mut iter = storage.iter_all(2);
+        assert_eq!(iter.next().unwrap().unwrap().version, 1);
+        assert_eq!(iter.next().unwrap().unwrap().version, 2);
+        assert!(matches!(iter.next(), Some(Err(DriverError::Cancelled))));
+        assert!(iter.next().is_none());
+        // Nothing more is fetched once the error has been returned
+        storage.driver.listed.set(0);
+        assert!(iter.next().is_none());
+        assert_eq!(storage.driver.listed.get(), 0);
+    }
+
+    #[test]
+    fn watch_restarts_from_expired_version() {
+        let storage = Storage::new(Faulty::new(usize::MAX, "1"), MaxHistory::NoLimit);
+        storage.create_locked(release("app", 1, Status::Superseded)).unwrap();
+        storage.create_locked(release("app", 2, Status::Deployed)).unwrap();
+        let mut watcher = storage.watch_from("1".to_string());
+        // The watch is restarted from the current version rather than retried
+        assert!(matches!(watcher.next(), Some(Err(DriverError::OutOfSync))));
+        assert_eq!(watcher.resource_version(), "2");
+        storage.create_locked(release("app", 3, Status::Deployed)).unwrap();
+        match watcher.next() {
+            Some(Ok(ReleaseEvent::Added(rel))) => assert_eq!(rel.version, 3),
+            e => panic!("unexpected watch result {:?}", e.map(|r| r.map(|_| ()))),
+        }
+    }
+}
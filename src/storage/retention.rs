@@ -0,0 +1,189 @@
+// This module contains the policies that decide which revisions of a release
+// are kept when its history is pruned. Policies only ever say what to keep,
+// so combining them with `or` keeps a revision if any of them wants it
+use crate::release::{Release, Status};
+use crate::storage::MaxHistory;
+use chrono::Utc;
+use std::collections::HashSet;
+use std::time::Duration;
+
+pub trait RetentionPolicy: Send + Sync {
+    // Returns the revision numbers in `history` that must not be deleted.
+    // The history is that of a single release, sorted oldest first
+    fn keep(&self, history: &[Release]) -> HashSet<usize>;
+
+    fn or<P: RetentionPolicy + 'static>(self, other: P) -> Any
+    where
+        Self: Sized + 'static,
+    {
+        Any(vec![Box::new(self), Box::new(other)])
+    }
+}
+
+impl RetentionPolicy for MaxHistory {
+    fn keep(&self, history: &[Release]) -> HashSet<usize> {
+        match self {
+            MaxHistory::NoLimit => history.iter().map(|r| r.version).collect(),
+            MaxHistory::Limit(n) => KeepLast(*n).keep(history),
+        }
+    }
+}
+
+// Keeps the most recent N revisions
+pub struct KeepLast(pub usize);
+
+impl RetentionPolicy for KeepLast {
+    fn keep(&self, history: &[Release]) -> HashSet<usize> {
+        history.iter().rev().take(self.0).map(|r| r.version).collect()
+    }
+}
+
+// Keeps the most recent deployed revision, so there is always something to
+// roll back to
+pub struct KeepLastDeployed;
+
+impl RetentionPolicy for KeepLastDeployed {
+    fn keep(&self, history: &[Release]) -> HashSet<usize> {
+        history.iter().rev().find(|r| r.info.status == Status::Deployed).map(|r| r.version).into_iter().collect()
+    }
+}
+
+// Keeps revisions last deployed within the given duration. Revisions that
+// were never deployed are not kept by this policy
+pub struct KeepNewerThan(pub Duration);
+
+impl RetentionPolicy for KeepNewerThan {
+    fn keep(&self, history: &[Release]) -> HashSet<usize> {
+        let now = Utc::now();
+        history.iter().filter(|r| {
+            match r.info.last_deployed {
+                // Deployments in the future (from clock skew) count as new
+                Some(t) => (now - t).to_std().map(|age| age < self.0).unwrap_or(true),
+                None => false,
+            }
+        }).map(|r| r.version).collect()
+    }
+}
+
+// Keeps every failed revision so they can be investigated later
+pub struct KeepFailed;
+
+impl RetentionPolicy for KeepFailed {
+    fn keep(&self, history: &[Release]) -> HashSet<usize> {
+        history.iter().filter(|r| r.info.status == Status::Failed).map(|r| r.version).collect()
+    }
+}
+
+// Keeps every revision kept by any of its policies
+pub struct Any(pub Vec<Box<dyn RetentionPolicy>>);
+
+impl RetentionPolicy for Any {
+    fn keep(&self, history: &[Release]) -> HashSet<usize> {
+        self.0.iter().flat_map(|p| p.keep(history)).collect()
+    }
+
+    fn or<P: RetentionPolicy + 'static>(mut self, other: P) -> Any {
+        self.0.push(Box::new(other));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::Info;
+    use crate::storage::Storage;
+    use crate::storage::driver::memory::Memory;
+    use chrono::{DateTime, Duration as ChronoDuration};
+
+    fn release(version: usize, status: Status, last_deployed: Option<DateTime<Utc>>) -> Release {
+        Release {
+            name: "app".to_string(),
+            version,
+            info: Info {
+                status,
+                last_deployed,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Numbers the revisions from 1, oldest first
+    fn history(statuses: &[Status]) -> Vec<Release> {
+        statuses.iter().enumerate().map(|(i, s)| release(i + 1, s.clone(), None)).collect()
+    }
+
+    fn sorted(keep: HashSet<usize>) -> Vec<usize> {
+        let mut keep: Vec<usize> = keep.into_iter().collect();
+        keep.sort_unstable();
+        keep
+    }
+
+    fn sample() -> Vec<Release> {
+        history(&[Status::Superseded, Status::Failed, Status::Deployed, Status::Failed, Status::Superseded, Status::PendingUpgrade])
+    }
+
+    #[test]
+    fn max_history() {
+        assert_eq!(sorted(MaxHistory::Limit(2).keep(&sample())), vec![5, 6]);
+        assert_eq!(sorted(MaxHistory::NoLimit.keep(&sample())), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn keep_last() {
+        assert_eq!(sorted(KeepLast(3).keep(&sample())), vec![4, 5, 6]);
+        assert_eq!(sorted(KeepLast(10).keep(&sample())), vec![1, 2, 3, 4, 5, 6]);
+        assert!(KeepLast(0).keep(&sample()).is_empty());
+    }
+
+    #[test]
+    fn keep_last_deployed() {
+        assert_eq!(sorted(KeepLastDeployed.keep(&sample())), vec![3]);
+        let none_deployed = history(&[Status::Failed, Status::Superseded]);
+        assert!(KeepLastDeployed.keep(&none_deployed).is_empty());
+    }
+
+    #[test]
+    fn keep_failed() {
+        assert_eq!(sorted(KeepFailed.keep(&sample())), vec![2, 4]);
+    }
+
+    #[test]
+    fn keep_newer_than() {
+        let now = Utc::now();
+        let history = vec![
+            release(1, Status::Superseded, Some(now - ChronoDuration::days(30))),
+            release(2, Status::Superseded, Some(now - ChronoDuration::hours(2))),
+            release(3, Status::Failed, None),
+            // Clock skew can put a deployment in the future
+            release(4, Status::Deployed, Some(now + ChronoDuration::minutes(5))),
+        ];
+        assert_eq!(sorted(KeepNewerThan(Duration::from_secs(24 * 60 * 60)).keep(&history)), vec![2, 4]);
+    }
+
+    #[test]
+    fn or_keeps_anything_a_policy_keeps() {
+        let policy = KeepLast(1).or(KeepLastDeployed);
+        assert_eq!(sorted(policy.keep(&sample())), vec![3, 6]);
+        let policy = KeepLast(1).or(KeepLastDeployed).or(KeepFailed);
+        assert_eq!(policy.0.len(), 3);
+        assert_eq!(sorted(policy.keep(&sample())), vec![2, 3, 4, 6]);
+        assert!(Any(Vec::new()).keep(&sample()).is_empty());
+    }
+
+    #[test]
+    fn storage_prunes_with_policy() {
+        let storage = Storage::new(Memory::new(), MaxHistory::NoLimit);
+        for rel in sample().into_iter() {
+            storage.create_locked(rel).unwrap();
+        }
+        let storage = storage.with_retention(KeepLast(2).or(KeepLastDeployed).or(KeepFailed));
+        let mut deleted = storage.prune("app").unwrap();
+        deleted.sort_unstable();
+        assert_eq!(deleted, vec![1]);
+        let mut versions: Vec<usize> = storage.history("app").unwrap().iter().map(|r| r.version).collect();
+        versions.sort_unstable();
+        assert_eq!(versions, vec![2, 3, 4, 5, 6]);
+    }
+}
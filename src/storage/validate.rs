@@ -0,0 +1,176 @@
+// This module checks release names and labels before they are written or
+// used in a query, so that bad input gets a clear error rather than an
+// opaque one from the API server
+use crate::storage::driver::DriverError;
+
+// Helm limits release names to 53 characters, leaving room in the 63
+// character limit on label values and resource names for a suffix
+pub const MAX_RELEASE_NAME_LEN: usize = 53;
+
+const MAX_LABEL_NAME_LEN: usize = 63;
+const MAX_LABEL_VALUE_LEN: usize = 63;
+const MAX_DNS_SUBDOMAIN_LEN: usize = 253;
+
+fn is_alphanumeric(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit()
+}
+
+// Checks a single DNS-1123 label: lowercase alphanumerics and '-', starting
+// and ending with an alphanumeric
+fn check_dns_label(label: &str) -> Result<(), String> {
+    if label.is_empty() {
+        return Err("must not have empty parts between dots".to_string())
+    }
+    if !label.chars().all(|c| is_alphanumeric(c) || c == '-') {
+        return Err("must consist of lowercase alphanumeric characters, '-' or '.'".to_string())
+    }
+    if label.starts_with('-') || label.ends_with('-') {
+        return Err("each part must start and end with an alphanumeric character".to_string())
+    }
+    Ok(())
+}
+
+// Checks a DNS-1123 subdomain: DNS-1123 labels separated by dots
+fn check_dns_subdomain(name: &str) -> Result<(), String> {
+    if name.len() > MAX_DNS_SUBDOMAIN_LEN {
+        return Err(format!("must be no more than {} characters", MAX_DNS_SUBDOMAIN_LEN))
+    }
+    name.split('.').try_for_each(check_dns_label)
+}
+
+// Checks the name part of a label key, or a non-empty label value:
+// alphanumerics, '-', '_' and '.', starting and ending with an alphanumeric
+fn check_label_chars(s: &str, max: usize) -> Result<(), String> {
+    if s.len() > max {
+        return Err(format!("must be no more than {} characters", max))
+    }
+    if !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err("must consist of alphanumeric characters, '-', '_' or '.'".to_string())
+    }
+    if !s.starts_with(|c: char| c.is_ascii_alphanumeric()) || !s.ends_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("must start and end with an alphanumeric character".to_string())
+    }
+    Ok(())
+}
+
+// Release names must be DNS-1123 subdomains of at most 53 characters, as
+// they are used in the names of storage objects and the resources a chart
+// creates
+pub fn validate_release_name(name: &str) -> Result<(), DriverError> {
+    let invalid = |reason: String| DriverError::InvalidReleaseName{name: name.to_string(), reason};
+    if name.is_empty() {
+        return Err(invalid("must not be empty".to_string()))
+    }
+    if name.len() > MAX_RELEASE_NAME_LEN {
+        return Err(invalid(format!("must be no more than {} characters", MAX_RELEASE_NAME_LEN)))
+    }
+    check_dns_subdomain(name).map_err(invalid)
+}
+
+// Label keys are an optional DNS subdomain prefix and a slash, followed by a
+// name of at most 63 characters
+pub fn validate_label_key(key: &str) -> Result<(), DriverError> {
+    let invalid = |reason: String| DriverError::InvalidLabel{label: key.to_string(), reason};
+    let name = match key.rfind('/') {
+        Some(i) => {
+            check_dns_subdomain(&key[..i]).map_err(|e| invalid(format!("prefix {}", e)))?;
+            &key[i + 1..]
+        },
+        None => key,
+    };
+    if name.is_empty() {
+        return Err(invalid("name must not be empty".to_string()))
+    }
+    check_label_chars(name, MAX_LABEL_NAME_LEN).map_err(invalid)
+}
+
+// Label values may be empty, otherwise they follow the same rules as the
+// name part of a key
+pub fn validate_label(key: &str, value: &str) -> Result<(), DriverError> {
+    validate_label_key(key)?;
+    if value.is_empty() {
+        return Ok(())
+    }
+    check_label_chars(value, MAX_LABEL_VALUE_LEN)
+        .map_err(|reason| DriverError::InvalidLabel{label: format!("{}={}", key, value), reason: format!("value {}", reason)})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_names() {
+        let cases: Vec<(String, bool)> = vec![
+            ("app".to_string(), true),
+            ("my-app-2".to_string(), true),
+            ("0app".to_string(), true),
+            ("app.example.com".to_string(), true),
+            ("a".repeat(MAX_RELEASE_NAME_LEN), true),
+            ("a".repeat(MAX_RELEASE_NAME_LEN + 1), false),
+            ("".to_string(), false),
+            ("-app".to_string(), false),
+            ("app-".to_string(), false),
+            ("app.-web".to_string(), false),
+            ("App".to_string(), false),
+            ("my_app".to_string(), false),
+            ("app..web".to_string(), false),
+            (".app".to_string(), false),
+            ("app.".to_string(), false),
+            ("app,owner=helm".to_string(), false),
+        ];
+        for (name, valid) in cases {
+            let result = validate_release_name(&name);
+            assert_eq!(result.is_ok(), valid, "{:?}: {:?}", name, result.err().map(|e| e.to_string()));
+        }
+    }
+
+    #[test]
+    fn label_keys() {
+        let long_prefix = format!("{}.com", "a".repeat(MAX_DNS_SUBDOMAIN_LEN));
+        let cases: Vec<(String, bool)> = vec![
+            ("owner".to_string(), true),
+            ("app.kubernetes.io/name".to_string(), true),
+            ("helm.sh/chart_Version-1.x".to_string(), true),
+            ("a".repeat(MAX_LABEL_NAME_LEN), true),
+            ("a".repeat(MAX_LABEL_NAME_LEN + 1), false),
+            (format!("example.com/{}", "a".repeat(MAX_LABEL_NAME_LEN + 1)), false),
+            (format!("{}/name", long_prefix), false),
+            ("".to_string(), false),
+            ("example.com/".to_string(), false),
+            ("Example.com/name".to_string(), false),
+            ("-example.com/name".to_string(), false),
+            ("example..com/name".to_string(), false),
+            ("/name".to_string(), false),
+            ("-name".to_string(), false),
+            ("name_".to_string(), false),
+            ("na me".to_string(), false),
+        ];
+        for (key, valid) in cases {
+            let result = validate_label_key(&key);
+            assert_eq!(result.is_ok(), valid, "{:?}: {:?}", key, result.err().map(|e| e.to_string()));
+        }
+    }
+
+    #[test]
+    fn label_values() {
+        let cases: Vec<(String, bool)> = vec![
+            ("".to_string(), true),
+            ("helm".to_string(), true),
+            ("DEPLOYED".to_string(), true),
+            ("v1.2.3_rc-1".to_string(), true),
+            ("a".repeat(MAX_LABEL_VALUE_LEN), true),
+            ("a".repeat(MAX_LABEL_VALUE_LEN + 1), false),
+            ("-helm".to_string(), false),
+            ("helm.".to_string(), false),
+            ("helm,name=app".to_string(), false),
+            ("a/b".to_string(), false),
+        ];
+        for (value, valid) in cases {
+            let result = validate_label("owner", &value);
+            assert_eq!(result.is_ok(), valid, "{:?}: {:?}", value, result.err().map(|e| e.to_string()));
+        }
+        // The key is checked too
+        assert!(validate_label("-owner", "helm").is_err());
+    }
+}